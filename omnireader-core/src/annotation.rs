@@ -1,5 +1,7 @@
 //! Annotation and reading position models
 
+use crate::error::OmniReaderError;
+use crate::locator::Locator;
 use uniffi;
 
 /// Type of annotation
@@ -7,6 +9,60 @@ use uniffi;
 pub enum AnnotationType {
     Highlight,
     Note,
+    Bookmark,
+}
+
+impl AnnotationType {
+    /// Get the string stored in the database
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AnnotationType::Highlight => "highlight",
+            AnnotationType::Note => "note",
+            AnnotationType::Bookmark => "bookmark",
+        }
+    }
+
+    /// Parse from the database string
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "highlight" => Some(AnnotationType::Highlight),
+            "note" => Some(AnnotationType::Note),
+            "bookmark" => Some(AnnotationType::Bookmark),
+            _ => None,
+        }
+    }
+}
+
+/// How well an annotation's stored location matches the current book content
+#[derive(Debug, Clone, Copy, PartialEq, Eq, uniffi::Enum)]
+pub enum AnchorStatus {
+    /// Location found where it was recorded
+    Anchored,
+    /// Location was moved by re-anchoring against new content
+    Relocated,
+    /// Location could not be found and needs review
+    Orphaned,
+}
+
+impl AnchorStatus {
+    /// Get the string stored in the database
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AnchorStatus::Anchored => "anchored",
+            AnchorStatus::Relocated => "relocated",
+            AnchorStatus::Orphaned => "orphaned",
+        }
+    }
+
+    /// Parse from the database string
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "anchored" => Some(AnchorStatus::Anchored),
+            "relocated" => Some(AnchorStatus::Relocated),
+            "orphaned" => Some(AnchorStatus::Orphaned),
+            _ => None,
+        }
+    }
 }
 
 /// Highlight color presets
@@ -20,6 +76,15 @@ pub enum HighlightColor {
 }
 
 impl HighlightColor {
+    /// Every preset, in palette order
+    pub const ALL: [HighlightColor; 5] = [
+        HighlightColor::Yellow,
+        HighlightColor::Green,
+        HighlightColor::Blue,
+        HighlightColor::Pink,
+        HighlightColor::Orange,
+    ];
+
     /// Get hex color string
     pub fn hex(&self) -> &'static str {
         match self {
@@ -31,6 +96,17 @@ impl HighlightColor {
         }
     }
 
+    /// Get the ID of the built-in palette entry for this preset
+    pub fn palette_id(&self) -> &'static str {
+        match self {
+            HighlightColor::Yellow => "yellow",
+            HighlightColor::Green => "green",
+            HighlightColor::Blue => "blue",
+            HighlightColor::Pink => "pink",
+            HighlightColor::Orange => "orange",
+        }
+    }
+
     /// Parse from hex string
     pub fn from_hex(hex: &str) -> Option<Self> {
         match hex.to_uppercase().as_str() {
@@ -44,8 +120,49 @@ impl HighlightColor {
     }
 }
 
-/// An annotation (highlight or note) on a book
-#[derive(Debug, Clone, uniffi::Record)]
+/// A user-editable highlight color with a semantic label
+#[derive(Debug, Clone, PartialEq, uniffi::Record)]
+pub struct PaletteColor {
+    /// Unique identifier (preset name for built-ins, UUID v4 otherwise)
+    pub id: String,
+    /// Display name or meaning, e.g. "definition" or "disagree"
+    pub name: String,
+    /// Hex color string (`#RRGGBB`)
+    pub hex: String,
+    /// Position in the palette (ascending)
+    pub position: u32,
+}
+
+impl PaletteColor {
+    /// Create a new palette color with generated UUID
+    pub fn new(name: String, hex: String, position: u32) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            name,
+            hex: hex.to_uppercase(),
+            position,
+        }
+    }
+
+    /// Whether `hex` is a `#RRGGBB` color (either case)
+    pub(crate) fn is_valid_hex(hex: &str) -> bool {
+        hex.len() == 7 && hex.starts_with('#') && hex[1..].chars().all(|c| c.is_ascii_hexdigit())
+    }
+
+    /// Check that `hex` is a `#RRGGBB` color
+    pub(crate) fn check_hex(hex: &str) -> Result<(), OmniReaderError> {
+        if Self::is_valid_hex(hex) {
+            Ok(())
+        } else {
+            Err(OmniReaderError::InvalidColor {
+                hex: hex.to_string(),
+            })
+        }
+    }
+}
+
+/// An annotation (highlight, note or bookmark) on a book
+#[derive(Debug, Clone, PartialEq, uniffi::Record)]
 pub struct Annotation {
     /// Unique identifier (UUID v4)
     pub id: String,
@@ -53,66 +170,126 @@ pub struct Annotation {
     pub book_id: String,
     /// Type of annotation
     pub annotation_type: AnnotationType,
-    /// Start position as percentage (0.0 - 100.0)
-    pub start_percent: f64,
-    /// End position as percentage (0.0 - 100.0)
-    pub end_percent: f64,
-    /// Page number (for display purposes)
-    pub page_number: u32,
-    /// Highlight color (hex string)
+    /// Location of the annotated range (start position, CFI range, text quote)
+    pub locator: Locator,
+    /// Highlight color (ID of a `PaletteColor`)
     pub color: String,
-    /// Selected text content
+    /// Selected text content (for bookmarks, a snippet at the position)
     pub selected_text: Option<String>,
-    /// User's note (optional)
+    /// User's note (for bookmarks, the optional label)
     pub note_text: Option<String>,
+    /// Title of the chapter containing the annotation, if known
+    pub chapter_title: Option<String>,
     /// Unix timestamp when created
     pub created_at: i64,
+    /// Unix timestamp of the last edit
+    pub updated_at: i64,
+    /// Whether the locator still matches the book content
+    pub anchor_status: AnchorStatus,
 }
 
 impl Annotation {
     /// Create a new highlight annotation
+    ///
+    /// The selected text doubles as the locator's text quote when the caller
+    /// did not capture one, so the highlight can be re-anchored later.
     pub fn new_highlight(
         book_id: String,
-        start_percent: f64,
-        end_percent: f64,
-        page_number: u32,
+        mut locator: Locator,
         color: HighlightColor,
         selected_text: Option<String>,
     ) -> Self {
+        if locator.text.is_none() && selected_text.is_some() {
+            locator = locator.with_text(None, selected_text.clone(), None);
+        }
+        let now = chrono::Utc::now().timestamp();
         Self {
             id: uuid::Uuid::new_v4().to_string(),
             book_id,
             annotation_type: AnnotationType::Highlight,
-            start_percent,
-            end_percent,
-            page_number,
-            color: color.hex().to_string(),
+            locator,
+            color: color.palette_id().to_string(),
             selected_text,
             note_text: None,
-            created_at: chrono::Utc::now().timestamp(),
+            chapter_title: None,
+            created_at: now,
+            updated_at: now,
+            anchor_status: AnchorStatus::Anchored,
         }
     }
 
     /// Create a new note annotation
-    pub fn new_note(
-        book_id: String,
-        start_percent: f64,
-        page_number: u32,
-        note_text: String,
-    ) -> Self {
+    pub fn new_note(book_id: String, locator: Locator, note_text: String) -> Self {
+        let now = chrono::Utc::now().timestamp();
         Self {
             id: uuid::Uuid::new_v4().to_string(),
             book_id,
             annotation_type: AnnotationType::Note,
-            start_percent,
-            end_percent: start_percent,
-            page_number,
-            color: HighlightColor::Yellow.hex().to_string(),
+            locator,
+            color: HighlightColor::Yellow.palette_id().to_string(),
             selected_text: None,
             note_text: Some(note_text),
-            created_at: chrono::Utc::now().timestamp(),
+            chapter_title: None,
+            created_at: now,
+            updated_at: now,
+            anchor_status: AnchorStatus::Anchored,
         }
     }
+
+    /// Create a new bookmark annotation
+    ///
+    /// Bookmarks mark a single position, so any range end in the locator is dropped.
+    pub fn new_bookmark(
+        book_id: String,
+        mut locator: Locator,
+        label: Option<String>,
+        chapter_title: Option<String>,
+        snippet: Option<String>,
+    ) -> Self {
+        locator.end_offset = locator.start_offset;
+        let now = chrono::Utc::now().timestamp();
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            book_id,
+            annotation_type: AnnotationType::Bookmark,
+            locator,
+            color: HighlightColor::Yellow.palette_id().to_string(),
+            selected_text: snippet,
+            note_text: label,
+            chapter_title,
+            created_at: now,
+            updated_at: now,
+            anchor_status: AnchorStatus::Anchored,
+        }
+    }
+}
+
+/// A previous version of an annotation, recorded before each edit
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct AnnotationRevision {
+    /// Revision identifier
+    pub id: i64,
+    /// Annotation this revision belongs to
+    pub annotation_id: String,
+    /// Type of annotation at the time
+    pub annotation_type: AnnotationType,
+    /// Location at the time
+    pub locator: Locator,
+    /// Highlight color (palette ID) at the time
+    pub color: String,
+    /// Selected text at the time
+    pub selected_text: Option<String>,
+    /// Note text at the time
+    pub note_text: Option<String>,
+    /// Unix timestamp when this version was replaced
+    pub revised_at: i64,
+}
+
+/// An annotation to insert together with its tags
+#[derive(Debug, Clone)]
+pub struct AnnotationImport {
+    pub annotation: Annotation,
+    pub tags: Vec<String>,
 }
 
 /// Tracks the user's reading position in a book
@@ -120,22 +297,24 @@ impl Annotation {
 pub struct ReadingPosition {
     /// Reference to book
     pub book_id: String,
-    /// Current position as percentage (0.0 - 100.0)
-    pub percent: f64,
-    /// Current page number
-    pub page_number: u32,
+    /// Current location in the book
+    pub locator: Locator,
     /// Unix timestamp of last update
     pub updated_at: i64,
 }
 
 impl ReadingPosition {
     /// Create a new reading position
-    pub fn new(book_id: String, percent: f64, page_number: u32) -> Self {
+    pub fn new(book_id: String, locator: Locator) -> Self {
         Self {
             book_id,
-            percent,
-            page_number,
+            locator,
             updated_at: chrono::Utc::now().timestamp(),
         }
     }
+
+    /// Current position as percentage (0.0 - 100.0)
+    pub fn percent(&self) -> f64 {
+        self.locator.percent()
+    }
 }
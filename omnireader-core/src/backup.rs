@@ -0,0 +1,856 @@
+//! Full-library backup and restore archives
+//!
+//! A backup is a single zip file holding:
+//! - `library.sqlite`: a consistent snapshot of the library, without covers,
+//!   settings and this device's sync and kosync identities
+//! - `covers/<book id>.<ext>`: cover images
+//! - `settings.json`: app settings
+//! - `books/<book id>.<ext>`: the book files, when requested
+//! - `manifest.json`: format and schema versions and a SHA-256 checksum of
+//!   every other entry
+//!
+//! Restoring verifies every checksum and the snapshot's integrity before
+//! touching the library, migrates the snapshot to the current schema, then
+//! merges it into or replaces the current library.
+//!
+//! An encrypted backup is the sealed stream of that zip file (see
+//! [`crate::crypto`]), preceded by `OMNIREADER-ENCRYPTED-BACKUP\n`, the
+//! length of its keyring as a big-endian `u32` and the keyring itself, so
+//! the passphrase is all a restore needs.
+//!
+//! Scratch copies of the library are encrypted under a throwaway key, and
+//! the archive inside an encrypted backup is only ever on disk as a
+//! [`SealedFile`], so creating or restoring a backup leaves no plaintext
+//! behind, even after a crash.
+
+use crate::cover;
+use crate::crypto::{Keyring, SealedFile};
+use crate::db::{self, Database, schema_version};
+use crate::error::OmniReaderError;
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, Write};
+use std::path::{Component, Path, PathBuf};
+use uniffi;
+use zip::result::ZipError;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+/// Value of the manifest's `format` field
+const FORMAT: &str = "omnireader-backup";
+/// Archive layout version written by this build
+const FORMAT_VERSION: u32 = 1;
+const MANIFEST_ENTRY: &str = "manifest.json";
+const DATABASE_ENTRY: &str = "library.sqlite";
+const SETTINGS_ENTRY: &str = "settings.json";
+/// Start of an encrypted backup
+const ENCRYPTED_MAGIC: &[u8] = b"OMNIREADER-ENCRYPTED-BACKUP\n";
+/// Associated data of the sealed archive
+const BACKUP_AAD: &[u8] = b"backup";
+
+/// Options for creating a backup
+#[derive(Debug, Clone, Default, uniffi::Record)]
+pub struct BackupOptions {
+    /// Store the book files in the archive as well
+    pub include_books: bool,
+    /// Encrypt the backup with a key unlocked by this passphrase
+    pub passphrase: Option<String>,
+}
+
+/// Outcome of creating a backup
+#[derive(Debug, Clone, Default, PartialEq, uniffi::Record)]
+pub struct BackupReport {
+    /// Books in the backup
+    pub books: u32,
+    /// Annotations in the backup
+    pub annotations: u32,
+    /// Book files stored in the archive
+    pub book_files: u32,
+    /// Titles of books whose file could not be read
+    pub missing_files: Vec<String>,
+}
+
+/// How a restored backup is combined with the current library
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, uniffi::Enum)]
+pub enum RestoreMode {
+    /// Add what is missing and keep the most recently updated annotations
+    /// and reading positions; local settings win
+    #[default]
+    Merge,
+    /// Discard the current library and settings in favour of the backup
+    Replace,
+}
+
+/// Options for restoring a backup
+#[derive(Debug, Clone, Default, uniffi::Record)]
+pub struct RestoreOptions {
+    pub mode: RestoreMode,
+    /// Folder to extract archived book files into; without it book files
+    /// are left in the archive and books keep their original paths
+    pub books_dir: Option<String>,
+    /// Passphrase of an encrypted backup
+    pub passphrase: Option<String>,
+}
+
+/// Outcome of restoring a backup
+#[derive(Debug, Clone, Default, PartialEq, uniffi::Record)]
+pub struct RestoreReport {
+    /// Books added (merge) or restored (replace)
+    pub books: u32,
+    /// Annotations added or updated (merge) or restored (replace)
+    pub annotations: u32,
+    /// Book files extracted from the archive
+    pub book_files: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Manifest {
+    format: String,
+    version: u32,
+    created_at: String,
+    schema_version: i64,
+    books: u32,
+    annotations: u32,
+    files: Vec<ManifestEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ManifestEntry {
+    path: String,
+    size: u64,
+    sha256: String,
+}
+
+/// Write a backup of the whole library to `path`
+pub fn create_backup(
+    db: &Database,
+    path: &str,
+    options: &BackupOptions,
+) -> Result<BackupReport, OmniReaderError> {
+    let Some(passphrase) = &options.passphrase else {
+        return write_archive(db, File::create(path)?, options);
+    };
+    // An encrypted backup is sealed from an archive written to a sealed
+    // scratch file first
+    let scratch = ScratchFile::new("backup-zip");
+    let mut archive = SealedFile::new(scratch.create()?);
+    let report = write_archive(db, &mut archive, options)?;
+    archive.rewind()?;
+
+    let keyring = Keyring::create(passphrase)?;
+    let stored = keyring.to_bytes();
+    let mut out = File::create(path)?;
+    out.write_all(ENCRYPTED_MAGIC)?;
+    out.write_all(&(stored.len() as u32).to_be_bytes())?;
+    out.write_all(&stored)?;
+    io::copy(&mut keyring.seal_reader(archive, BACKUP_AAD), &mut out)?;
+    out.sync_all()?;
+    Ok(report)
+}
+
+/// Write the backup archive of the whole library to `out`
+fn write_archive(
+    db: &Database,
+    out: impl Write + Seek,
+    options: &BackupOptions,
+) -> Result<BackupReport, OmniReaderError> {
+    let scratch = ScratchFile::new("backup");
+    let key = db::scratch_key();
+    db.snapshot_to(&scratch.0, &key)?;
+
+    let mut zip = ZipWriter::new(out);
+    let mut files = Vec::new();
+    let mut report = BackupReport::default();
+    let library = {
+        let snapshot = db::open_with_key(&scratch.0, &key)?;
+        let mut stmt = snapshot
+            .prepare("SELECT id, cover_data FROM books WHERE cover_data IS NOT NULL ORDER BY id")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let id: String = row.get(0)?;
+            let cover: Vec<u8> = row.get(1)?;
+            let name = format!("covers/{}.{}", id, image_extension(&cover));
+            files.push(add_entry(&mut zip, &name, &mut cover.as_slice(), stored())?);
+        }
+
+        if options.include_books {
+            let mut stmt = snapshot
+                .prepare("SELECT id, title, file_path, file_type FROM books ORDER BY added_at")?;
+            let mut rows = stmt.query([])?;
+            while let Some(row) = rows.next()? {
+                let (id, title, file_path, file_type): (String, String, String, String) =
+                    (row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?);
+                let Ok(mut file) = File::open(&file_path) else {
+                    report.missing_files.push(title);
+                    continue;
+                };
+                let name = format!("books/{}.{}", id, file_type);
+                files.push(add_entry(&mut zip, &name, &mut file, stored())?);
+                report.book_files += 1;
+            }
+        }
+
+        report.books = snapshot.query_row("SELECT COUNT(*) FROM books", [], |row| row.get(0))?;
+        report.annotations =
+            snapshot.query_row("SELECT COUNT(*) FROM annotations", [], |row| row.get(0))?;
+        // Sync identity belongs to this device, not to wherever the backup is restored
+        snapshot.execute_batch(
+            "UPDATE books SET cover_data = NULL, cover_hash = NULL; DELETE FROM settings; \
+             DELETE FROM sync_state; DELETE FROM sync_cursors; DELETE FROM sync_pending; \
+             DELETE FROM kosync_state;",
+        )?;
+        db::serialize_database(&snapshot)?
+    };
+    files.push(add_entry(
+        &mut zip,
+        DATABASE_ENTRY,
+        &mut library.as_slice(),
+        deflated(),
+    )?);
+
+    let settings = serde_json::to_vec_pretty(&db.get_settings()?).map_err(json_error)?;
+    files.push(add_entry(
+        &mut zip,
+        SETTINGS_ENTRY,
+        &mut settings.as_slice(),
+        deflated(),
+    )?);
+
+    let manifest = Manifest {
+        format: FORMAT.to_string(),
+        version: FORMAT_VERSION,
+        created_at: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        schema_version: schema_version(),
+        books: report.books,
+        annotations: report.annotations,
+        files,
+    };
+    let manifest = serde_json::to_vec_pretty(&manifest).map_err(json_error)?;
+    zip.start_file(MANIFEST_ENTRY, deflated())
+        .map_err(zip_error)?;
+    zip.write_all(&manifest)?;
+    zip.finish().map_err(zip_error)?.flush()?;
+    Ok(report)
+}
+
+/// Restore the backup at `path` into the library
+///
+/// The archive is fully validated first; a corrupted or incomplete backup
+/// returns an error and leaves the library untouched.
+pub fn restore_backup(
+    db: &Database,
+    path: &str,
+    options: &RestoreOptions,
+) -> Result<RestoreReport, OmniReaderError> {
+    if !Path::new(path).exists() {
+        return Err(OmniReaderError::FileNotFound {
+            path: path.to_string(),
+        });
+    }
+    match open_encrypted(path, options.passphrase.as_deref())? {
+        Some((_scratch, archive)) => {
+            restore_archive(db, ZipArchive::new(archive).map_err(zip_error)?, options)
+        }
+        None => restore_archive(
+            db,
+            ZipArchive::new(File::open(path)?).map_err(zip_error)?,
+            options,
+        ),
+    }
+}
+
+/// Restore a backup archive into the library
+fn restore_archive<R: Read + Seek>(
+    db: &Database,
+    mut archive: ZipArchive<R>,
+    options: &RestoreOptions,
+) -> Result<RestoreReport, OmniReaderError> {
+    let manifest = read_manifest(&mut archive)?;
+    for entry in &manifest.files {
+        verify_entry(&mut archive, entry)?;
+    }
+
+    let scratch = ScratchFile::new("restore");
+    let key = db::scratch_key();
+    {
+        let mut library = Vec::new();
+        archive
+            .by_name(DATABASE_ENTRY)
+            .map_err(zip_error)?
+            .read_to_end(&mut library)?;
+        let snapshot = db::deserialize_database(&library).map_err(|_| corrupted(DATABASE_ENTRY))?;
+        let status: String = snapshot.query_row("PRAGMA integrity_check", [], |row| row.get(0))?;
+        if status != "ok" {
+            return Err(corrupted(DATABASE_ENTRY));
+        }
+        db::export_database(&snapshot, &scratch.0, &key)?;
+    }
+    // Opening the snapshot brings it up to the current schema
+    drop(Database::open_encrypted(
+        scratch.0.to_string_lossy().to_string(),
+        key.clone(),
+    )?);
+
+    let mut report = RestoreReport::default();
+    let (books, annotations) = {
+        let snapshot = db::open_with_key(&scratch.0, &key)?;
+        let tx = snapshot.unchecked_transaction()?;
+        for entry in &manifest.files {
+            if let Some(id) = entry_id(&entry.path, "covers/") {
+                let mut cover = Vec::new();
+                archive
+                    .by_name(&entry.path)
+                    .map_err(zip_error)?
+                    .read_to_end(&mut cover)?;
+                tx.execute(
+                    "UPDATE books SET cover_data = ?1, cover_hash = ?2 WHERE id = ?3",
+                    params![cover, cover::cover_hash(&cover), id],
+                )?;
+            }
+        }
+        if let Some(dir) = &options.books_dir {
+            std::fs::create_dir_all(dir)?;
+            for entry in &manifest.files {
+                let Some(id) = entry_id(&entry.path, "books/") else {
+                    continue;
+                };
+                if options.mode == RestoreMode::Merge && db.get_book(id)?.is_some() {
+                    continue;
+                }
+                let original: Option<String> = tx
+                    .query_row(
+                        "SELECT file_path FROM books WHERE id = ?1",
+                        params![id],
+                        |row| row.get(0),
+                    )
+                    .ok();
+                let Some(original) = original else {
+                    continue;
+                };
+                let target = book_target(Path::new(dir), id, &original, &entry.path)
+                    .ok_or_else(|| corrupted(&entry.path))?;
+                extract_entry(&mut archive, &entry.path, &target)?;
+                tx.execute(
+                    "UPDATE books SET file_path = ?1 WHERE id = ?2",
+                    params![target.to_string_lossy(), id],
+                )?;
+                report.book_files += 1;
+            }
+        }
+        tx.commit()?;
+        (
+            snapshot.query_row("SELECT COUNT(*) FROM books", [], |row| row.get(0))?,
+            snapshot.query_row("SELECT COUNT(*) FROM annotations", [], |row| row.get(0))?,
+        )
+    };
+
+    let settings: BTreeMap<String, String> =
+        if manifest.files.iter().any(|f| f.path == SETTINGS_ENTRY) {
+            let mut json = Vec::new();
+            archive
+                .by_name(SETTINGS_ENTRY)
+                .map_err(zip_error)?
+                .read_to_end(&mut json)?;
+            serde_json::from_slice(&json).map_err(|_| corrupted(SETTINGS_ENTRY))?
+        } else {
+            BTreeMap::new()
+        };
+
+    match options.mode {
+        RestoreMode::Replace => {
+            db.replace_from(&scratch.0, &key)?;
+            for (key, value) in &settings {
+                db.set_setting(key, value)?;
+            }
+            report.books = books;
+            report.annotations = annotations;
+        }
+        RestoreMode::Merge => {
+            let merged = db.merge_from(&scratch.0, &key)?;
+            for (key, value) in &settings {
+                if db.get_setting(key)?.is_none() {
+                    db.set_setting(key, value)?;
+                }
+            }
+            report.books = merged.books;
+            report.annotations = merged.annotations;
+        }
+    }
+    Ok(report)
+}
+
+/// Decrypt an encrypted backup's archive into a sealed scratch file; `None`
+/// for a plain backup
+fn open_encrypted(
+    path: &str,
+    passphrase: Option<&str>,
+) -> Result<Option<(ScratchFile, SealedFile)>, OmniReaderError> {
+    let mut file = File::open(path)?;
+    let mut magic = vec![0u8; ENCRYPTED_MAGIC.len()];
+    if file.read_exact(&mut magic).is_err() || magic != ENCRYPTED_MAGIC {
+        return Ok(None);
+    }
+    let passphrase = passphrase.ok_or_else(|| OmniReaderError::Encryption {
+        message: "Backup is encrypted; a passphrase is required".to_string(),
+    })?;
+    let mut len = [0u8; 4];
+    file.read_exact(&mut len)
+        .map_err(|_| corrupted("keyring"))?;
+    let mut stored = vec![0u8; u32::from_be_bytes(len) as usize];
+    file.read_exact(&mut stored)
+        .map_err(|_| corrupted("keyring"))?;
+    let keyring = Keyring::unlock(&stored, passphrase)?;
+
+    let scratch = ScratchFile::new("restore-zip");
+    let mut archive = SealedFile::new(scratch.create()?);
+    io::copy(&mut keyring.open_reader(file, BACKUP_AAD)?, &mut archive).map_err(|e| {
+        if e.kind() == io::ErrorKind::InvalidData {
+            OmniReaderError::Encryption {
+                message: "Encrypted backup is corrupted or was tampered with".to_string(),
+            }
+        } else {
+            e.into()
+        }
+    })?;
+    archive.rewind()?;
+    Ok(Some((scratch, archive)))
+}
+
+/// Read and check the manifest of a backup archive
+fn read_manifest(archive: &mut ZipArchive<impl Read + Seek>) -> Result<Manifest, OmniReaderError> {
+    let mut json = Vec::new();
+    archive
+        .by_name(MANIFEST_ENTRY)
+        .map_err(|_| OmniReaderError::ParseError {
+            message: "Not an OmniReader backup: manifest.json is missing".to_string(),
+        })?
+        .read_to_end(&mut json)?;
+    let manifest: Manifest =
+        serde_json::from_slice(&json).map_err(|e| OmniReaderError::ParseError {
+            message: format!("Invalid backup manifest: {}", e),
+        })?;
+
+    if manifest.format != FORMAT {
+        return Err(OmniReaderError::ParseError {
+            message: format!("Not an OmniReader backup: format is {}", manifest.format),
+        });
+    }
+    if manifest.version > FORMAT_VERSION || manifest.schema_version > schema_version() {
+        return Err(OmniReaderError::ParseError {
+            message: "Backup was made by a newer version of OmniReader".to_string(),
+        });
+    }
+    if !manifest.files.iter().any(|f| f.path == DATABASE_ENTRY) {
+        return Err(OmniReaderError::ParseError {
+            message: format!("Backup is incomplete: {} is missing", DATABASE_ENTRY),
+        });
+    }
+    Ok(manifest)
+}
+
+/// Check an archive entry against its manifest size and checksum
+fn verify_entry(
+    archive: &mut ZipArchive<impl Read + Seek>,
+    entry: &ManifestEntry,
+) -> Result<(), OmniReaderError> {
+    let mut file = archive
+        .by_name(&entry.path)
+        .map_err(|_| OmniReaderError::ParseError {
+            message: format!("Backup is incomplete: {} is missing", entry.path),
+        })?;
+    let mut hasher = HashingWriter::new(io::sink());
+    io::copy(&mut file, &mut hasher).map_err(|_| corrupted(&entry.path))?;
+    let (size, sha256) = hasher.finish();
+    if size != entry.size || sha256 != entry.sha256 {
+        return Err(corrupted(&entry.path));
+    }
+    Ok(())
+}
+
+/// Write an archive entry to a file on disk
+fn extract_entry(
+    archive: &mut ZipArchive<impl Read + Seek>,
+    name: &str,
+    target: &Path,
+) -> Result<(), OmniReaderError> {
+    let mut file = archive.by_name(name).map_err(zip_error)?;
+    io::copy(&mut file, &mut File::create(target)?)?;
+    Ok(())
+}
+
+/// Add an entry to the archive, returning its manifest record
+fn add_entry(
+    zip: &mut ZipWriter<impl Write + Seek>,
+    name: &str,
+    data: &mut impl Read,
+    options: SimpleFileOptions,
+) -> Result<ManifestEntry, OmniReaderError> {
+    zip.start_file(name, options).map_err(zip_error)?;
+    let mut writer = HashingWriter::new(&mut *zip);
+    io::copy(data, &mut writer)?;
+    let (size, sha256) = writer.finish();
+    Ok(ManifestEntry {
+        path: name.to_string(),
+        size,
+        sha256,
+    })
+}
+
+/// Where to extract an archived book: its original file name, prefixed with
+/// the book ID when that name is already taken
+///
+/// `None` when the name would place the file outside `dir`.
+fn book_target(dir: &Path, id: &str, original: &str, entry: &str) -> Option<PathBuf> {
+    let name = Path::new(original)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| entry.trim_start_matches("books/").to_string());
+    let mut components = Path::new(&name).components();
+    if !matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    ) {
+        return None;
+    }
+    let target = dir.join(&name);
+    let target = if target.exists() {
+        dir.join(format!("{}-{}", id, name))
+    } else {
+        target
+    };
+    (target.parent() == Some(dir)).then_some(target)
+}
+
+/// Book ID of a `<prefix><id>.<ext>` entry; only UUIDs are accepted, so an
+/// ID never names another path
+fn entry_id<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    let name = path.strip_prefix(prefix)?;
+    let id = name.rsplit_once('.').map_or(name, |(id, _)| id);
+    uuid::Uuid::parse_str(id).is_ok().then_some(id)
+}
+
+fn image_extension(data: &[u8]) -> &'static str {
+    if data.starts_with(&[0xFF, 0xD8]) {
+        "jpg"
+    } else if data.starts_with(b"\x89PNG") {
+        "png"
+    } else if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP") {
+        "webp"
+    } else {
+        "img"
+    }
+}
+
+fn stored() -> SimpleFileOptions {
+    SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Stored)
+        .large_file(true)
+}
+
+fn deflated() -> SimpleFileOptions {
+    SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Deflated)
+        .large_file(true)
+}
+
+fn corrupted(entry: &str) -> OmniReaderError {
+    OmniReaderError::ParseError {
+        message: format!("Backup is corrupted: {} failed verification", entry),
+    }
+}
+
+fn zip_error(e: ZipError) -> OmniReaderError {
+    match e {
+        ZipError::Io(e) => e.into(),
+        e => OmniReaderError::ParseError {
+            message: format!("Invalid backup archive: {}", e),
+        },
+    }
+}
+
+fn json_error(e: serde_json::Error) -> OmniReaderError {
+    OmniReaderError::ParseError {
+        message: format!("Failed to serialize backup: {}", e),
+    }
+}
+
+/// Writer adapter computing the size and SHA-256 of everything written through it
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl<W: Write> HashingWriter<W> {
+    fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+            size: 0,
+        }
+    }
+
+    /// Size and lowercase hex SHA-256
+    fn finish(self) -> (u64, String) {
+        let digest = self.hasher.finalize();
+        let hex = digest.iter().map(|b| format!("{:02x}", b)).collect();
+        (self.size, hex)
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Temporary file, removed when dropped
+struct ScratchFile(PathBuf);
+
+impl ScratchFile {
+    fn new(purpose: &str) -> Self {
+        Self(std::env::temp_dir().join(format!(
+            "omnireader-{}-{}.tmp",
+            purpose,
+            uuid::Uuid::new_v4()
+        )))
+    }
+}
+
+impl ScratchFile {
+    /// Create the file, for reading and writing
+    fn create(&self) -> io::Result<File> {
+        File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&self.0)
+    }
+}
+
+impl Drop for ScratchFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::annotation::{Annotation, HighlightColor, ReadingPosition};
+    use crate::book::{Book, BookType};
+    use crate::locator::Locator;
+
+    fn highlight(book_id: &str, text: &str, updated_at: i64) -> Annotation {
+        let mut annotation = Annotation::new_highlight(
+            book_id.to_string(),
+            Locator::from_total_progression(0.5),
+            HighlightColor::Yellow,
+            Some(text.to_string()),
+        );
+        annotation.updated_at = updated_at;
+        annotation
+    }
+
+    #[test]
+    fn test_backup_and_restore() {
+        let dir = std::env::temp_dir().join(format!("omnireader-backup-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("dune.epub");
+        std::fs::write(&source, b"epub bytes").unwrap();
+
+        let db = Database::open_in_memory().unwrap();
+        let mut book = Book::new(
+            "Dune".to_string(),
+            Some("Frank Herbert".to_string()),
+            source.to_string_lossy().to_string(),
+            BookType::Epub,
+            10,
+        );
+        book.cover_data = Some(vec![0xFF, 0xD8, 0xFF, 0xE0, 1, 2, 3]);
+        db.insert_book(&book).unwrap();
+        let mut kept = highlight(&book.id, "old text", 100);
+        db.insert_annotation(&kept).unwrap();
+        let newer = highlight(&book.id, "fear is the mind-killer", 100);
+        db.insert_annotation(&newer).unwrap();
+        db.save_reading_position(&ReadingPosition::new(
+            book.id.clone(),
+            Locator::from_total_progression(0.3),
+        ))
+        .unwrap();
+        db.set_setting("theme", "sepia").unwrap();
+        db.save_kosync_device_id("SOURCE").unwrap();
+
+        let archive = dir.join("library.zip");
+        let archive = archive.to_string_lossy().to_string();
+        let report = create_backup(
+            &db,
+            &archive,
+            &BackupOptions {
+                include_books: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(report.books, 1);
+        assert_eq!(report.annotations, 2);
+        assert_eq!(report.book_files, 1);
+
+        // Replace into an empty library, extracting the book file
+        let restored = Database::open_in_memory().unwrap();
+        let books_dir = dir.join("restored");
+        let report = restore_backup(
+            &restored,
+            &archive,
+            &RestoreOptions {
+                mode: RestoreMode::Replace,
+                books_dir: Some(books_dir.to_string_lossy().to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            report,
+            RestoreReport {
+                books: 1,
+                annotations: 2,
+                book_files: 1
+            }
+        );
+        let restored_book = restored.get_book(&book.id).unwrap().unwrap();
+        assert_eq!(restored_book.cover_data, book.cover_data);
+        assert_eq!(
+            std::fs::read(&restored_book.file_path).unwrap(),
+            b"epub bytes"
+        );
+        assert!(
+            restored_book
+                .file_path
+                .starts_with(&*books_dir.to_string_lossy())
+        );
+        assert_eq!(
+            restored.get_setting("theme").unwrap().as_deref(),
+            Some("sepia")
+        );
+        assert!(restored.get_reading_position(&book.id).unwrap().is_some());
+        // The restored device gets its own kosync ID rather than the source's
+        assert_eq!(restored.kosync_device_id().unwrap(), None);
+
+        // Merge: local edits made later win, older local rows are replaced
+        let local = Database::open_in_memory().unwrap();
+        local.insert_book(&book).unwrap();
+        kept.selected_text = Some("edited locally".to_string());
+        kept.updated_at = 200;
+        local.insert_annotation(&kept).unwrap();
+        let mut stale = newer.clone();
+        stale.selected_text = Some("stale".to_string());
+        stale.updated_at = 50;
+        local.insert_annotation(&stale).unwrap();
+        local.set_setting("theme", "dark").unwrap();
+        let report = restore_backup(&local, &archive, &RestoreOptions::default()).unwrap();
+        assert_eq!(report.books, 0);
+        assert_eq!(report.annotations, 1);
+        let texts: Vec<String> = local
+            .get_annotations(&book.id)
+            .unwrap()
+            .into_iter()
+            .filter_map(|a| a.selected_text)
+            .collect();
+        assert!(texts.contains(&"edited locally".to_string()));
+        assert!(texts.contains(&"fear is the mind-killer".to_string()));
+        assert_eq!(local.get_setting("theme").unwrap().as_deref(), Some("dark"));
+
+        // A damaged archive is rejected before anything is restored
+        let mut bytes = std::fs::read(&archive).unwrap();
+        let cover_at = bytes
+            .windows(7)
+            .position(|w| w == [0xFF, 0xD8, 0xFF, 0xE0, 1, 2, 3])
+            .unwrap();
+        bytes[cover_at + 5] = 9;
+        std::fs::write(&archive, bytes).unwrap();
+        let empty = Database::open_in_memory().unwrap();
+        assert!(restore_backup(&empty, &archive, &RestoreOptions::default()).is_err());
+        assert!(empty.get_all_books().unwrap().is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_entry_paths_stay_inside() {
+        let id = uuid::Uuid::new_v4().to_string();
+        assert_eq!(
+            entry_id(&format!("books/{}.epub", id), "books/"),
+            Some(id.as_str())
+        );
+        assert_eq!(entry_id("books/../../x.epub", "books/"), None);
+        assert_eq!(entry_id("books/notes.epub", "books/"), None);
+
+        let dir = std::env::temp_dir().join(format!("omnireader-books-{}", uuid::Uuid::new_v4()));
+        let entry = format!("books/{}.epub", id);
+        assert_eq!(
+            book_target(&dir, &id, "/old/dune.epub", &entry),
+            Some(dir.join("dune.epub"))
+        );
+        assert_eq!(book_target(&dir, &id, "..", "books/../../x.epub"), None);
+        assert_eq!(book_target(&dir, &id, "/", "books/"), None);
+    }
+
+    #[test]
+    fn test_encrypted_backup() {
+        let dir = std::env::temp_dir().join(format!("omnireader-backup-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let db = Database::open_in_memory().unwrap();
+        let book = Book::new(
+            "Dune".to_string(),
+            Some("Frank Herbert".to_string()),
+            "/books/dune.epub".to_string(),
+            BookType::Epub,
+            10,
+        );
+        db.insert_book(&book).unwrap();
+        db.insert_annotation(&highlight(&book.id, "fear is the mind-killer", 100))
+            .unwrap();
+
+        let archive = dir.join("library.backup");
+        let archive = archive.to_string_lossy().to_string();
+        create_backup(
+            &db,
+            &archive,
+            &BackupOptions {
+                passphrase: Some("correct horse".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let bytes = std::fs::read(&archive).unwrap();
+        assert!(bytes.starts_with(ENCRYPTED_MAGIC));
+        assert!(!bytes.windows(6).any(|w| w == b"Herber"));
+
+        let restored = Database::open_in_memory().unwrap();
+        let with = |passphrase: Option<&str>| RestoreOptions {
+            passphrase: passphrase.map(str::to_string),
+            ..Default::default()
+        };
+        assert!(matches!(
+            restore_backup(&restored, &archive, &with(Some("battery staple"))),
+            Err(OmniReaderError::WrongPassphrase)
+        ));
+        assert!(restore_backup(&restored, &archive, &with(None)).is_err());
+        let report = restore_backup(&restored, &archive, &with(Some("correct horse"))).unwrap();
+        assert_eq!((report.books, report.annotations), (1, 1));
+        assert_eq!(restored.get_book(&book.id).unwrap().unwrap().title, "Dune");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
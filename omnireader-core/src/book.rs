@@ -28,6 +28,38 @@ impl BookType {
     }
 }
 
+/// Where a book stands in its reading lifecycle
+#[derive(Debug, Clone, Copy, PartialEq, Eq, uniffi::Enum)]
+pub enum ReadingStatus {
+    Unread,
+    Reading,
+    Finished,
+    Abandoned,
+}
+
+impl ReadingStatus {
+    /// Get the string stored in the database
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReadingStatus::Unread => "unread",
+            ReadingStatus::Reading => "reading",
+            ReadingStatus::Finished => "finished",
+            ReadingStatus::Abandoned => "abandoned",
+        }
+    }
+
+    /// Parse from the database string
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "unread" => Some(ReadingStatus::Unread),
+            "reading" => Some(ReadingStatus::Reading),
+            "finished" => Some(ReadingStatus::Finished),
+            "abandoned" => Some(ReadingStatus::Abandoned),
+            _ => None,
+        }
+    }
+}
+
 /// Represents an ebook in the library
 #[derive(Debug, Clone, uniffi::Record)]
 pub struct Book {
@@ -41,7 +73,7 @@ pub struct Book {
     pub file_path: String,
     /// Type of book (PDF or EPUB)
     pub file_type: BookType,
-    /// Cover image data (PNG bytes)
+    /// Cover image data (encoded PNG or JPEG bytes)
     pub cover_data: Option<Vec<u8>>,
     /// Unix timestamp when book was added
     pub added_at: i64,
@@ -49,6 +81,23 @@ pub struct Book {
     pub last_read_at: Option<i64>,
     /// Total pages (for PDF) or chapters (for EPUB)
     pub total_pages: u32,
+    /// Author name in sort order, e.g. "Le Guin, Ursula K."
+    pub author_sort: Option<String>,
+    /// Series name
+    pub series: Option<String>,
+    /// Position within the series (may be fractional, e.g. 1.5)
+    pub series_index: Option<f64>,
+    pub publisher: Option<String>,
+    /// Description or comments (may contain HTML)
+    pub description: Option<String>,
+    /// Rating out of 10 (two per star)
+    pub rating: Option<u32>,
+    /// Status of the current or last read
+    pub status: ReadingStatus,
+    /// Unix timestamp the current or last read started
+    pub started_at: Option<i64>,
+    /// Unix timestamp the last read was finished or abandoned
+    pub finished_at: Option<i64>,
 }
 
 impl Book {
@@ -70,10 +119,35 @@ impl Book {
             added_at: chrono::Utc::now().timestamp(),
             last_read_at: None,
             total_pages,
+            author_sort: None,
+            series: None,
+            series_index: None,
+            publisher: None,
+            description: None,
+            rating: None,
+            status: ReadingStatus::Unread,
+            started_at: None,
+            finished_at: None,
         }
     }
 }
 
+/// External identifier of a book, such as an ISBN
+#[derive(Debug, Clone, PartialEq, uniffi::Record)]
+pub struct BookIdentifier {
+    /// Identifier type, lowercase (e.g. "isbn", "goodreads", "doi")
+    pub scheme: String,
+    pub value: String,
+}
+
+/// A book to insert together with its tags and identifiers
+#[derive(Debug, Clone)]
+pub struct BookImport {
+    pub book: Book,
+    pub tags: Vec<String>,
+    pub identifiers: Vec<BookIdentifier>,
+}
+
 /// Metadata extracted from a book file
 #[derive(Debug, Clone, uniffi::Record)]
 pub struct BookMetadata {
@@ -0,0 +1,91 @@
+//! Bookmark creation with automatic chapter and snippet capture
+
+use crate::annotation::Annotation;
+use crate::book::{Book, BookType};
+use crate::epub;
+use crate::error::OmniReaderError;
+use crate::locator::Locator;
+use crate::search;
+
+/// Maximum length of the captured snippet, in characters
+const SNIPPET_CHARS: usize = 120;
+
+/// Create a bookmark at a location, capturing the chapter title and a text snippet
+///
+/// The bookmark is returned unsaved; store it with `Database::insert_annotation`.
+#[uniffi::export]
+pub fn capture_bookmark(
+    book: Book,
+    locator: Locator,
+    label: Option<String>,
+) -> Result<Annotation, OmniReaderError> {
+    let resources = search::load_text_resources(&book.file_path, book.file_type)?;
+    let count = resources.len() as u32;
+    let index = match book.file_type {
+        BookType::Pdf => locator.pdf_page(count),
+        BookType::Epub => locator.spine_index(count),
+    } as usize;
+
+    let snippet = resources.get(index).and_then(|resource| {
+        let len = resource.text.chars().count();
+        let offset = locator
+            .start_offset
+            .map(|offset| offset as usize)
+            .unwrap_or_else(|| (locator.progression * len as f64) as usize);
+        snippet_at(&resource.text, offset)
+    });
+
+    let chapter_title = match book.file_type {
+        BookType::Epub => {
+            let toc = epub::get_epub_toc(&book.file_path)?;
+            epub::chapter_title_for(&toc, index as u32, count)
+        }
+        BookType::Pdf => None,
+    };
+
+    Ok(Annotation::new_bookmark(
+        book.id,
+        locator,
+        label,
+        chapter_title,
+        snippet,
+    ))
+}
+
+/// Take up to `SNIPPET_CHARS` characters starting at the word containing `offset`
+fn snippet_at(text: &str, offset: usize) -> Option<String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut start = offset.min(chars.len());
+    while start > 0 && !chars[start - 1].is_whitespace() {
+        start -= 1;
+    }
+    let end = (start + SNIPPET_CHARS).min(chars.len());
+    let mut snippet: String = chars[start..end].iter().collect();
+    if end < chars.len()
+        && let Some(last_space) = snippet.rfind(char::is_whitespace)
+    {
+        snippet.truncate(last_space);
+    }
+    let snippet = snippet.trim().to_string();
+    (!snippet.is_empty()).then_some(snippet)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snippet_at() {
+        let text = "alpha beta gamma ".repeat(20);
+        let snippet = snippet_at(&text, 8).unwrap();
+        assert!(snippet.starts_with("beta gamma"));
+        assert!(snippet.chars().count() <= SNIPPET_CHARS);
+        assert!(
+            snippet.ends_with("alpha") || snippet.ends_with("beta") || snippet.ends_with("gamma")
+        );
+
+        assert_eq!(snippet_at("short text", 100).as_deref(), Some("text"));
+        assert_eq!(snippet_at("", 0), None);
+        assert_eq!(snippet_at("short text", 3).as_deref(), Some("short text"));
+    }
+}
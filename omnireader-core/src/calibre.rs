@@ -0,0 +1,381 @@
+//! Import of a Calibre library folder
+//!
+//! Calibre keeps its catalogue in `metadata.db` at the library root and each
+//! book in `<author>/<title> (<id>)/`, next to a `cover.jpg`. The database is
+//! opened read-only; book files stay where they are and are referenced by path.
+
+use crate::book::{Book, BookIdentifier, BookImport, BookType};
+use crate::db::Database;
+use crate::error::OmniReaderError;
+use crate::{epub, pdf};
+use rusqlite::{Connection, OpenFlags, params};
+use std::path::Path;
+use uniffi;
+
+/// Books inserted per transaction, bounding memory held by covers
+const BATCH_SIZE: usize = 200;
+
+/// Options for a Calibre import
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct CalibreImportOptions {
+    /// Formats to import, most preferred first; a book is imported once, in
+    /// the first of these formats it has
+    pub preferred_formats: Vec<BookType>,
+    /// Read each book's `cover.jpg`
+    pub import_covers: bool,
+}
+
+impl Default for CalibreImportOptions {
+    fn default() -> Self {
+        Self {
+            preferred_formats: vec![BookType::Epub, BookType::Pdf],
+            import_covers: true,
+        }
+    }
+}
+
+/// Outcome of a Calibre import
+#[derive(Debug, Clone, Default, PartialEq, uniffi::Record)]
+pub struct CalibreImportReport {
+    /// Books added to the library
+    pub imported: u32,
+    /// Books whose chosen file was already in the library
+    pub already_in_library: u32,
+    /// Titles of books with none of the preferred formats
+    pub unsupported: Vec<String>,
+    /// Titles of books whose format file is missing on disk
+    pub missing_files: Vec<String>,
+}
+
+/// One book as recorded in Calibre's `metadata.db`
+struct CalibreBook {
+    id: i64,
+    title: String,
+    author_sort: Option<String>,
+    series_index: Option<f64>,
+    path: String,
+    has_cover: bool,
+    added_at: Option<String>,
+}
+
+/// Import every book of a Calibre library
+pub fn import_calibre_library(
+    db: &Database,
+    library_path: &str,
+    options: &CalibreImportOptions,
+) -> Result<CalibreImportReport, OmniReaderError> {
+    let root = Path::new(library_path);
+    let metadata_path = root.join("metadata.db");
+    if !metadata_path.exists() {
+        return Err(OmniReaderError::FileNotFound {
+            path: metadata_path.to_string_lossy().to_string(),
+        });
+    }
+    let calibre = Connection::open_with_flags(
+        &metadata_path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )?;
+
+    let mut report = CalibreImportReport::default();
+    let mut batch = Vec::new();
+    for entry in read_books(&calibre)? {
+        let formats = read_formats(&calibre, entry.id)?;
+        let Some((file_type, file_name)) = options.preferred_formats.iter().find_map(|wanted| {
+            formats
+                .iter()
+                .find(|(file_type, _)| file_type == wanted)
+                .cloned()
+        }) else {
+            report.unsupported.push(entry.title);
+            continue;
+        };
+
+        let book_dir = root.join(&entry.path);
+        let file_path = book_dir.join(format!("{}.{}", file_name, file_type.extension()));
+        if !file_path.exists() {
+            report.missing_files.push(entry.title);
+            continue;
+        }
+        let file_path = file_path.to_string_lossy().to_string();
+        if db.book_exists_by_path(&file_path)?
+            || batch
+                .iter()
+                .any(|import: &BookImport| import.book.file_path == file_path)
+        {
+            report.already_in_library += 1;
+            continue;
+        }
+
+        batch.push(to_import(
+            &calibre, entry, &book_dir, file_path, file_type, options,
+        )?);
+        if batch.len() >= BATCH_SIZE {
+            report.imported += batch.len() as u32;
+            db.insert_books(&std::mem::take(&mut batch))?;
+        }
+    }
+    report.imported += batch.len() as u32;
+    db.insert_books(&batch)?;
+    Ok(report)
+}
+
+fn to_import(
+    calibre: &Connection,
+    entry: CalibreBook,
+    book_dir: &Path,
+    file_path: String,
+    file_type: BookType,
+    options: &CalibreImportOptions,
+) -> Result<BookImport, OmniReaderError> {
+    let authors = linked_names(
+        calibre,
+        "SELECT a.name FROM authors a JOIN books_authors_link l ON l.author = a.id
+         WHERE l.book = ?1 ORDER BY l.id",
+        entry.id,
+    )?;
+    let first = |sql: &str| -> Result<Option<String>, OmniReaderError> {
+        Ok(linked_names(calibre, sql, entry.id)?.into_iter().next())
+    };
+
+    let total_pages = match file_type {
+        BookType::Epub => epub::get_epub_chapter_count(&file_path),
+        BookType::Pdf => pdf::get_pdf_page_count(&file_path),
+    }
+    .unwrap_or(0);
+    let mut book = Book::new(
+        entry.title,
+        (!authors.is_empty()).then(|| authors.join(" & ")),
+        file_path,
+        file_type,
+        total_pages,
+    );
+    book.author_sort = entry.author_sort.filter(|s| !s.is_empty());
+    book.series = first(
+        "SELECT s.name FROM series s JOIN books_series_link l ON l.series = s.id WHERE l.book = ?1",
+    )?;
+    book.series_index = book.series.as_ref().and(entry.series_index);
+    book.publisher = first(
+        "SELECT p.name FROM publishers p JOIN books_publishers_link l ON l.publisher = p.id
+         WHERE l.book = ?1",
+    )?;
+    book.description = first("SELECT text FROM comments WHERE book = ?1")?;
+    book.rating = first(
+        "SELECT CAST(r.rating AS TEXT) FROM ratings r JOIN books_ratings_link l ON l.rating = r.id
+         WHERE l.book = ?1",
+    )?
+    .and_then(|rating| rating.parse().ok())
+    .filter(|rating| *rating > 0);
+    if let Some(added_at) = entry.added_at.as_deref().and_then(parse_timestamp) {
+        book.added_at = added_at;
+    }
+    if options.import_covers && entry.has_cover {
+        book.cover_data = std::fs::read(book_dir.join("cover.jpg")).ok();
+    }
+
+    let tags = linked_names(
+        calibre,
+        "SELECT t.name FROM tags t JOIN books_tags_link l ON l.tag = t.id WHERE l.book = ?1
+         ORDER BY t.name",
+        entry.id,
+    )?;
+    let mut stmt = calibre.prepare("SELECT type, val FROM identifiers WHERE book = ?1")?;
+    let identifiers = stmt
+        .query_map(params![entry.id], |row| {
+            Ok(BookIdentifier {
+                scheme: row.get(0)?,
+                value: row.get(1)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(BookImport {
+        book,
+        tags,
+        identifiers,
+    })
+}
+
+fn read_books(calibre: &Connection) -> Result<Vec<CalibreBook>, OmniReaderError> {
+    let mut stmt = calibre.prepare(
+        "SELECT id, title, author_sort, series_index, path, has_cover, timestamp
+         FROM books ORDER BY id",
+    )?;
+    let books = stmt
+        .query_map([], |row| {
+            Ok(CalibreBook {
+                id: row.get(0)?,
+                title: row.get(1)?,
+                author_sort: row.get(2)?,
+                series_index: row.get(3)?,
+                path: row.get(4)?,
+                has_cover: row.get::<_, Option<bool>>(5)?.unwrap_or(false),
+                added_at: row.get(6)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(books)
+}
+
+/// Supported formats of a book with their file name stems
+fn read_formats(
+    calibre: &Connection,
+    book: i64,
+) -> Result<Vec<(BookType, String)>, OmniReaderError> {
+    let mut stmt = calibre.prepare("SELECT format, name FROM data WHERE book = ?1")?;
+    let formats = stmt
+        .query_map(params![book], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(formats
+        .into_iter()
+        .filter_map(|(format, name)| BookType::from_extension(&format).map(|t| (t, name)))
+        .collect())
+}
+
+fn linked_names(
+    calibre: &Connection,
+    sql: &str,
+    book: i64,
+) -> Result<Vec<String>, OmniReaderError> {
+    let mut stmt = calibre.prepare(sql)?;
+    let names = stmt
+        .query_map(params![book], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(names
+        .into_iter()
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect())
+}
+
+/// Parse Calibre's `2023-04-01 10:20:30.123456+00:00` timestamps
+fn parse_timestamp(s: &str) -> Option<i64> {
+    chrono::DateTime::parse_from_rfc3339(&s.replacen(' ', "T", 1))
+        .ok()
+        .map(|datetime| datetime.timestamp())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The parts of Calibre's schema the importer reads
+    const CALIBRE_SCHEMA: &str = r#"
+        CREATE TABLE books (id INTEGER PRIMARY KEY, title TEXT, sort TEXT, timestamp TIMESTAMP,
+            series_index REAL NOT NULL DEFAULT 1.0, author_sort TEXT, path TEXT NOT NULL DEFAULT '',
+            has_cover BOOL DEFAULT 0);
+        CREATE TABLE authors (id INTEGER PRIMARY KEY, name TEXT NOT NULL, sort TEXT);
+        CREATE TABLE books_authors_link (id INTEGER PRIMARY KEY, book INTEGER, author INTEGER);
+        CREATE TABLE series (id INTEGER PRIMARY KEY, name TEXT NOT NULL);
+        CREATE TABLE books_series_link (id INTEGER PRIMARY KEY, book INTEGER, series INTEGER);
+        CREATE TABLE tags (id INTEGER PRIMARY KEY, name TEXT NOT NULL);
+        CREATE TABLE books_tags_link (id INTEGER PRIMARY KEY, book INTEGER, tag INTEGER);
+        CREATE TABLE publishers (id INTEGER PRIMARY KEY, name TEXT NOT NULL);
+        CREATE TABLE books_publishers_link (id INTEGER PRIMARY KEY, book INTEGER, publisher INTEGER);
+        CREATE TABLE ratings (id INTEGER PRIMARY KEY, rating INTEGER);
+        CREATE TABLE books_ratings_link (id INTEGER PRIMARY KEY, book INTEGER, rating INTEGER);
+        CREATE TABLE comments (id INTEGER PRIMARY KEY, book INTEGER, text TEXT);
+        CREATE TABLE identifiers (id INTEGER PRIMARY KEY, book INTEGER, type TEXT, val TEXT);
+        CREATE TABLE data (id INTEGER PRIMARY KEY, book INTEGER, format TEXT, uncompressed_size INTEGER, name TEXT);
+
+        INSERT INTO books VALUES
+            (1, 'The Dispossessed', 'Dispossessed, The', '2023-04-01 10:20:30.123456+00:00', 5.0,
+             'Le Guin, Ursula K.', 'Ursula K. Le Guin/The Dispossessed (1)', 1),
+            (2, 'Kindle Only', 'Kindle Only', '2023-04-02 00:00:00+00:00', 1.0, 'Nobody', 'Nobody/Kindle Only (2)', 0),
+            (3, 'Gone Missing', 'Gone Missing', '2023-04-03 00:00:00+00:00', 1.0, 'Nobody', 'Nobody/Gone Missing (3)', 0);
+        INSERT INTO authors VALUES (1, 'Ursula K. Le Guin', 'Le Guin, Ursula K.'), (2, 'Second Author', 'Author, Second');
+        INSERT INTO books_authors_link VALUES (1, 1, 1), (2, 1, 2);
+        INSERT INTO series VALUES (1, 'Hainish Cycle');
+        INSERT INTO books_series_link VALUES (1, 1, 1);
+        INSERT INTO tags VALUES (1, 'Science Fiction'), (2, 'Anarchism');
+        INSERT INTO books_tags_link VALUES (1, 1, 1), (2, 1, 2);
+        INSERT INTO publishers VALUES (1, 'Harper & Row');
+        INSERT INTO books_publishers_link VALUES (1, 1, 1);
+        INSERT INTO ratings VALUES (1, 8);
+        INSERT INTO books_ratings_link VALUES (1, 1, 1);
+        INSERT INTO comments VALUES (1, 1, '<p>An ambiguous utopia.</p>');
+        INSERT INTO identifiers VALUES (1, 1, 'isbn', '9780060125639'), (2, 1, 'goodreads', '13651');
+        INSERT INTO data VALUES
+            (1, 1, 'MOBI', 10, 'The Dispossessed - Ursula K. Le Guin'),
+            (2, 1, 'EPUB', 10, 'The Dispossessed - Ursula K. Le Guin'),
+            (3, 2, 'AZW3', 10, 'Kindle Only - Nobody'),
+            (4, 3, 'PDF', 10, 'Gone Missing - Nobody');
+    "#;
+
+    #[test]
+    fn test_import_calibre_library() {
+        let root =
+            std::env::temp_dir().join(format!("omnireader-calibre-{}", uuid::Uuid::new_v4()));
+        let book_dir = root.join("Ursula K. Le Guin/The Dispossessed (1)");
+        std::fs::create_dir_all(&book_dir).unwrap();
+        std::fs::write(
+            book_dir.join("The Dispossessed - Ursula K. Le Guin.epub"),
+            b"epub",
+        )
+        .unwrap();
+        std::fs::write(
+            book_dir.join("The Dispossessed - Ursula K. Le Guin.mobi"),
+            b"mobi",
+        )
+        .unwrap();
+        std::fs::write(book_dir.join("cover.jpg"), b"\xff\xd8cover").unwrap();
+        Connection::open(root.join("metadata.db"))
+            .unwrap()
+            .execute_batch(CALIBRE_SCHEMA)
+            .unwrap();
+
+        let db = Database::open_in_memory().unwrap();
+        let library = root.to_string_lossy().to_string();
+        let report =
+            import_calibre_library(&db, &library, &CalibreImportOptions::default()).unwrap();
+        assert_eq!(report.imported, 1);
+        assert_eq!(report.unsupported, vec!["Kindle Only".to_string()]);
+        assert_eq!(report.missing_files, vec!["Gone Missing".to_string()]);
+
+        let books = db.get_all_books().unwrap();
+        assert_eq!(books.len(), 1);
+        let book = &books[0];
+        assert_eq!(book.title, "The Dispossessed");
+        assert_eq!(book.file_type, BookType::Epub);
+        assert!(
+            book.file_path
+                .ends_with("The Dispossessed - Ursula K. Le Guin.epub")
+        );
+        assert_eq!(
+            book.author.as_deref(),
+            Some("Ursula K. Le Guin & Second Author")
+        );
+        assert_eq!(book.author_sort.as_deref(), Some("Le Guin, Ursula K."));
+        assert_eq!(book.series.as_deref(), Some("Hainish Cycle"));
+        assert_eq!(book.series_index, Some(5.0));
+        assert_eq!(book.publisher.as_deref(), Some("Harper & Row"));
+        assert_eq!(book.rating, Some(8));
+        assert_eq!(
+            book.description.as_deref(),
+            Some("<p>An ambiguous utopia.</p>")
+        );
+        assert_eq!(book.added_at, 1_680_344_430);
+        assert_eq!(book.cover_data.as_deref(), Some(&b"\xff\xd8cover"[..]));
+
+        let tags: Vec<String> = db
+            .get_book_tags(&book.id)
+            .unwrap()
+            .into_iter()
+            .map(|tag| tag.name)
+            .collect();
+        assert_eq!(tags, vec!["Anarchism", "Science Fiction"]);
+        let identifiers = db.get_book_identifiers(&book.id).unwrap();
+        assert_eq!(identifiers.len(), 2);
+        assert_eq!(identifiers[1].scheme, "isbn");
+        assert_eq!(identifiers[1].value, "9780060125639");
+
+        // Importing again skips books already in the library
+        let again =
+            import_calibre_library(&db, &library, &CalibreImportOptions::default()).unwrap();
+        assert_eq!(again.imported, 0);
+        assert_eq!(again.already_in_library, 1);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
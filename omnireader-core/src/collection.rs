@@ -0,0 +1,30 @@
+//! Collection model
+
+use uniffi;
+
+/// A user-created collection (shelf) of books in manual order
+#[derive(Debug, Clone, PartialEq, Eq, uniffi::Record)]
+pub struct Collection {
+    /// Unique identifier (UUID v4)
+    pub id: String,
+    pub name: String,
+    /// Display order among collections
+    pub position: u32,
+    /// Unix timestamp when the collection was created
+    pub created_at: i64,
+    /// Number of books in the collection
+    pub book_count: u32,
+}
+
+impl Collection {
+    /// Create a new, empty collection with generated UUID
+    pub fn new(name: String, position: u32) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            name: name.trim().to_string(),
+            position,
+            created_at: chrono::Utc::now().timestamp(),
+            book_count: 0,
+        }
+    }
+}
@@ -0,0 +1,546 @@
+//! Cover thumbnails and placeholders
+//!
+//! Covers are stored as imported: a PNG rendered from a PDF's first page, or
+//! whatever image an EPUB contains. Thumbnails are made from a normalized
+//! copy (any supported format, EXIF orientation applied, transparency on
+//! white) and cached on disk under `<cache dir>/covers/<book id>/`, named
+//! after a hash of the stored cover so a replaced cover never serves stale
+//! files.
+//! Each cover also gets a [`CoverPlaceholder`] with its dominant colour and a
+//! BlurHash, for drawing the grid before thumbnails load.
+
+use crate::db::Database;
+use crate::error::OmniReaderError;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageDecoder, ImageReader, RgbImage};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use uniffi;
+
+/// Longest side of a normalized cover
+const NORMALIZED_MAX: u32 = 1600;
+const JPEG_QUALITY: u8 = 85;
+/// Longest side of the image the dominant colour and BlurHash are taken from
+const SAMPLE_MAX: u32 = 64;
+/// Directory of the cache dir holding cover files, one directory per book
+const COVERS_DIR: &str = "covers";
+
+/// Thumbnail size, by longest side
+#[derive(Debug, Clone, Copy, PartialEq, Eq, uniffi::Enum)]
+pub enum CoverSize {
+    /// 200px, for dense grids and lists
+    Small,
+    /// 400px, for the library grid
+    Medium,
+    /// 800px, for book details
+    Large,
+}
+
+impl CoverSize {
+    pub fn max_dimension(&self) -> u32 {
+        match self {
+            CoverSize::Small => 200,
+            CoverSize::Medium => 400,
+            CoverSize::Large => 800,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            CoverSize::Small => "small",
+            CoverSize::Medium => "medium",
+            CoverSize::Large => "large",
+        }
+    }
+}
+
+/// Thumbnail image format
+#[derive(Debug, Clone, Copy, PartialEq, Eq, uniffi::Enum)]
+pub enum ThumbnailFormat {
+    /// Lossless WebP
+    WebP,
+    Jpeg,
+}
+
+impl ThumbnailFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ThumbnailFormat::WebP => "webp",
+            ThumbnailFormat::Jpeg => "jpg",
+        }
+    }
+}
+
+/// A cached thumbnail file
+#[derive(Debug, Clone, PartialEq, uniffi::Record)]
+pub struct CoverThumbnail {
+    pub path: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// What to draw while a cover loads
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, uniffi::Record)]
+pub struct CoverPlaceholder {
+    /// Most common colour, as `#RRGGBB`
+    pub dominant_color: String,
+    pub blurhash: String,
+    /// Size of the normalized cover, for its aspect ratio
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Re-encode any supported cover image as an upright JPEG of at most
+/// 1600px on its longest side
+#[uniffi::export]
+pub fn normalize_cover(data: Vec<u8>) -> Result<Vec<u8>, OmniReaderError> {
+    encode(&decode_cover(&data)?, ThumbnailFormat::Jpeg)
+}
+
+/// Thumbnail of a book's cover, made and cached on first use
+///
+/// Returns `None` when the book has no cover.
+pub fn cover_thumbnail(
+    db: &Database,
+    cache_dir: &str,
+    book_id: &str,
+    size: CoverSize,
+    format: ThumbnailFormat,
+) -> Result<Option<CoverThumbnail>, OmniReaderError> {
+    let Some(hash) = db.get_book_cover_hash(book_id)? else {
+        return Ok(None);
+    };
+    let dir = book_cache_dir(cache_dir, book_id);
+    let file_name = |hash: &str| format!("{}-{}.{}", hash, size.as_str(), format.extension());
+    let path = dir.join(file_name(&hash));
+    if path.exists() {
+        let (width, height) = image::image_dimensions(&path).map_err(image_error)?;
+        return Ok(Some(thumbnail(&path, width, height)));
+    }
+
+    // The cover may have changed since its hash was read
+    let Some(data) = db.get_book_cover(book_id)? else {
+        return Ok(None);
+    };
+    let hash = cover_hash(&data);
+    let path = dir.join(file_name(&hash));
+    let cover = decode_cover(&data)?;
+    let max = size.max_dimension();
+    let resized = if cover.width() > max || cover.height() > max {
+        DynamicImage::ImageRgb8(cover)
+            .resize(max, max, FilterType::CatmullRom)
+            .into_rgb8()
+    } else {
+        cover
+    };
+    write_cached(&dir, &hash, &path, &encode(&resized, format)?)?;
+    Ok(Some(thumbnail(&path, resized.width(), resized.height())))
+}
+
+/// Dominant colour and BlurHash of a book's cover, computed and cached on
+/// first use
+///
+/// Returns `None` when the book has no cover.
+pub fn cover_placeholder(
+    db: &Database,
+    cache_dir: &str,
+    book_id: &str,
+) -> Result<Option<CoverPlaceholder>, OmniReaderError> {
+    let Some(hash) = db.get_book_cover_hash(book_id)? else {
+        return Ok(None);
+    };
+    let dir = book_cache_dir(cache_dir, book_id);
+    let path = dir.join(format!("{}.json", hash));
+    if let Ok(json) = std::fs::read_to_string(&path)
+        && let Ok(placeholder) = serde_json::from_str(&json)
+    {
+        return Ok(Some(placeholder));
+    }
+
+    // The cover may have changed since its hash was read
+    let Some(data) = db.get_book_cover(book_id)? else {
+        return Ok(None);
+    };
+    let hash = cover_hash(&data);
+    let path = dir.join(format!("{}.json", hash));
+    let cover = decode_cover(&data)?;
+    let sample = DynamicImage::ImageRgb8(cover.clone())
+        .resize(SAMPLE_MAX, SAMPLE_MAX, FilterType::Triangle)
+        .into_rgb8();
+    let (x_components, y_components) = if cover.height() > cover.width() {
+        (3, 4)
+    } else {
+        (4, 3)
+    };
+    let placeholder = CoverPlaceholder {
+        dominant_color: dominant_color(&sample),
+        blurhash: blurhash(&sample, x_components, y_components),
+        width: cover.width(),
+        height: cover.height(),
+    };
+    let json = serde_json::to_vec(&placeholder).map_err(|e| OmniReaderError::ParseError {
+        message: format!("Failed to serialize cover placeholder: {}", e),
+    })?;
+    write_cached(&dir, &hash, &path, &json)?;
+    Ok(Some(placeholder))
+}
+
+/// Delete a book's cached thumbnails and placeholder
+///
+/// Meant for when a book is deleted or its cover replaced; files left behind
+/// are cleared the next time the book's cover is cached, or by
+/// [`prune_cover_cache`].
+pub fn clear_cover_cache(cache_dir: &str, book_id: &str) -> Result<(), OmniReaderError> {
+    match std::fs::remove_dir_all(book_cache_dir(cache_dir, book_id)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Delete cached thumbnails of books that were deleted or lost their cover,
+/// returning the number of books cleared
+///
+/// Only directories named after a book ID are considered, so other files
+/// sharing the cache dir are left alone.
+pub fn prune_cover_cache(db: &Database, cache_dir: &str) -> Result<u32, OmniReaderError> {
+    let entries = match std::fs::read_dir(Path::new(cache_dir).join(COVERS_DIR)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    let mut pruned = 0;
+    for entry in entries {
+        let entry = entry?;
+        let book_id = entry.file_name().to_string_lossy().to_string();
+        if uuid::Uuid::parse_str(&book_id).is_err() || !entry.file_type()?.is_dir() {
+            continue;
+        }
+        if db.get_book_cover_hash(&book_id)?.is_none() {
+            std::fs::remove_dir_all(entry.path())?;
+            pruned += 1;
+        }
+    }
+    Ok(pruned)
+}
+
+/// Decode a cover, upright, without transparency and at most
+/// [`NORMALIZED_MAX`] on its longest side
+fn decode_cover(data: &[u8]) -> Result<RgbImage, OmniReaderError> {
+    let mut decoder = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()?
+        .into_decoder()
+        .map_err(image_error)?;
+    let orientation = decoder.orientation().map_err(image_error)?;
+    let mut image = DynamicImage::from_decoder(decoder).map_err(image_error)?;
+    image.apply_orientation(orientation);
+    if image.width() > NORMALIZED_MAX || image.height() > NORMALIZED_MAX {
+        image = image.resize(NORMALIZED_MAX, NORMALIZED_MAX, FilterType::CatmullRom);
+    }
+    if !image.color().has_alpha() {
+        return Ok(image.into_rgb8());
+    }
+    let rgba = image.into_rgba8();
+    Ok(RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let [r, g, b, a] = rgba.get_pixel(x, y).0;
+        let on_white = |c: u8| ((c as u32 * a as u32 + 255 * (255 - a as u32)) / 255) as u8;
+        image::Rgb([on_white(r), on_white(g), on_white(b)])
+    }))
+}
+
+fn encode(image: &RgbImage, format: ThumbnailFormat) -> Result<Vec<u8>, OmniReaderError> {
+    let mut bytes = Vec::new();
+    match format {
+        ThumbnailFormat::WebP => image.write_with_encoder(WebPEncoder::new_lossless(&mut bytes)),
+        ThumbnailFormat::Jpeg => {
+            image.write_with_encoder(JpegEncoder::new_with_quality(&mut bytes, JPEG_QUALITY))
+        }
+    }
+    .map_err(image_error)?;
+    Ok(bytes)
+}
+
+/// The centre of the most common colour, with 4 bits per channel
+fn dominant_color(image: &RgbImage) -> String {
+    let mut buckets = vec![(0u32, [0u64; 3]); 4096];
+    for pixel in image.pixels() {
+        let [r, g, b] = pixel.0;
+        let bucket =
+            &mut buckets[(r as usize >> 4) << 8 | (g as usize >> 4) << 4 | b as usize >> 4];
+        bucket.0 += 1;
+        for (sum, c) in bucket.1.iter_mut().zip(pixel.0) {
+            *sum += c as u64;
+        }
+    }
+    let (count, sums) = buckets
+        .into_iter()
+        .max_by_key(|(count, _)| *count)
+        .unwrap_or_default();
+    let [r, g, b] = sums.map(|sum| sum.checked_div(count as u64).unwrap_or(0));
+    format!("#{:02X}{:02X}{:02X}", r, g, b)
+}
+
+const BASE83: &[u8] =
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+
+/// BlurHash (https://blurha.sh) of an image with `x_components` by
+/// `y_components` (1 - 9) cosine components
+fn blurhash(image: &RgbImage, x_components: u32, y_components: u32) -> String {
+    let (width, height) = image.dimensions();
+    let linear: Vec<[f64; 3]> = image.pixels().map(|p| p.0.map(srgb_to_linear)).collect();
+    let mut factors = Vec::new();
+    for j in 0..y_components {
+        for i in 0..x_components {
+            let mut factor = [0.0; 3];
+            for y in 0..height {
+                let basis_y = (std::f64::consts::PI * j as f64 * y as f64 / height as f64).cos();
+                for x in 0..width {
+                    let basis =
+                        basis_y * (std::f64::consts::PI * i as f64 * x as f64 / width as f64).cos();
+                    let pixel = linear[(y * width + x) as usize];
+                    for (f, c) in factor.iter_mut().zip(pixel) {
+                        *f += basis * c;
+                    }
+                }
+            }
+            let normalisation = if i == 0 && j == 0 { 1.0 } else { 2.0 };
+            let scale = normalisation / (width * height) as f64;
+            factors.push(factor.map(|f| f * scale));
+        }
+    }
+
+    let mut hash = base83((x_components - 1) + (y_components - 1) * 9, 1);
+    let (dc, ac) = factors.split_first().expect("at least one component");
+    let max_value = if ac.is_empty() {
+        hash.push_str(&base83(0, 1));
+        1.0
+    } else {
+        let actual_max = ac.iter().flatten().fold(0.0f64, |max, f| max.max(f.abs()));
+        let quantised = (actual_max * 166.0 - 0.5).floor().clamp(0.0, 82.0) as u32;
+        hash.push_str(&base83(quantised, 1));
+        (quantised + 1) as f64 / 166.0
+    };
+    let [r, g, b] = dc.map(linear_to_srgb);
+    hash.push_str(&base83((r << 16) | (g << 8) | b, 4));
+    for factor in ac {
+        let [r, g, b] = factor.map(|f| {
+            let v = f / max_value;
+            (v.signum() * v.abs().sqrt() * 9.0 + 9.5)
+                .floor()
+                .clamp(0.0, 18.0) as u32
+        });
+        hash.push_str(&base83(r * 19 * 19 + g * 19 + b, 2));
+    }
+    hash
+}
+
+fn base83(value: u32, length: u32) -> String {
+    (1..=length)
+        .map(|i| BASE83[(value / 83u32.pow(length - i) % 83) as usize] as char)
+        .collect()
+}
+
+fn srgb_to_linear(value: u8) -> f64 {
+    let v = value as f64 / 255.0;
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f64) -> u32 {
+    let v = value.clamp(0.0, 1.0);
+    if v <= 0.0031308 {
+        (v * 12.92 * 255.0 + 0.5) as u32
+    } else {
+        ((1.055 * v.powf(1.0 / 2.4) - 0.055) * 255.0 + 0.5) as u32
+    }
+}
+
+/// First 16 hex digits of the SHA-256 of a stored cover
+/// Short SHA-256 prefix naming a cover's cached files
+pub(crate) fn cover_hash(data: &[u8]) -> String {
+    Sha256::digest(data)[..8]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn book_cache_dir(cache_dir: &str, book_id: &str) -> PathBuf {
+    Path::new(cache_dir).join(COVERS_DIR).join(book_id)
+}
+
+/// Write a cache file atomically, first deleting the book's files made from
+/// an older cover
+fn write_cached(
+    dir: &Path,
+    hash: &str,
+    path: &Path,
+    contents: &[u8],
+) -> Result<(), OmniReaderError> {
+    std::fs::create_dir_all(dir)?;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_name().to_string_lossy().starts_with(hash) {
+            // Another thread may be replacing the same stale file
+            let _ = std::fs::remove_file(entry.path());
+        }
+    }
+    let temp = dir.join(format!("{}.{}.tmp", hash, uuid::Uuid::new_v4()));
+    std::fs::write(&temp, contents)?;
+    std::fs::rename(&temp, path)?;
+    Ok(())
+}
+
+fn thumbnail(path: &Path, width: u32, height: u32) -> CoverThumbnail {
+    CoverThumbnail {
+        path: path.to_string_lossy().to_string(),
+        width,
+        height,
+    }
+}
+
+fn image_error(e: image::ImageError) -> OmniReaderError {
+    OmniReaderError::ParseError {
+        message: format!("Invalid cover image: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::book::{Book, BookType};
+
+    fn png(image: DynamicImage) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        image
+            .write_to(&mut Cursor::new(&mut bytes), image::ImageFormat::Png)
+            .unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_cover_thumbnails() {
+        let cache =
+            std::env::temp_dir().join(format!("omnireader-covers-{}", uuid::Uuid::new_v4()));
+        let cache_dir = cache.to_string_lossy().to_string();
+        let db = Database::open_in_memory().unwrap();
+        let mut book = Book::new(
+            "Dune".to_string(),
+            None,
+            "/books/dune.epub".to_string(),
+            BookType::Epub,
+            10,
+        );
+        // Mostly orange with a half transparent top, which lands on white
+        let cover = image::RgbaImage::from_fn(600, 900, |_, y| match y {
+            0..100 => image::Rgba([0, 0, 0, 0]),
+            _ => image::Rgba([230, 120, 30, 255]),
+        });
+        book.cover_data = Some(png(DynamicImage::ImageRgba8(cover)));
+        db.insert_book(&book).unwrap();
+
+        let small = cover_thumbnail(
+            &db,
+            &cache_dir,
+            &book.id,
+            CoverSize::Small,
+            ThumbnailFormat::WebP,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!((small.width, small.height), (133, 200));
+        let decoded = image::open(&small.path).unwrap().into_rgb8();
+        assert_eq!(decoded.get_pixel(60, 2).0, [255, 255, 255]);
+        let large = cover_thumbnail(
+            &db,
+            &cache_dir,
+            &book.id,
+            CoverSize::Large,
+            ThumbnailFormat::Jpeg,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!((large.width, large.height), (533, 800));
+        assert_eq!(
+            cover_thumbnail(
+                &db,
+                &cache_dir,
+                &book.id,
+                CoverSize::Small,
+                ThumbnailFormat::WebP
+            )
+            .unwrap(),
+            Some(small.clone())
+        );
+
+        let placeholder = cover_placeholder(&db, &cache_dir, &book.id)
+            .unwrap()
+            .unwrap();
+        assert_eq!(placeholder.dominant_color, "#E6781E");
+        // 3 by 4 components for a portrait cover
+        assert_eq!(placeholder.blurhash.len(), 28);
+        assert!(placeholder.blurhash.starts_with('T'));
+        assert_eq!((placeholder.width, placeholder.height), (600, 900));
+
+        // A new cover replaces the cached files
+        db.set_book_cover(
+            &book.id,
+            Some(png(DynamicImage::ImageRgb8(RgbImage::new(300, 300)))),
+        )
+        .unwrap();
+        let replaced = cover_thumbnail(
+            &db,
+            &cache_dir,
+            &book.id,
+            CoverSize::Small,
+            ThumbnailFormat::WebP,
+        )
+        .unwrap()
+        .unwrap();
+        assert_ne!(replaced.path, small.path);
+        assert!(!Path::new(&small.path).exists() && !Path::new(&large.path).exists());
+
+        // Only directories of deleted books are pruned
+        let unrelated = [cache.join("pages"), cache.join(COVERS_DIR).join("fonts")];
+        for dir in &unrelated {
+            std::fs::create_dir_all(dir).unwrap();
+        }
+        db.delete_book(&book.id).unwrap();
+        assert_eq!(prune_cover_cache(&db, &cache_dir).unwrap(), 1);
+        assert!(!Path::new(&replaced.path).exists());
+        assert!(unrelated.iter().all(|dir| dir.exists()));
+
+        let mut other = Book::new(
+            "Emma".to_string(),
+            None,
+            "/books/emma.epub".to_string(),
+            BookType::Epub,
+            10,
+        );
+        other.cover_data = Some(png(DynamicImage::ImageRgb8(RgbImage::new(300, 300))));
+        db.insert_book(&other).unwrap();
+        let cached = cover_placeholder(&db, &cache_dir, &other.id).unwrap();
+        assert!(cached.is_some());
+        clear_cover_cache(&cache_dir, &other.id).unwrap();
+        assert!(!book_cache_dir(&cache_dir, &other.id).exists());
+        clear_cover_cache(&cache_dir, &other.id).unwrap();
+        assert!(normalize_cover(b"not an image".to_vec()).is_err());
+
+        std::fs::remove_dir_all(&cache).unwrap();
+    }
+
+    #[test]
+    fn test_blurhash() {
+        let image = RgbImage::from_pixel(8, 8, image::Rgb([255, 0, 0]));
+        // Size flag, AC maximum, then the average colour
+        assert_eq!(blurhash(&image, 1, 1), "00TI:j");
+        let hash = blurhash(&image, 4, 3);
+        assert_eq!(hash.len(), 28);
+        assert!(hash.starts_with('L') && hash[2..6] == *"TI:j");
+    }
+}
@@ -0,0 +1,782 @@
+//! Client-side encryption of data leaving the device
+//!
+//! Sync journals, WebDAV book uploads and backups can be sealed with keys
+//! that only the user's devices know:
+//! - A passphrase is stretched with Argon2id into a key-encryption key,
+//!   which unwraps the data keys of a [`Keyring`]. The keyring is stored
+//!   next to the data it protects, so every device with the passphrase can
+//!   open it.
+//! - Data is sealed with XChaCha20-Poly1305 under the keyring's current
+//!   data key. Each sealed message names its key, so data sealed before a
+//!   rotation stays readable.
+//! - The keyring holds a verification record sealed under the
+//!   key-encryption key, so a wrong passphrase is told apart from corrupted
+//!   data.
+//! - Large files are sealed as a stream of 64 KiB chunks. A random stream
+//!   ID, chunk numbers and a final-chunk flag are authenticated, so
+//!   reordered, truncated or spliced streams fail to open.
+//! - Scratch files that must not reach the disk in the clear are
+//!   [`SealedFile`]s, sealed block by block under a throwaway key.
+//! - Argon2id parameters read from a keyring are bounded, so a tampered
+//!   keyring cannot make unlocking exhaust memory or time.
+
+use crate::error::OmniReaderError;
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, OsRng, Payload};
+use chacha20poly1305::{KeyInit, XChaCha20Poly1305, XNonce};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// Value of the keyring's `format` field
+const KEYRING_FORMAT: &str = "omnireader-keyring";
+const KEYRING_VERSION: u32 = 1;
+/// Plaintext of the verification record
+const CHECK: &[u8] = b"omnireader keyring check";
+/// Key ID of the key-encryption key in sealed envelopes
+const KEK_ID: &str = "kek";
+
+const KEY_LEN: usize = 32;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const TAG_LEN: usize = 16;
+/// Plaintext bytes per stream chunk; only the final chunk is shorter
+const CHUNK_LEN: usize = 64 * 1024;
+const STREAM_MAGIC: &[u8; 4] = b"ORS1";
+/// Random bytes identifying a stream, bound into every chunk
+const STREAM_ID_LEN: usize = 16;
+/// Upper bounds of the Argon2id parameters accepted from a keyring
+const MAX_MEMORY_KIB: u32 = 256 * 1024;
+const MAX_ITERATIONS: u32 = 16;
+const MAX_PARALLELISM: u32 = 8;
+
+type Key = [u8; KEY_LEN];
+
+/// Keyring as stored next to the encrypted data
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct KeyringFile {
+    format: String,
+    version: u32,
+    kdf: Kdf,
+    /// Verification record, sealed under the key-encryption key
+    check: String,
+    /// Data keys, sealed under the key-encryption key
+    keys: Vec<WrappedKey>,
+    /// ID of the key new data is sealed with
+    current: String,
+}
+
+/// Argon2id parameters of the key-encryption key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Kdf {
+    algorithm: String,
+    salt: String,
+    memory_kib: u32,
+    iterations: u32,
+    parallelism: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct WrappedKey {
+    id: String,
+    key: String,
+    created_at: i64,
+}
+
+/// An unlocked keyring
+pub(crate) struct Keyring {
+    file: KeyringFile,
+    kek: Key,
+    keys: Vec<(String, Key)>,
+}
+
+impl Keyring {
+    /// A new keyring with one data key, locked by `passphrase`
+    pub fn create(passphrase: &str) -> Result<Self, OmniReaderError> {
+        let kdf = Kdf::generate();
+        let kek = kdf.derive(passphrase)?;
+        let mut keyring = Self {
+            file: KeyringFile {
+                format: KEYRING_FORMAT.to_string(),
+                version: KEYRING_VERSION,
+                check: BASE64.encode(seal_with(&kek, KEK_ID, CHECK, b"check")?),
+                kdf,
+                keys: Vec::new(),
+                current: String::new(),
+            },
+            kek,
+            keys: Vec::new(),
+        };
+        keyring.add_key()?;
+        Ok(keyring)
+    }
+
+    /// Open a stored keyring
+    ///
+    /// Fails with [`OmniReaderError::WrongPassphrase`] when the verification
+    /// record does not open with `passphrase`.
+    pub fn unlock(stored: &[u8], passphrase: &str) -> Result<Self, OmniReaderError> {
+        let file: KeyringFile =
+            serde_json::from_slice(stored).map_err(|e| OmniReaderError::ParseError {
+                message: format!("Invalid keyring: {}", e),
+            })?;
+        if file.format != KEYRING_FORMAT || file.version > KEYRING_VERSION {
+            return Err(OmniReaderError::UnsupportedFormat {
+                extension: format!("{} {}", file.format, file.version),
+            });
+        }
+        let kek = file.kdf.derive(passphrase)?;
+        let check = decode(&file.check)?;
+        if open_with(&kek, &check, b"check").as_deref() != Some(CHECK) {
+            return Err(OmniReaderError::WrongPassphrase);
+        }
+        let mut keys = Vec::new();
+        for wrapped in &file.keys {
+            let key = open_with(&kek, &decode(&wrapped.key)?, wrapped.id.as_bytes())
+                .and_then(|key| Key::try_from(key.as_slice()).ok())
+                .ok_or_else(|| corrupted("keyring"))?;
+            keys.push((wrapped.id.clone(), key));
+        }
+        if !keys.iter().any(|(id, _)| *id == file.current) {
+            return Err(corrupted("keyring"));
+        }
+        Ok(Self { file, kek, keys })
+    }
+
+    /// Serialized form, safe to store anywhere
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec_pretty(&self.file).unwrap_or_default()
+    }
+
+    /// Start sealing with a new data key, optionally changing the passphrase
+    ///
+    /// Earlier data keys are kept, so everything sealed so far stays
+    /// readable.
+    pub fn rotate(&mut self, new_passphrase: Option<&str>) -> Result<(), OmniReaderError> {
+        if let Some(passphrase) = new_passphrase {
+            let kdf = Kdf::generate();
+            self.kek = kdf.derive(passphrase)?;
+            self.file.kdf = kdf;
+            self.file.check = BASE64.encode(seal_with(&self.kek, KEK_ID, CHECK, b"check")?);
+            for (wrapped, (id, key)) in self.file.keys.iter_mut().zip(&self.keys) {
+                wrapped.key = BASE64.encode(seal_with(&self.kek, KEK_ID, key, id.as_bytes())?);
+            }
+        }
+        self.add_key()
+    }
+
+    /// Seal a message under the current data key; `aad` binds it to its purpose
+    pub fn seal(&self, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, OmniReaderError> {
+        let (id, key) = self.current();
+        seal_with(key, id, plaintext, aad)
+    }
+
+    /// Open a message sealed by [`Keyring::seal`] with the same `aad`
+    pub fn open(&self, sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>, OmniReaderError> {
+        let (id, _) = split_envelope(sealed).ok_or_else(|| corrupted("message"))?;
+        let key = self.key(id)?;
+        open_with(key, sealed, aad).ok_or_else(|| corrupted("message"))
+    }
+
+    /// Reader producing the sealed stream of everything `inner` yields
+    pub fn seal_reader<R: Read>(&self, inner: R, aad: &[u8]) -> SealReader<R> {
+        let (id, key) = self.current();
+        let mut header = STREAM_MAGIC.to_vec();
+        header.push(id.len() as u8);
+        header.extend_from_slice(id.as_bytes());
+        let mut stream_id = [0u8; STREAM_ID_LEN];
+        OsRng.fill_bytes(&mut stream_id);
+        header.extend_from_slice(&stream_id);
+        SealReader {
+            inner,
+            cipher: XChaCha20Poly1305::new(key.into()),
+            aad: aad.to_vec(),
+            stream_id,
+            index: 0,
+            buffer: header,
+            position: 0,
+            done: false,
+        }
+    }
+
+    /// Size of the sealed stream of `len` plaintext bytes
+    pub fn sealed_len(&self, len: u64) -> u64 {
+        let header = (STREAM_MAGIC.len() + 1 + self.current().0.len() + STREAM_ID_LEN) as u64;
+        let chunks = len / CHUNK_LEN as u64 + 1;
+        header + len + chunks * (NONCE_LEN + TAG_LEN) as u64
+    }
+
+    /// Reader yielding the plaintext of a sealed stream
+    ///
+    /// Reads fail with [`io::ErrorKind::InvalidData`] when the stream was
+    /// tampered with or cut short.
+    pub fn open_reader<R: Read>(
+        &self,
+        mut inner: R,
+        aad: &[u8],
+    ) -> Result<OpenReader<R>, OmniReaderError> {
+        let mut magic = [0u8; 5];
+        inner
+            .read_exact(&mut magic)
+            .map_err(|_| corrupted("stream"))?;
+        if &magic[..4] != STREAM_MAGIC {
+            return Err(corrupted("stream"));
+        }
+        let mut id = vec![0u8; magic[4] as usize];
+        inner.read_exact(&mut id).map_err(|_| corrupted("stream"))?;
+        let id = String::from_utf8(id).map_err(|_| corrupted("stream"))?;
+        let mut stream_id = [0u8; STREAM_ID_LEN];
+        inner
+            .read_exact(&mut stream_id)
+            .map_err(|_| corrupted("stream"))?;
+        Ok(OpenReader {
+            inner,
+            cipher: XChaCha20Poly1305::new(self.key(&id)?.into()),
+            aad: aad.to_vec(),
+            stream_id,
+            index: 0,
+            buffer: Vec::new(),
+            position: 0,
+            done: false,
+        })
+    }
+
+    fn current(&self) -> (&str, &Key) {
+        self.keys
+            .iter()
+            .find(|(id, _)| *id == self.file.current)
+            .map(|(id, key)| (id.as_str(), key))
+            .expect("unlocked keyring has its current key")
+    }
+
+    fn key(&self, id: &str) -> Result<&Key, OmniReaderError> {
+        self.keys
+            .iter()
+            .find(|(key_id, _)| key_id == id)
+            .map(|(_, key)| key)
+            .ok_or_else(|| OmniReaderError::Encryption {
+                message: format!("Unknown data key: {}", id),
+            })
+    }
+
+    fn add_key(&mut self) -> Result<(), OmniReaderError> {
+        let mut id = [0u8; 8];
+        OsRng.fill_bytes(&mut id);
+        let id: String = id.iter().map(|b| format!("{:02x}", b)).collect();
+        let mut key = [0u8; KEY_LEN];
+        OsRng.fill_bytes(&mut key);
+        self.file.keys.push(WrappedKey {
+            id: id.clone(),
+            key: BASE64.encode(seal_with(&self.kek, KEK_ID, &key, id.as_bytes())?),
+            created_at: chrono::Utc::now().timestamp(),
+        });
+        self.file.current = id.clone();
+        self.keys.push((id, key));
+        Ok(())
+    }
+}
+
+impl Kdf {
+    fn generate() -> Self {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        Self {
+            algorithm: "argon2id".to_string(),
+            salt: BASE64.encode(salt),
+            memory_kib: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+        }
+    }
+
+    fn derive(&self, passphrase: &str) -> Result<Key, OmniReaderError> {
+        if self.algorithm != "argon2id" {
+            return Err(OmniReaderError::UnsupportedFormat {
+                extension: self.algorithm.clone(),
+            });
+        }
+        if self.memory_kib > MAX_MEMORY_KIB
+            || self.iterations > MAX_ITERATIONS
+            || self.parallelism > MAX_PARALLELISM
+        {
+            return Err(OmniReaderError::Encryption {
+                message: format!(
+                    "Key derivation parameters out of range: {} KiB, {} iterations, {} lanes",
+                    self.memory_kib, self.iterations, self.parallelism
+                ),
+            });
+        }
+        let params = Params::new(
+            self.memory_kib,
+            self.iterations,
+            self.parallelism,
+            Some(KEY_LEN),
+        )
+        .map_err(kdf_error)?;
+        let mut key = [0u8; KEY_LEN];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &decode(&self.salt)?, &mut key)
+            .map_err(kdf_error)?;
+        Ok(key)
+    }
+}
+
+/// Reader sealing its inner reader chunk by chunk
+pub(crate) struct SealReader<R> {
+    inner: R,
+    cipher: XChaCha20Poly1305,
+    aad: Vec<u8>,
+    stream_id: [u8; STREAM_ID_LEN],
+    index: u64,
+    buffer: Vec<u8>,
+    position: usize,
+    done: bool,
+}
+
+impl<R: Read> Read for SealReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.buffer.len() && !self.done {
+            let mut chunk = Vec::with_capacity(CHUNK_LEN);
+            (&mut self.inner)
+                .take(CHUNK_LEN as u64)
+                .read_to_end(&mut chunk)?;
+            self.done = chunk.len() < CHUNK_LEN;
+            let mut nonce = [0u8; NONCE_LEN];
+            OsRng.fill_bytes(&mut nonce);
+            let aad = chunk_aad(&self.aad, &self.stream_id, self.index, self.done);
+            let sealed = self
+                .cipher
+                .encrypt(
+                    XNonce::from_slice(&nonce),
+                    Payload {
+                        msg: &chunk,
+                        aad: &aad,
+                    },
+                )
+                .map_err(|_| io::Error::other("encryption failed"))?;
+            self.buffer.clear();
+            self.buffer.extend_from_slice(&nonce);
+            self.buffer.extend(sealed);
+            self.position = 0;
+            self.index += 1;
+        }
+        let n = buf.len().min(self.buffer.len() - self.position);
+        buf[..n].copy_from_slice(&self.buffer[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+/// Reader opening a sealed stream chunk by chunk
+pub(crate) struct OpenReader<R> {
+    inner: R,
+    cipher: XChaCha20Poly1305,
+    aad: Vec<u8>,
+    stream_id: [u8; STREAM_ID_LEN],
+    index: u64,
+    buffer: Vec<u8>,
+    position: usize,
+    done: bool,
+}
+
+impl<R: Read> Read for OpenReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.buffer.len() && !self.done {
+            let mut chunk = Vec::with_capacity(NONCE_LEN + CHUNK_LEN + TAG_LEN);
+            (&mut self.inner)
+                .take((NONCE_LEN + CHUNK_LEN + TAG_LEN) as u64)
+                .read_to_end(&mut chunk)?;
+            if chunk.len() < NONCE_LEN + TAG_LEN {
+                return Err(invalid_stream());
+            }
+            let last = chunk.len() < NONCE_LEN + CHUNK_LEN + TAG_LEN;
+            let (nonce, sealed) = chunk.split_at(NONCE_LEN);
+            let aad = chunk_aad(&self.aad, &self.stream_id, self.index, last);
+            self.buffer = self
+                .cipher
+                .decrypt(
+                    XNonce::from_slice(nonce),
+                    Payload {
+                        msg: sealed,
+                        aad: &aad,
+                    },
+                )
+                .map_err(|_| invalid_stream())?;
+            self.position = 0;
+            self.index += 1;
+            self.done = last;
+        }
+        let n = buf.len().min(self.buffer.len() - self.position);
+        buf[..n].copy_from_slice(&self.buffer[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+/// A file sealed block by block under a throwaway key, for scratch data that
+/// must not reach the disk in the clear
+///
+/// Reads, writes and seeks like a plain file. Each block holds
+/// [`CHUNK_LEN`] plaintext bytes, sealed with its index as associated data
+/// when it is left after a change.
+pub(crate) struct SealedFile {
+    file: File,
+    cipher: XChaCha20Poly1305,
+    /// Plaintext length
+    len: u64,
+    position: u64,
+    /// Blocks written to the file so far
+    blocks: u64,
+    /// Index and plaintext of the block being read or written
+    block: Option<(u64, Vec<u8>)>,
+    dirty: bool,
+}
+
+impl SealedFile {
+    /// Seal everything written to `file`, which should be empty
+    pub fn new(file: File) -> Self {
+        let mut key = [0u8; KEY_LEN];
+        OsRng.fill_bytes(&mut key);
+        Self {
+            file,
+            cipher: XChaCha20Poly1305::new(&key.into()),
+            len: 0,
+            position: 0,
+            blocks: 0,
+            block: None,
+            dirty: false,
+        }
+    }
+
+    /// Plaintext of block `index`, loaded into memory
+    fn load(&mut self, index: u64) -> io::Result<&mut Vec<u8>> {
+        if self
+            .block
+            .as_ref()
+            .is_none_or(|(loaded, _)| *loaded != index)
+        {
+            self.store()?;
+            let data = if index < self.blocks {
+                let mut sealed = vec![0u8; NONCE_LEN + CHUNK_LEN + TAG_LEN];
+                self.file
+                    .seek(SeekFrom::Start(index * sealed.len() as u64))?;
+                self.file.read_exact(&mut sealed)?;
+                let (nonce, sealed) = sealed.split_at(NONCE_LEN);
+                self.cipher
+                    .decrypt(
+                        XNonce::from_slice(nonce),
+                        Payload {
+                            msg: sealed,
+                            aad: &index.to_be_bytes(),
+                        },
+                    )
+                    .map_err(|_| invalid_stream())?
+            } else {
+                vec![0u8; CHUNK_LEN]
+            };
+            self.block = Some((index, data));
+        }
+        Ok(&mut self.block.as_mut().expect("block was just loaded").1)
+    }
+
+    /// Seal the block in memory if it changed, and any blocks skipped before it
+    fn store(&mut self) -> io::Result<()> {
+        let Some((index, data)) = self.block.take() else {
+            return Ok(());
+        };
+        if self.dirty {
+            for gap in self.blocks..index {
+                self.seal_block(gap, &[0u8; CHUNK_LEN])?;
+            }
+            self.seal_block(index, &data)?;
+            self.blocks = self.blocks.max(index + 1);
+            self.dirty = false;
+        }
+        self.block = Some((index, data));
+        Ok(())
+    }
+
+    fn seal_block(&mut self, index: u64, data: &[u8]) -> io::Result<()> {
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        let sealed = self
+            .cipher
+            .encrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: data,
+                    aad: &index.to_be_bytes(),
+                },
+            )
+            .map_err(|_| io::Error::other("encryption failed"))?;
+        self.file.seek(SeekFrom::Start(
+            index * (NONCE_LEN + CHUNK_LEN + TAG_LEN) as u64,
+        ))?;
+        self.file.write_all(&nonce)?;
+        self.file.write_all(&sealed)
+    }
+}
+
+impl Read for SealedFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.len {
+            return Ok(0);
+        }
+        let offset = (self.position % CHUNK_LEN as u64) as usize;
+        let n = buf
+            .len()
+            .min(CHUNK_LEN - offset)
+            .min((self.len - self.position) as usize);
+        let block = self.load(self.position / CHUNK_LEN as u64)?;
+        buf[..n].copy_from_slice(&block[offset..offset + n]);
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for SealedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let offset = (self.position % CHUNK_LEN as u64) as usize;
+        let n = buf.len().min(CHUNK_LEN - offset);
+        let block = self.load(self.position / CHUNK_LEN as u64)?;
+        block[offset..offset + n].copy_from_slice(&buf[..n]);
+        self.dirty = true;
+        self.position += n as u64;
+        self.len = self.len.max(self.position);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.store()?;
+        self.file.flush()
+    }
+}
+
+impl Seek for SealedFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(delta) => self.len.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+        };
+        self.position = position.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "seek before start of file")
+        })?;
+        Ok(self.position)
+    }
+}
+
+/// Envelope: key ID length, key ID, nonce, ciphertext
+fn seal_with(
+    key: &Key,
+    id: &str,
+    plaintext: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>, OmniReaderError> {
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    let sealed = XChaCha20Poly1305::new(key.into())
+        .encrypt(
+            XNonce::from_slice(&nonce),
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
+        .map_err(|_| OmniReaderError::Encryption {
+            message: "Encryption failed".to_string(),
+        })?;
+    let mut envelope = vec![id.len() as u8];
+    envelope.extend_from_slice(id.as_bytes());
+    envelope.extend_from_slice(&nonce);
+    envelope.extend(sealed);
+    Ok(envelope)
+}
+
+fn open_with(key: &Key, envelope: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+    let (_, rest) = split_envelope(envelope)?;
+    let (nonce, sealed) = rest.split_at(NONCE_LEN);
+    XChaCha20Poly1305::new(key.into())
+        .decrypt(XNonce::from_slice(nonce), Payload { msg: sealed, aad })
+        .ok()
+}
+
+/// Key ID and the rest of an envelope
+fn split_envelope(envelope: &[u8]) -> Option<(&str, &[u8])> {
+    let (&len, rest) = envelope.split_first()?;
+    let id = std::str::from_utf8(rest.get(..len as usize)?).ok()?;
+    let rest = &rest[len as usize..];
+    (rest.len() >= NONCE_LEN + TAG_LEN).then_some((id, rest))
+}
+
+fn chunk_aad(aad: &[u8], stream_id: &[u8], index: u64, last: bool) -> Vec<u8> {
+    let mut chunk_aad = aad.to_vec();
+    chunk_aad.extend_from_slice(stream_id);
+    chunk_aad.extend_from_slice(&index.to_be_bytes());
+    chunk_aad.push(last as u8);
+    chunk_aad
+}
+
+fn decode(text: &str) -> Result<Vec<u8>, OmniReaderError> {
+    BASE64.decode(text).map_err(|_| corrupted("keyring"))
+}
+
+fn corrupted(what: &str) -> OmniReaderError {
+    OmniReaderError::Encryption {
+        message: format!("Encrypted {} is corrupted or was tampered with", what),
+    }
+}
+
+fn invalid_stream() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "encrypted stream is corrupted or was tampered with",
+    )
+}
+
+fn kdf_error(e: argon2::Error) -> OmniReaderError {
+    OmniReaderError::Encryption {
+        message: format!("Key derivation failed: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keyring_passphrase_and_rotation() {
+        let mut keyring = Keyring::create("correct horse").unwrap();
+        let sealed = keyring.seal(b"Dune", b"test").unwrap();
+        assert!(!sealed.windows(4).any(|w| w == b"Dune"));
+
+        let stored = keyring.to_bytes();
+        assert!(matches!(
+            Keyring::unlock(&stored, "battery staple"),
+            Err(OmniReaderError::WrongPassphrase)
+        ));
+        let unlocked = Keyring::unlock(&stored, "correct horse").unwrap();
+        assert_eq!(unlocked.open(&sealed, b"test").unwrap(), b"Dune");
+        assert!(unlocked.open(&sealed, b"other purpose").is_err());
+
+        keyring.rotate(Some("battery staple")).unwrap();
+        let rotated = Keyring::unlock(&keyring.to_bytes(), "battery staple").unwrap();
+        assert_ne!(rotated.current().0, unlocked.current().0);
+        assert_eq!(rotated.open(&sealed, b"test").unwrap(), b"Dune");
+        assert!(matches!(
+            Keyring::unlock(&keyring.to_bytes(), "correct horse"),
+            Err(OmniReaderError::WrongPassphrase)
+        ));
+    }
+
+    #[test]
+    fn test_stream_round_trip_and_truncation() {
+        let keyring = Keyring::create("passphrase").unwrap();
+        for len in [0, 10, CHUNK_LEN, CHUNK_LEN * 2 + 7] {
+            let plaintext: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            let mut sealed = Vec::new();
+            keyring
+                .seal_reader(plaintext.as_slice(), b"file")
+                .read_to_end(&mut sealed)
+                .unwrap();
+            assert_eq!(sealed.len() as u64, keyring.sealed_len(len as u64));
+
+            let mut opened = Vec::new();
+            keyring
+                .open_reader(sealed.as_slice(), b"file")
+                .unwrap()
+                .read_to_end(&mut opened)
+                .unwrap();
+            assert_eq!(opened, plaintext);
+
+            // Dropping the final chunk is detected
+            if len >= CHUNK_LEN {
+                let header = STREAM_MAGIC.len() + 1 + keyring.current().0.len() + STREAM_ID_LEN;
+                let cut = header + len / CHUNK_LEN * (NONCE_LEN + CHUNK_LEN + TAG_LEN);
+                let mut reader = keyring.open_reader(&sealed[..cut], b"file").unwrap();
+                assert!(reader.read_to_end(&mut Vec::new()).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn test_stream_splice_rejected() {
+        let keyring = Keyring::create("passphrase").unwrap();
+        let seal = |plaintext: &[u8]| {
+            let mut sealed = Vec::new();
+            keyring
+                .seal_reader(plaintext, b"file")
+                .read_to_end(&mut sealed)
+                .unwrap();
+            sealed
+        };
+        let first = seal(&vec![1u8; CHUNK_LEN + 7]);
+        let second = seal(&vec![2u8; CHUNK_LEN + 7]);
+
+        // The first chunk of another stream under the same key and purpose
+        let header = STREAM_MAGIC.len() + 1 + keyring.current().0.len() + STREAM_ID_LEN;
+        let chunk = NONCE_LEN + CHUNK_LEN + TAG_LEN;
+        let mut spliced = first[..header].to_vec();
+        spliced.extend_from_slice(&second[header..header + chunk]);
+        spliced.extend_from_slice(&first[header + chunk..]);
+        let mut reader = keyring.open_reader(spliced.as_slice(), b"file").unwrap();
+        assert!(reader.read_to_end(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_sealed_file() {
+        let path =
+            std::env::temp_dir().join(format!("omnireader-sealed-{}.tmp", uuid::Uuid::new_v4()));
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .unwrap();
+        let mut sealed = SealedFile::new(file);
+        let plaintext: Vec<u8> = (0..CHUNK_LEN * 2 + 7).map(|i| (i % 251) as u8).collect();
+        sealed.write_all(&plaintext).unwrap();
+        // Patch across a block boundary, as zip writers do with headers
+        sealed.seek(SeekFrom::Start(CHUNK_LEN as u64 - 2)).unwrap();
+        sealed.write_all(b"DUNE").unwrap();
+        sealed.flush().unwrap();
+        let on_disk = std::fs::read(&path).unwrap();
+        assert!(!on_disk.windows(4).any(|w| w == b"DUNE"));
+
+        let mut expected = plaintext;
+        expected[CHUNK_LEN - 2..CHUNK_LEN + 2].copy_from_slice(b"DUNE");
+        let mut read = Vec::new();
+        sealed.rewind().unwrap();
+        sealed.read_to_end(&mut read).unwrap();
+        assert_eq!(read, expected);
+        assert_eq!(
+            sealed.seek(SeekFrom::End(-3)).unwrap(),
+            expected.len() as u64 - 3
+        );
+        assert!(
+            sealed
+                .seek(SeekFrom::Current(-(expected.len() as i64)))
+                .is_err()
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_kdf_parameters_bounded() {
+        let keyring = Keyring::create("passphrase").unwrap();
+        let mut file: serde_json::Value = serde_json::from_slice(&keyring.to_bytes()).unwrap();
+        file["kdf"]["memoryKib"] = serde_json::Value::from(u32::MAX);
+        let stored = serde_json::to_vec(&file).unwrap();
+        assert!(matches!(
+            Keyring::unlock(&stored, "passphrase"),
+            Err(OmniReaderError::Encryption { .. })
+        ));
+    }
+}
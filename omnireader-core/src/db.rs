@@ -1,9 +1,36 @@
 //! SQLite database layer
+//!
+//! SQLite is built with SQLCipher: a library opened with a key is encrypted
+//! page by page at rest, one opened without is a plain SQLite file.
 
-use crate::annotation::{Annotation, AnnotationType, ReadingPosition};
-use crate::book::{Book, BookType};
+use crate::annotation::{
+    AnchorStatus, Annotation, AnnotationImport, AnnotationRevision, AnnotationType, HighlightColor,
+    PaletteColor, ReadingPosition,
+};
+use crate::backup::RestoreReport;
+use crate::book::{Book, BookIdentifier, BookImport, BookType, ReadingStatus};
+use crate::collection::Collection;
+use crate::cover;
 use crate::error::OmniReaderError;
-use rusqlite::{Connection, params};
+use crate::library::{
+    BookSummary, DEFAULT_PAGE_SIZE, LibraryPage, LibraryQuery, PROGRESS, decode_cursor,
+    encode_cursor,
+};
+use crate::locator::{Locator, LocatorText};
+use crate::notebook::NotebookQuery;
+use crate::session::ReadingSession;
+use crate::shelf::{ShelfQuery, SmartShelf};
+use crate::status::BookRead;
+use crate::sync::{JournalCursor, SyncField};
+use crate::tag::Tag;
+use rusqlite::serialize::OwnedData;
+use rusqlite::types::Value;
+use rusqlite::{
+    Connection, DatabaseName, ErrorCode, OptionalExtension, Row, params, params_from_iter,
+};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::ptr::NonNull;
 use std::sync::Mutex;
 use uniffi;
 
@@ -11,6 +38,8 @@ use uniffi;
 #[derive(uniffi::Object)]
 pub struct Database {
     conn: Mutex<Connection>,
+    /// Opened with a key, see [`Database::open_encrypted`]
+    encrypted: bool,
 }
 
 #[uniffi::export]
@@ -19,8 +48,28 @@ impl Database {
     #[uniffi::constructor]
     pub fn open(path: String) -> Result<Self, OmniReaderError> {
         let conn = Connection::open(&path)?;
+        check_key(&conn)?;
         let db = Self {
             conn: Mutex::new(conn),
+            encrypted: false,
+        };
+        db.initialize_schema()?;
+        Ok(db)
+    }
+
+    /// Open or create an encrypted database at the specified path
+    ///
+    /// Fails with [`OmniReaderError::WrongPassphrase`] when `key` does not
+    /// open the file, including when it is a plaintext library; convert
+    /// those with [`encrypt_database`] first.
+    #[uniffi::constructor]
+    pub fn open_encrypted(path: String, key: String) -> Result<Self, OmniReaderError> {
+        let conn = Connection::open(&path)?;
+        set_key(&conn, &key)?;
+        check_key(&conn)?;
+        let db = Self {
+            conn: Mutex::new(conn),
+            encrypted: true,
         };
         db.initialize_schema()?;
         Ok(db)
@@ -32,10 +81,184 @@ impl Database {
         let conn = Connection::open_in_memory()?;
         let db = Self {
             conn: Mutex::new(conn),
+            encrypted: false,
         };
         db.initialize_schema()?;
         Ok(db)
     }
+
+    /// Change the key of an encrypted database
+    pub fn rekey(&self, new_key: String) -> Result<(), OmniReaderError> {
+        if !self.encrypted {
+            return Err(OmniReaderError::Encryption {
+                message: "Database is not encrypted; convert it with encrypt_database".to_string(),
+            });
+        }
+        if new_key.is_empty() {
+            return Err(empty_key());
+        }
+        let conn = self.conn.lock().unwrap();
+        conn.pragma_update(None, "rekey", &new_key)?;
+        Ok(())
+    }
+}
+
+/// Encrypt the plaintext database at `path` in place
+///
+/// The database must not be open. It is copied into an encrypted file next
+/// to it, which then replaces the original.
+#[uniffi::export]
+pub fn encrypt_database(path: String, key: String) -> Result<(), OmniReaderError> {
+    if key.is_empty() {
+        return Err(empty_key());
+    }
+    convert_database(&path, None, &key)
+}
+
+/// Decrypt the encrypted database at `path` in place
+///
+/// The database must not be open.
+#[uniffi::export]
+pub fn decrypt_database(path: String, key: String) -> Result<(), OmniReaderError> {
+    convert_database(&path, Some(&key), "")
+}
+
+/// Copy the database at `path` into a file encrypted with `to_key` (plain
+/// when empty) and swap it in
+fn convert_database(
+    path: &str,
+    from_key: Option<&str>,
+    to_key: &str,
+) -> Result<(), OmniReaderError> {
+    if !Path::new(path).exists() {
+        return Err(OmniReaderError::FileNotFound {
+            path: path.to_string(),
+        });
+    }
+    let conn = Connection::open(path)?;
+    if let Some(key) = from_key {
+        set_key(&conn, key)?;
+    }
+    check_key(&conn)?;
+
+    let converted = PathBuf::from(format!("{}.converting", path));
+    let _ = std::fs::remove_file(&converted);
+    let exported = export_database(&conn, &converted, to_key);
+    drop(conn);
+    if let Err(e) = exported {
+        let _ = std::fs::remove_file(&converted);
+        return Err(e);
+    }
+    std::fs::rename(&converted, path)?;
+    Ok(())
+}
+
+/// Copy the database of `conn` into a new file at `path`, encrypted with
+/// `key` (plain when empty)
+pub(crate) fn export_database(
+    conn: &Connection,
+    path: &Path,
+    key: &str,
+) -> Result<(), OmniReaderError> {
+    export_with(conn, path, key, |_| Ok(()))
+}
+
+/// Export the database of `conn` into `path`, attached as `exported`, and
+/// run `then` before detaching it
+fn export_with<T>(
+    conn: &Connection,
+    path: &Path,
+    key: &str,
+    then: impl FnOnce(&Connection) -> rusqlite::Result<T>,
+) -> Result<T, OmniReaderError> {
+    let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    conn.execute(
+        "ATTACH DATABASE ?1 AS exported KEY ?2",
+        params![path.to_string_lossy(), key],
+    )?;
+    let exported = conn
+        .query_row("SELECT sqlcipher_export('exported')", [], |_| Ok(()))
+        .and_then(|_| {
+            // Not part of the export
+            conn.pragma_update(
+                Some(DatabaseName::Attached("exported")),
+                "user_version",
+                version,
+            )
+        })
+        .and_then(|_| then(conn));
+    conn.execute_batch("DETACH DATABASE exported")?;
+    Ok(exported?)
+}
+
+/// Random raw key for a scratch copy of the database; raw keys skip
+/// SQLCipher's key derivation
+pub(crate) fn scratch_key() -> String {
+    format!(
+        "x'{}{}'",
+        uuid::Uuid::new_v4().simple(),
+        uuid::Uuid::new_v4().simple()
+    )
+}
+
+/// Open a database file encrypted with `key`, without migrating it
+pub(crate) fn open_with_key(path: &Path, key: &str) -> Result<Connection, OmniReaderError> {
+    let conn = Connection::open(path)?;
+    set_key(&conn, key)?;
+    check_key(&conn)?;
+    Ok(conn)
+}
+
+/// The plain SQLite file of the database of `conn`, built in memory
+pub(crate) fn serialize_database(conn: &Connection) -> Result<Vec<u8>, OmniReaderError> {
+    export_with(conn, Path::new(":memory:"), "", |conn| {
+        Ok(conn.serialize(DatabaseName::Attached("exported"))?.to_vec())
+    })
+}
+
+/// Open a plain SQLite file held in memory
+pub(crate) fn deserialize_database(bytes: &[u8]) -> Result<Connection, OmniReaderError> {
+    let mut conn = Connection::open_in_memory()?;
+    // SQLite takes ownership of the copy, which must come from its allocator
+    let copy = NonNull::new(unsafe { rusqlite::ffi::sqlite3_malloc64(bytes.len() as u64) })
+        .ok_or_else(|| OmniReaderError::Database {
+            message: "Out of memory loading a database".to_string(),
+        })?
+        .cast::<u8>();
+    // SAFETY: `copy` was just allocated by SQLite with room for `bytes`
+    let data = unsafe {
+        std::ptr::copy_nonoverlapping(bytes.as_ptr(), copy.as_ptr(), bytes.len());
+        OwnedData::from_raw_nonnull(copy, bytes.len())
+    };
+    conn.deserialize(DatabaseName::Main, data, false)?;
+    Ok(conn)
+}
+
+fn set_key(conn: &Connection, key: &str) -> Result<(), OmniReaderError> {
+    if key.is_empty() {
+        return Err(empty_key());
+    }
+    conn.pragma_update(None, "key", key)?;
+    Ok(())
+}
+
+/// Fail with `WrongPassphrase` when the database cannot be read with its key
+fn check_key(conn: &Connection) -> Result<(), OmniReaderError> {
+    match conn.query_row("SELECT COUNT(*) FROM sqlite_master", [], |row| {
+        row.get::<_, i64>(0)
+    }) {
+        Ok(_) => Ok(()),
+        Err(rusqlite::Error::SqliteFailure(e, _)) if e.code == ErrorCode::NotADatabase => {
+            Err(OmniReaderError::WrongPassphrase)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn empty_key() -> OmniReaderError {
+    OmniReaderError::Encryption {
+        message: "Encryption key must not be empty".to_string(),
+    }
 }
 
 impl Database {
@@ -79,6 +302,14 @@ impl Database {
             CREATE INDEX IF NOT EXISTS idx_annotations_book_id ON annotations(book_id);
             "#,
         )?;
+
+        let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        for (index, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+            let tx = conn.unchecked_transaction()?;
+            tx.execute_batch(migration)?;
+            tx.pragma_update(None, "user_version", (index + 1) as i64)?;
+            tx.commit()?;
+        }
         Ok(())
     }
 
@@ -87,83 +318,204 @@ impl Database {
     /// Insert a new book into the database
     pub fn insert_book(&self, book: &Book) -> Result<(), OmniReaderError> {
         let conn = self.conn.lock().unwrap();
-        conn.execute(
-            r#"
-            INSERT INTO books (id, title, author, file_path, file_type, cover_data, added_at, last_read_at, total_pages)
-            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
-            "#,
-            params![
-                book.id,
-                book.title,
-                book.author,
-                book.file_path,
-                book.file_type.extension(),
-                book.cover_data,
-                book.added_at,
-                book.last_read_at,
-                book.total_pages,
-            ],
-        )?;
+        insert_book_row(&conn, book)
+    }
+
+    /// Insert several books with their tags and identifiers in one transaction
+    pub fn insert_books(&self, imports: &[BookImport]) -> Result<(), OmniReaderError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        for BookImport {
+            book,
+            tags,
+            identifiers,
+        } in imports
+        {
+            insert_book_row(&tx, book)?;
+            for name in tags {
+                let tag = find_or_create_tag(&tx, name)?;
+                tx.execute(
+                    "INSERT OR IGNORE INTO book_tags (book_id, tag_id) VALUES (?1, ?2)",
+                    params![book.id, tag.id],
+                )?;
+            }
+            replace_identifiers(&tx, &book.id, identifiers)?;
+        }
+        tx.commit()?;
         Ok(())
     }
 
     /// Get all books, sorted by recently added
     pub fn get_all_books(&self) -> Result<Vec<Book>, OmniReaderError> {
         let conn = self.conn.lock().unwrap();
-        let mut stmt = conn.prepare(
-            "SELECT id, title, author, file_path, file_type, cover_data, added_at, last_read_at, total_pages 
-             FROM books ORDER BY added_at DESC"
-        )?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM books ORDER BY added_at DESC",
+            BOOK_COLUMNS
+        ))?;
 
         let books = stmt
-            .query_map([], |row| {
-                let file_type_str: String = row.get(4)?;
-                let file_type = BookType::from_extension(&file_type_str).unwrap_or(BookType::Pdf);
-                Ok(Book {
-                    id: row.get(0)?,
-                    title: row.get(1)?,
-                    author: row.get(2)?,
-                    file_path: row.get(3)?,
-                    file_type,
-                    cover_data: row.get(5)?,
-                    added_at: row.get(6)?,
-                    last_read_at: row.get(7)?,
-                    total_pages: row.get(8)?,
-                })
-            })?
+            .query_map([], book_from_row)?
             .collect::<Result<Vec<_>, _>>()?;
 
         Ok(books)
     }
 
-    /// Get a single book by ID
-    pub fn get_book(&self, id: &str) -> Result<Option<Book>, OmniReaderError> {
+    /// Get one page of the library as book summaries, without covers
+    pub fn query_library(&self, query: &LibraryQuery) -> Result<LibraryPage, OmniReaderError> {
+        let (mut clause, mut values) = match &query.filter {
+            Some(filter) => ShelfQuery::parse(filter)?.where_clause(chrono::Utc::now().timestamp()),
+            None => ("1".to_string(), Vec::new()),
+        };
+        if let Some(collection_id) = &query.collection_id {
+            values.push(Value::Text(collection_id.clone()));
+            clause = format!(
+                "{} AND b.id IN (SELECT book_id FROM collection_books WHERE collection_id = ?{})",
+                clause,
+                values.len()
+            );
+        }
         let conn = self.conn.lock().unwrap();
-        let mut stmt = conn.prepare(
-            "SELECT id, title, author, file_path, file_type, cover_data, added_at, last_read_at, total_pages 
-             FROM books WHERE id = ?1"
+        let total: u32 = conn.query_row(
+            &format!("SELECT COUNT(*) FROM books b WHERE {}", clause),
+            params_from_iter(values.iter()),
+            |row| row.get(0),
         )?;
 
-        let mut rows = stmt.query(params![id])?;
-        if let Some(row) = rows.next()? {
-            let file_type_str: String = row.get(4)?;
-            let file_type = BookType::from_extension(&file_type_str).unwrap_or(BookType::Pdf);
-            Ok(Some(Book {
-                id: row.get(0)?,
-                title: row.get(1)?,
-                author: row.get(2)?,
-                file_path: row.get(3)?,
-                file_type,
-                cover_data: row.get(5)?,
-                added_at: row.get(6)?,
-                last_read_at: row.get(7)?,
-                total_pages: row.get(8)?,
-            }))
-        } else {
-            Ok(None)
+        let keys = query.sort.keys();
+        let direction = if query.descending { "DESC" } else { "ASC" };
+        if let Some(cursor) = &query.after {
+            let cursor = decode_cursor(cursor, keys.len())?;
+            let placeholders = (values.len() + 1..=values.len() + cursor.len())
+                .map(|i| format!("?{}", i))
+                .collect::<Vec<_>>()
+                .join(", ");
+            clause = format!(
+                "{} AND ({}, b.id) {} ({})",
+                clause,
+                keys.join(", "),
+                if query.descending { "<" } else { ">" },
+                placeholders
+            );
+            values.extend(cursor);
+        }
+        let order = keys
+            .iter()
+            .chain(std::iter::once(&"b.id"))
+            .map(|key| format!("{} {}", key, direction))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).max(1) as usize;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {}, {}, {} FROM books b WHERE {} ORDER BY {} LIMIT {}",
+            SUMMARY_COLUMNS,
+            PROGRESS,
+            keys.join(", "),
+            clause,
+            order,
+            limit + 1
+        ))?;
+
+        let mut rows = stmt.query(params_from_iter(values))?;
+        let mut books = Vec::new();
+        let mut last_key = Vec::new();
+        let mut more = false;
+        while let Some(row) = rows.next()? {
+            if books.len() == limit {
+                more = true;
+                break;
+            }
+            let book = summary_from_row(row)?;
+            last_key = (0..keys.len())
+                .map(|i| row.get::<_, Value>(SUMMARY_COLUMN_COUNT + i))
+                .collect::<Result<Vec<_>, _>>()?;
+            last_key.push(Value::Text(book.id.clone()));
+            books.push(book);
+        }
+
+        Ok(LibraryPage {
+            books,
+            total,
+            next_cursor: more.then(|| encode_cursor(&last_key)),
+        })
+    }
+
+    /// Get a book's cover image data
+    pub fn get_book_cover(&self, id: &str) -> Result<Option<Vec<u8>>, OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        let cover = conn
+            .query_row(
+                "SELECT cover_data FROM books WHERE id = ?1",
+                params![id],
+                |row| row.get::<_, Option<Vec<u8>>>(0),
+            )
+            .optional()?;
+        Ok(cover.flatten())
+    }
+
+    /// Get the hash identifying a book's cover, without loading the image
+    ///
+    /// Covers stored before hashes were kept are hashed on first lookup.
+    pub(crate) fn get_book_cover_hash(&self, id: &str) -> Result<Option<String>, OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        let row = conn
+            .query_row(
+                "SELECT cover_hash, cover_data IS NOT NULL FROM books WHERE id = ?1",
+                params![id],
+                |row| Ok((row.get::<_, Option<String>>(0)?, row.get::<_, bool>(1)?)),
+            )
+            .optional()?;
+        match row {
+            Some((Some(hash), true)) => Ok(Some(hash)),
+            Some((None, true)) => {
+                let data: Vec<u8> = conn.query_row(
+                    "SELECT cover_data FROM books WHERE id = ?1",
+                    params![id],
+                    |row| row.get(0),
+                )?;
+                let hash = cover::cover_hash(&data);
+                conn.execute(
+                    "UPDATE books SET cover_hash = ?1 WHERE id = ?2",
+                    params![hash, id],
+                )?;
+                Ok(Some(hash))
+            }
+            _ => Ok(None),
         }
     }
 
+    /// Replace or remove a book's cover image
+    ///
+    /// Cached thumbnails live outside the database; see
+    /// `cover::clear_cover_cache`.
+    pub fn set_book_cover(&self, id: &str, cover: Option<Vec<u8>>) -> Result<(), OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE books SET cover_data = ?1, cover_hash = ?2 WHERE id = ?3",
+            params![cover, cover.as_deref().map(cover::cover_hash), id],
+        )?;
+        Ok(())
+    }
+
+    /// Get a single book by ID
+    pub fn get_book(&self, id: &str) -> Result<Option<Book>, OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        let book = conn
+            .query_row(
+                &format!("SELECT {} FROM books WHERE id = ?1", BOOK_COLUMNS),
+                params![id],
+                book_from_row,
+            )
+            .optional()?;
+        Ok(book)
+    }
+
+    /// Get a single book by ID, failing when it does not exist
+    pub(crate) fn require_book(&self, id: &str) -> Result<Book, OmniReaderError> {
+        self.get_book(id)?.ok_or_else(|| OmniReaderError::Database {
+            message: format!("Book not found: {}", id),
+        })
+    }
+
     /// Check if a book with the given file path exists
     pub fn book_exists_by_path(&self, file_path: &str) -> Result<bool, OmniReaderError> {
         let conn = self.conn.lock().unwrap();
@@ -175,10 +527,30 @@ impl Database {
         Ok(count > 0)
     }
 
-    /// Delete a book and all its annotations
+    /// Delete a book with its annotations, reading data, tags and collection entries
+    ///
+    /// Cached cover thumbnails live outside the database; see
+    /// `cover::clear_cover_cache`.
     pub fn delete_book(&self, id: &str) -> Result<(), OmniReaderError> {
-        let conn = self.conn.lock().unwrap();
-        conn.execute("DELETE FROM books WHERE id = ?1", params![id])?;
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        for table in ANNOTATION_TABLES {
+            tx.execute(
+                &format!(
+                    "DELETE FROM {} WHERE annotation_id IN (SELECT id FROM annotations WHERE book_id = ?1)",
+                    table
+                ),
+                params![id],
+            )?;
+        }
+        for table in BOOK_TABLES {
+            tx.execute(
+                &format!("DELETE FROM {} WHERE book_id = ?1", table),
+                params![id],
+            )?;
+        }
+        tx.execute("DELETE FROM books WHERE id = ?1", params![id])?;
+        tx.commit()?;
         Ok(())
     }
 
@@ -193,191 +565,3170 @@ impl Database {
         Ok(())
     }
 
-    // === Annotation Operations ===
-
-    /// Insert a new annotation
-    pub fn insert_annotation(&self, annotation: &Annotation) -> Result<(), OmniReaderError> {
-        let conn = self.conn.lock().unwrap();
-        let annotation_type = match annotation.annotation_type {
-            AnnotationType::Highlight => "highlight",
-            AnnotationType::Note => "note",
-        };
-        conn.execute(
-            r#"
-            INSERT INTO annotations (id, book_id, annotation_type, start_percent, end_percent, page_number, color, selected_text, note_text, created_at)
-            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
-            "#,
-            params![
-                annotation.id,
-                annotation.book_id,
-                annotation_type,
-                annotation.start_percent,
-                annotation.end_percent,
-                annotation.page_number,
-                annotation.color,
-                annotation.selected_text,
-                annotation.note_text,
-                annotation.created_at,
-            ],
-        )?;
-        Ok(())
-    }
-
-    /// Get all annotations for a book
-    pub fn get_annotations(&self, book_id: &str) -> Result<Vec<Annotation>, OmniReaderError> {
+    /// Get a book's external identifiers, sorted by scheme
+    pub fn get_book_identifiers(
+        &self,
+        book_id: &str,
+    ) -> Result<Vec<BookIdentifier>, OmniReaderError> {
         let conn = self.conn.lock().unwrap();
         let mut stmt = conn.prepare(
-            "SELECT id, book_id, annotation_type, start_percent, end_percent, page_number, color, selected_text, note_text, created_at 
-             FROM annotations WHERE book_id = ?1 ORDER BY start_percent"
+            "SELECT scheme, value FROM book_identifiers WHERE book_id = ?1 ORDER BY scheme",
         )?;
 
-        let annotations = stmt
+        let identifiers = stmt
             .query_map(params![book_id], |row| {
-                let type_str: String = row.get(2)?;
-                let annotation_type = match type_str.as_str() {
-                    "note" => AnnotationType::Note,
-                    _ => AnnotationType::Highlight,
-                };
-                Ok(Annotation {
-                    id: row.get(0)?,
-                    book_id: row.get(1)?,
-                    annotation_type,
-                    start_percent: row.get(3)?,
-                    end_percent: row.get(4)?,
-                    page_number: row.get(5)?,
-                    color: row.get(6)?,
-                    selected_text: row.get(7)?,
-                    note_text: row.get(8)?,
-                    created_at: row.get(9)?,
+                Ok(BookIdentifier {
+                    scheme: row.get(0)?,
+                    value: row.get(1)?,
                 })
             })?
             .collect::<Result<Vec<_>, _>>()?;
 
-        Ok(annotations)
+        Ok(identifiers)
     }
 
-    /// Delete an annotation
-    pub fn delete_annotation(&self, id: &str) -> Result<(), OmniReaderError> {
-        let conn = self.conn.lock().unwrap();
-        conn.execute("DELETE FROM annotations WHERE id = ?1", params![id])?;
+    /// Replace a book's external identifiers
+    pub fn set_book_identifiers(
+        &self,
+        book_id: &str,
+        identifiers: &[BookIdentifier],
+    ) -> Result<(), OmniReaderError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        replace_identifiers(&tx, book_id, identifiers)?;
+        tx.commit()?;
         Ok(())
     }
 
-    // === Reading Position Operations ===
+    /// Tag a book, creating the tag if it does not exist yet
+    pub fn add_book_tag(&self, book_id: &str, name: &str) -> Result<Tag, OmniReaderError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let tag = find_or_create_tag(&tx, name)?;
+        tx.execute(
+            "INSERT OR IGNORE INTO book_tags (book_id, tag_id) VALUES (?1, ?2)",
+            params![book_id, tag.id],
+        )?;
+        tx.commit()?;
+        Ok(tag)
+    }
 
-    /// Save or update reading position
-    pub fn save_reading_position(&self, position: &ReadingPosition) -> Result<(), OmniReaderError> {
+    /// Tag several books, creating the tag if it does not exist yet
+    pub fn tag_books(&self, book_ids: &[String], name: &str) -> Result<Tag, OmniReaderError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let tag = find_or_create_tag(&tx, name)?;
+        for book_id in book_ids {
+            tx.execute(
+                "INSERT OR IGNORE INTO book_tags (book_id, tag_id) SELECT id, ?2 FROM books WHERE id = ?1",
+                params![book_id, tag.id],
+            )?;
+        }
+        tx.commit()?;
+        Ok(tag)
+    }
+
+    /// Remove a tag from a book
+    pub fn remove_book_tag(&self, book_id: &str, name: &str) -> Result<(), OmniReaderError> {
+        self.untag_books(&[book_id.to_string()], name)
+    }
+
+    /// Remove a tag from several books
+    pub fn untag_books(&self, book_ids: &[String], name: &str) -> Result<(), OmniReaderError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        for book_id in book_ids {
+            tx.execute(
+                r#"
+                DELETE FROM book_tags WHERE book_id = ?1
+                    AND tag_id IN (SELECT id FROM tags WHERE name = ?2 COLLATE NOCASE)
+                "#,
+                params![book_id, name.trim()],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Get the books carrying a tag, sorted by title
+    pub fn get_books_by_tag(&self, name: &str) -> Result<Vec<Book>, OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM books WHERE id IN (
+                SELECT bt.book_id FROM book_tags bt JOIN tags t ON t.id = bt.tag_id
+                WHERE t.name = ?1 COLLATE NOCASE
+             ) ORDER BY title COLLATE NOCASE",
+            BOOK_COLUMNS
+        ))?;
+
+        let books = stmt
+            .query_map(params![name.trim()], book_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(books)
+    }
+
+    /// Get the tags of a book, sorted by name
+    pub fn get_book_tags(&self, book_id: &str) -> Result<Vec<Tag>, OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT t.id, t.name FROM tags t JOIN book_tags bt ON bt.tag_id = t.id 
+             WHERE bt.book_id = ?1 ORDER BY t.name COLLATE NOCASE",
+        )?;
+
+        let tags = stmt
+            .query_map(params![book_id], tag_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(tags)
+    }
+
+    // === Collection Operations ===
+
+    /// Create an empty collection after the existing ones
+    pub fn create_collection(&self, name: &str) -> Result<Collection, OmniReaderError> {
         let conn = self.conn.lock().unwrap();
+        let name = collection_name(name)?;
+        let position: u32 = conn.query_row(
+            "SELECT COALESCE(MAX(position) + 1, 0) FROM collections",
+            [],
+            |row| row.get(0),
+        )?;
+        let collection = Collection::new(name.to_string(), position);
         conn.execute(
-            r#"
-            INSERT INTO reading_positions (book_id, percent, page_number, updated_at)
-            VALUES (?1, ?2, ?3, ?4)
-            ON CONFLICT(book_id) DO UPDATE SET
-                percent = excluded.percent,
-                page_number = excluded.page_number,
-                updated_at = excluded.updated_at
-            "#,
+            "INSERT INTO collections (id, name, position, created_at) VALUES (?1, ?2, ?3, ?4)",
             params![
-                position.book_id,
-                position.percent,
-                position.page_number,
-                position.updated_at,
+                collection.id,
+                collection.name,
+                collection.position,
+                collection.created_at
             ],
         )?;
-        Ok(())
+        Ok(collection)
     }
 
-    /// Get reading position for a book
-    pub fn get_reading_position(
-        &self,
-        book_id: &str,
-    ) -> Result<Option<ReadingPosition>, OmniReaderError> {
+    /// Rename a collection
+    pub fn rename_collection(&self, id: &str, name: &str) -> Result<(), OmniReaderError> {
         let conn = self.conn.lock().unwrap();
-        let mut stmt = conn.prepare(
-            "SELECT book_id, percent, page_number, updated_at FROM reading_positions WHERE book_id = ?1"
+        let name = collection_name(name)?;
+        let changed = conn.execute(
+            "UPDATE collections SET name = ?1 WHERE id = ?2",
+            params![name, id],
         )?;
-
-        let mut rows = stmt.query(params![book_id])?;
-        if let Some(row) = rows.next()? {
-            Ok(Some(ReadingPosition {
-                book_id: row.get(0)?,
-                percent: row.get(1)?,
-                page_number: row.get(2)?,
-                updated_at: row.get(3)?,
-            }))
-        } else {
-            Ok(None)
+        if changed == 0 {
+            return Err(collection_not_found(id));
         }
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Delete a collection; its books stay in the library
+    pub fn delete_collection(&self, id: &str) -> Result<(), OmniReaderError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "DELETE FROM collection_books WHERE collection_id = ?1",
+            params![id],
+        )?;
+        tx.execute("DELETE FROM collections WHERE id = ?1", params![id])?;
+        tx.commit()?;
+        Ok(())
+    }
 
-    #[test]
-    fn test_database_creation() {
-        let db = Database::open_in_memory().unwrap();
-        let books = db.get_all_books().unwrap();
-        assert!(books.is_empty());
+    /// Get a single collection by ID
+    pub fn get_collection(&self, id: &str) -> Result<Option<Collection>, OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        let collection = conn
+            .query_row(
+                &format!(
+                    "SELECT {} FROM collections c WHERE c.id = ?1",
+                    COLLECTION_COLUMNS
+                ),
+                params![id],
+                collection_from_row,
+            )
+            .optional()?;
+        Ok(collection)
     }
 
-    #[test]
-    fn test_book_crud() {
-        let db = Database::open_in_memory().unwrap();
+    /// Get all collections, in display order
+    pub fn get_collections(&self) -> Result<Vec<Collection>, OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM collections c ORDER BY c.position, c.name COLLATE NOCASE",
+            COLLECTION_COLUMNS
+        ))?;
 
-        let book = Book::new(
-            "Test Book".to_string(),
-            Some("Test Author".to_string()),
-            "/path/to/book.pdf".to_string(),
-            BookType::Pdf,
-            100,
-        );
+        let collections = stmt
+            .query_map([], collection_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
 
-        db.insert_book(&book).unwrap();
+        Ok(collections)
+    }
 
-        let books = db.get_all_books().unwrap();
-        assert_eq!(books.len(), 1);
-        assert_eq!(books[0].title, "Test Book");
+    /// Get the collections a book is in, in display order
+    pub fn get_book_collections(&self, book_id: &str) -> Result<Vec<Collection>, OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM collections c WHERE c.id IN (
+                SELECT collection_id FROM collection_books WHERE book_id = ?1
+             ) ORDER BY c.position, c.name COLLATE NOCASE",
+            COLLECTION_COLUMNS
+        ))?;
 
-        let fetched = db.get_book(&book.id).unwrap();
-        assert!(fetched.is_some());
-        assert_eq!(fetched.unwrap().author, Some("Test Author".to_string()));
+        let collections = stmt
+            .query_map(params![book_id], collection_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
 
-        db.delete_book(&book.id).unwrap();
-        let books = db.get_all_books().unwrap();
-        assert!(books.is_empty());
+        Ok(collections)
     }
 
-    #[test]
-    fn test_annotations() {
-        let db = Database::open_in_memory().unwrap();
+    /// Put collections in the order of `ids`; collections not listed follow
+    /// in their current order
+    pub fn reorder_collections(&self, ids: &[String]) -> Result<(), OmniReaderError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let current: Vec<String> = tx
+            .prepare("SELECT id FROM collections ORDER BY position, name COLLATE NOCASE")?
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        for (position, id) in manual_order(&current, ids).iter().enumerate() {
+            tx.execute(
+                "UPDATE collections SET position = ?1 WHERE id = ?2",
+                params![position as i64, id],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
 
-        let book = Book::new(
-            "Test Book".to_string(),
-            None,
-            "/path/to/book.epub".to_string(),
-            BookType::Epub,
-            10,
-        );
-        db.insert_book(&book).unwrap();
+    /// Append books to the end of a collection, skipping books already in it
+    /// and IDs not in the library; returns the number added
+    pub fn add_books_to_collection(
+        &self,
+        collection_id: &str,
+        book_ids: &[String],
+    ) -> Result<u32, OmniReaderError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        require_collection(&tx, collection_id)?;
+        let now = chrono::Utc::now().timestamp();
+        let mut added = 0;
+        for book_id in book_ids {
+            added += tx.execute(
+                r#"
+                INSERT OR IGNORE INTO collection_books (collection_id, book_id, position, added_at)
+                SELECT ?1, id,
+                    (SELECT COALESCE(MAX(position) + 1, 0) FROM collection_books WHERE collection_id = ?1),
+                    ?3
+                FROM books WHERE id = ?2
+                "#,
+                params![collection_id, book_id, now],
+            )?;
+        }
+        tx.commit()?;
+        Ok(added as u32)
+    }
 
-        let highlight = Annotation::new_highlight(
-            book.id.clone(),
-            10.0,
-            15.0,
-            1,
-            crate::annotation::HighlightColor::Yellow,
-            Some("Selected text".to_string()),
-        );
-        db.insert_annotation(&highlight).unwrap();
+    /// Remove books from a collection; they stay in the library
+    pub fn remove_books_from_collection(
+        &self,
+        collection_id: &str,
+        book_ids: &[String],
+    ) -> Result<(), OmniReaderError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        for book_id in book_ids {
+            tx.execute(
+                "DELETE FROM collection_books WHERE collection_id = ?1 AND book_id = ?2",
+                params![collection_id, book_id],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
 
-        let annotations = db.get_annotations(&book.id).unwrap();
-        assert_eq!(annotations.len(), 1);
+    /// Put a collection's books in the order of `book_ids`; books not listed
+    /// follow in their current order
+    pub fn reorder_collection_books(
+        &self,
+        collection_id: &str,
+        book_ids: &[String],
+    ) -> Result<(), OmniReaderError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        require_collection(&tx, collection_id)?;
+        let current: Vec<String> = tx
+            .prepare(
+                "SELECT book_id FROM collection_books WHERE collection_id = ?1 \
+                 ORDER BY position, added_at",
+            )?
+            .query_map(params![collection_id], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        for (position, book_id) in manual_order(&current, book_ids).iter().enumerate() {
+            tx.execute(
+                "UPDATE collection_books SET position = ?1 WHERE collection_id = ?2 AND book_id = ?3",
+                params![position as i64, collection_id, book_id],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Get the books of a collection, in manual order
+    pub fn get_collection_books(&self, collection_id: &str) -> Result<Vec<Book>, OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        require_collection(&conn, collection_id)?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM books JOIN (
+                SELECT book_id, position AS entry_position, added_at AS entry_added_at
+                FROM collection_books WHERE collection_id = ?1
+             ) e ON e.book_id = books.id
+             ORDER BY e.entry_position, e.entry_added_at",
+            BOOK_COLUMNS
+        ))?;
+
+        let books = stmt
+            .query_map(params![collection_id], book_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(books)
+    }
+
+    // === Smart Shelf Operations ===
+
+    /// Save a query as a smart shelf after the existing ones
+    ///
+    /// Fails with `InvalidQuery` when the query does not parse.
+    pub fn create_smart_shelf(
+        &self,
+        name: &str,
+        query: &str,
+    ) -> Result<SmartShelf, OmniReaderError> {
+        ShelfQuery::parse(query)?;
+        let conn = self.conn.lock().unwrap();
+        let name = shelf_name(name)?;
+        let position: u32 = conn.query_row(
+            "SELECT COALESCE(MAX(position) + 1, 0) FROM smart_shelves",
+            [],
+            |row| row.get(0),
+        )?;
+        let shelf = SmartShelf::new(name.to_string(), query.to_string(), position);
+        conn.execute(
+            "INSERT INTO smart_shelves (id, name, query, position, created_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![shelf.id, shelf.name, shelf.query, shelf.position, shelf.created_at],
+        )?;
+        Ok(shelf)
+    }
+
+    /// Change the name and query of a smart shelf
+    pub fn update_smart_shelf(
+        &self,
+        id: &str,
+        name: &str,
+        query: &str,
+    ) -> Result<(), OmniReaderError> {
+        ShelfQuery::parse(query)?;
+        let conn = self.conn.lock().unwrap();
+        let changed = conn.execute(
+            "UPDATE smart_shelves SET name = ?1, query = ?2 WHERE id = ?3",
+            params![shelf_name(name)?, query.trim(), id],
+        )?;
+        if changed == 0 {
+            return Err(shelf_not_found(id));
+        }
+        Ok(())
+    }
+
+    /// Delete a smart shelf
+    pub fn delete_smart_shelf(&self, id: &str) -> Result<(), OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("DELETE FROM smart_shelves WHERE id = ?1", params![id])?;
+        Ok(())
+    }
+
+    /// Get all smart shelves, in display order
+    pub fn get_smart_shelves(&self) -> Result<Vec<SmartShelf>, OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, name, query, position, created_at FROM smart_shelves \
+             ORDER BY position, name COLLATE NOCASE",
+        )?;
+
+        let shelves = stmt
+            .query_map([], shelf_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(shelves)
+    }
+
+    /// Get the books currently on a smart shelf, sorted by recently added
+    pub fn get_smart_shelf_books(&self, id: &str) -> Result<Vec<Book>, OmniReaderError> {
+        let query = {
+            let conn = self.conn.lock().unwrap();
+            conn.query_row(
+                "SELECT query FROM smart_shelves WHERE id = ?1",
+                params![id],
+                |row| row.get::<_, String>(0),
+            )
+            .optional()?
+            .ok_or_else(|| shelf_not_found(id))?
+        };
+        self.query_books(&query)
+    }
+
+    /// Get the books matching a shelf query, sorted by recently added
+    pub fn query_books(&self, query: &str) -> Result<Vec<Book>, OmniReaderError> {
+        let (clause, values) =
+            ShelfQuery::parse(query)?.where_clause(chrono::Utc::now().timestamp());
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM books b WHERE {} ORDER BY b.added_at DESC",
+            BOOK_COLUMNS, clause
+        ))?;
+
+        let books = stmt
+            .query_map(params_from_iter(values), book_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(books)
+    }
+
+    // === Annotation Operations ===
+
+    /// Insert a new annotation; its color must be in the palette
+    pub fn insert_annotation(&self, annotation: &Annotation) -> Result<(), OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        insert_annotation_row(&conn, annotation)
+    }
+
+    /// Insert several annotations with their tags in one transaction,
+    /// skipping IDs that already exist
+    ///
+    /// Returns the number of annotations inserted.
+    pub fn insert_annotations(&self, imports: &[AnnotationImport]) -> Result<u32, OmniReaderError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let mut inserted = 0;
+        for AnnotationImport { annotation, tags } in imports {
+            if annotation_by_id(&tx, &annotation.id)?.is_some() {
+                continue;
+            }
+            insert_annotation_row(&tx, annotation)?;
+            for name in tags {
+                let tag = find_or_create_tag(&tx, name)?;
+                tx.execute(
+                    "INSERT OR IGNORE INTO annotation_tags (annotation_id, tag_id) VALUES (?1, ?2)",
+                    params![annotation.id, tag.id],
+                )?;
+            }
+            inserted += 1;
+        }
+        tx.commit()?;
+        Ok(inserted)
+    }
+
+    /// Get a single annotation by ID
+    pub fn get_annotation(&self, id: &str) -> Result<Option<Annotation>, OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        annotation_by_id(&conn, id)
+    }
+
+    /// Get all annotations for a book
+    pub fn get_annotations(&self, book_id: &str) -> Result<Vec<Annotation>, OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {ANNOTATION_COLUMNS} FROM annotations WHERE book_id = ?1 ORDER BY total_progression"
+        ))?;
+
+        let annotations = stmt
+            .query_map(params![book_id], annotation_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(annotations)
+    }
+
+    /// Get annotations of one type for a book
+    pub fn get_annotations_by_type(
+        &self,
+        book_id: &str,
+        annotation_type: AnnotationType,
+    ) -> Result<Vec<Annotation>, OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {ANNOTATION_COLUMNS} FROM annotations 
+             WHERE book_id = ?1 AND annotation_type = ?2 ORDER BY total_progression"
+        ))?;
+
+        let annotations = stmt
+            .query_map(
+                params![book_id, annotation_type.as_str()],
+                annotation_from_row,
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(annotations)
+    }
+
+    /// Get all bookmarks for a book, in reading order
+    pub fn get_bookmarks(&self, book_id: &str) -> Result<Vec<Annotation>, OmniReaderError> {
+        self.get_annotations_by_type(book_id, AnnotationType::Bookmark)
+    }
+
+    /// Get annotations across the library whose color has the given palette label
+    ///
+    /// Labels are matched case-insensitively; `book_id` optionally narrows to one book.
+    pub fn get_annotations_by_label(
+        &self,
+        label: &str,
+        book_id: Option<&str>,
+    ) -> Result<Vec<Annotation>, OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        let columns = annotation_columns("a");
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {columns} FROM annotations a JOIN palette_colors p ON p.id = a.color 
+             WHERE p.name = ?1 COLLATE NOCASE AND (?2 IS NULL OR a.book_id = ?2) 
+             ORDER BY a.book_id, a.total_progression"
+        ))?;
+
+        let annotations = stmt
+            .query_map(params![label, book_id], annotation_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(annotations)
+    }
+
+    /// Get annotations of a book that could not be re-anchored and need review
+    pub fn get_orphaned_annotations(
+        &self,
+        book_id: &str,
+    ) -> Result<Vec<Annotation>, OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {ANNOTATION_COLUMNS} FROM annotations 
+             WHERE book_id = ?1 AND anchor_status = ?2 ORDER BY total_progression"
+        ))?;
+
+        let annotations = stmt
+            .query_map(
+                params![book_id, AnchorStatus::Orphaned.as_str()],
+                annotation_from_row,
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(annotations)
+    }
+
+    /// Move an annotation to a new location and record how it was anchored
+    pub fn update_annotation_anchor(
+        &self,
+        id: &str,
+        locator: &Locator,
+        status: AnchorStatus,
+    ) -> Result<(), OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        let now = chrono::Utc::now().timestamp();
+        let (text_before, text_highlight, text_after) = locator_text_columns(locator);
+        conn.execute(
+            r#"
+            UPDATE annotations SET anchor_status = ?2, updated_at = ?13,
+                href = ?3, page = ?4, progression = ?5, total_progression = ?6, cfi = ?7,
+                start_offset = ?8, end_offset = ?9, text_before = ?10, text_highlight = ?11, text_after = ?12,
+                end_total_progression = ?14
+            WHERE id = ?1
+            "#,
+            params![
+                id,
+                status.as_str(),
+                locator.href,
+                locator.page,
+                locator.progression,
+                locator.total_progression,
+                locator.cfi,
+                locator.start_offset,
+                locator.end_offset,
+                text_before,
+                text_highlight,
+                text_after,
+                now,
+                locator.end_total_progression,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Replace an annotation's note text (`None` removes the note)
+    pub fn update_annotation_note(
+        &self,
+        id: &str,
+        note_text: Option<String>,
+    ) -> Result<(), OmniReaderError> {
+        self.edit_annotation(id, |annotation| annotation.note_text = note_text)
+    }
+
+    /// Change an annotation's highlight color (ID of a color in the palette)
+    pub fn update_annotation_color(
+        &self,
+        id: &str,
+        palette_id: &str,
+    ) -> Result<(), OmniReaderError> {
+        self.edit_annotation(id, |annotation| annotation.color = palette_id.to_string())
+    }
+
+    /// Move an annotation to a new user-selected range
+    pub fn update_annotation_range(
+        &self,
+        id: &str,
+        locator: &Locator,
+        selected_text: Option<String>,
+    ) -> Result<(), OmniReaderError> {
+        self.edit_annotation(id, |annotation| {
+            annotation.locator = locator.clone();
+            annotation.selected_text = selected_text;
+            annotation.anchor_status = AnchorStatus::Anchored;
+        })
+    }
+
+    /// Change an annotation's type
+    pub fn update_annotation_type(
+        &self,
+        id: &str,
+        annotation_type: AnnotationType,
+    ) -> Result<(), OmniReaderError> {
+        self.edit_annotation(id, |annotation| {
+            annotation.annotation_type = annotation_type
+        })
+    }
+
+    /// Get earlier versions of an annotation, most recent first
+    pub fn get_annotation_revisions(
+        &self,
+        annotation_id: &str,
+    ) -> Result<Vec<AnnotationRevision>, OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {REVISION_COLUMNS} FROM annotation_revisions 
+             WHERE annotation_id = ?1 ORDER BY id DESC"
+        ))?;
+
+        let revisions = stmt
+            .query_map(params![annotation_id], revision_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(revisions)
+    }
+
+    /// Restore an annotation to an earlier revision
+    ///
+    /// The current version is itself kept as a revision, so a restore can be undone.
+    pub fn restore_annotation_revision(&self, revision_id: i64) -> Result<(), OmniReaderError> {
+        let revision = {
+            let conn = self.conn.lock().unwrap();
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {REVISION_COLUMNS} FROM annotation_revisions WHERE id = ?1"
+            ))?;
+            let mut rows = stmt.query(params![revision_id])?;
+            match rows.next()? {
+                Some(row) => revision_from_row(row)?,
+                None => {
+                    return Err(OmniReaderError::Database {
+                        message: format!("Annotation revision not found: {}", revision_id),
+                    });
+                }
+            }
+        };
+
+        self.edit_annotation(&revision.annotation_id, |annotation| {
+            annotation.annotation_type = revision.annotation_type;
+            annotation.locator = revision.locator;
+            annotation.color = revision.color;
+            annotation.selected_text = revision.selected_text;
+            annotation.note_text = revision.note_text;
+        })
+    }
+
+    /// Apply an edit to an annotation, recording its previous version
+    fn edit_annotation(
+        &self,
+        id: &str,
+        edit: impl FnOnce(&mut Annotation),
+    ) -> Result<(), OmniReaderError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let Some(mut annotation) = annotation_by_id(&tx, id)? else {
+            return Err(OmniReaderError::Database {
+                message: format!("Annotation not found: {}", id),
+            });
+        };
+        let now = chrono::Utc::now().timestamp();
+
+        let (text_before, text_highlight, text_after) = locator_text_columns(&annotation.locator);
+        tx.execute(
+            r#"
+            INSERT INTO annotation_revisions (annotation_id, annotation_type, color, selected_text, note_text, revised_at,
+                href, page, progression, total_progression, cfi, start_offset, end_offset, text_before, text_highlight, text_after,
+                end_total_progression)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17)
+            "#,
+            params![
+                annotation.id,
+                annotation.annotation_type.as_str(),
+                annotation.color,
+                annotation.selected_text,
+                annotation.note_text,
+                now,
+                annotation.locator.href,
+                annotation.locator.page,
+                annotation.locator.progression,
+                annotation.locator.total_progression,
+                annotation.locator.cfi,
+                annotation.locator.start_offset,
+                annotation.locator.end_offset,
+                text_before,
+                text_highlight,
+                text_after,
+                annotation.locator.end_total_progression,
+            ],
+        )?;
+
+        edit(&mut annotation);
+        require_palette_color(&tx, &annotation.color)?;
+        let (text_before, text_highlight, text_after) = locator_text_columns(&annotation.locator);
+        tx.execute(
+            r#"
+            UPDATE annotations SET annotation_type = ?2, color = ?3, selected_text = ?4, note_text = ?5,
+                anchor_status = ?6, updated_at = ?7,
+                href = ?8, page = ?9, progression = ?10, total_progression = ?11, cfi = ?12,
+                start_offset = ?13, end_offset = ?14, text_before = ?15, text_highlight = ?16, text_after = ?17,
+                end_total_progression = ?18
+            WHERE id = ?1
+            "#,
+            params![
+                annotation.id,
+                annotation.annotation_type.as_str(),
+                annotation.color,
+                annotation.selected_text,
+                annotation.note_text,
+                annotation.anchor_status.as_str(),
+                now,
+                annotation.locator.href,
+                annotation.locator.page,
+                annotation.locator.progression,
+                annotation.locator.total_progression,
+                annotation.locator.cfi,
+                annotation.locator.start_offset,
+                annotation.locator.end_offset,
+                text_before,
+                text_highlight,
+                text_after,
+                annotation.locator.end_total_progression,
+            ],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Delete an annotation with its revisions and tag links
+    pub fn delete_annotation(&self, id: &str) -> Result<(), OmniReaderError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        delete_annotation_rows(&tx, id)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    // === Tag and Notebook Operations ===
+
+    /// Tag an annotation, creating the tag if it does not exist yet
+    pub fn add_annotation_tag(
+        &self,
+        annotation_id: &str,
+        name: &str,
+    ) -> Result<Tag, OmniReaderError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let tag = find_or_create_tag(&tx, name)?;
+        tx.execute(
+            "INSERT OR IGNORE INTO annotation_tags (annotation_id, tag_id) VALUES (?1, ?2)",
+            params![annotation_id, tag.id],
+        )?;
+        tx.commit()?;
+        Ok(tag)
+    }
+
+    /// Remove a tag from an annotation
+    pub fn remove_annotation_tag(
+        &self,
+        annotation_id: &str,
+        name: &str,
+    ) -> Result<(), OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            r#"
+            DELETE FROM annotation_tags WHERE annotation_id = ?1
+                AND tag_id IN (SELECT id FROM tags WHERE name = ?2 COLLATE NOCASE)
+            "#,
+            params![annotation_id, name.trim()],
+        )?;
+        Ok(())
+    }
+
+    /// Get the tags of an annotation, sorted by name
+    pub fn get_annotation_tags(&self, annotation_id: &str) -> Result<Vec<Tag>, OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT t.id, t.name FROM tags t JOIN annotation_tags at ON at.tag_id = t.id 
+             WHERE at.annotation_id = ?1 ORDER BY t.name COLLATE NOCASE",
+        )?;
+
+        let tags = stmt
+            .query_map(params![annotation_id], tag_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(tags)
+    }
+
+    /// Get every tag in the library, sorted by name
+    pub fn get_all_tags(&self) -> Result<Vec<Tag>, OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT id, name FROM tags ORDER BY name COLLATE NOCASE")?;
+
+        let tags = stmt
+            .query_map([], tag_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(tags)
+    }
+
+    /// Query annotations across all books, newest first
+    pub fn query_notebook(
+        &self,
+        query: &NotebookQuery,
+    ) -> Result<Vec<Annotation>, OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        let (clause, mut values) = query.where_clause();
+        values.push(Value::Integer(query.limit.map(i64::from).unwrap_or(-1)));
+        values.push(Value::Integer(query.offset.into()));
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM annotations a WHERE {} 
+             ORDER BY a.created_at DESC, a.id LIMIT ?{} OFFSET ?{}",
+            annotation_columns("a"),
+            clause,
+            values.len() - 1,
+            values.len()
+        ))?;
+
+        let annotations = stmt
+            .query_map(params_from_iter(values), annotation_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(annotations)
+    }
+
+    // === Palette Operations ===
+
+    /// Get the highlight palette, in display order
+    pub fn get_palette(&self) -> Result<Vec<PaletteColor>, OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, name, hex, position FROM palette_colors ORDER BY position, name",
+        )?;
+
+        let palette = stmt
+            .query_map([], palette_color_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(palette)
+    }
+
+    /// Get a single palette color by ID
+    pub fn get_palette_color(&self, id: &str) -> Result<Option<PaletteColor>, OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt =
+            conn.prepare("SELECT id, name, hex, position FROM palette_colors WHERE id = ?1")?;
+
+        let mut rows = stmt.query(params![id])?;
+        if let Some(row) = rows.next()? {
+            Ok(Some(palette_color_from_row(row)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Insert or replace a palette color
+    pub fn save_palette_color(&self, color: &PaletteColor) -> Result<(), OmniReaderError> {
+        PaletteColor::check_hex(&color.hex)?;
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            r#"
+            INSERT INTO palette_colors (id, name, hex, position)
+            VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT(id) DO UPDATE SET
+                name = excluded.name,
+                hex = excluded.hex,
+                position = excluded.position
+            "#,
+            params![
+                color.id,
+                color.name,
+                color.hex.to_uppercase(),
+                color.position
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Reorder the palette; colors are positioned in the order of `ids`
+    pub fn reorder_palette(&self, ids: &[String]) -> Result<(), OmniReaderError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        for (position, id) in ids.iter().enumerate() {
+            tx.execute(
+                "UPDATE palette_colors SET position = ?1 WHERE id = ?2",
+                params![position as u32, id],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Delete a palette color that no annotation uses
+    ///
+    /// Built-in colors cannot be deleted: new annotations and imports fall
+    /// back to them.
+    pub fn delete_palette_color(&self, id: &str) -> Result<(), OmniReaderError> {
+        if HighlightColor::ALL
+            .iter()
+            .any(|color| color.palette_id() == id)
+        {
+            return Err(OmniReaderError::Database {
+                message: format!("Palette color {} is built in", id),
+            });
+        }
+        let conn = self.conn.lock().unwrap();
+        let in_use: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM annotations WHERE color = ?1",
+            params![id],
+            |row| row.get(0),
+        )?;
+        if in_use > 0 {
+            return Err(OmniReaderError::Database {
+                message: format!("Palette color {} is used by {} annotations", id, in_use),
+            });
+        }
+        conn.execute("DELETE FROM palette_colors WHERE id = ?1", params![id])?;
+        Ok(())
+    }
+
+    /// Find the palette color with a hex value, adding it to the palette if missing
+    ///
+    /// Used for colors arriving from imports and other readers, so they keep a
+    /// palette entry (named after the hex value until the user labels it).
+    pub fn palette_color_for_hex(&self, hex: &str) -> Result<PaletteColor, OmniReaderError> {
+        PaletteColor::check_hex(hex)?;
+        let hex = hex.to_uppercase();
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, name, hex, position FROM palette_colors WHERE hex = ?1 ORDER BY position LIMIT 1",
+        )?;
+        let mut rows = stmt.query(params![hex])?;
+        if let Some(row) = rows.next()? {
+            return Ok(palette_color_from_row(row)?);
+        }
+
+        let position: u32 = conn.query_row(
+            "SELECT COALESCE(MAX(position) + 1, 0) FROM palette_colors",
+            [],
+            |row| row.get(0),
+        )?;
+        let color = PaletteColor::new(hex.clone(), hex, position);
+        conn.execute(
+            "INSERT INTO palette_colors (id, name, hex, position) VALUES (?1, ?2, ?3, ?4)",
+            params![color.id, color.name, color.hex, color.position],
+        )?;
+        Ok(color)
+    }
+
+    // === Settings Operations ===
+
+    /// Get a setting's value
+    pub fn get_setting(&self, key: &str) -> Result<Option<String>, OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        let value = conn
+            .query_row(
+                "SELECT value FROM settings WHERE key = ?1",
+                params![key],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(value)
+    }
+
+    /// Insert or replace a setting
+    pub fn set_setting(&self, key: &str, value: &str) -> Result<(), OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO settings (key, value) VALUES (?1, ?2) \
+             ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+            params![key, value],
+        )?;
+        Ok(())
+    }
+
+    /// Get all settings, keyed by name
+    pub fn get_settings(&self) -> Result<BTreeMap<String, String>, OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT key, value FROM settings")?;
+        let settings = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<BTreeMap<_, _>, _>>()?;
+        Ok(settings)
+    }
+
+    // === Backup Operations ===
+
+    /// Write a consistent copy of the database to `path`, encrypted with
+    /// `key` (see [`scratch_key`]) so it never reaches the disk in the clear
+    pub(crate) fn snapshot_to(&self, path: &Path, key: &str) -> Result<(), OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        export_database(&conn, path, key)
+    }
+
+    /// Replace the whole database with the (migrated) database at `path`,
+    /// encrypted with `key`
+    pub(crate) fn replace_from(&self, path: &Path, key: &str) -> Result<(), OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "ATTACH DATABASE ?1 AS backup KEY ?2",
+            params![path.to_string_lossy(), key],
+        )?;
+        let replaced = replace_attached(&conn);
+        conn.execute_batch("DETACH DATABASE backup")?;
+        replaced
+    }
+
+    /// Merge the (migrated) database at `path` into this one
+    ///
+    /// Books, identifiers, palette colors and tags are added when missing.
+    /// Annotations and reading positions are added, or replace local rows
+    /// that were updated less recently. Rows of books that could not be added
+    /// (e.g. the same file under another ID) are skipped.
+    pub(crate) fn merge_from(
+        &self,
+        path: &Path,
+        key: &str,
+    ) -> Result<RestoreReport, OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "ATTACH DATABASE ?1 AS backup KEY ?2",
+            params![path.to_string_lossy(), key],
+        )?;
+        let merged = merge_attached(&conn);
+        conn.execute_batch("DETACH DATABASE backup")?;
+        merged
+    }
+
+    // === Sync Operations ===
+
+    /// This device's sync ID and clock, if it has synced before
+    pub(crate) fn sync_state(&self) -> Result<Option<(String, String)>, OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        let state = conn
+            .query_row(
+                "SELECT device_id, clock FROM sync_state WHERE id = 1",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        Ok(state)
+    }
+
+    /// Save this device's sync ID and clock
+    pub(crate) fn save_sync_state(
+        &self,
+        device_id: &str,
+        clock: &str,
+    ) -> Result<(), OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO sync_state (id, device_id, clock) VALUES (1, ?1, ?2) \
+             ON CONFLICT(id) DO UPDATE SET device_id = excluded.device_id, clock = excluded.clock",
+            params![device_id, clock],
+        )?;
+        Ok(())
+    }
+
+    /// This device's kosync ID, if it has used kosync before
+    pub(crate) fn kosync_device_id(&self) -> Result<Option<String>, OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        let id = conn
+            .query_row(
+                "SELECT device_id FROM kosync_state WHERE id = 1",
+                [],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(id)
+    }
+
+    /// Save this device's kosync ID
+    pub(crate) fn save_kosync_device_id(&self, device_id: &str) -> Result<(), OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO kosync_state (id, device_id) VALUES (1, ?1) \
+             ON CONFLICT(id) DO UPDATE SET device_id = excluded.device_id",
+            params![device_id],
+        )?;
+        Ok(())
+    }
+
+    /// Every synced field with its last known value and version
+    pub(crate) fn sync_fields(&self) -> Result<Vec<SyncField>, OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt =
+            conn.prepare("SELECT entity, entity_id, field, value, hlc FROM sync_fields")?;
+        let fields = stmt
+            .query_map([], |row| {
+                Ok(SyncField {
+                    entity: row.get(0)?,
+                    entity_id: row.get(1)?,
+                    field: row.get(2)?,
+                    value: row.get(3)?,
+                    hlc: row.get(4)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(fields)
+    }
+
+    /// Record new versions of synced fields
+    pub(crate) fn save_sync_fields(&self, fields: &[SyncField]) -> Result<(), OmniReaderError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        save_sync_fields(&tx, fields)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// How far another device's journal has been read
+    pub(crate) fn sync_cursor(&self, device_id: &str) -> Result<JournalCursor, OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        let cursor = conn
+            .query_row(
+                "SELECT read_offset, etag FROM sync_cursors WHERE device_id = ?1",
+                params![device_id],
+                |row| {
+                    Ok(JournalCursor {
+                        offset: row.get::<_, i64>(0)? as u64,
+                        etag: row.get(1)?,
+                    })
+                },
+            )
+            .optional()?;
+        Ok(cursor.unwrap_or_default())
+    }
+
+    /// Save how far another device's journal has been read
+    pub(crate) fn save_sync_cursor(
+        &self,
+        device_id: &str,
+        cursor: &JournalCursor,
+    ) -> Result<(), OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO sync_cursors (device_id, read_offset, etag) VALUES (?1, ?2, ?3) \
+             ON CONFLICT(device_id) DO UPDATE SET read_offset = excluded.read_offset, etag = excluded.etag",
+            params![device_id, cursor.offset as i64, cursor.etag],
+        )?;
+        Ok(())
+    }
+
+    /// Record local changes and queue their journal lines for upload, atomically
+    pub(crate) fn queue_sync_batches(
+        &self,
+        fields: &[SyncField],
+        batches: &[String],
+    ) -> Result<(), OmniReaderError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        save_sync_fields(&tx, fields)?;
+        for batch in batches {
+            tx.execute(
+                "INSERT INTO sync_outbox (batch) VALUES (?1)",
+                params![batch],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Journal batches not yet written to the shared store, oldest first
+    pub(crate) fn sync_outbox(&self) -> Result<Vec<(i64, String)>, OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT seq, batch FROM sync_outbox ORDER BY seq")?;
+        let batches = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(batches)
+    }
+
+    /// Drop a journal batch once it is in the shared store
+    pub(crate) fn remove_sync_batch(&self, seq: i64) -> Result<(), OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("DELETE FROM sync_outbox WHERE seq = ?1", params![seq])?;
+        Ok(())
+    }
+
+    /// Entities received from other devices that could not be written yet
+    pub(crate) fn sync_pending(&self) -> Result<Vec<(String, String)>, OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT entity, entity_id FROM sync_pending")?;
+        let pending = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(pending)
+    }
+
+    /// Replace the set of entities that could not be written yet
+    pub(crate) fn set_sync_pending(
+        &self,
+        pending: &[(String, String)],
+    ) -> Result<(), OmniReaderError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM sync_pending", [])?;
+        for (entity, entity_id) in pending {
+            tx.execute(
+                "INSERT INTO sync_pending (entity, entity_id) VALUES (?1, ?2)",
+                params![entity, entity_id],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// When each row of a synced table was last written here, in milliseconds
+    pub(crate) fn sync_change_times(
+        &self,
+        table: &str,
+    ) -> Result<HashMap<String, u64>, OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt =
+            conn.prepare("SELECT row_key, changed_at FROM sync_changes WHERE table_name = ?1")?;
+        let times = stmt
+            .query_map(params![table], |row| {
+                Ok((row.get(0)?, row.get::<_, i64>(1)?.max(0) as u64))
+            })?
+            .collect::<Result<HashMap<_, _>, _>>()?;
+        Ok(times)
+    }
+
+    /// Read the key and the given columns of every row of a synced table
+    pub(crate) fn sync_rows(
+        &self,
+        table: &str,
+        key: &str,
+        columns: &[&str],
+    ) -> Result<Vec<(String, Vec<Value>)>, OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {}, {} FROM {}",
+            key,
+            columns.join(", "),
+            table
+        ))?;
+        let rows = stmt
+            .query_map([], |row| {
+                let values = (1..=columns.len())
+                    .map(|i| row.get(i))
+                    .collect::<Result<Vec<Value>, _>>()?;
+                Ok((row.get(0)?, values))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(rows)
+    }
+
+    /// Write merged rows into a synced table and delete tombstoned ones
+    ///
+    /// Returns the keys of rows that could not be written, e.g. a book whose
+    /// file path is already used by another book on this device.
+    pub(crate) fn apply_sync_rows(
+        &self,
+        table: &str,
+        key: &str,
+        columns: &[&str],
+        upserts: &[(String, Vec<Value>)],
+        deletes: &[String],
+    ) -> Result<Vec<String>, OmniReaderError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let mut failed = Vec::new();
+        {
+            let placeholders = (1..=columns.len() + 1)
+                .map(|i| format!("?{}", i))
+                .collect::<Vec<_>>()
+                .join(", ");
+            let updates = columns
+                .iter()
+                .map(|c| format!("{c} = excluded.{c}"))
+                .collect::<Vec<_>>()
+                .join(", ");
+            let mut upsert = tx.prepare(&format!(
+                "INSERT INTO {table} ({key}, {}) VALUES ({placeholders}) \
+                 ON CONFLICT({key}) DO UPDATE SET {updates}",
+                columns.join(", "),
+            ))?;
+            for (id, values) in upserts {
+                let params = std::iter::once(Value::Text(id.clone())).chain(values.iter().cloned());
+                if upsert.execute(params_from_iter(params)).is_err() {
+                    failed.push(id.clone());
+                }
+            }
+            let mut delete = tx.prepare(&format!("DELETE FROM {table} WHERE {key} = ?1"))?;
+            for id in deletes {
+                if table == "annotations" {
+                    delete_annotation_rows(&tx, id)?;
+                } else {
+                    delete.execute(params![id])?;
+                }
+            }
+        }
+        tx.commit()?;
+        Ok(failed)
+    }
+
+    // === Reading Session Operations ===
+
+    /// The session still being read, if any
+    pub(crate) fn open_reading_session(&self) -> Result<Option<ReadingSession>, OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        let session = conn
+            .query_row(
+                &format!(
+                    "SELECT {} FROM reading_sessions WHERE ended_at IS NULL \
+                     ORDER BY last_activity_at DESC LIMIT 1",
+                    SESSION_COLUMNS
+                ),
+                [],
+                session_from_row,
+            )
+            .optional()?;
+        Ok(session)
+    }
+
+    /// Insert or update a reading session
+    pub(crate) fn save_reading_session(
+        &self,
+        session: &ReadingSession,
+    ) -> Result<(), OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            &format!(
+                "INSERT OR REPLACE INTO reading_sessions ({}) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+                SESSION_COLUMNS
+            ),
+            params![
+                session.id,
+                session.book_id,
+                session.started_at,
+                session.ended_at,
+                session.last_activity_at,
+                session.start_progression,
+                session.end_progression,
+                session.start_page,
+                session.end_page,
+                session.progress,
+                session.pages_read,
+                session.characters_read as i64,
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn delete_reading_session(&self, id: &str) -> Result<(), OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("DELETE FROM reading_sessions WHERE id = ?1", params![id])?;
+        Ok(())
+    }
+
+    /// Reading sessions of a book, oldest first
+    pub fn get_reading_sessions(
+        &self,
+        book_id: &str,
+    ) -> Result<Vec<ReadingSession>, OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM reading_sessions WHERE book_id = ?1 ORDER BY started_at",
+            SESSION_COLUMNS
+        ))?;
+        let sessions = stmt
+            .query_map(params![book_id], session_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(sessions)
+    }
+
+    /// Reading sessions of every book started at or after `since`, oldest first
+    pub fn get_reading_sessions_since(
+        &self,
+        since: i64,
+    ) -> Result<Vec<ReadingSession>, OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM reading_sessions WHERE started_at >= ?1 ORDER BY started_at",
+            SESSION_COLUMNS
+        ))?;
+        let sessions = stmt
+            .query_map(params![since], session_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(sessions)
+    }
+
+    /// Cached character count of every spine item of an EPUB
+    pub(crate) fn book_lengths(&self, book_id: &str) -> Result<Option<Vec<u64>>, OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        let chapters: Option<String> = conn
+            .query_row(
+                "SELECT chapters FROM book_lengths WHERE book_id = ?1",
+                params![book_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(chapters.and_then(|json| serde_json::from_str(&json).ok()))
+    }
+
+    pub(crate) fn save_book_lengths(
+        &self,
+        book_id: &str,
+        chapters: &[u64],
+    ) -> Result<(), OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        let json = serde_json::to_string(chapters).unwrap_or_default();
+        conn.execute(
+            "INSERT OR REPLACE INTO book_lengths (book_id, chapters) VALUES (?1, ?2)",
+            params![book_id, json],
+        )?;
+        Ok(())
+    }
+
+    // === Reading Status Operations ===
+
+    /// Set a book's reading status and the dates of its current or last read
+    pub(crate) fn set_book_status(
+        &self,
+        book_id: &str,
+        status: ReadingStatus,
+        started_at: Option<i64>,
+        finished_at: Option<i64>,
+    ) -> Result<(), OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE books SET reading_status = ?1, started_at = ?2, finished_at = ?3 WHERE id = ?4",
+            params![status.as_str(), started_at, finished_at, book_id],
+        )?;
+        Ok(())
+    }
+
+    /// Insert or update a read
+    pub(crate) fn save_book_read(&self, read: &BookRead) -> Result<(), OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            &format!(
+                "INSERT OR REPLACE INTO book_reads ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                READ_COLUMNS
+            ),
+            params![
+                read.id,
+                read.book_id,
+                read.status.as_str(),
+                read.started_at,
+                read.finished_at,
+                read.rating,
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn delete_book_read_row(&self, id: &str) -> Result<(), OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("DELETE FROM book_reads WHERE id = ?1", params![id])?;
+        Ok(())
+    }
+
+    /// Get a single read by ID
+    pub fn get_book_read(&self, id: &str) -> Result<Option<BookRead>, OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        let read = conn
+            .query_row(
+                &format!("SELECT {} FROM book_reads WHERE id = ?1", READ_COLUMNS),
+                params![id],
+                read_from_row,
+            )
+            .optional()?;
+        Ok(read)
+    }
+
+    /// Reads of a book, oldest first
+    pub fn get_book_reads(&self, book_id: &str) -> Result<Vec<BookRead>, OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM book_reads WHERE book_id = ?1 ORDER BY started_at, rowid",
+            READ_COLUMNS
+        ))?;
+        let reads = stmt
+            .query_map(params![book_id], read_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(reads)
+    }
+
+    /// Reads of every book finished in `[start, end)`, in order of finishing
+    pub fn get_reads_finished_between(
+        &self,
+        start: i64,
+        end: i64,
+    ) -> Result<Vec<BookRead>, OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM book_reads WHERE status = 'finished' \
+             AND finished_at >= ?1 AND finished_at < ?2 ORDER BY finished_at",
+            READ_COLUMNS
+        ))?;
+        let reads = stmt
+            .query_map(params![start, end], read_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(reads)
+    }
+
+    // === Reading Position Operations ===
+
+    /// Save or update reading position
+    ///
+    /// The book's status is left as it is; positions reached by reading go
+    /// through `track_reading_position`, which also crosses status thresholds.
+    pub fn save_reading_position(&self, position: &ReadingPosition) -> Result<(), OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        let (text_before, text_highlight, text_after) = locator_text_columns(&position.locator);
+        conn.execute(
+            r#"
+            INSERT INTO reading_positions (book_id, updated_at,
+                href, page, progression, total_progression, cfi, start_offset, end_offset, text_before, text_highlight, text_after,
+                end_total_progression)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)
+            ON CONFLICT(book_id) DO UPDATE SET
+                updated_at = excluded.updated_at,
+                href = excluded.href,
+                page = excluded.page,
+                progression = excluded.progression,
+                total_progression = excluded.total_progression,
+                cfi = excluded.cfi,
+                start_offset = excluded.start_offset,
+                end_offset = excluded.end_offset,
+                text_before = excluded.text_before,
+                text_highlight = excluded.text_highlight,
+                text_after = excluded.text_after,
+                end_total_progression = excluded.end_total_progression
+            "#,
+            params![
+                position.book_id,
+                position.updated_at,
+                position.locator.href,
+                position.locator.page,
+                position.locator.progression,
+                position.locator.total_progression,
+                position.locator.cfi,
+                position.locator.start_offset,
+                position.locator.end_offset,
+                text_before,
+                text_highlight,
+                text_after,
+                position.locator.end_total_progression,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Get reading position for a book
+    pub fn get_reading_position(
+        &self,
+        book_id: &str,
+    ) -> Result<Option<ReadingPosition>, OmniReaderError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT book_id, updated_at, 
+                    href, page, progression, total_progression, cfi, start_offset, end_offset, text_before, text_highlight, text_after,
+                    end_total_progression
+             FROM reading_positions WHERE book_id = ?1"
+        )?;
+
+        let mut rows = stmt.query(params![book_id])?;
+        if let Some(row) = rows.next()? {
+            Ok(Some(ReadingPosition {
+                book_id: row.get(0)?,
+                locator: locator_from_row(row, 2)?,
+                updated_at: row.get(1)?,
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// Schema migrations, applied in order and tracked through `PRAGMA user_version`
+const MIGRATIONS: &[&str] = &[
+    // 1: replace percent/page columns with locator columns
+    r#"
+    ALTER TABLE annotations ADD COLUMN href TEXT;
+    ALTER TABLE annotations ADD COLUMN page INTEGER;
+    ALTER TABLE annotations ADD COLUMN progression REAL NOT NULL DEFAULT 0;
+    ALTER TABLE annotations ADD COLUMN total_progression REAL NOT NULL DEFAULT 0;
+    ALTER TABLE annotations ADD COLUMN cfi TEXT;
+    ALTER TABLE annotations ADD COLUMN start_offset INTEGER;
+    ALTER TABLE annotations ADD COLUMN end_offset INTEGER;
+    ALTER TABLE annotations ADD COLUMN text_before TEXT;
+    ALTER TABLE annotations ADD COLUMN text_highlight TEXT;
+    ALTER TABLE annotations ADD COLUMN text_after TEXT;
+    ALTER TABLE annotations ADD COLUMN end_total_progression REAL;
+    UPDATE annotations SET page = page_number, total_progression = start_percent / 100.0,
+        end_total_progression = end_percent / 100.0;
+    ALTER TABLE annotations DROP COLUMN start_percent;
+    ALTER TABLE annotations DROP COLUMN end_percent;
+    ALTER TABLE annotations DROP COLUMN page_number;
+
+    ALTER TABLE reading_positions ADD COLUMN href TEXT;
+    ALTER TABLE reading_positions ADD COLUMN page INTEGER;
+    ALTER TABLE reading_positions ADD COLUMN progression REAL NOT NULL DEFAULT 0;
+    ALTER TABLE reading_positions ADD COLUMN total_progression REAL NOT NULL DEFAULT 0;
+    ALTER TABLE reading_positions ADD COLUMN cfi TEXT;
+    ALTER TABLE reading_positions ADD COLUMN start_offset INTEGER;
+    ALTER TABLE reading_positions ADD COLUMN end_offset INTEGER;
+    ALTER TABLE reading_positions ADD COLUMN text_before TEXT;
+    ALTER TABLE reading_positions ADD COLUMN text_highlight TEXT;
+    ALTER TABLE reading_positions ADD COLUMN text_after TEXT;
+    UPDATE reading_positions SET page = page_number, total_progression = percent / 100.0;
+    ALTER TABLE reading_positions DROP COLUMN percent;
+    ALTER TABLE reading_positions DROP COLUMN page_number;
+    "#,
+    // 2: re-anchoring status of annotations
+    r#"
+    ALTER TABLE annotations ADD COLUMN anchor_status TEXT NOT NULL DEFAULT 'anchored';
+    "#,
+    // 3: edit timestamps and revision history
+    r#"
+    ALTER TABLE annotations ADD COLUMN updated_at INTEGER NOT NULL DEFAULT 0;
+    UPDATE annotations SET updated_at = created_at;
+
+    CREATE TABLE annotation_revisions (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        annotation_id TEXT NOT NULL REFERENCES annotations(id) ON DELETE CASCADE,
+        annotation_type TEXT NOT NULL,
+        color TEXT NOT NULL,
+        selected_text TEXT,
+        note_text TEXT,
+        revised_at INTEGER NOT NULL,
+        href TEXT,
+        page INTEGER,
+        progression REAL NOT NULL DEFAULT 0,
+        total_progression REAL NOT NULL DEFAULT 0,
+        cfi TEXT,
+        start_offset INTEGER,
+        end_offset INTEGER,
+        text_before TEXT,
+        text_highlight TEXT,
+        text_after TEXT
+    );
+    CREATE INDEX idx_annotation_revisions_annotation_id ON annotation_revisions(annotation_id);
+    "#,
+    // 4: chapter titles captured with annotations (bookmarks)
+    r#"
+    ALTER TABLE annotations ADD COLUMN chapter_title TEXT;
+    "#,
+    // 5: user-editable highlight palette; annotation colors become palette IDs
+    r#"
+    CREATE TABLE palette_colors (
+        id TEXT PRIMARY KEY,
+        name TEXT NOT NULL,
+        hex TEXT NOT NULL,
+        position INTEGER NOT NULL
+    );
+    INSERT INTO palette_colors (id, name, hex, position) VALUES
+        ('yellow', 'Yellow', '#FFEB3B', 0),
+        ('green', 'Green', '#4CAF50', 1),
+        ('blue', 'Blue', '#2196F3', 2),
+        ('pink', 'Pink', '#E91E63', 3),
+        ('orange', 'Orange', '#FF9800', 4);
+
+    INSERT INTO palette_colors (id, name, hex, position)
+    SELECT lower(hex(randomblob(16))), color, color, 4 + row_number() OVER (ORDER BY color)
+    FROM (
+        SELECT DISTINCT upper(color) AS color FROM annotations
+        UNION SELECT DISTINCT upper(color) FROM annotation_revisions
+    )
+    WHERE color NOT IN (SELECT hex FROM palette_colors);
+
+    UPDATE annotations SET color = (SELECT id FROM palette_colors p WHERE p.hex = upper(annotations.color));
+    UPDATE annotation_revisions SET color = (SELECT id FROM palette_colors p WHERE p.hex = upper(annotation_revisions.color));
+    "#,
+    // 6: annotation tags and full-text index over selected and note text
+    r#"
+    CREATE TABLE tags (
+        id TEXT PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE COLLATE NOCASE
+    );
+    CREATE TABLE annotation_tags (
+        annotation_id TEXT NOT NULL REFERENCES annotations(id) ON DELETE CASCADE,
+        tag_id TEXT NOT NULL REFERENCES tags(id) ON DELETE CASCADE,
+        PRIMARY KEY (annotation_id, tag_id)
+    );
+    CREATE INDEX idx_annotation_tags_tag_id ON annotation_tags(tag_id);
+
+    CREATE VIRTUAL TABLE annotations_fts USING fts5(
+        selected_text, note_text, content='annotations', content_rowid='rowid'
+    );
+    INSERT INTO annotations_fts (rowid, selected_text, note_text)
+        SELECT rowid, selected_text, note_text FROM annotations;
+    CREATE TRIGGER annotations_fts_insert AFTER INSERT ON annotations BEGIN
+        INSERT INTO annotations_fts (rowid, selected_text, note_text)
+        VALUES (new.rowid, new.selected_text, new.note_text);
+    END;
+    CREATE TRIGGER annotations_fts_delete AFTER DELETE ON annotations BEGIN
+        INSERT INTO annotations_fts (annotations_fts, rowid, selected_text, note_text)
+        VALUES ('delete', old.rowid, old.selected_text, old.note_text);
+    END;
+    CREATE TRIGGER annotations_fts_update AFTER UPDATE OF selected_text, note_text ON annotations BEGIN
+        INSERT INTO annotations_fts (annotations_fts, rowid, selected_text, note_text)
+        VALUES ('delete', old.rowid, old.selected_text, old.note_text);
+        INSERT INTO annotations_fts (rowid, selected_text, note_text)
+        VALUES (new.rowid, new.selected_text, new.note_text);
+    END;
+    "#,
+    // 7: richer book metadata (series, publisher, ratings, ...), identifiers and book tags
+    r#"
+    ALTER TABLE books ADD COLUMN author_sort TEXT;
+    ALTER TABLE books ADD COLUMN series TEXT;
+    ALTER TABLE books ADD COLUMN series_index REAL;
+    ALTER TABLE books ADD COLUMN publisher TEXT;
+    ALTER TABLE books ADD COLUMN description TEXT;
+    ALTER TABLE books ADD COLUMN rating INTEGER;
+    CREATE TABLE book_identifiers (
+        book_id TEXT NOT NULL REFERENCES books(id) ON DELETE CASCADE,
+        scheme TEXT NOT NULL,
+        value TEXT NOT NULL,
+        PRIMARY KEY (book_id, scheme)
+    );
+    CREATE TABLE book_tags (
+        book_id TEXT NOT NULL REFERENCES books(id) ON DELETE CASCADE,
+        tag_id TEXT NOT NULL REFERENCES tags(id) ON DELETE CASCADE,
+        PRIMARY KEY (book_id, tag_id)
+    );
+    CREATE INDEX idx_book_tags_tag_id ON book_tags(tag_id);
+    "#,
+    // 8: app settings as key/value pairs
+    r#"
+    CREATE TABLE settings (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+    "#,
+    // 9: shared-folder sync state, per-field versions and journal read offsets
+    r#"
+    CREATE TABLE sync_state (
+        id INTEGER PRIMARY KEY CHECK (id = 1),
+        device_id TEXT NOT NULL,
+        clock TEXT NOT NULL
+    );
+    CREATE TABLE sync_fields (
+        entity TEXT NOT NULL,
+        entity_id TEXT NOT NULL,
+        field TEXT NOT NULL,
+        value TEXT NOT NULL,
+        hlc TEXT NOT NULL,
+        PRIMARY KEY (entity, entity_id, field)
+    );
+    CREATE TABLE sync_cursors (
+        device_id TEXT PRIMARY KEY,
+        read_offset INTEGER NOT NULL
+    );
+    CREATE TABLE sync_pending (
+        entity TEXT NOT NULL,
+        entity_id TEXT NOT NULL,
+        PRIMARY KEY (entity, entity_id)
+    );
+    "#,
+    // 10: journal batches awaiting upload, and cached journal ETags
+    r#"
+    CREATE TABLE sync_outbox (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        batch TEXT NOT NULL
+    );
+    ALTER TABLE sync_cursors ADD COLUMN etag TEXT;
+    "#,
+    // 11: reading sessions, and cached EPUB chapter lengths to count characters read
+    r#"
+    CREATE TABLE reading_sessions (
+        id TEXT PRIMARY KEY,
+        book_id TEXT NOT NULL REFERENCES books(id) ON DELETE CASCADE,
+        started_at INTEGER NOT NULL,
+        ended_at INTEGER,
+        last_activity_at INTEGER NOT NULL,
+        start_progression REAL NOT NULL,
+        end_progression REAL NOT NULL,
+        start_page INTEGER,
+        end_page INTEGER,
+        progress REAL NOT NULL DEFAULT 0,
+        pages_read REAL NOT NULL DEFAULT 0,
+        characters_read INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE INDEX idx_reading_sessions_book_id ON reading_sessions(book_id);
+    CREATE INDEX idx_reading_sessions_started_at ON reading_sessions(started_at);
+    CREATE TABLE book_lengths (
+        book_id TEXT PRIMARY KEY REFERENCES books(id) ON DELETE CASCADE,
+        chapters TEXT NOT NULL
+    );
+    "#,
+    // 12: reading status of books, and the history of their reads
+    r#"
+    ALTER TABLE books ADD COLUMN reading_status TEXT NOT NULL DEFAULT 'unread';
+    ALTER TABLE books ADD COLUMN started_at INTEGER;
+    ALTER TABLE books ADD COLUMN finished_at INTEGER;
+    CREATE TABLE book_reads (
+        id TEXT PRIMARY KEY,
+        book_id TEXT NOT NULL REFERENCES books(id) ON DELETE CASCADE,
+        status TEXT NOT NULL,
+        started_at INTEGER NOT NULL,
+        finished_at INTEGER,
+        rating INTEGER
+    );
+    CREATE INDEX idx_book_reads_book_id ON book_reads(book_id);
+    "#,
+    // 13: collections of books in manual order
+    r#"
+    CREATE TABLE collections (
+        id TEXT PRIMARY KEY,
+        name TEXT NOT NULL,
+        position INTEGER NOT NULL,
+        created_at INTEGER NOT NULL
+    );
+    CREATE TABLE collection_books (
+        collection_id TEXT NOT NULL REFERENCES collections(id) ON DELETE CASCADE,
+        book_id TEXT NOT NULL REFERENCES books(id) ON DELETE CASCADE,
+        position INTEGER NOT NULL,
+        added_at INTEGER NOT NULL,
+        PRIMARY KEY (collection_id, book_id)
+    );
+    CREATE INDEX idx_collection_books_book_id ON collection_books(book_id);
+    "#,
+    // 14: smart shelves (saved library queries)
+    r#"
+    CREATE TABLE smart_shelves (
+        id TEXT PRIMARY KEY,
+        name TEXT NOT NULL,
+        query TEXT NOT NULL,
+        position INTEGER NOT NULL,
+        created_at INTEGER NOT NULL
+    );
+    "#,
+    // 15: end of ranges in the locators of revisions and reading positions
+    r#"
+    ALTER TABLE annotation_revisions ADD COLUMN end_total_progression REAL;
+    ALTER TABLE reading_positions ADD COLUMN end_total_progression REAL;
+    "#,
+    // 16: when synced rows were last written here, so sync versions changes
+    // by edit time; built-in palette colors are the same on every device and
+    // start out synced
+    r##"
+    INSERT OR IGNORE INTO sync_fields (entity, entity_id, field, value, hlc) VALUES
+        ('palette', 'yellow', 'name', '"Yellow"', '0000000000000-000000-'),
+        ('palette', 'yellow', 'hex', '"#FFEB3B"', '0000000000000-000000-'),
+        ('palette', 'yellow', 'position', '0', '0000000000000-000000-'),
+        ('palette', 'green', 'name', '"Green"', '0000000000000-000000-'),
+        ('palette', 'green', 'hex', '"#4CAF50"', '0000000000000-000000-'),
+        ('palette', 'green', 'position', '1', '0000000000000-000000-'),
+        ('palette', 'blue', 'name', '"Blue"', '0000000000000-000000-'),
+        ('palette', 'blue', 'hex', '"#2196F3"', '0000000000000-000000-'),
+        ('palette', 'blue', 'position', '2', '0000000000000-000000-'),
+        ('palette', 'pink', 'name', '"Pink"', '0000000000000-000000-'),
+        ('palette', 'pink', 'hex', '"#E91E63"', '0000000000000-000000-'),
+        ('palette', 'pink', 'position', '3', '0000000000000-000000-'),
+        ('palette', 'orange', 'name', '"Orange"', '0000000000000-000000-'),
+        ('palette', 'orange', 'hex', '"#FF9800"', '0000000000000-000000-'),
+        ('palette', 'orange', 'position', '4', '0000000000000-000000-');
+    CREATE TABLE sync_changes (
+        table_name TEXT NOT NULL,
+        row_key TEXT NOT NULL,
+        changed_at INTEGER NOT NULL,
+        PRIMARY KEY (table_name, row_key)
+    );
+    CREATE TRIGGER books_sync_insert AFTER INSERT ON books BEGIN
+        INSERT INTO sync_changes VALUES ('books', new.id, CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER))
+        ON CONFLICT DO UPDATE SET changed_at = excluded.changed_at;
+    END;
+    CREATE TRIGGER books_sync_update AFTER UPDATE ON books BEGIN
+        INSERT INTO sync_changes VALUES ('books', new.id, CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER))
+        ON CONFLICT DO UPDATE SET changed_at = excluded.changed_at;
+    END;
+    CREATE TRIGGER books_sync_delete AFTER DELETE ON books BEGIN
+        INSERT INTO sync_changes VALUES ('books', old.id, CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER))
+        ON CONFLICT DO UPDATE SET changed_at = excluded.changed_at;
+    END;
+    CREATE TRIGGER book_reads_sync_insert AFTER INSERT ON book_reads BEGIN
+        INSERT INTO sync_changes VALUES ('book_reads', new.id, CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER))
+        ON CONFLICT DO UPDATE SET changed_at = excluded.changed_at;
+    END;
+    CREATE TRIGGER book_reads_sync_update AFTER UPDATE ON book_reads BEGIN
+        INSERT INTO sync_changes VALUES ('book_reads', new.id, CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER))
+        ON CONFLICT DO UPDATE SET changed_at = excluded.changed_at;
+    END;
+    CREATE TRIGGER book_reads_sync_delete AFTER DELETE ON book_reads BEGIN
+        INSERT INTO sync_changes VALUES ('book_reads', old.id, CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER))
+        ON CONFLICT DO UPDATE SET changed_at = excluded.changed_at;
+    END;
+    CREATE TRIGGER palette_colors_sync_insert AFTER INSERT ON palette_colors BEGIN
+        INSERT INTO sync_changes VALUES ('palette_colors', new.id, CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER))
+        ON CONFLICT DO UPDATE SET changed_at = excluded.changed_at;
+    END;
+    CREATE TRIGGER palette_colors_sync_update AFTER UPDATE ON palette_colors BEGIN
+        INSERT INTO sync_changes VALUES ('palette_colors', new.id, CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER))
+        ON CONFLICT DO UPDATE SET changed_at = excluded.changed_at;
+    END;
+    CREATE TRIGGER palette_colors_sync_delete AFTER DELETE ON palette_colors BEGIN
+        INSERT INTO sync_changes VALUES ('palette_colors', old.id, CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER))
+        ON CONFLICT DO UPDATE SET changed_at = excluded.changed_at;
+    END;
+    CREATE TRIGGER annotations_sync_insert AFTER INSERT ON annotations BEGIN
+        INSERT INTO sync_changes VALUES ('annotations', new.id, CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER))
+        ON CONFLICT DO UPDATE SET changed_at = excluded.changed_at;
+    END;
+    CREATE TRIGGER annotations_sync_update AFTER UPDATE ON annotations BEGIN
+        INSERT INTO sync_changes VALUES ('annotations', new.id, CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER))
+        ON CONFLICT DO UPDATE SET changed_at = excluded.changed_at;
+    END;
+    CREATE TRIGGER annotations_sync_delete AFTER DELETE ON annotations BEGIN
+        INSERT INTO sync_changes VALUES ('annotations', old.id, CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER))
+        ON CONFLICT DO UPDATE SET changed_at = excluded.changed_at;
+    END;
+    CREATE TRIGGER reading_positions_sync_insert AFTER INSERT ON reading_positions BEGIN
+        INSERT INTO sync_changes VALUES ('reading_positions', new.book_id, CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER))
+        ON CONFLICT DO UPDATE SET changed_at = excluded.changed_at;
+    END;
+    CREATE TRIGGER reading_positions_sync_update AFTER UPDATE ON reading_positions BEGIN
+        INSERT INTO sync_changes VALUES ('reading_positions', new.book_id, CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER))
+        ON CONFLICT DO UPDATE SET changed_at = excluded.changed_at;
+    END;
+    CREATE TRIGGER reading_positions_sync_delete AFTER DELETE ON reading_positions BEGIN
+        INSERT INTO sync_changes VALUES ('reading_positions', old.book_id, CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER))
+        ON CONFLICT DO UPDATE SET changed_at = excluded.changed_at;
+    END;
+    "##,
+    // 17: this device's kosync ID, kept out of settings so backups leave it behind
+    r#"
+    CREATE TABLE kosync_state (
+        id INTEGER PRIMARY KEY CHECK (id = 1),
+        device_id TEXT NOT NULL
+    );
+    INSERT INTO kosync_state (id, device_id)
+        SELECT 1, value FROM settings WHERE key = 'kosync.device_id';
+    DELETE FROM settings WHERE key = 'kosync.device_id';
+    "#,
+    // 18: cover hashes, so cached thumbnails are found without loading covers
+    r#"
+    ALTER TABLE books ADD COLUMN cover_hash TEXT;
+    "#,
+];
+
+/// Schema version a fully migrated database is at
+pub(crate) fn schema_version() -> i64 {
+    MIGRATIONS.len() as i64
+}
+
+/// Replace the rows of every table in `main` with those of the attached
+/// `backup` database, which has the same schema
+fn replace_attached(conn: &Connection) -> Result<(), OmniReaderError> {
+    let tx = conn.unchecked_transaction()?;
+    let tables: Vec<String> = tx
+        .prepare(
+            "SELECT name FROM pragma_table_list WHERE schema = 'main' AND type = 'table' \
+             AND name NOT LIKE 'sqlite_%'",
+        )?
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    // Checked once every table is filled, whatever order they come in
+    tx.pragma_update(None, "defer_foreign_keys", true)?;
+    for table in &tables {
+        tx.execute(&format!("DELETE FROM main.\"{}\"", table), [])?;
+    }
+    for table in tables {
+        let columns: Vec<String> = tx
+            .prepare("SELECT name FROM pragma_table_info(?1, 'main')")?
+            .query_map([&table], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        let columns = columns
+            .iter()
+            .map(|c| format!("\"{}\"", c))
+            .collect::<Vec<_>>()
+            .join(", ");
+        // Full-text rows follow through the annotation triggers; the sync
+        // triggers may already have filled rows the backup replaces
+        tx.execute(
+            &format!(
+                "INSERT OR REPLACE INTO main.\"{table}\" ({columns}) SELECT {columns} FROM backup.\"{table}\""
+            ),
+            [],
+        )?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// Merge every table of the attached `backup` database into `main`
+fn merge_attached(conn: &Connection) -> Result<RestoreReport, OmniReaderError> {
+    const IN_BOOKS: &str = "book_id IN (SELECT id FROM main.books)";
+    let tx = conn.unchecked_transaction()?;
+    let books = merge_rows(&tx, "books", "id", None, "true")?;
+    merge_rows(&tx, "book_identifiers", "book_id, scheme", None, IN_BOOKS)?;
+    merge_rows(&tx, "palette_colors", "id", None, "true")?;
+    merge_rows(&tx, "tags", "id", None, "true")?;
+    let annotations = merge_rows(&tx, "annotations", "id", Some("updated_at"), IN_BOOKS)?;
+    merge_rows(
+        &tx,
+        "reading_positions",
+        "book_id",
+        Some("updated_at"),
+        IN_BOOKS,
+    )?;
+    // A session still open in the backup would compete with the local one
+    merge_rows(
+        &tx,
+        "reading_sessions",
+        "id",
+        None,
+        &format!("{} AND ended_at IS NOT NULL", IN_BOOKS),
+    )?;
+    merge_rows(&tx, "book_reads", "id", None, IN_BOOKS)?;
+    merge_rows(&tx, "collections", "id", None, "true")?;
+    merge_rows(&tx, "smart_shelves", "id", None, "true")?;
+    merge_rows(
+        &tx,
+        "collection_books",
+        "collection_id, book_id",
+        None,
+        &format!(
+            "{} AND collection_id IN (SELECT id FROM main.collections)",
+            IN_BOOKS
+        ),
+    )?;
+    tx.execute_batch(
+        r#"
+        INSERT INTO main.annotation_revisions (
+            annotation_id, annotation_type, color, selected_text, note_text, revised_at,
+            href, page, progression, total_progression, cfi, start_offset, end_offset,
+            text_before, text_highlight, text_after, end_total_progression
+        )
+        SELECT annotation_id, annotation_type, color, selected_text, note_text, revised_at,
+            href, page, progression, total_progression, cfi, start_offset, end_offset,
+            text_before, text_highlight, text_after, end_total_progression
+        FROM backup.annotation_revisions r
+        WHERE r.annotation_id IN (SELECT id FROM main.annotations)
+          AND NOT EXISTS (
+            SELECT 1 FROM main.annotation_revisions m
+            WHERE m.annotation_id = r.annotation_id AND m.revised_at = r.revised_at
+          )
+        ORDER BY r.id;
+
+        -- tags are matched by name, as a backed-up tag may exist locally under another ID
+        INSERT OR IGNORE INTO main.annotation_tags (annotation_id, tag_id)
+        SELECT at.annotation_id, t.id
+        FROM backup.annotation_tags at
+        JOIN backup.tags bt ON bt.id = at.tag_id
+        JOIN main.tags t ON t.name = bt.name
+        WHERE at.annotation_id IN (SELECT id FROM main.annotations);
+
+        INSERT OR IGNORE INTO main.book_tags (book_id, tag_id)
+        SELECT bt.book_id, t.id
+        FROM backup.book_tags bt
+        JOIN backup.tags btag ON btag.id = bt.tag_id
+        JOIN main.tags t ON t.name = btag.name
+        WHERE bt.book_id IN (SELECT id FROM main.books);
+        "#,
+    )?;
+    tx.commit()?;
+    Ok(RestoreReport {
+        books,
+        annotations,
+        book_files: 0,
+    })
+}
+
+/// Copy rows of `table` from `backup` into `main`, returning the rows written
+///
+/// Without `newer`, rows conflicting with an existing key are ignored; with
+/// it, they replace the local row when their `newer` column is greater.
+fn merge_rows(
+    conn: &Connection,
+    table: &str,
+    key: &str,
+    newer: Option<&str>,
+    filter: &str,
+) -> Result<u32, OmniReaderError> {
+    let columns = conn
+        .prepare(&format!(
+            "SELECT name FROM pragma_table_info('{}', 'main')",
+            table
+        ))?
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    let list = columns.join(", ");
+    let sql = match newer {
+        None => format!(
+            "INSERT OR IGNORE INTO main.{t} ({list}) SELECT {list} FROM backup.{t} WHERE {filter}",
+            t = table,
+        ),
+        Some(newer) => {
+            let updates = columns
+                .iter()
+                .map(|c| format!("{c} = excluded.{c}"))
+                .collect::<Vec<_>>()
+                .join(", ");
+            format!(
+                "INSERT INTO main.{t} ({list}) SELECT {list} FROM backup.{t} WHERE {filter} \
+                 ON CONFLICT({key}) DO UPDATE SET {updates} WHERE excluded.{newer} > {t}.{newer}",
+                t = table,
+            )
+        }
+    };
+    Ok(conn.execute(&sql, [])? as u32)
+}
+
+fn save_sync_fields(conn: &Connection, fields: &[SyncField]) -> Result<(), OmniReaderError> {
+    let mut stmt = conn.prepare(
+        "INSERT INTO sync_fields (entity, entity_id, field, value, hlc) VALUES (?1, ?2, ?3, ?4, ?5) \
+         ON CONFLICT(entity, entity_id, field) DO UPDATE SET value = excluded.value, hlc = excluded.hlc",
+    )?;
+    for field in fields {
+        stmt.execute(params![
+            field.entity,
+            field.entity_id,
+            field.field,
+            field.value,
+            field.hlc
+        ])?;
+    }
+    Ok(())
+}
+
+/// Fail unless `id` is a color in the palette
+fn require_palette_color(conn: &Connection, id: &str) -> Result<(), OmniReaderError> {
+    let exists: bool = conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM palette_colors WHERE id = ?1)",
+        params![id],
+        |row| row.get(0),
+    )?;
+    if exists {
+        Ok(())
+    } else {
+        Err(OmniReaderError::Database {
+            message: format!("Palette color not found: {}", id),
+        })
+    }
+}
+
+/// Tables holding rows of an annotation, deleted along with it (foreign
+/// keys are not enforced)
+const ANNOTATION_TABLES: &[&str] = &["annotation_tags", "annotation_revisions"];
+
+/// Delete an annotation and the rows in `ANNOTATION_TABLES` that belong to it
+fn delete_annotation_rows(conn: &Connection, id: &str) -> Result<(), OmniReaderError> {
+    for table in ANNOTATION_TABLES {
+        conn.execute(
+            &format!("DELETE FROM {} WHERE annotation_id = ?1", table),
+            params![id],
+        )?;
+    }
+    conn.execute("DELETE FROM annotations WHERE id = ?1", params![id])?;
+    Ok(())
+}
+
+/// Tables whose rows belong to one book, by `book_id`, deleted with it
+const BOOK_TABLES: &[&str] = &[
+    "annotations",
+    "reading_positions",
+    "book_identifiers",
+    "book_tags",
+    "reading_sessions",
+    "book_lengths",
+    "book_reads",
+    "collection_books",
+];
+
+/// Column list (over alias `c`) matching `collection_from_row`
+const COLLECTION_COLUMNS: &str = "c.id, c.name, c.position, c.created_at, \
+     (SELECT COUNT(*) FROM collection_books cb WHERE cb.collection_id = c.id)";
+
+fn collection_from_row(row: &Row) -> rusqlite::Result<Collection> {
+    Ok(Collection {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        position: row.get(2)?,
+        created_at: row.get(3)?,
+        book_count: row.get(4)?,
+    })
+}
+
+/// `current` IDs with those in `first` moved to the front, in that order
+fn manual_order<'a>(current: &'a [String], first: &'a [String]) -> Vec<&'a String> {
+    let mut order: Vec<&String> = Vec::with_capacity(current.len());
+    for id in first {
+        if current.contains(id) && !order.contains(&id) {
+            order.push(id);
+        }
+    }
+    for id in current {
+        if !order.contains(&id) {
+            order.push(id);
+        }
+    }
+    order
+}
+
+fn shelf_from_row(row: &Row) -> rusqlite::Result<SmartShelf> {
+    Ok(SmartShelf {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        query: row.get(2)?,
+        position: row.get(3)?,
+        created_at: row.get(4)?,
+    })
+}
+
+fn shelf_name(name: &str) -> Result<&str, OmniReaderError> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(OmniReaderError::Database {
+            message: "Shelf name must not be empty".to_string(),
+        });
+    }
+    Ok(name)
+}
+
+fn shelf_not_found(id: &str) -> OmniReaderError {
+    OmniReaderError::Database {
+        message: format!("Smart shelf not found: {}", id),
+    }
+}
+
+fn collection_name(name: &str) -> Result<&str, OmniReaderError> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(OmniReaderError::Database {
+            message: "Collection name must not be empty".to_string(),
+        });
+    }
+    Ok(name)
+}
+
+fn require_collection(conn: &Connection, id: &str) -> Result<(), OmniReaderError> {
+    let exists: bool = conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM collections WHERE id = ?1)",
+        params![id],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        return Err(collection_not_found(id));
+    }
+    Ok(())
+}
+
+fn collection_not_found(id: &str) -> OmniReaderError {
+    OmniReaderError::Database {
+        message: format!("Collection not found: {}", id),
+    }
+}
+
+/// Column list matching `read_from_row`
+const READ_COLUMNS: &str = "id, book_id, status, started_at, finished_at, rating";
+
+fn read_from_row(row: &Row) -> rusqlite::Result<BookRead> {
+    let status: String = row.get(2)?;
+    Ok(BookRead {
+        id: row.get(0)?,
+        book_id: row.get(1)?,
+        status: ReadingStatus::parse(&status).unwrap_or(ReadingStatus::Reading),
+        started_at: row.get(3)?,
+        finished_at: row.get(4)?,
+        rating: row.get(5)?,
+    })
+}
+
+/// Column list (over alias `b`) matching `summary_from_row`, which reads
+/// `PROGRESS` right after them
+const SUMMARY_COLUMNS: &str = "b.id, b.title, b.author, b.file_type, b.added_at, b.last_read_at, \
+     b.total_pages, b.series, b.series_index, b.rating, b.reading_status, b.cover_data IS NOT NULL";
+
+/// Number of columns `summary_from_row` reads
+const SUMMARY_COLUMN_COUNT: usize = 13;
+
+fn summary_from_row(row: &Row) -> rusqlite::Result<BookSummary> {
+    let file_type: String = row.get(3)?;
+    let status: String = row.get(10)?;
+    Ok(BookSummary {
+        id: row.get(0)?,
+        title: row.get(1)?,
+        author: row.get(2)?,
+        file_type: BookType::from_extension(&file_type).unwrap_or(BookType::Pdf),
+        added_at: row.get(4)?,
+        last_read_at: row.get(5)?,
+        total_pages: row.get(6)?,
+        series: row.get(7)?,
+        series_index: row.get(8)?,
+        rating: row.get(9)?,
+        status: ReadingStatus::parse(&status).unwrap_or(ReadingStatus::Unread),
+        has_cover: row.get(11)?,
+        progress: row.get(12)?,
+    })
+}
+
+/// Column list matching `book_from_row`
+const BOOK_COLUMNS: &str = "id, title, author, file_path, file_type, cover_data, added_at, last_read_at, total_pages, \
+     author_sort, series, series_index, publisher, description, rating, reading_status, started_at, finished_at";
+
+fn book_from_row(row: &Row) -> rusqlite::Result<Book> {
+    let file_type: String = row.get(4)?;
+    Ok(Book {
+        id: row.get(0)?,
+        title: row.get(1)?,
+        author: row.get(2)?,
+        file_path: row.get(3)?,
+        file_type: BookType::from_extension(&file_type).unwrap_or(BookType::Pdf),
+        cover_data: row.get(5)?,
+        added_at: row.get(6)?,
+        last_read_at: row.get(7)?,
+        total_pages: row.get(8)?,
+        author_sort: row.get(9)?,
+        series: row.get(10)?,
+        series_index: row.get(11)?,
+        publisher: row.get(12)?,
+        description: row.get(13)?,
+        rating: row.get(14)?,
+        status: ReadingStatus::parse(&row.get::<_, String>(15)?).unwrap_or(ReadingStatus::Unread),
+        started_at: row.get(16)?,
+        finished_at: row.get(17)?,
+    })
+}
+
+/// Insert an annotation whose color must be in the palette
+fn insert_annotation_row(
+    conn: &Connection,
+    annotation: &Annotation,
+) -> Result<(), OmniReaderError> {
+    require_palette_color(conn, &annotation.color)?;
+    let (text_before, text_highlight, text_after) = locator_text_columns(&annotation.locator);
+    conn.execute(
+        r#"
+        INSERT INTO annotations (id, book_id, annotation_type, color, selected_text, note_text, created_at, anchor_status, updated_at, chapter_title,
+            href, page, progression, total_progression, cfi, start_offset, end_offset, text_before, text_highlight, text_after,
+            end_total_progression)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21)
+        "#,
+        params![
+            annotation.id,
+            annotation.book_id,
+            annotation.annotation_type.as_str(),
+            annotation.color,
+            annotation.selected_text,
+            annotation.note_text,
+            annotation.created_at,
+            annotation.anchor_status.as_str(),
+            annotation.updated_at,
+            annotation.chapter_title,
+            annotation.locator.href,
+            annotation.locator.page,
+            annotation.locator.progression,
+            annotation.locator.total_progression,
+            annotation.locator.cfi,
+            annotation.locator.start_offset,
+            annotation.locator.end_offset,
+            text_before,
+            text_highlight,
+            text_after,
+            annotation.locator.end_total_progression,
+        ],
+    )?;
+    Ok(())
+}
+
+fn insert_book_row(conn: &Connection, book: &Book) -> Result<(), OmniReaderError> {
+    conn.execute(
+        &format!(
+            "INSERT INTO books ({}, cover_hash) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19)",
+            BOOK_COLUMNS
+        ),
+        params![
+            book.id,
+            book.title,
+            book.author,
+            book.file_path,
+            book.file_type.extension(),
+            book.cover_data,
+            book.added_at,
+            book.last_read_at,
+            book.total_pages,
+            book.author_sort,
+            book.series,
+            book.series_index,
+            book.publisher,
+            book.description,
+            book.rating,
+            book.status.as_str(),
+            book.started_at,
+            book.finished_at,
+            book.cover_data.as_deref().map(cover::cover_hash),
+        ],
+    )?;
+    Ok(())
+}
+
+fn replace_identifiers(
+    conn: &Connection,
+    book_id: &str,
+    identifiers: &[BookIdentifier],
+) -> Result<(), OmniReaderError> {
+    conn.execute(
+        "DELETE FROM book_identifiers WHERE book_id = ?1",
+        params![book_id],
+    )?;
+    for identifier in identifiers {
+        conn.execute(
+            "INSERT OR REPLACE INTO book_identifiers (book_id, scheme, value) VALUES (?1, ?2, ?3)",
+            params![
+                book_id,
+                identifier.scheme.trim().to_lowercase(),
+                identifier.value.trim()
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+/// Column list matching `annotation_from_row`
+const ANNOTATION_COLUMNS: &str = "id, book_id, annotation_type, color, selected_text, note_text, created_at, anchor_status, updated_at, chapter_title, \
+     href, page, progression, total_progression, cfi, start_offset, end_offset, text_before, text_highlight, text_after, \
+     end_total_progression";
+
+/// `ANNOTATION_COLUMNS` qualified with a table alias
+fn annotation_columns(alias: &str) -> String {
+    ANNOTATION_COLUMNS
+        .split(',')
+        .map(|column| format!("{}.{}", alias, column.trim()))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Read an annotation selected with `ANNOTATION_COLUMNS`
+fn annotation_from_row(row: &Row) -> rusqlite::Result<Annotation> {
+    let type_str: String = row.get(2)?;
+    let status_str: String = row.get(7)?;
+    Ok(Annotation {
+        id: row.get(0)?,
+        book_id: row.get(1)?,
+        annotation_type: AnnotationType::parse(&type_str).unwrap_or(AnnotationType::Highlight),
+        locator: locator_from_row(row, 10)?,
+        color: row.get(3)?,
+        selected_text: row.get(4)?,
+        note_text: row.get(5)?,
+        chapter_title: row.get(9)?,
+        created_at: row.get(6)?,
+        updated_at: row.get(8)?,
+        anchor_status: AnchorStatus::parse(&status_str).unwrap_or(AnchorStatus::Anchored),
+    })
+}
+
+/// Look up one annotation on an open connection
+fn annotation_by_id(conn: &Connection, id: &str) -> Result<Option<Annotation>, OmniReaderError> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {ANNOTATION_COLUMNS} FROM annotations WHERE id = ?1"
+    ))?;
+    let mut rows = stmt.query(params![id])?;
+    match rows.next()? {
+        Some(row) => Ok(Some(annotation_from_row(row)?)),
+        None => Ok(None),
+    }
+}
+
+/// Read a tag selected as `id, name`
+fn tag_from_row(row: &Row) -> rusqlite::Result<Tag> {
+    Ok(Tag {
+        id: row.get(0)?,
+        name: row.get(1)?,
+    })
+}
+
+/// Look up a tag by name (case-insensitive), creating it if missing
+fn find_or_create_tag(conn: &Connection, name: &str) -> Result<Tag, OmniReaderError> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(OmniReaderError::Database {
+            message: "Tag name must not be empty".to_string(),
+        });
+    }
+    let existing = conn
+        .query_row(
+            "SELECT id, name FROM tags WHERE name = ?1 COLLATE NOCASE",
+            params![name],
+            tag_from_row,
+        )
+        .optional()?;
+    if let Some(tag) = existing {
+        return Ok(tag);
+    }
+
+    let tag = Tag::new(name.to_string());
+    conn.execute(
+        "INSERT INTO tags (id, name) VALUES (?1, ?2)",
+        params![tag.id, tag.name],
+    )?;
+    Ok(tag)
+}
+
+/// Read a palette color selected as `id, name, hex, position`
+fn palette_color_from_row(row: &Row) -> rusqlite::Result<PaletteColor> {
+    Ok(PaletteColor {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        hex: row.get(2)?,
+        position: row.get(3)?,
+    })
+}
+
+/// Column list matching `revision_from_row`
+const REVISION_COLUMNS: &str = "id, annotation_id, annotation_type, color, selected_text, note_text, revised_at, \
+     href, page, progression, total_progression, cfi, start_offset, end_offset, text_before, text_highlight, text_after, \
+     end_total_progression";
+
+/// Read a revision selected with `REVISION_COLUMNS`
+fn revision_from_row(row: &Row) -> rusqlite::Result<AnnotationRevision> {
+    let type_str: String = row.get(2)?;
+    Ok(AnnotationRevision {
+        id: row.get(0)?,
+        annotation_id: row.get(1)?,
+        annotation_type: AnnotationType::parse(&type_str).unwrap_or(AnnotationType::Highlight),
+        locator: locator_from_row(row, 7)?,
+        color: row.get(3)?,
+        selected_text: row.get(4)?,
+        note_text: row.get(5)?,
+        revised_at: row.get(6)?,
+    })
+}
+
+/// Column list matching `session_from_row`
+const SESSION_COLUMNS: &str = "id, book_id, started_at, ended_at, last_activity_at, \
+     start_progression, end_progression, start_page, end_page, progress, pages_read, characters_read";
+
+fn session_from_row(row: &Row) -> rusqlite::Result<ReadingSession> {
+    Ok(ReadingSession {
+        id: row.get(0)?,
+        book_id: row.get(1)?,
+        started_at: row.get(2)?,
+        ended_at: row.get(3)?,
+        last_activity_at: row.get(4)?,
+        start_progression: row.get(5)?,
+        end_progression: row.get(6)?,
+        start_page: row.get(7)?,
+        end_page: row.get(8)?,
+        progress: row.get(9)?,
+        pages_read: row.get(10)?,
+        characters_read: row.get::<_, i64>(11)?.max(0) as u64,
+    })
+}
+
+/// Read a locator stored in the standard locator columns, starting at `offset`
+///
+/// Column order: href, page, progression, total_progression, cfi,
+/// start_offset, end_offset, text_before, text_highlight, text_after,
+/// end_total_progression
+fn locator_from_row(row: &Row, offset: usize) -> rusqlite::Result<Locator> {
+    let before: Option<String> = row.get(offset + 7)?;
+    let highlight: Option<String> = row.get(offset + 8)?;
+    let after: Option<String> = row.get(offset + 9)?;
+    let text = if before.is_none() && highlight.is_none() && after.is_none() {
+        None
+    } else {
+        Some(LocatorText {
+            before,
+            highlight,
+            after,
+        })
+    };
+    Ok(Locator {
+        href: row.get(offset)?,
+        page: row.get(offset + 1)?,
+        progression: row.get(offset + 2)?,
+        total_progression: row.get(offset + 3)?,
+        end_total_progression: row.get(offset + 10)?,
+        cfi: row.get(offset + 4)?,
+        start_offset: row.get(offset + 5)?,
+        end_offset: row.get(offset + 6)?,
+        text,
+    })
+}
+
+/// Split a locator's text quote into its three nullable columns
+fn locator_text_columns(locator: &Locator) -> (Option<&String>, Option<&String>, Option<&String>) {
+    match &locator.text {
+        Some(text) => (
+            text.before.as_ref(),
+            text.highlight.as_ref(),
+            text.after.as_ref(),
+        ),
+        None => (None, None, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::library::LibrarySort;
+
+    #[test]
+    fn test_database_creation() {
+        let db = Database::open_in_memory().unwrap();
+        let books = db.get_all_books().unwrap();
+        assert!(books.is_empty());
+    }
+
+    #[test]
+    fn test_book_crud() {
+        let db = Database::open_in_memory().unwrap();
+
+        let book = Book::new(
+            "Test Book".to_string(),
+            Some("Test Author".to_string()),
+            "/path/to/book.pdf".to_string(),
+            BookType::Pdf,
+            100,
+        );
+
+        db.insert_book(&book).unwrap();
+
+        let books = db.get_all_books().unwrap();
+        assert_eq!(books.len(), 1);
+        assert_eq!(books[0].title, "Test Book");
+
+        let fetched = db.get_book(&book.id).unwrap();
+        assert!(fetched.is_some());
+        assert_eq!(fetched.unwrap().author, Some("Test Author".to_string()));
+
+        db.delete_book(&book.id).unwrap();
+        let books = db.get_all_books().unwrap();
+        assert!(books.is_empty());
+    }
+
+    #[test]
+    fn test_annotations() {
+        let db = Database::open_in_memory().unwrap();
+
+        let book = Book::new(
+            "Test Book".to_string(),
+            None,
+            "/path/to/book.epub".to_string(),
+            BookType::Epub,
+            10,
+        );
+        db.insert_book(&book).unwrap();
+
+        let highlight = Annotation::new_highlight(
+            book.id.clone(),
+            Locator::from_epub_spine(1, 10, Some("ch2.xhtml".to_string()), 0.0).with_text(
+                None,
+                Some("Selected text".to_string()),
+                None,
+            ),
+            crate::annotation::HighlightColor::Yellow,
+            Some("Selected text".to_string()),
+        );
+        db.insert_annotation(&highlight).unwrap();
+
+        let annotations = db.get_annotations(&book.id).unwrap();
+        assert_eq!(annotations.len(), 1);
         assert_eq!(
             annotations[0].selected_text,
             Some("Selected text".to_string())
         );
+        assert_eq!(annotations[0].locator, highlight.locator);
+        assert_eq!(annotations[0].anchor_status, AnchorStatus::Anchored);
+
+        let moved = Locator::from_epub_spine(2, 10, Some("ch3.xhtml".to_string()), 0.25);
+        db.update_annotation_anchor(&highlight.id, &moved, AnchorStatus::Orphaned)
+            .unwrap();
+        let orphaned = db.get_orphaned_annotations(&book.id).unwrap();
+        assert_eq!(orphaned.len(), 1);
+        assert_eq!(orphaned[0].locator, moved);
+    }
+
+    #[test]
+    fn test_annotation_edits_and_revisions() {
+        let db = Database::open_in_memory().unwrap();
+        let book = Book::new(
+            "Test Book".to_string(),
+            None,
+            "/path/to/book.pdf".to_string(),
+            BookType::Pdf,
+            10,
+        );
+        db.insert_book(&book).unwrap();
+
+        let note = Annotation::new_note(
+            book.id.clone(),
+            Locator::from_pdf_page(2, 10),
+            "first draft".to_string(),
+        );
+        db.insert_annotation(&note).unwrap();
+
+        db.update_annotation_note(&note.id, Some("second draft".to_string()))
+            .unwrap();
+        db.update_annotation_color(
+            &note.id,
+            crate::annotation::HighlightColor::Blue.palette_id(),
+        )
+        .unwrap();
+
+        let edited = db.get_annotation(&note.id).unwrap().unwrap();
+        assert_eq!(edited.note_text.as_deref(), Some("second draft"));
+        assert_eq!(edited.color, "blue");
+        assert_eq!(edited.created_at, note.created_at);
+
+        let revisions = db.get_annotation_revisions(&note.id).unwrap();
+        assert_eq!(revisions.len(), 2);
+        assert_eq!(revisions[0].note_text.as_deref(), Some("second draft"));
+        assert_eq!(revisions[1].note_text.as_deref(), Some("first draft"));
+
+        db.restore_annotation_revision(revisions[1].id).unwrap();
+        let restored = db.get_annotation(&note.id).unwrap().unwrap();
+        assert_eq!(restored.note_text.as_deref(), Some("first draft"));
+        assert_eq!(restored.color, "yellow");
+        assert_eq!(db.get_annotation_revisions(&note.id).unwrap().len(), 3);
+
+        let bookmark = Annotation::new_bookmark(
+            book.id.clone(),
+            Locator::from_pdf_page(5, 10),
+            Some("Come back here".to_string()),
+            Some("Chapter 2".to_string()),
+            Some("It was a dark and stormy night".to_string()),
+        );
+        db.insert_annotation(&bookmark).unwrap();
+        let bookmarks = db.get_bookmarks(&book.id).unwrap();
+        assert_eq!(bookmarks.len(), 1);
+        assert_eq!(bookmarks[0].annotation_type, AnnotationType::Bookmark);
+        assert_eq!(bookmarks[0].chapter_title.as_deref(), Some("Chapter 2"));
+        assert_eq!(db.get_annotations(&book.id).unwrap().len(), 2);
+
+        assert!(db.update_annotation_note("missing", None).is_err());
+
+        db.delete_annotation(&note.id).unwrap();
+        assert!(db.get_annotation_revisions(&note.id).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_palette() {
+        let db = Database::open_in_memory().unwrap();
+        let book = Book::new(
+            "Test Book".to_string(),
+            None,
+            "/path/to/book.epub".to_string(),
+            BookType::Epub,
+            10,
+        );
+        db.insert_book(&book).unwrap();
+
+        let palette = db.get_palette().unwrap();
+        assert_eq!(palette.len(), 5);
+        assert_eq!(palette[0].hex, "#FFEB3B");
+
+        let definition = PaletteColor::new("definition".to_string(), "#00aa00".to_string(), 5);
+        db.save_palette_color(&definition).unwrap();
+        assert_eq!(
+            db.palette_color_for_hex("#00AA00").unwrap().id,
+            definition.id
+        );
+        let synced = db.palette_color_for_hex("#123456").unwrap();
+        assert_eq!(synced.position, 6);
+
+        let mut highlight = Annotation::new_highlight(
+            book.id.clone(),
+            Locator::from_total_progression(0.1),
+            crate::annotation::HighlightColor::Green,
+            Some("A term".to_string()),
+        );
+        highlight.color = definition.id.clone();
+        db.insert_annotation(&highlight).unwrap();
+
+        let labelled = db.get_annotations_by_label("Definition", None).unwrap();
+        assert_eq!(labelled.len(), 1);
+        assert_eq!(labelled[0].id, highlight.id);
+        assert!(db.delete_palette_color(&definition.id).is_err());
+        // Built-in colors stay, even when unused
+        assert!(db.delete_palette_color("pink").is_err());
+        assert!(db.get_palette_color("pink").unwrap().is_some());
+
+        db.reorder_palette(&[synced.id.clone(), "yellow".to_string()])
+            .unwrap();
+        assert_eq!(db.get_palette().unwrap()[0].id, synced.id);
+
+        for hex in ["00AA00", "#0A0", "#GG0000", "#00AA00 "] {
+            assert!(matches!(
+                db.palette_color_for_hex(hex),
+                Err(OmniReaderError::InvalidColor { .. })
+            ));
+        }
+        let mut invalid = definition.clone();
+        invalid.hex = "green".to_string();
+        assert!(db.save_palette_color(&invalid).is_err());
+        highlight.id = uuid::Uuid::new_v4().to_string();
+        highlight.color = "no-such-color".to_string();
+        assert!(db.insert_annotation(&highlight).is_err());
+        assert!(
+            db.update_annotation_color(&labelled[0].id, "no-such-color")
+                .is_err()
+        );
+        assert_eq!(
+            db.get_annotation(&labelled[0].id).unwrap().unwrap().color,
+            definition.id
+        );
+    }
+
+    #[test]
+    fn test_tags_and_notebook_query() {
+        let db = Database::open_in_memory().unwrap();
+        let mut notes = Vec::new();
+        for i in 0..3 {
+            let book = Book::new(
+                format!("Book {}", i),
+                None,
+                format!("/path/to/book{}.epub", i),
+                BookType::Epub,
+                10,
+            );
+            db.insert_book(&book).unwrap();
+            let mut note = Annotation::new_note(
+                book.id.clone(),
+                Locator::from_total_progression(0.5),
+                format!("Memory palace idea {}", i),
+            );
+            note.created_at = 1_000 + i;
+            db.insert_annotation(&note).unwrap();
+            notes.push(note);
+        }
+        db.add_annotation_tag(&notes[0].id, "thesis-ch3").unwrap();
+        db.add_annotation_tag(&notes[2].id, "Thesis-CH3").unwrap();
+        db.add_annotation_tag(&notes[2].id, "review").unwrap();
+        assert_eq!(db.get_all_tags().unwrap().len(), 2);
+        assert_eq!(db.get_annotation_tags(&notes[2].id).unwrap().len(), 2);
+
+        let tagged = db
+            .query_notebook(&NotebookQuery {
+                tags: vec!["thesis-ch3".to_string()],
+                ..Default::default()
+            })
+            .unwrap();
+        let ids: Vec<_> = tagged.iter().map(|a| a.id.as_str()).collect();
+        assert_eq!(ids, vec![notes[2].id.as_str(), notes[0].id.as_str()]);
+
+        db.update_annotation_note(&notes[1].id, Some("Unrelated thought".to_string()))
+            .unwrap();
+        let found = db
+            .query_notebook(&NotebookQuery {
+                text: Some("palac".to_string()),
+                created_before: Some(1_002),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].id, notes[0].id);
+
+        db.remove_annotation_tag(&notes[0].id, "THESIS-ch3")
+            .unwrap();
+        let page = db
+            .query_notebook(&NotebookQuery {
+                annotation_types: vec![AnnotationType::Note],
+                limit: Some(1),
+                offset: 1,
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].id, notes[1].id);
+
+        // Deleted annotations leave no tag links to count or filter on
+        db.delete_annotation(&notes[2].id).unwrap();
+        assert!(db.get_annotation_tags(&notes[2].id).unwrap().is_empty());
+        let reviewed = db
+            .query_notebook(&NotebookQuery {
+                tags: vec!["review".to_string()],
+                ..Default::default()
+            })
+            .unwrap();
+        assert!(reviewed.is_empty());
+        let links: i64 = db
+            .conn
+            .lock()
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM annotation_tags", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(links, 0);
+    }
+
+    #[test]
+    fn test_collections_and_book_tags() {
+        let db = Database::open_in_memory().unwrap();
+        let books: Vec<Book> = (0..3)
+            .map(|i| {
+                let book = Book::new(
+                    format!("Book {}", i),
+                    None,
+                    format!("/path/to/book{}.pdf", i),
+                    BookType::Pdf,
+                    10,
+                );
+                db.insert_book(&book).unwrap();
+                book
+            })
+            .collect();
+        let ids: Vec<String> = books.iter().map(|b| b.id.clone()).collect();
+
+        let favorites = db.create_collection(" Favorites ").unwrap();
+        let to_read = db.create_collection("To Read").unwrap();
+        assert_eq!(favorites.name, "Favorites");
+        assert_eq!(to_read.position, 1);
+        db.reorder_collections(&[to_read.id.clone(), favorites.id.clone()])
+            .unwrap();
+        assert_eq!(db.get_collections().unwrap()[0].id, to_read.id);
+        assert!(db.create_collection("  ").is_err());
+
+        let added = db
+            .add_books_to_collection(
+                &favorites.id,
+                &[ids[2].clone(), ids[0].clone(), "missing".to_string()],
+            )
+            .unwrap();
+        assert_eq!(added, 2);
+        assert_eq!(db.add_books_to_collection(&favorites.id, &ids).unwrap(), 1);
+        let titles = |collection: &str| -> Vec<String> {
+            db.get_collection_books(collection)
+                .unwrap()
+                .into_iter()
+                .map(|b| b.title)
+                .collect()
+        };
+        assert_eq!(titles(&favorites.id), vec!["Book 2", "Book 0", "Book 1"]);
+        db.reorder_collection_books(&favorites.id, &[ids[0].clone(), ids[1].clone()])
+            .unwrap();
+        assert_eq!(titles(&favorites.id), vec!["Book 0", "Book 1", "Book 2"]);
+        db.remove_books_from_collection(&favorites.id, &[ids[1].clone()])
+            .unwrap();
+        assert_eq!(
+            db.get_collection(&favorites.id)
+                .unwrap()
+                .unwrap()
+                .book_count,
+            2
+        );
+        assert!(db.get_collection_books("missing").is_err());
+
+        db.tag_books(&ids, "Fantasy").unwrap();
+        db.untag_books(&ids[1..], "fantasy").unwrap();
+        assert_eq!(db.get_books_by_tag("FANTASY").unwrap().len(), 1);
+
+        // Deleting a book removes it everywhere; the collection stays
+        let note = Annotation::new_note(
+            ids[0].clone(),
+            Locator::from_pdf_page(1, 10),
+            "Gone".to_string(),
+        );
+        db.insert_annotation(&note).unwrap();
+        db.add_annotation_tag(&note.id, "draft").unwrap();
+        db.delete_book(&ids[0]).unwrap();
+        assert_eq!(titles(&favorites.id), vec!["Book 2"]);
+        assert!(db.get_books_by_tag("Fantasy").unwrap().is_empty());
+        assert!(db.get_annotations(&ids[0]).unwrap().is_empty());
+        assert!(db.get_annotation_tags(&note.id).unwrap().is_empty());
+        assert!(db.get_book_collections(&ids[0]).unwrap().is_empty());
+
+        db.delete_collection(&favorites.id).unwrap();
+        assert_eq!(db.get_collections().unwrap().len(), 1);
+        assert!(db.get_book(&ids[2]).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_smart_shelves() {
+        let db = Database::open_in_memory().unwrap();
+        let mut earthsea = Book::new(
+            "A Wizard of Earthsea".to_string(),
+            Some("Ursula K. Le Guin".to_string()),
+            "/books/earthsea.epub".to_string(),
+            BookType::Epub,
+            10,
+        );
+        earthsea.rating = Some(10);
+        earthsea.status = ReadingStatus::Reading;
+        db.insert_book(&earthsea).unwrap();
+        let mut manual = Book::new(
+            "Manual".to_string(),
+            None,
+            "/books/manual.pdf".to_string(),
+            BookType::Pdf,
+            300,
+        );
+        manual.added_at -= 90 * 86_400;
+        db.insert_book(&manual).unwrap();
+        db.add_book_tag(&earthsea.id, "Fantasy").unwrap();
+        db.save_reading_position(&ReadingPosition::new(
+            earthsea.id.clone(),
+            Locator::from_total_progression(0.6),
+        ))
+        .unwrap();
+
+        let titles = |query: &str| -> Vec<String> {
+            db.query_books(query)
+                .unwrap()
+                .into_iter()
+                .map(|b| b.title)
+                .collect()
+        };
+        assert_eq!(titles(""), vec!["A Wizard of Earthsea", "Manual"]);
+        assert_eq!(
+            titles(
+                "author:le guin status:reading tag:fantasy added:<30d format:epub progress:>50%"
+            ),
+            vec!["A Wizard of Earthsea"]
+        );
+        assert_eq!(titles("-tag:fantasy"), vec!["Manual"]);
+        assert_eq!(titles("added:>30d pages:>=300"), vec!["Manual"]);
+        assert_eq!(titles("rating:5 wizard"), vec!["A Wizard of Earthsea"]);
+        assert!(titles("-read:<1y status:unread").contains(&"Manual".to_string()));
+        assert!(titles("collection:none").is_empty());
+
+        let shelf = db
+            .create_smart_shelf("Unread PDFs", "format:pdf status:unread")
+            .unwrap();
+        assert_eq!(
+            db.get_smart_shelf_books(&shelf.id).unwrap()[0].id,
+            manual.id
+        );
+        assert!(matches!(
+            db.create_smart_shelf("Broken", "format:mobi"),
+            Err(OmniReaderError::InvalidQuery { position: 7, .. })
+        ));
+        db.update_smart_shelf(&shelf.id, "Long reads", "pages:>100")
+            .unwrap();
+        let shelves = db.get_smart_shelves().unwrap();
+        assert_eq!(shelves.len(), 1);
+        assert_eq!(shelves[0].query, "pages:>100");
+        db.delete_smart_shelf(&shelf.id).unwrap();
+        assert!(db.get_smart_shelf_books(&shelf.id).is_err());
+    }
+
+    #[test]
+    fn test_library_pages() {
+        let db = Database::open_in_memory().unwrap();
+        let titles = [
+            "The Dispossessed",
+            "Annihilation",
+            "A Memory Called Empire",
+            "Blindsight",
+            "Dune",
+        ];
+        for (i, title) in titles.iter().enumerate() {
+            let mut book = Book::new(
+                title.to_string(),
+                None,
+                format!("/books/{}.epub", i),
+                BookType::Epub,
+                10,
+            );
+            book.added_at = 1_000 + i as i64;
+            if i == 0 {
+                book.cover_data = Some(vec![1, 2, 3]);
+            }
+            db.insert_book(&book).unwrap();
+        }
+
+        let mut query = LibraryQuery {
+            sort: LibrarySort::Title,
+            descending: false,
+            limit: Some(2),
+            ..Default::default()
+        };
+        let mut listed = Vec::new();
+        loop {
+            let page = db.query_library(&query).unwrap();
+            assert_eq!(page.total, 5);
+            listed.extend(page.books.into_iter().map(|b| b.title));
+            match page.next_cursor {
+                Some(cursor) => query.after = Some(cursor),
+                None => break,
+            }
+        }
+        assert_eq!(
+            listed,
+            vec![
+                "Annihilation",
+                "Blindsight",
+                "The Dispossessed",
+                "Dune",
+                "A Memory Called Empire"
+            ]
+        );
+
+        let page = db.query_library(&LibraryQuery::default()).unwrap();
+        assert_eq!(page.books[0].title, "Dune");
+        assert!(page.next_cursor.is_none());
+        let dispossessed = page.books.iter().find(|b| b.has_cover).unwrap();
+        assert_eq!(
+            db.get_book_cover(&dispossessed.id).unwrap(),
+            Some(vec![1, 2, 3])
+        );
+        let hash = cover::cover_hash(&[1, 2, 3]);
+        assert_eq!(
+            db.get_book_cover_hash(&dispossessed.id).unwrap(),
+            Some(hash.clone())
+        );
+        // Covers stored before hashes were kept get one on first lookup
+        db.conn
+            .lock()
+            .unwrap()
+            .execute("UPDATE books SET cover_hash = NULL", [])
+            .unwrap();
+        assert_eq!(
+            db.get_book_cover_hash(&dispossessed.id).unwrap(),
+            Some(hash)
+        );
+        assert_eq!(db.get_book_cover_hash(&page.books[0].id).unwrap(), None);
+
+        let filtered = db
+            .query_library(&LibraryQuery {
+                filter: Some("-title:the -title:dune".to_string()),
+                descending: false,
+                limit: Some(1),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(filtered.total, 3);
+        assert_eq!(filtered.books[0].title, "Annihilation");
+
+        let shelf = db.create_collection("Favorites").unwrap();
+        db.add_books_to_collection(&shelf.id, std::slice::from_ref(&dispossessed.id))
+            .unwrap();
+        let page = db
+            .query_library(&LibraryQuery {
+                collection_id: Some(shelf.id.clone()),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(page.total, 1);
+        assert!(
+            db.query_library(&LibraryQuery {
+                after: Some("bogus".to_string()),
+                ..Default::default()
+            })
+            .is_err()
+        );
     }
 
     #[test]
@@ -393,18 +3744,139 @@ mod tests {
         );
         db.insert_book(&book).unwrap();
 
-        let position = ReadingPosition::new(book.id.clone(), 25.5, 13);
+        let position = ReadingPosition::new(book.id.clone(), Locator::from_pdf_page(13, 50));
         db.save_reading_position(&position).unwrap();
 
         let fetched = db.get_reading_position(&book.id).unwrap();
         assert!(fetched.is_some());
-        assert_eq!(fetched.unwrap().percent, 25.5);
+        assert_eq!(fetched.unwrap().percent(), 26.0);
 
         // Update position
-        let new_position = ReadingPosition::new(book.id.clone(), 50.0, 25);
+        let new_position = ReadingPosition::new(book.id.clone(), Locator::from_pdf_page(25, 50));
         db.save_reading_position(&new_position).unwrap();
 
-        let fetched = db.get_reading_position(&book.id).unwrap();
-        assert_eq!(fetched.unwrap().percent, 50.0);
+        let fetched = db.get_reading_position(&book.id).unwrap().unwrap();
+        assert_eq!(fetched.percent(), 50.0);
+        assert_eq!(fetched.locator.page, Some(25));
+    }
+
+    #[test]
+    fn test_migrates_legacy_schema() {
+        let path = std::env::temp_dir().join(format!("omnireader-{}.db", uuid::Uuid::new_v4()));
+        {
+            let conn = Connection::open(&path).unwrap();
+            conn.execute_batch(
+                r#"
+                CREATE TABLE books (
+                    id TEXT PRIMARY KEY, title TEXT NOT NULL, author TEXT,
+                    file_path TEXT NOT NULL UNIQUE, file_type TEXT NOT NULL, cover_data BLOB,
+                    added_at INTEGER NOT NULL, last_read_at INTEGER, total_pages INTEGER NOT NULL DEFAULT 0
+                );
+                CREATE TABLE annotations (
+                    id TEXT PRIMARY KEY, book_id TEXT NOT NULL, annotation_type TEXT NOT NULL,
+                    start_percent REAL NOT NULL, end_percent REAL NOT NULL, page_number INTEGER NOT NULL,
+                    color TEXT NOT NULL, selected_text TEXT, note_text TEXT, created_at INTEGER NOT NULL
+                );
+                CREATE TABLE reading_positions (
+                    book_id TEXT PRIMARY KEY, percent REAL NOT NULL,
+                    page_number INTEGER NOT NULL, updated_at INTEGER NOT NULL
+                );
+                INSERT INTO books VALUES ('b1', 'Old Book', NULL, '/old.pdf', 'pdf', NULL, 0, NULL, 40);
+                INSERT INTO annotations VALUES ('a1', 'b1', 'highlight', 25.0, 30.0, 10, '#FFEB3B', 'old', NULL, 0);
+                INSERT INTO reading_positions VALUES ('b1', 50.0, 20, 0);
+                "#,
+            )
+            .unwrap();
+        }
+
+        let db = Database::open(path.to_string_lossy().to_string()).unwrap();
+        let annotations = db.get_annotations("b1").unwrap();
+        assert_eq!(annotations[0].locator.page, Some(10));
+        assert_eq!(annotations[0].color, "yellow");
+        assert_eq!(annotations[0].locator.percent(), 25.0);
+        assert_eq!(annotations[0].locator.end_total_progression, Some(0.3));
+        let position = db.get_reading_position("b1").unwrap().unwrap();
+        assert_eq!(position.percent(), 50.0);
+
+        drop(db);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_encrypted_database() {
+        let dir = std::env::temp_dir().join(format!("omnireader-cipher-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("library.sqlite").to_string_lossy().to_string();
+        let book = Book::new(
+            "Dune".to_string(),
+            None,
+            "/books/dune.epub".to_string(),
+            BookType::Epub,
+            10,
+        );
+        let highlight = Annotation::new_highlight(
+            book.id.clone(),
+            Locator::from_total_progression(0.25),
+            crate::annotation::HighlightColor::Yellow,
+            Some("fear is the mind-killer".to_string()),
+        );
+        {
+            let db = Database::open(path.clone()).unwrap();
+            db.insert_book(&book).unwrap();
+            db.insert_annotation(&highlight).unwrap();
+        }
+
+        encrypt_database(path.clone(), "spice".to_string()).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        assert!(!bytes.windows(11).any(|w| w == b"mind-killer"));
+        assert!(matches!(
+            Database::open(path.clone()),
+            Err(OmniReaderError::WrongPassphrase)
+        ));
+        assert!(matches!(
+            Database::open_encrypted(path.clone(), "melange".to_string()),
+            Err(OmniReaderError::WrongPassphrase)
+        ));
+
+        {
+            let db = Database::open_encrypted(path.clone(), "spice".to_string()).unwrap();
+            assert_eq!(db.get_book(&book.id).unwrap().unwrap().title, "Dune");
+
+            // Backup snapshots are sealed under their own key and restore
+            // into the encrypted library
+            let snapshot = dir.join("snapshot.sqlite");
+            let key = scratch_key();
+            db.snapshot_to(&snapshot, &key).unwrap();
+            let bytes = std::fs::read(&snapshot).unwrap();
+            assert!(!bytes.windows(11).any(|w| w == b"mind-killer"));
+            let plain = serialize_database(&open_with_key(&snapshot, &key).unwrap()).unwrap();
+            assert!(plain.windows(11).any(|w| w == b"mind-killer"));
+            let reopened = deserialize_database(&plain).unwrap();
+            let count: i64 = reopened
+                .query_row("SELECT COUNT(*) FROM annotations", [], |row| row.get(0))
+                .unwrap();
+            assert_eq!(count, 1);
+            db.delete_annotation(&highlight.id).unwrap();
+            db.replace_from(&snapshot, &key).unwrap();
+            let found = db
+                .query_notebook(&NotebookQuery {
+                    text: Some("mind".to_string()),
+                    ..Default::default()
+                })
+                .unwrap();
+            assert_eq!(found.len(), 1);
+
+            db.rekey("melange".to_string()).unwrap();
+        }
+        assert!(Database::open_encrypted(path.clone(), "spice".to_string()).is_err());
+        drop(Database::open_encrypted(path.clone(), "melange".to_string()).unwrap());
+
+        decrypt_database(path.clone(), "melange".to_string()).unwrap();
+        let db = Database::open(path.clone()).unwrap();
+        assert_eq!(db.get_annotations(&book.id).unwrap().len(), 1);
+        assert!(db.rekey("spice".to_string()).is_err());
+
+        drop(db);
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -2,8 +2,9 @@
 
 use crate::book::BookMetadata;
 use crate::error::OmniReaderError;
+use crate::locator::Locator;
 use epub::doc::EpubDoc;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 /// Chapter content from EPUB
 #[derive(Debug, Clone, uniffi::Record)]
@@ -18,7 +19,19 @@ pub struct EpubChapter {
 pub struct TocEntry {
     pub index: u32,
     pub title: String,
-    pub path: String,
+    pub locator: Locator,
+}
+
+/// Plain text of one spine item
+pub(crate) struct ChapterText {
+    pub href: String,
+    pub text: String,
+}
+
+/// Raw (X)HTML of one spine item
+pub(crate) struct ChapterHtml {
+    pub href: String,
+    pub html: String,
 }
 
 /// Extract metadata from an EPUB file
@@ -66,14 +79,21 @@ pub fn get_epub_toc(file_path: &str) -> Result<Vec<TocEntry>, OmniReaderError> {
         message: format!("Failed to open EPUB: {}", e),
     })?;
 
+    let spine_count = doc.get_num_chapters() as u32;
     let toc: Vec<TocEntry> = doc
         .toc
         .iter()
         .enumerate()
-        .map(|(idx, nav_point)| TocEntry {
-            index: idx as u32,
-            title: nav_point.label.clone(),
-            path: nav_point.content.to_string_lossy().to_string(),
+        .map(|(idx, nav_point)| {
+            let href = nav_point.content.to_string_lossy().to_string();
+            // Fragment identifiers are not part of the spine resource path
+            let resource = PathBuf::from(href.split('#').next().unwrap_or_default());
+            let spine_index = doc.resource_uri_to_chapter(&resource).unwrap_or(0) as u32;
+            TocEntry {
+                index: idx as u32,
+                title: nav_point.label.clone(),
+                locator: Locator::from_epub_spine(spine_index, spine_count, Some(href), 0.0),
+            }
         })
         .collect();
 
@@ -143,3 +163,478 @@ pub fn get_epub_cover(file_path: &str) -> Result<Option<Vec<u8>>, OmniReaderErro
 
     Ok(doc.get_cover().map(|(data, _mime)| data))
 }
+
+/// Title of the last TOC entry starting at or before a spine item
+pub(crate) fn chapter_title_for(
+    toc: &[TocEntry],
+    spine_index: u32,
+    spine_count: u32,
+) -> Option<String> {
+    toc.iter()
+        .filter(|entry| entry.locator.spine_index(spine_count) <= spine_index)
+        .max_by_key(|entry| entry.locator.spine_index(spine_count))
+        .map(|entry| entry.title.clone())
+}
+
+/// Extract the plain text of every spine item, in reading order
+pub(crate) fn extract_epub_text(file_path: &str) -> Result<Vec<ChapterText>, OmniReaderError> {
+    Ok(extract_epub_html(file_path)?
+        .into_iter()
+        .map(|chapter| ChapterText {
+            text: html_to_text(&chapter.html),
+            href: chapter.href,
+        })
+        .collect())
+}
+
+/// Extract the raw (X)HTML of every spine item, in reading order
+pub(crate) fn extract_epub_html(file_path: &str) -> Result<Vec<ChapterHtml>, OmniReaderError> {
+    let mut doc = EpubDoc::new(file_path).map_err(|e| OmniReaderError::ParseError {
+        message: format!("Failed to open EPUB: {}", e),
+    })?;
+
+    let mut chapters = Vec::new();
+    for index in 0..doc.get_num_chapters() {
+        doc.set_current_chapter(index);
+        let href = doc
+            .get_current_path()
+            .map(|p| p.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let html = doc
+            .get_current_str()
+            .map(|(content, _mime)| content)
+            .unwrap_or_default();
+        chapters.push(ChapterHtml { href, html });
+    }
+    Ok(chapters)
+}
+
+/// Convert (X)HTML chapter content to plain text with collapsed whitespace
+pub(crate) fn html_to_text(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    scan_html(html, |token| match token {
+        HtmlToken::Text(raw) => text.push_str(&decode_entities(raw)),
+        HtmlToken::Open { name, .. } | HtmlToken::Close(name) if is_block(&name) => text.push(' '),
+        _ => {}
+    });
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Token from a lenient (X)HTML scan
+enum HtmlToken<'a> {
+    Open { name: String, self_closing: bool },
+    Close(String),
+    Text(&'a str),
+}
+
+/// Scan (X)HTML into tags and raw text, skipping head, script and style content
+fn scan_html<'a>(html: &'a str, mut visit: impl FnMut(HtmlToken<'a>)) {
+    let mut rest = html;
+    let mut skip_until: Option<String> = None;
+
+    while let Some(lt) = rest.find('<') {
+        if skip_until.is_none() && lt > 0 {
+            visit(HtmlToken::Text(&rest[..lt]));
+        }
+        let Some(gt) = rest[lt..].find('>') else {
+            rest = "";
+            break;
+        };
+        let tag = rest[lt + 1..lt + gt].trim().to_lowercase();
+        rest = &rest[lt + gt + 1..];
+
+        let name: String = tag
+            .trim_start_matches('/')
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric())
+            .collect();
+        if let Some(end) = &skip_until {
+            if tag.starts_with('/') && name == *end {
+                skip_until = None;
+            }
+            continue;
+        }
+        if name.is_empty() {
+            continue;
+        }
+        let self_closing = tag.ends_with('/') || is_void(&name);
+        match name.as_str() {
+            "head" | "script" | "style" if !tag.starts_with('/') && !self_closing => {
+                skip_until = Some(name);
+            }
+            _ if tag.starts_with('/') => visit(HtmlToken::Close(name)),
+            _ => visit(HtmlToken::Open { name, self_closing }),
+        }
+    }
+    if skip_until.is_none() && !rest.is_empty() {
+        visit(HtmlToken::Text(rest));
+    }
+}
+
+/// Tags that separate words when flattened to text
+fn is_block(name: &str) -> bool {
+    matches!(
+        name,
+        "p" | "div"
+            | "br"
+            | "li"
+            | "tr"
+            | "h1"
+            | "h2"
+            | "h3"
+            | "h4"
+            | "h5"
+            | "h6"
+            | "blockquote"
+            | "section"
+    )
+}
+
+/// Elements that never have content
+fn is_void(name: &str) -> bool {
+    matches!(
+        name,
+        "br" | "hr" | "img" | "meta" | "link" | "input" | "col" | "area" | "base" | "wbr"
+    )
+}
+
+/// Maps positions within one spine item between crengine XPointers (as used by
+/// KOReader) and character offsets into the item's `html_to_text` output
+///
+/// XPointers here are relative to the spine item's `DocFragment`, e.g.
+/// `/body/div/p[3]/text().12`. Sibling indices are 1-based and omitted when an
+/// element (or text node) has no siblings of the same name, like crengine does.
+pub(crate) struct XPointerMap {
+    text_nodes: Vec<MappedText>,
+}
+
+struct MappedText {
+    /// Path from `body` as (name, 1-based index) pairs, ending in ("text()", n)
+    segments: Vec<(String, usize)>,
+    /// Display form of the path, without the character offset
+    path: String,
+    /// Text offset of each character, and whether it is visible (not collapsed)
+    chars: Vec<(usize, bool)>,
+    /// Text offset just past the node
+    end: usize,
+}
+
+/// Parent element, character offsets and end offset of a text node being mapped
+type RawText = (usize, Vec<(usize, bool)>, usize);
+
+struct DomElement {
+    name: String,
+    parent: Option<usize>,
+    /// Child elements and text nodes, in document order
+    children: Vec<DomChild>,
+}
+
+enum DomChild {
+    Element(usize),
+    Text(usize),
+}
+
+impl XPointerMap {
+    /// Build the map for one chapter's (X)HTML
+    pub fn new(html: &str) -> Self {
+        let mut elements = vec![DomElement {
+            name: String::new(),
+            parent: None,
+            children: Vec::new(),
+        }];
+        let mut current = 0;
+        // Raw text nodes as (parent element, chars with offsets, end offset)
+        let mut texts: Vec<RawText> = Vec::new();
+        let mut len = 0;
+        let mut pending_space = false;
+
+        scan_html(html, |token| match token {
+            HtmlToken::Text(raw) => {
+                let decoded = decode_entities(raw);
+                let mut chars = Vec::with_capacity(decoded.len());
+                for c in decoded.chars() {
+                    if c.is_whitespace() {
+                        pending_space |= len > 0;
+                        chars.push((len + pending_space as usize, false));
+                    } else {
+                        if pending_space {
+                            len += 1;
+                            pending_space = false;
+                        }
+                        chars.push((len, true));
+                        len += 1;
+                    }
+                }
+                if chars.iter().any(|(_, visible)| *visible) {
+                    elements[current].children.push(DomChild::Text(texts.len()));
+                    texts.push((current, chars, len));
+                }
+            }
+            HtmlToken::Open { name, self_closing } => {
+                if is_block(&name) {
+                    pending_space |= len > 0;
+                }
+                let index = elements.len();
+                elements.push(DomElement {
+                    name,
+                    parent: Some(current),
+                    children: Vec::new(),
+                });
+                elements[current].children.push(DomChild::Element(index));
+                if !self_closing {
+                    current = index;
+                }
+            }
+            HtmlToken::Close(name) => {
+                if is_block(&name) {
+                    pending_space |= len > 0;
+                }
+                let mut open = Some(current);
+                while let Some(index) = open {
+                    if elements[index].name == name {
+                        current = elements[index].parent.unwrap_or(0);
+                        break;
+                    }
+                    open = elements[index].parent;
+                }
+            }
+        });
+
+        let text_nodes = texts
+            .into_iter()
+            .enumerate()
+            .filter_map(|(text_index, (parent, chars, end))| {
+                let text_position = sibling_position(
+                    &elements[parent],
+                    |child| matches!(child, DomChild::Text(i) if *i == text_index),
+                    |child| matches!(child, DomChild::Text(_)),
+                );
+                let mut segments = vec![("text()".to_string(), text_position.0, text_position.1)];
+                let mut element = parent;
+                loop {
+                    let name = elements[element].name.clone();
+                    let parent = elements[element].parent?;
+                    let (position, count) = sibling_position(
+                        &elements[parent],
+                        |child| matches!(child, DomChild::Element(i) if *i == element),
+                        |child| matches!(child, DomChild::Element(i) if elements[*i].name == name),
+                    );
+                    segments.push((name.clone(), position, count));
+                    if name == "body" {
+                        break;
+                    }
+                    element = parent;
+                }
+                segments.reverse();
+                let path = segments
+                    .iter()
+                    .map(|(name, position, count)| {
+                        if *count > 1 {
+                            format!("/{}[{}]", name, position)
+                        } else {
+                            format!("/{}", name)
+                        }
+                    })
+                    .collect();
+                Some(MappedText {
+                    segments: segments
+                        .into_iter()
+                        .map(|(name, position, _)| (name, position))
+                        .collect(),
+                    path,
+                    chars,
+                    end,
+                })
+            })
+            .collect();
+
+        Self { text_nodes }
+    }
+
+    /// Text offset of a position XPointer; `end` treats it as an exclusive range end
+    pub fn offset(&self, xpointer: &str, end: bool) -> Option<usize> {
+        let (path, char_offset) = match xpointer.rsplit_once('.') {
+            Some((path, offset)) if offset.chars().all(|c| c.is_ascii_digit()) => {
+                (path, offset.parse().ok()?)
+            }
+            _ => (xpointer, 0),
+        };
+        let segments = parse_path(path)?;
+        let node = self
+            .text_nodes
+            .iter()
+            .find(|node| node.segments == segments)
+            .or_else(|| {
+                // An element pointer addresses its first text
+                self.text_nodes
+                    .iter()
+                    .find(|node| node.segments.starts_with(&segments))
+            })?;
+
+        if end {
+            Some(
+                node.chars[..char_offset.min(node.chars.len())]
+                    .iter()
+                    .rev()
+                    .find(|(_, visible)| *visible)
+                    .map(|(offset, _)| offset + 1)
+                    .unwrap_or_else(|| node.chars.first().map_or(node.end, |c| c.0)),
+            )
+        } else {
+            Some(node.chars.get(char_offset).map_or(node.end, |c| c.0))
+        }
+    }
+
+    /// XPointer for a text offset; `end` treats it as an exclusive range end
+    pub fn xpointer(&self, offset: usize, end: bool) -> Option<String> {
+        let found = self.text_nodes.iter().find_map(|node| {
+            node.chars
+                .iter()
+                .position(|&(o, visible)| {
+                    visible && if end { o + 1 >= offset } else { o >= offset }
+                })
+                .map(|index| (node, if end { index + 1 } else { index }))
+        });
+        let (node, index) = found.or_else(|| {
+            let last = self.text_nodes.last()?;
+            Some((last, last.chars.len()))
+        })?;
+        Some(format!("{}.{}", node.path, index))
+    }
+}
+
+/// 1-based position of a child among siblings matching `same_kind`, and their count
+fn sibling_position(
+    parent: &DomElement,
+    is_target: impl Fn(&DomChild) -> bool,
+    same_kind: impl Fn(&DomChild) -> bool,
+) -> (usize, usize) {
+    let mut position = 0;
+    let mut count = 0;
+    for child in &parent.children {
+        if same_kind(child) {
+            count += 1;
+            if is_target(child) {
+                position = count;
+            }
+        }
+    }
+    (position, count)
+}
+
+/// Parse `/body/div/p[3]/text()` into (name, index) pairs, defaulting indices to 1
+fn parse_path(path: &str) -> Option<Vec<(String, usize)>> {
+    path.split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| {
+            let (name, index) = match segment.strip_suffix(']').and_then(|s| s.rsplit_once('[')) {
+                Some((name, index)) => (name, index.parse().ok()?),
+                None => (segment, 1),
+            };
+            Some((name.to_lowercase(), index))
+        })
+        .collect()
+}
+
+fn decode_entities(s: &str) -> String {
+    if !s.contains('&') {
+        return s.to_string();
+    }
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let decoded = rest.find(';').filter(|&semi| semi <= 10).and_then(|semi| {
+            let entity = &rest[1..semi];
+            let c = match entity {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "nbsp" => Some(' '),
+                _ => entity
+                    .strip_prefix("#x")
+                    .or_else(|| entity.strip_prefix("#X"))
+                    .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                    .or_else(|| entity.strip_prefix('#').and_then(|dec| dec.parse().ok()))
+                    .and_then(char::from_u32),
+            };
+            c.map(|c| (c, semi))
+        });
+        match decoded {
+            Some((c, semi)) => {
+                out.push(c);
+                rest = &rest[semi + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_html_to_text() {
+        let html = r#"<?xml version="1.0"?><html><head><title>Skip me</title><style>p{}</style></head>
+            <body><h1>Chapter&nbsp;One</h1><p>Fish &amp; chips<br/>cost &#163;5 &#x2014; cheap.</p></body></html>"#;
+        assert_eq!(
+            html_to_text(html),
+            "Chapter One Fish & chips cost £5 — cheap."
+        );
+    }
+
+    #[test]
+    fn test_xpointer_map() {
+        let html = r#"<html><head><title>T</title></head><body>
+            <h1>Title</h1>
+            <p>First para.</p>
+            <p>Second <em>emphasised</em> para &amp; more.</p>
+            <div><p>Nested</p></div>
+            </body></html>"#;
+        let text = html_to_text(html);
+        assert_eq!(
+            text,
+            "Title First para. Second emphasised para & more. Nested"
+        );
+        let map = XPointerMap::new(html);
+
+        let second = text.find("Second").unwrap();
+        assert_eq!(
+            map.xpointer(second, false).unwrap(),
+            "/body/p[2]/text()[1].0"
+        );
+        let emphasised = text.find("emphasised").unwrap();
+        assert_eq!(
+            map.xpointer(emphasised, false).unwrap(),
+            "/body/p[2]/em/text().0"
+        );
+        let more_end = text.find("more.").unwrap() + 5;
+        assert_eq!(
+            map.xpointer(more_end, true).unwrap(),
+            "/body/p[2]/text()[2].13"
+        );
+        assert_eq!(
+            map.xpointer(text.find("Nested").unwrap(), false).unwrap(),
+            "/body/div/p/text().0"
+        );
+
+        assert_eq!(map.offset("/body/p[2]/text()[1].0", false), Some(second));
+        assert_eq!(
+            map.offset("/body/p[2]/em/text().0", false),
+            Some(emphasised)
+        );
+        assert_eq!(map.offset("/body/p[2]/text()[2].13", true), Some(more_end));
+        assert_eq!(map.offset("/body/h1/text().2", false), Some(2));
+        assert_eq!(map.offset("/body/div[1]/p[1]", false), text.find("Nested"));
+        assert_eq!(map.offset("/body/table/text().0", false), None);
+    }
+}
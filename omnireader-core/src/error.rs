@@ -19,6 +19,19 @@ pub enum OmniReaderError {
 
     #[error("IO error: {message}")]
     IoError { message: String },
+
+    #[error("Wrong passphrase")]
+    WrongPassphrase,
+
+    #[error("Encryption error: {message}")]
+    Encryption { message: String },
+
+    #[error("Invalid color: {hex} (expected #RRGGBB)")]
+    InvalidColor { hex: String },
+
+    /// `position` is the character offset in the query where the problem starts
+    #[error("Invalid query at {position}: {message}")]
+    InvalidQuery { message: String, position: u32 },
 }
 
 impl From<rusqlite::Error> for OmniReaderError {
@@ -0,0 +1,371 @@
+//! Reading goals and the year-in-review report
+//!
+//! Goals are kept in the settings table and measured against reading
+//! sessions and finished reads, so a reread finished again counts again.
+
+use crate::annotation::AnnotationType;
+use crate::db::Database;
+use crate::error::OmniReaderError;
+use crate::notebook::NotebookQuery;
+use crate::session::{self, ReadingSession, ReadingTotals};
+use chrono::{Datelike, NaiveDate};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use uniffi;
+
+const GOALS_SETTING: &str = "reading.goals";
+
+/// Number of books listed as most highlighted
+const MOST_HIGHLIGHTED: usize = 5;
+
+/// Reading goals; `None` turns a goal off
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, uniffi::Record)]
+pub struct ReadingGoals {
+    pub books_per_year: Option<u32>,
+    pub minutes_per_day: Option<u32>,
+    pub pages_per_week: Option<u32>,
+}
+
+/// Progress toward one goal
+#[derive(Debug, Clone, PartialEq, uniffi::Record)]
+pub struct GoalProgress {
+    pub target: u32,
+    /// Amount reached so far in the current period
+    pub current: f64,
+    pub met: bool,
+}
+
+/// Progress toward each goal that is set, in the current year, day and week
+#[derive(Debug, Clone, Default, PartialEq, uniffi::Record)]
+pub struct ReadingGoalsProgress {
+    pub books_this_year: Option<GoalProgress>,
+    pub minutes_today: Option<GoalProgress>,
+    pub pages_this_week: Option<GoalProgress>,
+}
+
+/// A book finished during the report year
+#[derive(Debug, Clone, PartialEq, uniffi::Record)]
+pub struct FinishedBook {
+    pub book_id: String,
+    pub title: String,
+    pub author: Option<String>,
+    /// Unix timestamp
+    pub finished_at: i64,
+}
+
+/// A book by number of highlights made during the report year
+#[derive(Debug, Clone, PartialEq, uniffi::Record)]
+pub struct HighlightedBook {
+    pub book_id: String,
+    pub title: String,
+    pub highlights: u32,
+}
+
+/// Reading in books with one tag during the report year
+#[derive(Debug, Clone, PartialEq, uniffi::Record)]
+pub struct GenreReading {
+    /// Book tag name
+    pub genre: String,
+    /// Books read at all, finished or not
+    pub books: u32,
+    pub seconds: i64,
+}
+
+/// Year-in-review report
+#[derive(Debug, Clone, PartialEq, uniffi::Record)]
+pub struct YearInReview {
+    pub year: i32,
+    /// In order of finishing
+    pub books_finished: Vec<FinishedBook>,
+    pub totals: ReadingTotals,
+    /// Reading time per month, January first
+    pub monthly_seconds: Vec<i64>,
+    pub longest_streak_days: u32,
+    /// Most highlights first
+    pub most_highlighted: Vec<HighlightedBook>,
+    /// Most reading time first; untagged books are left out
+    pub genres: Vec<GenreReading>,
+}
+
+/// Get the reading goals
+pub fn get_reading_goals(db: &Database) -> Result<ReadingGoals, OmniReaderError> {
+    match db.get_setting(GOALS_SETTING)? {
+        Some(json) => serde_json::from_str(&json).map_err(|e| OmniReaderError::ParseError {
+            message: format!("Invalid reading goals: {}", e),
+        }),
+        None => Ok(ReadingGoals::default()),
+    }
+}
+
+/// Replace the reading goals
+pub fn set_reading_goals(db: &Database, goals: &ReadingGoals) -> Result<(), OmniReaderError> {
+    let json = serde_json::to_string(goals).map_err(|e| OmniReaderError::ParseError {
+        message: format!("Failed to serialize reading goals: {}", e),
+    })?;
+    db.set_setting(GOALS_SETTING, &json)
+}
+
+/// Progress toward the reading goals; days are calendar days at
+/// `utc_offset_minutes` from UTC and weeks start on Monday
+pub fn reading_goals_progress(
+    db: &Database,
+    utc_offset_minutes: i32,
+) -> Result<ReadingGoalsProgress, OmniReaderError> {
+    goals_progress_at(db, chrono::Utc::now().timestamp(), utc_offset_minutes)
+}
+
+/// Year-in-review report for a calendar year at `utc_offset_minutes` from UTC
+pub fn year_in_review(
+    db: &Database,
+    year: i32,
+    utc_offset_minutes: i32,
+) -> Result<YearInReview, OmniReaderError> {
+    let (start, end) = year_range(year, utc_offset_minutes)?;
+    let sessions = sessions_between(db, start, end)?;
+    let mut books_finished = Vec::new();
+    for read in db.get_reads_finished_between(start, end)? {
+        if let Some(book) = db.get_book(&read.book_id)? {
+            books_finished.push(FinishedBook {
+                book_id: book.id,
+                title: book.title,
+                author: book.author,
+                finished_at: read.finished_at.unwrap_or(read.started_at),
+            });
+        }
+    }
+
+    let mut monthly_seconds = vec![0; 12];
+    let mut days = BTreeSet::new();
+    for session in &sessions {
+        let day = session::local_date(session.started_at, utc_offset_minutes);
+        monthly_seconds[day.month0() as usize] += session.duration();
+        days.insert(day);
+    }
+
+    let mut genres: HashMap<String, (HashSet<&str>, i64)> = HashMap::new();
+    let mut tags: HashMap<&str, Vec<String>> = HashMap::new();
+    for session in &sessions {
+        if !tags.contains_key(session.book_id.as_str()) {
+            let names = db
+                .get_book_tags(&session.book_id)?
+                .into_iter()
+                .map(|tag| tag.name)
+                .collect();
+            tags.insert(&session.book_id, names);
+        }
+        for name in &tags[session.book_id.as_str()] {
+            let genre = genres.entry(name.clone()).or_default();
+            genre.0.insert(&session.book_id);
+            genre.1 += session.duration();
+        }
+    }
+    let mut genres: Vec<GenreReading> = genres
+        .into_iter()
+        .map(|(genre, (books, seconds))| GenreReading {
+            genre,
+            books: books.len() as u32,
+            seconds,
+        })
+        .collect();
+    genres.sort_by(|a, b| {
+        b.seconds
+            .cmp(&a.seconds)
+            .then_with(|| a.genre.cmp(&b.genre))
+    });
+
+    Ok(YearInReview {
+        year,
+        books_finished,
+        totals: session::totals(&sessions),
+        monthly_seconds,
+        longest_streak_days: session::longest_streak(&days),
+        most_highlighted: most_highlighted(db, start, end)?,
+        genres,
+    })
+}
+
+fn goals_progress_at(
+    db: &Database,
+    now: i64,
+    utc_offset_minutes: i32,
+) -> Result<ReadingGoalsProgress, OmniReaderError> {
+    let goals = get_reading_goals(db)?;
+    let today = session::local_date(now, utc_offset_minutes);
+    let progress = |target: Option<u32>, current: f64| {
+        target.map(|target| GoalProgress {
+            target,
+            current,
+            met: current >= target as f64,
+        })
+    };
+
+    let books_this_year = match goals.books_per_year {
+        Some(target) => {
+            let (start, end) = year_range(today.year(), utc_offset_minutes)?;
+            let finished = db.get_reads_finished_between(start, end)?;
+            progress(Some(target), finished.len() as f64)
+        }
+        None => None,
+    };
+
+    let today_start = day_start(today, utc_offset_minutes);
+    let today_sessions = sessions_between(db, today_start, i64::MAX)?;
+    let minutes: i64 = today_sessions.iter().map(ReadingSession::duration).sum();
+
+    let week = day_start(session::week_start(today), utc_offset_minutes);
+    let pages: f64 = sessions_between(db, week, i64::MAX)?
+        .iter()
+        .map(|session| session.pages_read)
+        .sum();
+
+    Ok(ReadingGoalsProgress {
+        books_this_year,
+        minutes_today: progress(goals.minutes_per_day, minutes as f64 / 60.0),
+        pages_this_week: progress(goals.pages_per_week, pages),
+    })
+}
+
+fn most_highlighted(
+    db: &Database,
+    start: i64,
+    end: i64,
+) -> Result<Vec<HighlightedBook>, OmniReaderError> {
+    let highlights = db.query_notebook(&NotebookQuery {
+        annotation_types: vec![AnnotationType::Highlight],
+        created_after: Some(start),
+        created_before: Some(end),
+        ..Default::default()
+    })?;
+    let mut counts: HashMap<String, u32> = HashMap::new();
+    for highlight in highlights {
+        *counts.entry(highlight.book_id).or_default() += 1;
+    }
+    let mut counts: Vec<(String, u32)> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+    let mut books = Vec::new();
+    for (book_id, highlights) in counts {
+        if books.len() == MOST_HIGHLIGHTED {
+            break;
+        }
+        if let Some(book) = db.get_book(&book_id)? {
+            books.push(HighlightedBook {
+                book_id,
+                title: book.title,
+                highlights,
+            });
+        }
+    }
+    Ok(books)
+}
+
+/// Sessions started in `[start, end)`
+fn sessions_between(
+    db: &Database,
+    start: i64,
+    end: i64,
+) -> Result<Vec<ReadingSession>, OmniReaderError> {
+    let mut sessions = db.get_reading_sessions_since(start)?;
+    sessions.retain(|session| session.started_at < end);
+    Ok(sessions)
+}
+
+/// Unix timestamps of the start of `year` and of the next year
+fn year_range(year: i32, utc_offset_minutes: i32) -> Result<(i64, i64), OmniReaderError> {
+    let first_day = |year| {
+        NaiveDate::from_ymd_opt(year, 1, 1).ok_or_else(|| OmniReaderError::ParseError {
+            message: format!("Invalid year: {}", year),
+        })
+    };
+    Ok((
+        day_start(first_day(year)?, utc_offset_minutes),
+        day_start(first_day(year + 1)?, utc_offset_minutes),
+    ))
+}
+
+fn day_start(day: NaiveDate, utc_offset_minutes: i32) -> i64 {
+    day.and_time(chrono::NaiveTime::MIN).and_utc().timestamp() - utc_offset_minutes as i64 * 60
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::annotation::{Annotation, HighlightColor, ReadingPosition};
+    use crate::book::{Book, BookType};
+    use crate::locator::Locator;
+    use crate::session::track_reading_position;
+
+    fn turn(db: &Database, book: &Book, page: u32, at: i64) {
+        let mut position = ReadingPosition::new(
+            book.id.clone(),
+            Locator::from_pdf_page(page, book.total_pages),
+        );
+        position.updated_at = at;
+        track_reading_position(db, &position).unwrap();
+    }
+
+    #[test]
+    fn test_goals_and_year_in_review() {
+        let db = Database::open_in_memory().unwrap();
+        let book = Book::new(
+            "Short Stories".to_string(),
+            Some("Ted Chiang".to_string()),
+            "/books/stories.pdf".to_string(),
+            BookType::Pdf,
+            20,
+        );
+        db.insert_book(&book).unwrap();
+        db.add_book_tag(&book.id, "Science Fiction").unwrap();
+
+        // Wednesday 2025-03-12, 10:00 UTC: the whole book in 40 minutes
+        let start = 1_741_773_600;
+        for page in 0..20 {
+            turn(&db, &book, page, start + page as i64 * 120);
+        }
+        // Thursday: the last pages again, which does not finish it twice
+        turn(&db, &book, 15, start + 86_400);
+        turn(&db, &book, 19, start + 86_400 + 240);
+
+        let mut highlight = Annotation::new_highlight(
+            book.id.clone(),
+            Locator::from_pdf_page(3, 20),
+            HighlightColor::Yellow,
+            Some("story".to_string()),
+        );
+        highlight.created_at = start + 300;
+        db.insert_annotation(&highlight).unwrap();
+
+        assert_eq!(get_reading_goals(&db).unwrap(), ReadingGoals::default());
+        let goals = ReadingGoals {
+            books_per_year: Some(12),
+            minutes_per_day: Some(30),
+            pages_per_week: None,
+        };
+        set_reading_goals(&db, &goals).unwrap();
+        assert_eq!(get_reading_goals(&db).unwrap(), goals);
+
+        let progress = goals_progress_at(&db, start + 86_400 + 600, 0).unwrap();
+        assert_eq!(progress.books_this_year.unwrap().current, 1.0);
+        let minutes = progress.minutes_today.unwrap();
+        assert_eq!(minutes.current, 4.0);
+        assert!(!minutes.met);
+        assert!(progress.pages_this_week.is_none());
+
+        let review = year_in_review(&db, 2025, 0).unwrap();
+        assert_eq!(review.books_finished.len(), 1);
+        assert_eq!(review.books_finished[0].finished_at, start + 38 * 60);
+        assert_eq!(review.totals.seconds, 38 * 60 + 240);
+        assert_eq!(review.monthly_seconds[2], 38 * 60 + 240);
+        assert_eq!(review.longest_streak_days, 2);
+        assert_eq!(review.most_highlighted[0].highlights, 1);
+        assert_eq!(review.genres[0].genre, "Science Fiction");
+        assert_eq!(review.genres[0].books, 1);
+
+        assert!(
+            year_in_review(&db, 2024, 0)
+                .unwrap()
+                .books_finished
+                .is_empty()
+        );
+    }
+}
@@ -0,0 +1,798 @@
+//! Import of Kindle `My Clippings.txt` highlights, notes and bookmarks
+//!
+//! Clippings are matched to library books by title and author, positioned by
+//! searching the book text for the highlighted passage, and stored with IDs
+//! derived from their content so a reimport does not create duplicates.
+//! The parser understands the metadata line in the locales Kindle ships
+//! (English, German, French, Spanish, Italian, Portuguese, Dutch, Japanese,
+//! Chinese) and both the current and the older "Loc." formats.
+
+use crate::annotation::{AnchorStatus, Annotation, AnnotationImport, HighlightColor};
+use crate::book::{Book, BookType};
+use crate::db::Database;
+use crate::error::OmniReaderError;
+use crate::locator::Locator;
+use crate::reanchor::{self, TextQuoteSelector};
+use crate::search::{self, TextResource};
+use chrono::{NaiveDate, NaiveTime};
+use std::collections::{HashMap, HashSet};
+use uniffi;
+
+/// Line separating clippings
+const SEPARATOR: &str = "==========";
+
+/// Where a subtitle or edition starts after a title
+const SUBTITLE_SEPARATORS: &[&str] = &[":", " (", "：", "（"];
+
+/// Namespace for content-derived clipping IDs
+const CLIPPING_NAMESPACE: uuid::Uuid =
+    uuid::Uuid::from_u128(0x6f6d6e69_7265_6164_6572_6b696e646c65);
+
+const BOOKMARK_WORDS: &[&str] = &[
+    "bookmark",
+    "lesezeichen",
+    "signet",
+    "marcador",
+    "segnalibro",
+    "bladwijzer",
+    "ブックマーク",
+    "书签",
+];
+const HIGHLIGHT_WORDS: &[&str] = &[
+    "highlight",
+    "markierung",
+    "surlignement",
+    "subrayado",
+    "evidenziazione",
+    "destaque",
+    "markering",
+    "ハイライト",
+    "标注",
+];
+const NOTE_WORDS: &[&str] = &["note", "notiz", "nota", "notitie", "メモ", "笔记"];
+const PAGE_WORDS: &[&str] = &["page", "seite", "página", "pagina", "pagina's"];
+const PAGE_SUFFIX_WORDS: &[&str] = &["ページ", "页"];
+const LOCATION_WORDS: &[&str] = &[
+    "location",
+    "loc.",
+    "position",
+    "emplacement",
+    "posición",
+    "posizione",
+    "posição",
+    "positie",
+    "位置",
+];
+const MONTHS: [&[&str]; 12] = [
+    &[
+        "january", "januar", "janvier", "enero", "gennaio", "janeiro", "januari",
+    ],
+    &[
+        "february",
+        "februar",
+        "février",
+        "febrero",
+        "febbraio",
+        "fevereiro",
+        "februari",
+    ],
+    &["march", "märz", "mars", "marzo", "março", "maart"],
+    &["april", "avril", "abril", "aprile"],
+    &["may", "mai", "mayo", "maggio", "maio", "mei"],
+    &["june", "juni", "juin", "junio", "giugno", "junho"],
+    &["july", "juli", "juillet", "julio", "luglio", "julho"],
+    &["august", "août", "agosto", "augustus"],
+    &[
+        "september",
+        "septembre",
+        "septiembre",
+        "settembre",
+        "setembro",
+    ],
+    &[
+        "october", "oktober", "octobre", "octubre", "ottobre", "outubro",
+    ],
+    &["november", "novembre", "noviembre", "novembro"],
+    &[
+        "december",
+        "dezember",
+        "décembre",
+        "diciembre",
+        "dicembre",
+        "dezembro",
+    ],
+];
+
+/// Kind of Kindle clipping
+#[derive(Debug, Clone, Copy, PartialEq, Eq, uniffi::Enum)]
+pub enum ClippingKind {
+    Highlight,
+    Note,
+    Bookmark,
+}
+
+/// One entry of `My Clippings.txt`
+#[derive(Debug, Clone, PartialEq, uniffi::Record)]
+pub struct KindleClipping {
+    pub title: String,
+    pub author: Option<String>,
+    pub kind: ClippingKind,
+    /// Printed page number (1-based), if the device reported one
+    pub page: Option<u32>,
+    pub location_start: Option<u32>,
+    pub location_end: Option<u32>,
+    /// Unix timestamp the clipping was added, if the date could be parsed
+    pub added_at: Option<i64>,
+    /// Highlighted text or note text (empty for bookmarks)
+    pub text: String,
+}
+
+/// A clipping whose book is not in the library
+#[derive(Debug, Clone, PartialEq, uniffi::Record)]
+pub struct UnmatchedClipping {
+    pub title: String,
+    pub author: Option<String>,
+    pub kind: ClippingKind,
+    pub text: String,
+}
+
+/// Outcome of a clippings import
+#[derive(Debug, Clone, Default, PartialEq, uniffi::Record)]
+pub struct KindleImportReport {
+    /// Annotations created
+    pub imported: u32,
+    /// Clippings skipped because they were imported before
+    pub duplicates: u32,
+    /// Imported annotations that could not be positioned and need review
+    pub unanchored: u32,
+    /// Clippings whose book could not be found in the library
+    pub unmatched: Vec<UnmatchedClipping>,
+}
+
+/// Parse the contents of a `My Clippings.txt` file
+#[uniffi::export]
+pub fn parse_kindle_clippings(contents: &str) -> Vec<KindleClipping> {
+    contents
+        .split(SEPARATOR)
+        .filter_map(parse_clipping)
+        .collect()
+}
+
+/// Import clippings into the library in one transaction, positioning them
+/// within matched books
+pub fn import_kindle_clippings(
+    db: &Database,
+    contents: &str,
+) -> Result<KindleImportReport, OmniReaderError> {
+    import_clippings_with(db, contents, |book| {
+        search::load_text_resources(&book.file_path, book.file_type).ok()
+    })
+}
+
+/// Import with a custom loader for book text (None when the file is unreadable)
+pub(crate) fn import_clippings_with(
+    db: &Database,
+    contents: &str,
+    mut load_text: impl FnMut(&Book) -> Option<Vec<TextResource>>,
+) -> Result<KindleImportReport, OmniReaderError> {
+    let books = db.get_all_books()?;
+    let mut texts: HashMap<String, Option<Vec<TextResource>>> = HashMap::new();
+    // Highlights placed during this import, for positioning notes and bookmarks
+    let mut placed: HashMap<String, Vec<(u32, u32, Locator)>> = HashMap::new();
+    let mut seen = HashSet::new();
+    let mut imports = Vec::new();
+    let mut report = KindleImportReport::default();
+
+    for clipping in parse_kindle_clippings(contents) {
+        let Some(book) = match_book(&books, &clipping) else {
+            report.unmatched.push(UnmatchedClipping {
+                title: clipping.title,
+                author: clipping.author,
+                kind: clipping.kind,
+                text: clipping.text,
+            });
+            continue;
+        };
+
+        let id = clipping_id(&clipping);
+        if !seen.insert(id.clone()) || db.get_annotation(&id)?.is_some() {
+            report.duplicates += 1;
+            continue;
+        }
+
+        let resources = texts
+            .entry(book.id.clone())
+            .or_insert_with(|| load_text(book));
+        let located = match clipping.kind {
+            ClippingKind::Highlight => resources
+                .as_deref()
+                .and_then(|resources| locate_text(&clipping.text, book, &clipping, resources)),
+            _ => clipping.location_start.and_then(|location| {
+                placed.get(&book.id).and_then(|highlights| {
+                    highlights
+                        .iter()
+                        .find(|(start, end, _)| (*start..=*end).contains(&location))
+                        .map(|(_, _, locator)| locator.clone())
+                })
+            }),
+        };
+        let page_fallback = match (book.file_type, clipping.page) {
+            (BookType::Pdf, Some(page)) => Some(Locator::from_pdf_page(
+                page.saturating_sub(1),
+                book.total_pages,
+            )),
+            _ => None,
+        };
+        let anchored = located.is_some() || page_fallback.is_some();
+        let locator = located
+            .or(page_fallback)
+            .unwrap_or_else(|| Locator::from_total_progression(0.0));
+
+        if clipping.kind == ClippingKind::Highlight
+            && let Some(start) = clipping.location_start
+        {
+            placed.entry(book.id.clone()).or_default().push((
+                start,
+                clipping.location_end.unwrap_or(start),
+                locator.clone(),
+            ));
+        }
+
+        let mut annotation = match clipping.kind {
+            ClippingKind::Highlight => Annotation::new_highlight(
+                book.id.clone(),
+                locator,
+                HighlightColor::Yellow,
+                Some(clipping.text.clone()),
+            ),
+            ClippingKind::Note => {
+                Annotation::new_note(book.id.clone(), locator, clipping.text.clone())
+            }
+            ClippingKind::Bookmark => {
+                Annotation::new_bookmark(book.id.clone(), locator, None, None, None)
+            }
+        };
+        annotation.id = id;
+        if let Some(added_at) = clipping.added_at {
+            annotation.created_at = added_at;
+            annotation.updated_at = added_at;
+        }
+        if !anchored {
+            annotation.anchor_status = AnchorStatus::Orphaned;
+            report.unanchored += 1;
+        }
+        imports.push(AnnotationImport {
+            annotation,
+            tags: Vec::new(),
+        });
+    }
+    report.imported = db.insert_annotations(&imports)?;
+    Ok(report)
+}
+
+/// Find a highlighted passage in the book text
+fn locate_text(
+    text: &str,
+    book: &Book,
+    clipping: &KindleClipping,
+    resources: &[TextResource],
+) -> Option<Locator> {
+    let exact = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if exact.is_empty() {
+        return None;
+    }
+    let hint = match (book.file_type, clipping.page) {
+        (BookType::Pdf, Some(page)) => {
+            Locator::from_pdf_page(page.saturating_sub(1), book.total_pages)
+        }
+        _ => Locator::from_total_progression(0.0),
+    };
+    let quote = TextQuoteSelector {
+        exact,
+        prefix: None,
+        suffix: None,
+    };
+    let found = reanchor::locate_quote(&quote, &hint, resources)?;
+    Some(search::locator_for_range(
+        resources,
+        found.index,
+        found.start,
+        found.end,
+    ))
+}
+
+/// Pick the library book best matching a clipping's title and author
+///
+/// Titles match when equal once normalized, or when one is the other with a
+/// subtitle or edition added ("Dune" and "Dune: Deluxe Edition").
+fn match_book<'a>(books: &'a [Book], clipping: &KindleClipping) -> Option<&'a Book> {
+    let title = normalize(&clipping.title);
+    let title_without_subtitle = normalize(main_title(&clipping.title));
+    let author = clipping.author.as_deref().map(name_tokens);
+
+    books
+        .iter()
+        .filter_map(|book| {
+            let book_title = normalize(&book.title);
+            let title_score = if book_title == title {
+                2
+            } else if !title.is_empty()
+                && !book_title.is_empty()
+                && (normalize(main_title(&book.title)) == title
+                    || title_without_subtitle == book_title)
+            {
+                1
+            } else {
+                return None;
+            };
+            let author_score = match (&author, book.author.as_deref().map(name_tokens)) {
+                (Some(a), Some(b)) if a == &b => 2,
+                (Some(a), Some(b)) if a.iter().any(|token| b.contains(token)) => 1,
+                (Some(_), Some(_)) => return None,
+                _ => 0,
+            };
+            Some((title_score + author_score, book))
+        })
+        .max_by_key(|(score, _)| *score)
+        .map(|(_, book)| book)
+}
+
+/// A title without its subtitle or edition
+fn main_title(title: &str) -> &str {
+    SUBTITLE_SEPARATORS
+        .iter()
+        .filter_map(|separator| title.find(separator))
+        .min()
+        .map_or(title, |index| &title[..index])
+}
+
+/// Stable ID for a clipping, so reimports are recognised
+fn clipping_id(clipping: &KindleClipping) -> String {
+    let key = format!(
+        "{}\u{1f}{:?}\u{1f}{:?}\u{1f}{:?}\u{1f}{:?}\u{1f}{}",
+        normalize(&clipping.title),
+        clipping.kind,
+        clipping.page,
+        clipping.location_start,
+        clipping.location_end,
+        clipping.text.trim()
+    );
+    uuid::Uuid::new_v5(&CLIPPING_NAMESPACE, key.as_bytes()).to_string()
+}
+
+fn parse_clipping(entry: &str) -> Option<KindleClipping> {
+    let mut lines = entry
+        .lines()
+        .map(|line| line.trim_start_matches('\u{feff}').trim_end());
+    let header = lines.by_ref().find(|line| !line.trim().is_empty())?.trim();
+    let meta = lines.next()?.trim();
+    let text = lines.collect::<Vec<_>>().join("\n").trim().to_string();
+
+    let (title, author) = split_title_author(header);
+    let meta_lower = meta.to_lowercase();
+    let segments: Vec<&str> = meta_lower.split('|').collect();
+    let (date_segment, position_segments) = segments.split_last()?;
+    // Japanese puts the kind after the page, so look at every position segment
+    let description = position_segments.join("|");
+
+    let kind = if contains_any(&description, BOOKMARK_WORDS) {
+        ClippingKind::Bookmark
+    } else if contains_any(&description, HIGHLIGHT_WORDS) {
+        ClippingKind::Highlight
+    } else if contains_any(&description, NOTE_WORDS) {
+        ClippingKind::Note
+    } else {
+        return None;
+    };
+
+    let mut page = None;
+    let mut location = None;
+    for segment in position_segments {
+        page = page.or_else(|| page_number(segment));
+        location = location.or_else(|| location_range(segment));
+    }
+
+    Some(KindleClipping {
+        title,
+        author,
+        kind,
+        page,
+        location_start: location.map(|(start, _)| start),
+        location_end: location.map(|(_, end)| end),
+        added_at: parse_added_date(date_segment),
+        text,
+    })
+}
+
+/// Split `Title (Author)`; the author is the last balanced parenthesised group
+fn split_title_author(header: &str) -> (String, Option<String>) {
+    if !header.ends_with(')') {
+        return (header.to_string(), None);
+    }
+    let mut depth = 0;
+    for (index, c) in header.char_indices().rev() {
+        match c {
+            ')' => depth += 1,
+            '(' => {
+                depth -= 1;
+                if depth == 0 {
+                    let title = header[..index].trim();
+                    let author = header[index + 1..header.len() - 1].trim();
+                    if title.is_empty() {
+                        break;
+                    }
+                    return (
+                        title.to_string(),
+                        (!author.is_empty()).then(|| author.to_string()),
+                    );
+                }
+            }
+            _ => {}
+        }
+    }
+    (header.to_string(), None)
+}
+
+fn page_number(segment: &str) -> Option<u32> {
+    for word in PAGE_WORDS {
+        if let Some(index) = find_word(segment, word) {
+            return numbers(&segment[index + word.len()..]).first().copied();
+        }
+    }
+    for word in PAGE_SUFFIX_WORDS {
+        if let Some(index) = segment.find(word) {
+            return numbers(&segment[..index]).last().copied();
+        }
+    }
+    None
+}
+
+fn location_range(segment: &str) -> Option<(u32, u32)> {
+    let index = LOCATION_WORDS
+        .iter()
+        .find_map(|word| segment.find(word).map(|index| index + word.len()))?;
+    let rest = &segment[index..];
+    let start_at = rest.find(|c: char| c.is_ascii_digit())?;
+    let rest = &rest[start_at..];
+    let start_digits: String = rest.chars().take_while(char::is_ascii_digit).collect();
+    let start: u32 = start_digits.parse().ok()?;
+
+    let after = &rest[start_digits.len()..];
+    let end = after
+        .strip_prefix('-')
+        .map(|s| {
+            s.chars()
+                .take_while(char::is_ascii_digit)
+                .collect::<String>()
+        })
+        .filter(|digits| !digits.is_empty())
+        .and_then(|digits| {
+            let end: u32 = digits.parse().ok()?;
+            if end >= start {
+                return Some(end);
+            }
+            // Older firmware abbreviates ranges: "123-25" means 123-125
+            let prefix = &start_digits[..start_digits.len().saturating_sub(digits.len())];
+            format!("{}{}", prefix, digits).parse().ok()
+        })
+        .unwrap_or(start);
+    Some((start, end))
+}
+
+/// Parse the "Added on ..." segment in any supported locale
+fn parse_added_date(segment: &str) -> Option<i64> {
+    let pm = segment.contains(" pm") || segment.contains("下午") || segment.contains("午後");
+    let am = segment.contains(" am") || segment.contains("上午") || segment.contains("午前");
+
+    // Pull out the time first so its digits are not mistaken for the date
+    let mut time = NaiveTime::from_hms_opt(0, 0, 0)?;
+    let mut rest = segment.to_string();
+    if let Some(colon) = segment.match_indices(':').map(|(i, _)| i).find(|&i| {
+        segment[..i].ends_with(|c: char| c.is_ascii_digit())
+            && segment[i + 1..].starts_with(|c: char| c.is_ascii_digit())
+    }) {
+        let start = segment[..colon]
+            .rfind(|c: char| !c.is_ascii_digit())
+            .map(|i| i + segment[i..].chars().next().map_or(1, char::len_utf8))
+            .unwrap_or(0);
+        let end = segment[colon..]
+            .find(|c: char| !(c.is_ascii_digit() || c == ':'))
+            .map(|i| colon + i)
+            .unwrap_or(segment.len());
+        let parts: Vec<u32> = segment[start..end]
+            .split(':')
+            .filter_map(|p| p.parse().ok())
+            .collect();
+        let mut hour = *parts.first()?;
+        if pm && hour < 12 {
+            hour += 12;
+        } else if am && hour == 12 {
+            hour = 0;
+        }
+        time = NaiveTime::from_hms_opt(
+            hour,
+            *parts.get(1).unwrap_or(&0),
+            *parts.get(2).unwrap_or(&0),
+        )?;
+        rest.replace_range(start..end, " ");
+    }
+
+    let nums = numbers(&rest);
+    let month_by_name = rest.split(|c: char| !c.is_alphabetic()).find_map(|word| {
+        MONTHS
+            .iter()
+            .position(|names| names.contains(&word))
+            .map(|i| i as u32 + 1)
+    });
+    let year = *nums.iter().find(|&&n| n >= 1000)?;
+    let (month, day) = match month_by_name {
+        Some(month) => (month, *nums.iter().find(|&&n| (1..=31).contains(&n))?),
+        None => {
+            // Numeric dates (Japanese and Chinese): year, month, day in order
+            let after_year: Vec<u32> = nums
+                .iter()
+                .skip_while(|&&n| n != year)
+                .skip(1)
+                .copied()
+                .collect();
+            (*after_year.first()?, *after_year.get(1)?)
+        }
+    };
+
+    let date = NaiveDate::from_ymd_opt(year as i32, month, day)?;
+    Some(date.and_time(time).and_utc().timestamp())
+}
+
+/// All ASCII digit runs in a string
+fn numbers(s: &str) -> Vec<u32> {
+    s.split(|c: char| !c.is_ascii_digit())
+        .filter_map(|n| n.parse().ok())
+        .collect()
+}
+
+fn contains_any(s: &str, words: &[&str]) -> bool {
+    words.iter().any(|word| s.contains(word))
+}
+
+/// Find a word that is not part of a longer word
+fn find_word(s: &str, word: &str) -> Option<usize> {
+    s.match_indices(word).map(|(i, _)| i).find(|&i| {
+        !s[..i].ends_with(char::is_alphabetic)
+            && !s[i + word.len()..].starts_with(char::is_alphabetic)
+    })
+}
+
+/// Lowercase alphanumeric words separated by single spaces
+fn normalize(s: &str) -> String {
+    s.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Name tokens, order-insensitive ("Le Guin, Ursula K." == "Ursula K. Le Guin")
+fn name_tokens(name: &str) -> Vec<String> {
+    let mut tokens: Vec<String> = normalize(name)
+        .split(' ')
+        .filter(|token| token.chars().count() > 1)
+        .map(str::to_string)
+        .collect();
+    tokens.sort();
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIPPINGS: &str = "\u{feff}The Left Hand of Darkness (Le Guin, Ursula K.)
+- Your Highlight on page 12 | Location 150-152 | Added on Monday, 1 January 2024 10:05:30
+
+Light is the left hand of darkness
+==========
+The Left Hand of Darkness (Le Guin, Ursula K.)
+- Your Note on page 12 | Location 152 | Added on Monday, January 1, 2024 10:06:00 PM
+
+A lovely line.
+==========
+Der Prozess (Franz Kafka)
+- Ihre Markierung bei Position 123-25 | Hinzugefügt am Dienstag, 2. Januar 2024 08:00:00
+
+Jemand musste Josef K. verleumdet haben
+==========
+Le Petit Prince (Antoine de Saint-Exupéry)
+- Votre signet sur la page 7 | emplacement 90 | Ajouté le mercredi 3 janvier 2024 09:15:00
+
+
+==========
+吾輩は猫である (夏目 漱石)
+- 12ページ|位置No. 200-210の ハイライト |作成日: 2024年1月4日木曜日 午後3:20:00
+
+吾輩は猫である。名前はまだ無い。
+==========
+";
+
+    #[test]
+    fn test_parse_locales() {
+        let clippings = parse_kindle_clippings(CLIPPINGS);
+        assert_eq!(clippings.len(), 5);
+
+        let first = &clippings[0];
+        assert_eq!(first.title, "The Left Hand of Darkness");
+        assert_eq!(first.author.as_deref(), Some("Le Guin, Ursula K."));
+        assert_eq!(first.kind, ClippingKind::Highlight);
+        assert_eq!(first.page, Some(12));
+        assert_eq!(
+            (first.location_start, first.location_end),
+            (Some(150), Some(152))
+        );
+        assert_eq!(first.added_at, Some(1_704_103_530));
+        assert_eq!(first.text, "Light is the left hand of darkness");
+
+        assert_eq!(clippings[1].kind, ClippingKind::Note);
+        assert_eq!(clippings[1].added_at, Some(1_704_146_760));
+
+        let german = &clippings[2];
+        assert_eq!(german.kind, ClippingKind::Highlight);
+        assert_eq!(
+            (german.location_start, german.location_end),
+            (Some(123), Some(125))
+        );
+        assert_eq!(german.added_at, Some(1_704_182_400));
+
+        let french = &clippings[3];
+        assert_eq!(french.kind, ClippingKind::Bookmark);
+        assert_eq!(french.page, Some(7));
+        assert!(french.text.is_empty());
+
+        let japanese = &clippings[4];
+        assert_eq!(japanese.kind, ClippingKind::Highlight);
+        assert_eq!(japanese.page, Some(12));
+        assert_eq!(japanese.location_start, Some(200));
+        assert_eq!(japanese.added_at, Some(1_704_381_600));
+    }
+
+    #[test]
+    fn test_parse_chinese() {
+        let clippings = parse_kindle_clippings(
+            "三体 (刘慈欣)
+- 您在第 5 页（位置 #64-65）的标注 | 添加于 2024年1月5日星期五 下午2:30:00
+
+不要回答！不要回答！
+==========
+三体 (刘慈欣)
+- 您在位置 #65 的笔记 | 添加于 2024年1月5日星期五 上午9:05:00
+
+警告
+==========
+三体 (刘慈欣)
+- 您在位置 #70 的书签 | 添加于 2024年1月5日星期五 上午9:06:00
+
+
+==========
+",
+        );
+        assert_eq!(clippings.len(), 3);
+
+        let highlight = &clippings[0];
+        assert_eq!(highlight.title, "三体");
+        assert_eq!(highlight.author.as_deref(), Some("刘慈欣"));
+        assert_eq!(highlight.kind, ClippingKind::Highlight);
+        assert_eq!(highlight.page, Some(5));
+        assert_eq!(
+            (highlight.location_start, highlight.location_end),
+            (Some(64), Some(65))
+        );
+        assert_eq!(highlight.added_at, Some(1_704_465_000));
+        assert_eq!(highlight.text, "不要回答！不要回答！");
+
+        let note = &clippings[1];
+        assert_eq!(note.kind, ClippingKind::Note);
+        assert_eq!(note.page, None);
+        assert_eq!(note.location_start, Some(65));
+        assert_eq!(note.added_at, Some(1_704_445_500));
+        assert_eq!(clippings[2].kind, ClippingKind::Bookmark);
+    }
+
+    #[test]
+    fn test_match_book_titles() {
+        let book = |title: &str| {
+            Book::new(
+                title.to_string(),
+                None,
+                format!("/books/{}.epub", title),
+                BookType::Epub,
+                1,
+            )
+        };
+        let books = vec![book("It Ends with Us"), book("Dune (Deluxe Edition)")];
+        let clipping = |title: &str| KindleClipping {
+            title: title.to_string(),
+            author: None,
+            kind: ClippingKind::Highlight,
+            page: None,
+            location_start: None,
+            location_end: None,
+            added_at: None,
+            text: String::new(),
+        };
+
+        assert!(match_book(&books, &clipping("It")).is_none());
+        assert!(match_book(&books, &clipping("I")).is_none());
+        assert!(match_book(&books, &clipping("Dune Messiah")).is_none());
+        assert_eq!(
+            match_book(&books, &clipping("Dune")).unwrap().title,
+            "Dune (Deluxe Edition)"
+        );
+        assert_eq!(
+            match_book(&books, &clipping("It Ends with Us: A Novel"))
+                .unwrap()
+                .title,
+            "It Ends with Us"
+        );
+    }
+
+    #[test]
+    fn test_import_matches_positions_and_deduplicates() {
+        let db = Database::open_in_memory().unwrap();
+        let le_guin = Book::new(
+            "The Left Hand of Darkness: 50th Anniversary Edition".to_string(),
+            Some("Ursula K. Le Guin".to_string()),
+            "/books/left-hand.epub".to_string(),
+            BookType::Epub,
+            2,
+        );
+        let kafka = Book::new(
+            "Der Prozess".to_string(),
+            Some("Franz Kafka".to_string()),
+            "/books/prozess.pdf".to_string(),
+            BookType::Pdf,
+            300,
+        );
+        db.insert_book(&le_guin).unwrap();
+        db.insert_book(&kafka).unwrap();
+
+        let load = |book: &Book| {
+            (book.id == le_guin.id).then(|| {
+                vec![
+                    TextResource {
+                        href: Some("ch1.xhtml".to_string()),
+                        page: None,
+                        text: "Opening chapter.".to_string(),
+                    },
+                    TextResource {
+                        href: Some("ch2.xhtml".to_string()),
+                        page: None,
+                        text: "Light is the left hand of darkness, and darkness the right."
+                            .to_string(),
+                    },
+                ]
+            })
+        };
+
+        let report = import_clippings_with(&db, CLIPPINGS, load).unwrap();
+        assert_eq!(report.imported, 3);
+        assert_eq!(report.duplicates, 0);
+        // Kafka highlight has no page and an unreadable file
+        assert_eq!(report.unanchored, 1);
+        assert_eq!(report.unmatched.len(), 2);
+        assert_eq!(report.unmatched[0].title, "Le Petit Prince");
+
+        let annotations = db.get_annotations(&le_guin.id).unwrap();
+        assert_eq!(annotations.len(), 2);
+        let highlight = &annotations[0];
+        assert_eq!(highlight.locator.href.as_deref(), Some("ch2.xhtml"));
+        assert_eq!(highlight.locator.start_offset, Some(0));
+        assert_eq!(highlight.created_at, 1_704_103_530);
+        // The note shares the highlight's location range
+        assert_eq!(annotations[1].locator, highlight.locator);
+
+        let orphaned = db.get_orphaned_annotations(&kafka.id).unwrap();
+        assert_eq!(orphaned.len(), 1);
+
+        let again = import_clippings_with(&db, CLIPPINGS, load).unwrap();
+        assert_eq!(again.imported, 0);
+        assert_eq!(again.duplicates, 3);
+    }
+}
@@ -0,0 +1,988 @@
+//! KOReader sidecar (`.sdr/metadata.<ext>.lua`) import and export
+//!
+//! KOReader keeps highlights, bookmarks, progress and per-book settings in a
+//! Lua table next to the book. EPUB positions are crengine XPointers
+//! (`/body/DocFragment[N]/body/p[3]/text().12`), mapped through
+//! `epub::XPointerMap`; PDF positions are 1-based page numbers. Export
+//! rewrites the annotations and progress of an existing sidecar and keeps
+//! every other setting as it was.
+
+use crate::annotation::{
+    AnchorStatus, Annotation, AnnotationImport, AnnotationType, HighlightColor, PaletteColor,
+    ReadingPosition,
+};
+use crate::book::{Book, BookType};
+use crate::db::Database;
+use crate::epub::{self, XPointerMap};
+use crate::error::OmniReaderError;
+use crate::locator::Locator;
+use crate::lua::{self, LuaTable, LuaValue};
+use crate::reanchor::{self, TextQuoteSelector};
+use crate::search::{self, TextResource};
+use crate::status;
+use chrono::{DateTime, NaiveDateTime};
+use std::path::{Path, PathBuf};
+use uniffi;
+
+/// Namespace for IDs of imported KOReader annotations
+const KOREADER_NAMESPACE: uuid::Uuid =
+    uuid::Uuid::from_u128(0x6b6f7265_6164_6572_7364_726964730000);
+
+/// First line KOReader writes to its sidecars
+const SIDECAR_HEADER: &str = "we can read Lua syntax here!";
+
+/// Date format of KOReader's `datetime` fields
+const DATETIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// KOReader highlight colors and their RGB values
+const KOREADER_COLORS: &[(&str, &str)] = &[
+    ("red", "#FF3300"),
+    ("orange", "#FF8800"),
+    ("yellow", "#FFFF33"),
+    ("green", "#00AA66"),
+    ("olive", "#88FF77"),
+    ("cyan", "#00FFEE"),
+    ("blue", "#0066FF"),
+    ("purple", "#EE00FF"),
+    ("gray", "#808080"),
+];
+
+/// Outcome of a KOReader sidecar import
+#[derive(Debug, Clone, Default, PartialEq, uniffi::Record)]
+pub struct KoreaderImportReport {
+    /// Annotations created
+    pub imported: u32,
+    /// Sidecar annotations already present in the library
+    pub duplicates: u32,
+    /// Imported annotations whose position could not be resolved
+    pub unanchored: u32,
+    /// Whether the sidecar's progress replaced the saved reading position
+    pub position_updated: bool,
+}
+
+/// Text and XPointer maps of a book, used to resolve positions precisely
+pub(crate) struct BookContent {
+    pub resources: Vec<TextResource>,
+    /// One map per spine item (EPUB only)
+    pub maps: Vec<XPointerMap>,
+}
+
+/// A position in KOReader's terms
+#[derive(Debug, Clone, PartialEq)]
+enum KoPosition {
+    XPointer(String),
+    Page(u32),
+}
+
+/// One highlight or bookmark read from a sidecar
+struct KoEntry {
+    pos0: Option<KoPosition>,
+    pos1: Option<KoPosition>,
+    page: Option<KoPosition>,
+    text: Option<String>,
+    note: Option<String>,
+    chapter: Option<String>,
+    color: Option<String>,
+    datetime: Option<i64>,
+    datetime_updated: Option<i64>,
+}
+
+impl KoEntry {
+    fn is_bookmark(&self) -> bool {
+        self.pos0.is_none()
+    }
+}
+
+/// Default sidecar location: `<dir>/<name>.sdr/metadata.<ext>.lua`
+pub fn koreader_sidecar_path(book: &Book) -> PathBuf {
+    let path = Path::new(&book.file_path);
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let extension = path
+        .extension()
+        .map(|s| s.to_string_lossy().to_lowercase())
+        .unwrap_or_else(|| book.file_type.extension().to_string());
+    path.with_file_name(format!("{}.sdr", stem))
+        .join(format!("metadata.{}.lua", extension))
+}
+
+/// Import annotations and progress from a book's KOReader sidecar
+///
+/// `sidecar_path` overrides the default location next to the book file.
+pub fn import_koreader_sidecar(
+    db: &Database,
+    book_id: &str,
+    sidecar_path: Option<&str>,
+) -> Result<KoreaderImportReport, OmniReaderError> {
+    let book = db.require_book(book_id)?;
+    let path = sidecar_path
+        .map(PathBuf::from)
+        .unwrap_or_else(|| koreader_sidecar_path(&book));
+    if !path.exists() {
+        return Err(OmniReaderError::FileNotFound {
+            path: path.to_string_lossy().to_string(),
+        });
+    }
+    let source = std::fs::read_to_string(&path)?;
+    let modified_at = std::fs::metadata(&path)?
+        .modified()
+        .ok()
+        .map(|time| DateTime::<chrono::Utc>::from(time).timestamp())
+        .unwrap_or_else(|| chrono::Utc::now().timestamp());
+    let content = load_book_content(&book);
+    import_koreader_metadata(db, &book, &source, modified_at, content.as_ref())
+}
+
+/// Write a book's annotations and progress to its KOReader sidecar
+///
+/// Settings already in the sidecar are preserved. Returns the sidecar path.
+pub fn export_koreader_sidecar(
+    db: &Database,
+    book_id: &str,
+    sidecar_path: Option<&str>,
+) -> Result<String, OmniReaderError> {
+    let book = db.require_book(book_id)?;
+    let path = sidecar_path
+        .map(PathBuf::from)
+        .unwrap_or_else(|| koreader_sidecar_path(&book));
+    let existing = match std::fs::read_to_string(&path) {
+        Ok(source) => Some(source),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+    let content = load_book_content(&book);
+    let rendered = render_koreader_metadata(db, &book, existing.as_deref(), content.as_ref())?;
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(&path, rendered)?;
+    Ok(path.to_string_lossy().to_string())
+}
+
+/// Load the text (and XPointer maps) of a book; None when the file is unreadable
+pub(crate) fn load_book_content(book: &Book) -> Option<BookContent> {
+    match book.file_type {
+        BookType::Epub => {
+            let chapters = epub::extract_epub_html(&book.file_path).ok()?;
+            Some(BookContent {
+                maps: chapters
+                    .iter()
+                    .map(|chapter| XPointerMap::new(&chapter.html))
+                    .collect(),
+                resources: chapters
+                    .into_iter()
+                    .map(|chapter| TextResource {
+                        text: epub::html_to_text(&chapter.html),
+                        href: Some(chapter.href),
+                        page: None,
+                    })
+                    .collect(),
+            })
+        }
+        BookType::Pdf => Some(BookContent {
+            resources: search::load_text_resources(&book.file_path, book.file_type).ok()?,
+            maps: Vec::new(),
+        }),
+    }
+}
+
+/// Import the contents of a sidecar; its progress wins if newer than ours
+pub(crate) fn import_koreader_metadata(
+    db: &Database,
+    book: &Book,
+    source: &str,
+    modified_at: i64,
+    content: Option<&BookContent>,
+) -> Result<KoreaderImportReport, OmniReaderError> {
+    let root = lua::parse(source)?;
+    let root = root.as_table().ok_or_else(|| OmniReaderError::ParseError {
+        message: "KOReader sidecar does not contain a table".to_string(),
+    })?;
+
+    let mut report = KoreaderImportReport::default();
+    let mut existing = db.get_annotations(&book.id)?;
+    let mut imports = Vec::new();
+    for entry in read_entries(root, book.file_type) {
+        let (annotation, anchored) = to_annotation(db, book, &entry, content)?;
+        if db.get_annotation(&annotation.id)?.is_some()
+            || existing
+                .iter()
+                .any(|a| a.id == annotation.id || same_annotation(a, &annotation, book))
+        {
+            report.duplicates += 1;
+            continue;
+        }
+        if !anchored {
+            report.unanchored += 1;
+        }
+        existing.push(annotation.clone());
+        imports.push(AnnotationImport {
+            annotation,
+            tags: Vec::new(),
+        });
+    }
+    report.imported = db.insert_annotations(&imports)?;
+
+    if let Some(locator) = read_position(root, book, content) {
+        let current = db.get_reading_position(&book.id)?;
+        if current.is_none_or(|position| position.updated_at < modified_at) {
+            status::save_read_position(
+                db,
+                book,
+                &ReadingPosition {
+                    book_id: book.id.clone(),
+                    locator,
+                    updated_at: modified_at,
+                },
+            )?;
+            report.position_updated = true;
+        }
+    }
+    Ok(report)
+}
+
+/// Render a sidecar for a book, merging into an existing one if given
+pub(crate) fn render_koreader_metadata(
+    db: &Database,
+    book: &Book,
+    existing: Option<&str>,
+    content: Option<&BookContent>,
+) -> Result<String, OmniReaderError> {
+    let mut root = match existing.map(lua::parse).transpose()? {
+        Some(LuaValue::Table(table)) => table,
+        _ => LuaTable::default(),
+    };
+    let previous: Vec<LuaTable> = root
+        .get("annotations")
+        .and_then(LuaValue::as_table)
+        .map(|table| {
+            table
+                .array_values()
+                .into_iter()
+                .filter_map(|value| value.as_table().cloned())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let palette = db.get_palette()?;
+    let mut entries = Vec::new();
+    for annotation in db.get_annotations(&book.id)? {
+        let (pos0, pos1) = positions_for(book, &annotation.locator, content);
+        let text = annotation.selected_text.clone().unwrap_or_default();
+        // Keep fields we do not model (drawer, PDF coordinates) from the matching entry
+        let mut entry = previous
+            .iter()
+            .find(|candidate| {
+                candidate
+                    .get("text")
+                    .and_then(LuaValue::as_str)
+                    .unwrap_or("")
+                    == text
+                    && read_position_value(candidate.get("page"), book.file_type).as_ref()
+                        == Some(&pos0)
+            })
+            .cloned()
+            .unwrap_or_default();
+
+        entry.set("page", position_value(&pos0));
+        if let KoPosition::Page(page) = pos0 {
+            entry.set("pageno", page);
+        }
+        if annotation.annotation_type == AnnotationType::Bookmark {
+            entry.remove("pos0");
+            entry.remove("pos1");
+            entry.remove("drawer");
+            entry.remove("color");
+        } else {
+            if entry.get("pos0").and_then(LuaValue::as_table).is_none()
+                || book.file_type == BookType::Epub
+            {
+                entry.set("pos0", position_value_full(&pos0));
+                entry.set("pos1", position_value_full(&pos1));
+            }
+            if entry.get("drawer").is_none() {
+                entry.set("drawer", "lighten");
+            }
+            let hex = palette
+                .iter()
+                .find(|color| color.id == annotation.color)
+                .map(|color| color.hex.as_str())
+                .unwrap_or(HighlightColor::Yellow.hex());
+            entry.set("color", koreader_color(&annotation.color, hex));
+        }
+        entry.set("text", text);
+        match &annotation.note_text {
+            Some(note) => entry.set("note", note.as_str()),
+            None => entry.remove("note"),
+        }
+        match &annotation.chapter_title {
+            Some(chapter) => entry.set("chapter", chapter.as_str()),
+            None => entry.remove("chapter"),
+        }
+        entry.set("datetime", format_datetime(annotation.created_at));
+        entry.set("datetime_updated", format_datetime(annotation.updated_at));
+        entries.push(LuaValue::Table(entry));
+    }
+    root.set("annotations", LuaTable::array(entries));
+    // Older sidecar layouts, superseded by `annotations`
+    root.remove("highlight");
+    root.remove("bookmarks");
+
+    if let Some(position) = db.get_reading_position(&book.id)? {
+        match positions_for(book, &position.locator, content).0 {
+            KoPosition::XPointer(xpointer) => root.set("last_xpointer", xpointer),
+            KoPosition::Page(page) => root.set("last_page", page),
+        }
+        root.set("percent_finished", position.locator.total_progression);
+    }
+    if root.get("doc_path").is_none() {
+        root.set("doc_path", book.file_path.as_str());
+    }
+    if root.get("doc_pages").is_none() && book.file_type == BookType::Pdf {
+        root.set("doc_pages", book.total_pages);
+    }
+
+    Ok(lua::serialize(&LuaValue::Table(root), SIDECAR_HEADER))
+}
+
+/// Highlights and bookmarks from both the current and the legacy sidecar layout
+fn read_entries(root: &LuaTable, file_type: BookType) -> Vec<KoEntry> {
+    let read = |table: &LuaTable, note_key: &str| KoEntry {
+        pos0: read_position_value(table.get("pos0"), file_type),
+        pos1: read_position_value(table.get("pos1"), file_type),
+        page: read_position_value(table.get("page"), file_type),
+        text: non_empty(table.get("text")),
+        note: non_empty(table.get(note_key)),
+        chapter: non_empty(table.get("chapter")),
+        color: non_empty(table.get("color")),
+        datetime: table.get("datetime").and_then(parse_datetime),
+        datetime_updated: table.get("datetime_updated").and_then(parse_datetime),
+    };
+
+    if let Some(annotations) = root.get("annotations").and_then(LuaValue::as_table) {
+        return annotations
+            .array_values()
+            .into_iter()
+            .filter_map(LuaValue::as_table)
+            .map(|table| read(table, "note"))
+            .collect();
+    }
+
+    // Legacy: highlights keyed by page, bookmarks in a separate list
+    let mut entries = Vec::new();
+    if let Some(highlights) = root.get("highlight").and_then(LuaValue::as_table) {
+        for (_, page) in &highlights.entries {
+            let Some(page) = page.as_table() else {
+                continue;
+            };
+            for item in page
+                .array_values()
+                .into_iter()
+                .filter_map(LuaValue::as_table)
+            {
+                let mut entry = read(item, "note");
+                entry.page = entry.pos0.clone();
+                entries.push(entry);
+            }
+        }
+    }
+    if let Some(bookmarks) = root.get("bookmarks").and_then(LuaValue::as_table) {
+        for item in bookmarks
+            .array_values()
+            .into_iter()
+            .filter_map(LuaValue::as_table)
+        {
+            if item.get("highlighted").and_then(LuaValue::as_bool) == Some(true) {
+                continue;
+            }
+            let mut entry = read(item, "note");
+            entry.pos0 = None;
+            entry.pos1 = None;
+            entry.text = non_empty(item.get("notes"));
+            entries.push(entry);
+        }
+    }
+    entries
+}
+
+/// A position from an XPointer string, a page number, or a `{ page = N }` table
+fn read_position_value(value: Option<&LuaValue>, file_type: BookType) -> Option<KoPosition> {
+    match (value?, file_type) {
+        (LuaValue::Str(s), BookType::Epub) if s.starts_with('/') => {
+            Some(KoPosition::XPointer(s.clone()))
+        }
+        (LuaValue::Table(table), BookType::Pdf) => table
+            .get("page")
+            .and_then(LuaValue::as_u32)
+            .map(KoPosition::Page),
+        (value, BookType::Pdf) => value.as_u32().map(KoPosition::Page),
+        _ => None,
+    }
+}
+
+fn position_value(position: &KoPosition) -> LuaValue {
+    match position {
+        KoPosition::XPointer(xpointer) => LuaValue::from(xpointer.as_str()),
+        KoPosition::Page(page) => LuaValue::from(*page),
+    }
+}
+
+/// `pos0`/`pos1` value; PDF positions carry page coordinates, unknown here
+fn position_value_full(position: &KoPosition) -> LuaValue {
+    match position {
+        KoPosition::Page(page) => {
+            let mut table = LuaTable::default();
+            table.set("page", *page);
+            table.set("x", 0.0);
+            table.set("y", 0.0);
+            LuaValue::Table(table)
+        }
+        xpointer => position_value(xpointer),
+    }
+}
+
+fn non_empty(value: Option<&LuaValue>) -> Option<String> {
+    value
+        .and_then(LuaValue::as_str)
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(str::to_string)
+}
+
+fn parse_datetime(value: &LuaValue) -> Option<i64> {
+    NaiveDateTime::parse_from_str(value.as_str()?, DATETIME_FORMAT)
+        .ok()
+        .map(|datetime| datetime.and_utc().timestamp())
+}
+
+fn format_datetime(timestamp: i64) -> String {
+    DateTime::from_timestamp(timestamp, 0)
+        .unwrap_or_default()
+        .format(DATETIME_FORMAT)
+        .to_string()
+}
+
+/// Build an annotation from a sidecar entry; the flag is false if unpositioned
+fn to_annotation(
+    db: &Database,
+    book: &Book,
+    entry: &KoEntry,
+    content: Option<&BookContent>,
+) -> Result<(Annotation, bool), OmniReaderError> {
+    let start = entry.pos0.as_ref().or(entry.page.as_ref());
+    let located = start.and_then(|start| {
+        resolve_position(
+            book,
+            start,
+            entry.pos1.as_ref(),
+            entry.text.as_deref(),
+            content,
+        )
+    });
+    let anchored = located.is_some();
+    let locator = located.unwrap_or_else(|| Locator::from_total_progression(0.0));
+
+    let mut annotation = if entry.is_bookmark() {
+        Annotation::new_bookmark(
+            book.id.clone(),
+            locator,
+            entry.note.clone(),
+            entry.chapter.clone(),
+            entry.text.clone(),
+        )
+    } else {
+        let mut highlight = Annotation::new_highlight(
+            book.id.clone(),
+            locator,
+            HighlightColor::Yellow,
+            entry.text.clone(),
+        );
+        highlight.color = palette_id_for(db, entry.color.as_deref())?;
+        highlight.note_text = entry.note.clone();
+        highlight.chapter_title = entry.chapter.clone();
+        highlight
+    };
+
+    let key = format!(
+        "{}\u{1f}{:?}\u{1f}{}",
+        book.id,
+        start,
+        entry.text.as_deref().unwrap_or("")
+    );
+    annotation.id = uuid::Uuid::new_v5(&KOREADER_NAMESPACE, key.as_bytes()).to_string();
+    if let Some(created_at) = entry.datetime {
+        annotation.created_at = created_at;
+        annotation.updated_at = entry.datetime_updated.unwrap_or(created_at);
+    }
+    if !anchored {
+        annotation.anchor_status = AnchorStatus::Orphaned;
+    }
+    Ok((annotation, anchored))
+}
+
+/// Resolve a KOReader position (and optional range end) to a locator
+fn resolve_position(
+    book: &Book,
+    start: &KoPosition,
+    end: Option<&KoPosition>,
+    text: Option<&str>,
+    content: Option<&BookContent>,
+) -> Option<Locator> {
+    let resources = content.map(|content| content.resources.as_slice());
+    let (index, hint) = match start {
+        KoPosition::XPointer(xpointer) => {
+            let (index, inner) = split_fragment(xpointer)?;
+            let count = resources.map_or(book.total_pages, |r| r.len() as u32);
+            if let Some(content) = content
+                && let Some(map) = content.maps.get(index as usize)
+                && let Some(start_offset) = map.offset(inner, false)
+            {
+                let end_offset = match end {
+                    Some(KoPosition::XPointer(end)) => split_fragment(end)
+                        .filter(|(end_index, _)| *end_index == index)
+                        .and_then(|(_, inner)| map.offset(inner, true)),
+                    _ => None,
+                }
+                .unwrap_or(start_offset)
+                .max(start_offset);
+                return Some(search::locator_for_range(
+                    &content.resources,
+                    index as usize,
+                    start_offset,
+                    end_offset,
+                ));
+            }
+            let href = resources
+                .and_then(|r| r.get(index as usize))
+                .and_then(|resource| resource.href.clone());
+            (index, Locator::from_epub_spine(index, count, href, 0.0))
+        }
+        KoPosition::Page(page) => {
+            let index = page.checked_sub(1)?;
+            (index, Locator::from_pdf_page(index, book.total_pages))
+        }
+    };
+
+    // Refine with the highlighted text when the book is readable
+    if let (Some(resources), Some(text)) = (resources, text) {
+        let quote = TextQuoteSelector {
+            exact: text.split_whitespace().collect::<Vec<_>>().join(" "),
+            prefix: None,
+            suffix: None,
+        };
+        if let Some(found) = reanchor::locate_quote(&quote, &hint, resources)
+            && found.index == index as usize
+        {
+            return Some(search::locator_for_range(
+                resources,
+                found.index,
+                found.start,
+                found.end,
+            ));
+        }
+    }
+    Some(hint)
+}
+
+/// Split `/body/DocFragment[N]/rest` into the 0-based spine index and `/rest`
+fn split_fragment(xpointer: &str) -> Option<(u32, &str)> {
+    let rest = xpointer.strip_prefix("/body/DocFragment[")?;
+    let (number, inner) = rest.split_once(']')?;
+    let number: u32 = number.parse().ok()?;
+    Some((number.checked_sub(1)?, inner))
+}
+
+/// KOReader start and end positions for a locator
+fn positions_for(
+    book: &Book,
+    locator: &Locator,
+    content: Option<&BookContent>,
+) -> (KoPosition, KoPosition) {
+    match book.file_type {
+        BookType::Pdf => {
+            let page = KoPosition::Page(locator.pdf_page(book.total_pages) + 1);
+            (page.clone(), page)
+        }
+        BookType::Epub => {
+            let count = content.map_or(book.total_pages, |c| c.resources.len() as u32);
+            let index = locator.spine_index(count);
+            let fragment = format!("/body/DocFragment[{}]", index + 1);
+            let map = content.and_then(|content| content.maps.get(index as usize));
+            let xpointer = |offset: Option<u32>, end: bool| {
+                map.zip(offset)
+                    .and_then(|(map, offset)| map.xpointer(offset as usize, end))
+                    .map(|inner| format!("{}{}", fragment, inner))
+                    .unwrap_or_else(|| format!("{}/body", fragment))
+            };
+            (
+                KoPosition::XPointer(xpointer(locator.start_offset, false)),
+                KoPosition::XPointer(xpointer(locator.end_offset.or(locator.start_offset), true)),
+            )
+        }
+    }
+}
+
+/// Reading position from `last_xpointer` / `last_page`, else `percent_finished`
+fn read_position(root: &LuaTable, book: &Book, content: Option<&BookContent>) -> Option<Locator> {
+    let percent = root.get("percent_finished").and_then(LuaValue::as_f64);
+    let position = match book.file_type {
+        BookType::Epub => root.get("last_xpointer"),
+        BookType::Pdf => root.get("last_page"),
+    };
+    progress_locator(
+        book,
+        read_position_value(position, book.file_type),
+        percent,
+        content,
+    )
+}
+
+/// KOReader progress string for a locator: an XPointer (EPUB) or a 1-based page number (PDF)
+pub(crate) fn progress_string(
+    book: &Book,
+    locator: &Locator,
+    content: Option<&BookContent>,
+) -> String {
+    match positions_for(book, locator, content).0 {
+        KoPosition::XPointer(xpointer) => xpointer,
+        KoPosition::Page(page) => page.to_string(),
+    }
+}
+
+/// Locator for a KOReader progress string and overall progress (0-1)
+pub(crate) fn locator_for_progress(
+    book: &Book,
+    progress: &str,
+    percent: Option<f64>,
+    content: Option<&BookContent>,
+) -> Option<Locator> {
+    let position = match book.file_type {
+        BookType::Epub if progress.starts_with('/') => {
+            Some(KoPosition::XPointer(progress.to_string()))
+        }
+        BookType::Pdf => progress.trim().parse().ok().map(KoPosition::Page),
+        _ => None,
+    };
+    progress_locator(book, position, percent, content)
+}
+
+/// Locator for a position, refined or replaced by overall progress
+fn progress_locator(
+    book: &Book,
+    position: Option<KoPosition>,
+    percent: Option<f64>,
+    content: Option<&BookContent>,
+) -> Option<Locator> {
+    let mut locator =
+        position.and_then(|position| resolve_position(book, &position, None, None, content));
+    if let (Some(locator), Some(percent)) = (&mut locator, percent)
+        && locator.start_offset.is_none()
+        && book.file_type == BookType::Epub
+    {
+        // Chapter-level position: KOReader's overall progress is more precise
+        locator.total_progression = percent.clamp(0.0, 1.0);
+    }
+    locator.or_else(|| {
+        percent.map(|p| Locator::from_percent(book.file_type, p * 100.0, book.total_pages))
+    })
+}
+
+/// Whether two annotations describe the same thing at the same place
+fn same_annotation(a: &Annotation, b: &Annotation, book: &Book) -> bool {
+    let resource = |annotation: &Annotation| match book.file_type {
+        BookType::Pdf => annotation.locator.pdf_page(book.total_pages),
+        BookType::Epub => annotation.locator.spine_index(book.total_pages),
+    };
+    let offsets_agree = match (a.locator.start_offset, b.locator.start_offset) {
+        (Some(x), Some(y)) => x == y,
+        _ => true,
+    };
+    let text = |annotation: &Annotation| {
+        annotation
+            .selected_text
+            .as_deref()
+            .map(|s| s.split_whitespace().collect::<Vec<_>>().join(" "))
+    };
+    a.annotation_type == b.annotation_type
+        && resource(a) == resource(b)
+        && offsets_agree
+        && text(a) == text(b)
+}
+
+/// Palette ID for a KOReader color name, adding a palette entry if needed
+fn palette_id_for(db: &Database, color: Option<&str>) -> Result<String, OmniReaderError> {
+    let Some(color) = color.map(str::to_lowercase) else {
+        return Ok(HighlightColor::Yellow.palette_id().to_string());
+    };
+    if db.get_palette_color(&color)?.is_some() {
+        return Ok(color);
+    }
+    let hex = KOREADER_COLORS
+        .iter()
+        .find(|(name, _)| *name == color)
+        .map(|(_, hex)| *hex)
+        .or_else(|| PaletteColor::is_valid_hex(&color).then_some(color.as_str()));
+    match hex {
+        Some(hex) => Ok(db.palette_color_for_hex(hex)?.id),
+        None => Ok(HighlightColor::Yellow.palette_id().to_string()),
+    }
+}
+
+/// KOReader color name for a palette color: its ID if KOReader knows it, else the nearest
+fn koreader_color(palette_id: &str, hex: &str) -> &'static str {
+    if let Some((name, _)) = KOREADER_COLORS.iter().find(|(name, _)| *name == palette_id) {
+        return name;
+    }
+    let target = rgb(hex);
+    KOREADER_COLORS
+        .iter()
+        .min_by_key(|(_, candidate)| {
+            let candidate = rgb(candidate);
+            (0..3)
+                .map(|i| (target[i] - candidate[i]).pow(2))
+                .sum::<i32>()
+        })
+        .map_or("yellow", |(name, _)| name)
+}
+
+fn rgb(hex: &str) -> [i32; 3] {
+    let hex = hex.trim_start_matches('#');
+    let channel = |i: usize| {
+        hex.get(i..i + 2)
+            .and_then(|c| i32::from_str_radix(c, 16).ok())
+            .unwrap_or(0)
+    };
+    [channel(0), channel(2), channel(4)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::book::ReadingStatus;
+
+    const CHAPTER_ONE: &str = "<html><head><title>One</title></head><body>\
+        <h1>Chapter One</h1><p>It was a bright cold day in April.</p>\
+        <p>The clocks were striking thirteen.</p></body></html>";
+    const CHAPTER_TWO: &str = "<html><body><h1>Chapter Two</h1>\
+        <p>Outside, even through the <em>shut</em> window-pane, the world looked cold.</p>\
+        </body></html>";
+
+    fn epub_content() -> BookContent {
+        let chapters = [("one.xhtml", CHAPTER_ONE), ("two.xhtml", CHAPTER_TWO)];
+        BookContent {
+            resources: chapters
+                .iter()
+                .map(|(href, html)| TextResource {
+                    href: Some(href.to_string()),
+                    page: None,
+                    text: epub::html_to_text(html),
+                })
+                .collect(),
+            maps: chapters
+                .iter()
+                .map(|(_, html)| XPointerMap::new(html))
+                .collect(),
+        }
+    }
+
+    const EPUB_SIDECAR: &str = r#"-- we can read Lua syntax here!
+return {
+    ["annotations"] = {
+        [1] = {
+            ["chapter"] = "Chapter One",
+            ["color"] = "cyan",
+            ["datetime"] = "2024-03-01 09:30:00",
+            ["drawer"] = "underscore",
+            ["note"] = "Orwell's opening",
+            ["page"] = "/body/DocFragment[1]/body/p[2]/text().4",
+            ["pos0"] = "/body/DocFragment[1]/body/p[2]/text().4",
+            ["pos1"] = "/body/DocFragment[1]/body/p[2]/text().34",
+            ["text"] = "clocks were striking thirteen.",
+        },
+        [2] = {
+            ["chapter"] = "Chapter Two",
+            ["datetime"] = "2024-03-02 20:00:00",
+            ["page"] = "/body/DocFragment[2]/body/p/text()[2].0",
+            ["text"] = "in Chapter Two",
+        },
+    },
+    ["font_face"] = "Noto Serif",
+    ["last_xpointer"] = "/body/DocFragment[2]/body/p/em/text().0",
+    ["percent_finished"] = 0.62,
+}
+"#;
+
+    #[test]
+    fn test_epub_sidecar_round_trip() {
+        let db = Database::open_in_memory().unwrap();
+        let book = Book::new(
+            "1984".to_string(),
+            None,
+            "/books/1984.epub".to_string(),
+            BookType::Epub,
+            2,
+        );
+        db.insert_book(&book).unwrap();
+        let content = epub_content();
+
+        let report =
+            import_koreader_metadata(&db, &book, EPUB_SIDECAR, 1_709_400_000, Some(&content))
+                .unwrap();
+        assert_eq!(report.imported, 2);
+        assert_eq!(report.unanchored, 0);
+        assert!(report.position_updated);
+
+        let annotations = db.get_annotations(&book.id).unwrap();
+        let highlight = &annotations[0];
+        assert_eq!(highlight.annotation_type, AnnotationType::Highlight);
+        let chapter_text = &content.resources[0].text;
+        let start = chapter_text.find("clocks").unwrap() as u32;
+        assert_eq!(highlight.locator.start_offset, Some(start));
+        assert_eq!(
+            highlight.locator.end_offset,
+            Some(chapter_text.len() as u32)
+        );
+        assert_eq!(highlight.note_text.as_deref(), Some("Orwell's opening"));
+        assert_eq!(highlight.chapter_title.as_deref(), Some("Chapter One"));
+        assert_eq!(highlight.created_at, 1_709_285_400);
+        assert_eq!(
+            db.get_palette_color(&highlight.color).unwrap().unwrap().hex,
+            "#00FFEE"
+        );
+
+        let bookmark = &annotations[1];
+        assert_eq!(bookmark.annotation_type, AnnotationType::Bookmark);
+        assert_eq!(bookmark.locator.href.as_deref(), Some("two.xhtml"));
+        let second = &content.resources[1].text;
+        assert_eq!(
+            bookmark.locator.start_offset,
+            Some(second.find(" window-pane").unwrap() as u32 + 1)
+        );
+
+        let position = db.get_reading_position(&book.id).unwrap().unwrap();
+        assert_eq!(
+            position.locator.start_offset,
+            Some(second.find("shut").unwrap() as u32)
+        );
+        // Progress made in KOReader starts a read
+        let imported = db.get_book(&book.id).unwrap().unwrap();
+        assert_eq!(imported.status, ReadingStatus::Reading);
+        assert_eq!(imported.started_at, Some(1_709_400_000));
+
+        // An older sidecar does not override newer progress
+        let stale = import_koreader_metadata(&db, &book, EPUB_SIDECAR, 0, Some(&content)).unwrap();
+        assert_eq!(stale.duplicates, 2);
+        assert!(!stale.position_updated);
+
+        let rendered =
+            render_koreader_metadata(&db, &book, Some(EPUB_SIDECAR), Some(&content)).unwrap();
+        let root = lua::parse(&rendered).unwrap();
+        let root = root.as_table().unwrap();
+        assert_eq!(root.get("font_face").unwrap().as_str(), Some("Noto Serif"));
+        assert_eq!(
+            root.get("last_xpointer").unwrap().as_str(),
+            Some("/body/DocFragment[2]/body/p/em/text().0")
+        );
+        let entries = root
+            .get("annotations")
+            .unwrap()
+            .as_table()
+            .unwrap()
+            .array_values();
+        let first = entries[0].as_table().unwrap();
+        assert_eq!(
+            first.get("pos0").unwrap().as_str(),
+            Some("/body/DocFragment[1]/body/p[2]/text().4")
+        );
+        assert_eq!(
+            first.get("pos1").unwrap().as_str(),
+            Some("/body/DocFragment[1]/body/p[2]/text().34")
+        );
+        assert_eq!(first.get("drawer").unwrap().as_str(), Some("underscore"));
+        assert_eq!(first.get("color").unwrap().as_str(), Some("cyan"));
+        assert_eq!(
+            first.get("datetime").unwrap().as_str(),
+            Some("2024-03-01 09:30:00")
+        );
+        let second_entry = entries[1].as_table().unwrap();
+        assert!(second_entry.get("pos0").is_none());
+
+        // Reimporting our own export finds nothing new
+        let again = import_koreader_metadata(&db, &book, &rendered, 0, Some(&content)).unwrap();
+        assert_eq!(again.imported, 0);
+        assert_eq!(again.duplicates, 2);
+    }
+
+    #[test]
+    fn test_legacy_pdf_sidecar() {
+        let db = Database::open_in_memory().unwrap();
+        let book = Book::new(
+            "Manual".to_string(),
+            None,
+            "/books/manual.pdf".to_string(),
+            BookType::Pdf,
+            40,
+        );
+        db.insert_book(&book).unwrap();
+
+        let sidecar = r#"return {
+            ["highlight"] = {
+                [7] = {
+                    [1] = {
+                        ["datetime"] = "2023-05-05 12:00:00",
+                        ["drawer"] = "lighten",
+                        ["pos0"] = { ["page"] = 7, ["x"] = 120.5, ["y"] = 300 },
+                        ["pos1"] = { ["page"] = 7, ["x"] = 410, ["y"] = 318 },
+                        ["text"] = "Torque the bolts to 12 Nm",
+                    },
+                },
+            },
+            ["bookmarks"] = {
+                [1] = { ["page"] = 7, ["highlighted"] = true, ["notes"] = "Torque the bolts to 12 Nm" },
+                [2] = { ["page"] = 20, ["notes"] = "Wiring diagram", ["datetime"] = "2023-05-06 08:00:00" },
+            },
+            ["last_page"] = 21,
+            ["percent_finished"] = 0.5,
+        }"#;
+        let report = import_koreader_metadata(&db, &book, sidecar, 100, None).unwrap();
+        assert_eq!(report.imported, 2);
+
+        let annotations = db.get_annotations(&book.id).unwrap();
+        assert_eq!(annotations[0].locator.page, Some(6));
+        assert_eq!(annotations[0].annotation_type, AnnotationType::Highlight);
+        assert_eq!(annotations[1].locator.page, Some(19));
+        assert_eq!(
+            annotations[1].selected_text.as_deref(),
+            Some("Wiring diagram")
+        );
+        let position = db.get_reading_position(&book.id).unwrap().unwrap();
+        assert_eq!(position.locator.page, Some(20));
+
+        let rendered = render_koreader_metadata(&db, &book, Some(sidecar), None).unwrap();
+        let root = lua::parse(&rendered).unwrap();
+        let root = root.as_table().unwrap();
+        assert!(root.get("highlight").is_none());
+        assert_eq!(root.get("last_page").unwrap().as_u32(), Some(21));
+        let entries = root
+            .get("annotations")
+            .unwrap()
+            .as_table()
+            .unwrap()
+            .array_values();
+        let pos0 = entries[0]
+            .as_table()
+            .unwrap()
+            .get("pos0")
+            .unwrap()
+            .as_table()
+            .unwrap();
+        assert_eq!(pos0.get("page").unwrap().as_u32(), Some(7));
+        assert_eq!(
+            entries[1].as_table().unwrap().get("page").unwrap().as_u32(),
+            Some(20)
+        );
+    }
+}
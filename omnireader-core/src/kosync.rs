@@ -0,0 +1,484 @@
+//! KOReader progress sync (kosync) client
+//!
+//! Pushes and pulls reading positions against a KOReader sync server
+//! (`koreader-sync-server` or a compatible self-hosted one). Documents are
+//! identified the way KOReader does it: by a partial MD5 of the file
+//! contents, or by the MD5 of the file name. Progress is an XPointer for
+//! EPUB and a 1-based page number for PDF, sent with an overall percentage.
+
+use crate::annotation::ReadingPosition;
+use crate::book::{Book, BookType};
+use crate::db::Database;
+use crate::error::OmniReaderError;
+use crate::koreader;
+use crate::status;
+use md5::{Digest, Md5};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use uniffi;
+use ureq::Agent;
+use ureq::http::Response;
+
+/// Media type of the kosync API, version 1
+const ACCEPT: &str = "application/vnd.koreader.v1+json";
+
+/// How documents are matched across devices (KOReader's "checksum method")
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, uniffi::Enum)]
+pub enum KosyncMatching {
+    /// Partial MD5 of the file contents
+    #[default]
+    Binary,
+    /// MD5 of the file name
+    FileName,
+}
+
+/// Account and device settings for a kosync server
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct KosyncConfig {
+    /// Server URL, e.g. `https://sync.koreader.rocks`
+    pub server_url: String,
+    pub username: String,
+    pub password: String,
+    /// Name shown to other devices, e.g. "OmniReader on iPad"
+    pub device_name: String,
+    /// Must match the setting of the KOReader devices
+    pub matching: KosyncMatching,
+}
+
+/// Progress of one document as stored on the server
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, uniffi::Record)]
+pub struct KosyncProgress {
+    /// Document hash
+    pub document: String,
+    /// XPointer (EPUB) or 1-based page number (PDF)
+    pub progress: String,
+    /// Overall progress, 0-1
+    pub percentage: f64,
+    pub device: String,
+    pub device_id: String,
+    /// Unix timestamp of the update, set by the server
+    #[serde(default)]
+    pub timestamp: i64,
+}
+
+/// KOReader's document hash of a file
+#[uniffi::export]
+pub fn kosync_document_hash(
+    path: &str,
+    matching: KosyncMatching,
+) -> Result<String, OmniReaderError> {
+    let path = Path::new(path);
+    match matching {
+        KosyncMatching::FileName => {
+            let name = path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default();
+            Ok(hex(&Md5::digest(name.as_bytes())))
+        }
+        KosyncMatching::Binary => {
+            if !path.exists() {
+                return Err(OmniReaderError::FileNotFound {
+                    path: path.to_string_lossy().to_string(),
+                });
+            }
+            // 1 KiB samples at 0, 1 KiB, 4 KiB, 16 KiB, ... up to 1 GiB, stopping at the end
+            let mut file = File::open(path)?;
+            let len = file.metadata()?.len();
+            let mut md5 = Md5::new();
+            for i in -1i32..=10 {
+                let offset = if i < 0 { 0 } else { 1024u64 << (2 * i) };
+                if offset >= len {
+                    break;
+                }
+                file.seek(SeekFrom::Start(offset))?;
+                let mut sample = Vec::with_capacity(1024);
+                (&mut file).take(1024).read_to_end(&mut sample)?;
+                md5.update(&sample);
+            }
+            Ok(hex(&md5.finalize()))
+        }
+    }
+}
+
+/// Create an account on the server
+pub fn kosync_register(config: &KosyncConfig) -> Result<(), OmniReaderError> {
+    let body = serde_json::json!({
+        "username": config.username,
+        "password": auth_key(&config.password),
+    });
+    let response = Client::new(config).send("POST", "/users/create", Some(body))?;
+    match response.status().as_u16() {
+        200..=299 => Ok(()),
+        402 => Err(OmniReaderError::IoError {
+            message: format!("Sync username is already taken: {}", config.username),
+        }),
+        status => Err(status_error("register", status)),
+    }
+}
+
+/// Check the account credentials
+pub fn kosync_authorize(config: &KosyncConfig) -> Result<(), OmniReaderError> {
+    let response = Client::new(config).send("GET", "/users/auth", None)?;
+    match response.status().as_u16() {
+        200..=299 => Ok(()),
+        status => Err(status_error("authorize", status)),
+    }
+}
+
+/// Progress stored on the server for a document hash, if any
+pub fn kosync_fetch_progress(
+    config: &KosyncConfig,
+    document: &str,
+) -> Result<Option<KosyncProgress>, OmniReaderError> {
+    let mut response =
+        Client::new(config).send("GET", &format!("/syncs/progress/{}", document), None)?;
+    match response.status().as_u16() {
+        200..=299 => {}
+        404 => return Ok(None),
+        status => return Err(status_error("fetch progress", status)),
+    }
+    let body = response
+        .body_mut()
+        .read_to_string()
+        .map_err(request_error)?;
+    // The server answers `{}` for documents without progress
+    let value: serde_json::Value = serde_json::from_str(&body).map_err(parse_error)?;
+    if value.get("progress").is_none() {
+        return Ok(None);
+    }
+    serde_json::from_value(value).map(Some).map_err(parse_error)
+}
+
+/// Push a book's saved reading position; false when it has none
+pub fn kosync_push_progress(
+    db: &Database,
+    config: &KosyncConfig,
+    book_id: &str,
+) -> Result<bool, OmniReaderError> {
+    let book = db.require_book(book_id)?;
+    let Some(position) = db.get_reading_position(book_id)? else {
+        return Ok(false);
+    };
+    let content = needs_content(&book)
+        .then(|| koreader::load_book_content(&book))
+        .flatten();
+    let progress = KosyncProgress {
+        document: kosync_document_hash(&book.file_path, config.matching)?,
+        progress: koreader::progress_string(&book, &position.locator, content.as_ref()),
+        percentage: position.locator.total_progression,
+        device: config.device_name.clone(),
+        device_id: device_id(db)?,
+        timestamp: 0,
+    };
+    let body = serde_json::json!({
+        "document": progress.document,
+        "progress": progress.progress,
+        "percentage": progress.percentage,
+        "device": progress.device,
+        "device_id": progress.device_id,
+    });
+    let response = Client::new(config).send("PUT", "/syncs/progress", Some(body))?;
+    match response.status().as_u16() {
+        200..=299 => Ok(true),
+        status => Err(status_error("push progress", status)),
+    }
+}
+
+/// Pull a book's progress from the server
+///
+/// Progress pushed by another device more recently than the saved position
+/// replaces it and is returned, starting or finishing a read if it crosses a
+/// status threshold; otherwise nothing changes.
+pub fn kosync_pull_progress(
+    db: &Database,
+    config: &KosyncConfig,
+    book_id: &str,
+) -> Result<Option<ReadingPosition>, OmniReaderError> {
+    let book = db.require_book(book_id)?;
+    let document = kosync_document_hash(&book.file_path, config.matching)?;
+    let Some(remote) = kosync_fetch_progress(config, &document)? else {
+        return Ok(None);
+    };
+    if remote.device_id == device_id(db)? {
+        return Ok(None);
+    }
+    if let Some(local) = db.get_reading_position(book_id)?
+        && local.updated_at >= remote.timestamp
+    {
+        return Ok(None);
+    }
+
+    let content = needs_content(&book)
+        .then(|| koreader::load_book_content(&book))
+        .flatten();
+    let Some(locator) = koreader::locator_for_progress(
+        &book,
+        &remote.progress,
+        Some(remote.percentage),
+        content.as_ref(),
+    ) else {
+        return Ok(None);
+    };
+    let position = ReadingPosition {
+        book_id: book.id.clone(),
+        locator,
+        updated_at: remote.timestamp,
+    };
+    status::save_read_position(db, &book, &position)?;
+    Ok(Some(position))
+}
+
+/// HTTP client carrying the kosync authentication headers
+struct Client<'a> {
+    agent: Agent,
+    config: &'a KosyncConfig,
+}
+
+impl<'a> Client<'a> {
+    fn new(config: &'a KosyncConfig) -> Self {
+        let agent = Agent::config_builder()
+            .http_status_as_error(false)
+            .build()
+            .new_agent();
+        Self { agent, config }
+    }
+
+    fn send(
+        &self,
+        method: &str,
+        path: &str,
+        body: Option<serde_json::Value>,
+    ) -> Result<Response<ureq::Body>, OmniReaderError> {
+        let url = format!("{}{}", self.config.server_url.trim_end_matches('/'), path);
+        let request = ureq::http::Request::builder()
+            .method(method)
+            .uri(url)
+            .header("Accept", ACCEPT)
+            .header("x-auth-user", &self.config.username)
+            .header("x-auth-key", auth_key(&self.config.password));
+        let result = match body {
+            Some(body) => request
+                .header("Content-Type", "application/json")
+                .body(body.to_string())
+                .map(|request| self.agent.run(request)),
+            None => request.body(()).map(|request| self.agent.run(request)),
+        };
+        result
+            .map_err(|e| OmniReaderError::IoError {
+                message: format!("Invalid sync request: {}", e),
+            })?
+            .map_err(request_error)
+    }
+}
+
+/// kosync sends the MD5 of the password, never the password itself
+fn auth_key(password: &str) -> String {
+    hex(&Md5::digest(password.as_bytes()))
+}
+
+/// This device's kosync ID, created on first use
+fn device_id(db: &Database) -> Result<String, OmniReaderError> {
+    if let Some(id) = db.kosync_device_id()? {
+        return Ok(id);
+    }
+    let id = uuid::Uuid::new_v4().simple().to_string().to_uppercase();
+    db.save_kosync_device_id(&id)?;
+    Ok(id)
+}
+
+/// EPUB XPointers need the book's markup; PDF pages do not
+fn needs_content(book: &Book) -> bool {
+    book.file_type == BookType::Epub
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn request_error(e: ureq::Error) -> OmniReaderError {
+    OmniReaderError::IoError {
+        message: format!("Sync request failed: {}", e),
+    }
+}
+
+fn status_error(action: &str, status: u16) -> OmniReaderError {
+    let message = match status {
+        401 => format!("Sync server rejected the credentials ({})", action),
+        status => format!("Sync server failed to {} (status {})", action, status),
+    };
+    OmniReaderError::IoError { message }
+}
+
+fn parse_error(e: serde_json::Error) -> OmniReaderError {
+    OmniReaderError::ParseError {
+        message: format!("Invalid sync server response: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::locator::Locator;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use tiny_http::{Response, Server};
+
+    /// Mock kosync server: one account, progress keyed by document
+    fn mock_server() -> (String, Arc<Mutex<HashMap<String, serde_json::Value>>>) {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let url = format!(
+            "http://127.0.0.1:{}",
+            server.server_addr().to_ip().unwrap().port()
+        );
+        let store = Arc::new(Mutex::new(HashMap::new()));
+        let progress = store.clone();
+        std::thread::spawn(move || {
+            for mut request in server.incoming_requests() {
+                let header = |name: &'static str| {
+                    request
+                        .headers()
+                        .iter()
+                        .find(|h| h.field.equiv(name))
+                        .map(|h| h.value.to_string())
+                };
+                let authorized = header("x-auth-user").as_deref() == Some("reader")
+                    && header("x-auth-key") == Some(auth_key("secret"))
+                    && header("Accept").as_deref() == Some(ACCEPT);
+                let mut body = String::new();
+                request.as_reader().read_to_string(&mut body).unwrap();
+                let (status, reply) = match (request.method().as_str(), request.url()) {
+                    (_, _) if !authorized => (401, "{}".to_string()),
+                    ("GET", "/users/auth") => (200, r#"{"authorized":"OK"}"#.to_string()),
+                    ("PUT", "/syncs/progress") => {
+                        let mut value: serde_json::Value = serde_json::from_str(&body).unwrap();
+                        value["timestamp"] = serde_json::json!(chrono::Utc::now().timestamp());
+                        let document = value["document"].as_str().unwrap().to_string();
+                        progress.lock().unwrap().insert(document.clone(), value);
+                        (200, format!(r#"{{"document":"{}"}}"#, document))
+                    }
+                    ("GET", url) if url.starts_with("/syncs/progress/") => {
+                        let document = url.trim_start_matches("/syncs/progress/");
+                        let value = progress.lock().unwrap().get(document).cloned();
+                        (200, value.map_or("{}".to_string(), |v| v.to_string()))
+                    }
+                    _ => (404, "{}".to_string()),
+                };
+                let _ = request.respond(Response::from_string(reply).with_status_code(status));
+            }
+        });
+        (url, store)
+    }
+
+    #[test]
+    fn test_document_hash() {
+        let path =
+            std::env::temp_dir().join(format!("omnireader-kosync-{}.pdf", uuid::Uuid::new_v4()));
+        // Small files are hashed whole; larger ones only at the sample offsets
+        std::fs::write(&path, b"%PDF-1.4 tiny").unwrap();
+        let path_str = path.to_string_lossy().to_string();
+        assert_eq!(
+            kosync_document_hash(&path_str, KosyncMatching::Binary).unwrap(),
+            hex(&Md5::digest(b"%PDF-1.4 tiny"))
+        );
+        let large: Vec<u8> = (0..20_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &large).unwrap();
+        let mut expected = Md5::new();
+        for offset in [0usize, 1024, 4096, 16384] {
+            expected.update(&large[offset..(offset + 1024).min(large.len())]);
+        }
+        assert_eq!(
+            kosync_document_hash(&path_str, KosyncMatching::Binary).unwrap(),
+            hex(&expected.finalize())
+        );
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        assert_eq!(
+            kosync_document_hash(&path_str, KosyncMatching::FileName).unwrap(),
+            hex(&Md5::digest(name.as_bytes()))
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_push_and_pull_progress() {
+        let (url, store) = mock_server();
+        let config = KosyncConfig {
+            server_url: url,
+            username: "reader".to_string(),
+            password: "secret".to_string(),
+            device_name: "OmniReader".to_string(),
+            matching: KosyncMatching::Binary,
+        };
+        kosync_authorize(&config).unwrap();
+        let wrong = KosyncConfig {
+            password: "wrong".to_string(),
+            ..config.clone()
+        };
+        assert!(kosync_authorize(&wrong).is_err());
+
+        let path =
+            std::env::temp_dir().join(format!("omnireader-kosync-{}.pdf", uuid::Uuid::new_v4()));
+        std::fs::write(&path, vec![7u8; 5000]).unwrap();
+        let db = Database::open_in_memory().unwrap();
+        let book = Book::new(
+            "Manual".to_string(),
+            None,
+            path.to_string_lossy().to_string(),
+            BookType::Pdf,
+            100,
+        );
+        db.insert_book(&book).unwrap();
+        assert!(!kosync_push_progress(&db, &config, &book.id).unwrap());
+
+        let mut position = ReadingPosition::new(book.id.clone(), Locator::from_pdf_page(41, 100));
+        position.updated_at -= 60;
+        db.save_reading_position(&position).unwrap();
+        assert!(kosync_push_progress(&db, &config, &book.id).unwrap());
+        let document = kosync_document_hash(&book.file_path, KosyncMatching::Binary).unwrap();
+        let pushed = kosync_fetch_progress(&config, &document).unwrap().unwrap();
+        assert_eq!(pushed.progress, "42");
+        assert!((pushed.percentage - 0.41).abs() < 1e-9);
+
+        // Our own progress is not pulled back
+        assert!(
+            kosync_pull_progress(&db, &config, &book.id)
+                .unwrap()
+                .is_none()
+        );
+
+        // A KOReader device reads on to page 60
+        store.lock().unwrap().insert(
+            document.clone(),
+            serde_json::json!({
+                "document": document,
+                "progress": "60",
+                "percentage": 0.59,
+                "device": "Kobo",
+                "device_id": "KOBO",
+                "timestamp": chrono::Utc::now().timestamp(),
+            }),
+        );
+        let pulled = kosync_pull_progress(&db, &config, &book.id)
+            .unwrap()
+            .unwrap();
+        assert_eq!(pulled.locator.pdf_page(100), 59);
+        assert_eq!(
+            db.get_reading_position(&book.id)
+                .unwrap()
+                .unwrap()
+                .locator
+                .pdf_page(100),
+            59
+        );
+        // Already applied: nothing newer on the server
+        assert!(
+            kosync_pull_progress(&db, &config, &book.id)
+                .unwrap()
+                .is_none()
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -2,22 +2,60 @@
 //!
 //! This crate provides the core functionality for OmniReader:
 //! - Book parsing (PDF, EPUB)
-//! - Local SQLite database
-//! - Annotation management
+//! - Local SQLite database, optionally encrypted at rest (SQLCipher), with zip backup and restore
+//! - Paginated, sorted and filtered library listing with lazily loaded covers
+//! - Cover thumbnails in several sizes (WebP, JPEG) cached on disk, with colour and BlurHash placeholders
+//! - Collections in manual order, free-form book tags and smart shelves (saved queries)
+//! - Annotation management (highlights, notes, bookmarks), tags and re-anchoring
+//! - Cross-library notebook queries
+//! - Markdown and W3C Web Annotation (JSON-LD) export
+//! - Kindle clippings, KOReader sidecar and Calibre library import
+//! - KOReader sidecar and Calibre-compatible OPF library export
+//! - Multi-device sync through a shared folder or WebDAV, and KOReader progress sync
+//! - Passphrase-based end-to-end encryption of sync data and backups
+//! - Reading sessions, reading statistics and time-left estimates
+//! - Reading status (unread, reading, finished, abandoned) with reread history
+//! - Reading goals and a year-in-review report
+//! - Format-agnostic locators and in-book search
 //! - UniFFI bindings for Swift/Kotlin
 
 pub mod annotation;
+pub mod backup;
 pub mod book;
+pub mod bookmark;
+pub mod calibre;
+pub mod collection;
+pub mod cover;
+pub mod crypto;
 pub mod db;
 pub mod epub;
 pub mod error;
+pub mod goals;
+pub mod kindle;
+pub mod koreader;
+pub mod kosync;
+pub mod library;
+pub mod locator;
+pub mod markdown;
+pub mod notebook;
+pub mod opf;
 pub mod pdf;
+pub mod reanchor;
+pub mod search;
+pub mod session;
+pub mod shelf;
+pub mod status;
+pub mod sync;
+pub mod tag;
+pub mod web_annotation;
+pub mod webdav;
 
-use uniffi;
+mod lua;
 
-pub use annotation::{Annotation, AnnotationType, ReadingPosition};
-pub use book::{Book, BookType};
+pub use annotation::{AnchorStatus, Annotation, AnnotationType, ReadingPosition};
+pub use book::{Book, BookIdentifier, BookType};
 pub use db::Database;
 pub use error::OmniReaderError;
+pub use locator::{Locator, LocatorText};
 
 uniffi::setup_scaffolding!();
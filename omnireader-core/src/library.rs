@@ -0,0 +1,178 @@
+//! Paginated library listing
+//!
+//! Pages use keyset pagination: a page ends with a cursor holding the sort key
+//! of its last book and the next page starts right after it, so pages stay
+//! fast however far the list is scrolled and do not shift when books are
+//! added. Summaries leave out the cover, which is fetched on its own with
+//! `Database::get_book_cover` when a book is shown.
+
+use crate::book::{BookType, ReadingStatus};
+use crate::error::OmniReaderError;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64;
+use rusqlite::types::Value;
+use uniffi;
+
+/// Page size when a query does not set one
+pub const DEFAULT_PAGE_SIZE: u32 = 100;
+
+/// Title without a leading English article, for sorting
+const TITLE_SORT: &str = "(CASE \
+     WHEN b.title LIKE 'the %' THEN substr(b.title, 5) \
+     WHEN b.title LIKE 'an %' THEN substr(b.title, 4) \
+     WHEN b.title LIKE 'a %' THEN substr(b.title, 3) \
+     ELSE b.title END) COLLATE NOCASE";
+
+/// Progress through the book (0.0 - 1.0), zero when never opened
+pub(crate) const PROGRESS: &str = "COALESCE((SELECT rp.total_progression FROM reading_positions rp \
+     WHERE rp.book_id = b.id), 0)";
+
+/// A book without its cover, for lists
+#[derive(Debug, Clone, PartialEq, uniffi::Record)]
+pub struct BookSummary {
+    pub id: String,
+    pub title: String,
+    pub author: Option<String>,
+    pub file_type: BookType,
+    pub added_at: i64,
+    pub last_read_at: Option<i64>,
+    pub total_pages: u32,
+    pub series: Option<String>,
+    pub series_index: Option<f64>,
+    /// Rating out of 10 (two per star)
+    pub rating: Option<u32>,
+    pub status: ReadingStatus,
+    /// Position in the whole book (0.0 - 1.0)
+    pub progress: f64,
+    pub has_cover: bool,
+}
+
+/// Sort order of a library listing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, uniffi::Enum)]
+pub enum LibrarySort {
+    /// Title, ignoring a leading "The", "A" or "An"
+    Title,
+    /// Author sort name (or author), then title
+    Author,
+    /// Last read; never read books sort as oldest
+    LastRead,
+    Added,
+    /// Progress through the book
+    Progress,
+    /// Series, then position in the series
+    Series,
+}
+
+impl LibrarySort {
+    /// Sort key expressions over alias `b`, most significant first; the book
+    /// ID breaks ties
+    pub(crate) fn keys(self) -> &'static [&'static str] {
+        match self {
+            LibrarySort::Title => &[TITLE_SORT],
+            LibrarySort::Author => &[
+                "COALESCE(b.author_sort, b.author, '') COLLATE NOCASE",
+                TITLE_SORT,
+            ],
+            LibrarySort::LastRead => &["COALESCE(b.last_read_at, 0)"],
+            LibrarySort::Added => &["b.added_at"],
+            LibrarySort::Progress => &[PROGRESS],
+            LibrarySort::Series => &[
+                "COALESCE(b.series, '') COLLATE NOCASE",
+                "COALESCE(b.series_index, 0)",
+            ],
+        }
+    }
+}
+
+/// One page request of a library listing
+#[derive(Debug, Clone, PartialEq, uniffi::Record)]
+pub struct LibraryQuery {
+    pub sort: LibrarySort,
+    pub descending: bool,
+    /// Filter in the smart shelf query language (see [`crate::shelf`])
+    pub filter: Option<String>,
+    /// Only books in this collection
+    pub collection_id: Option<String>,
+    /// Books per page; [`DEFAULT_PAGE_SIZE`] when unset
+    pub limit: Option<u32>,
+    /// `next_cursor` of the previous page; `None` for the first page
+    pub after: Option<String>,
+}
+
+impl Default for LibraryQuery {
+    /// Most recently added first, like `Database::get_all_books`
+    fn default() -> Self {
+        Self {
+            sort: LibrarySort::Added,
+            descending: true,
+            filter: None,
+            collection_id: None,
+            limit: None,
+            after: None,
+        }
+    }
+}
+
+/// One page of a library listing
+#[derive(Debug, Clone, PartialEq, uniffi::Record)]
+pub struct LibraryPage {
+    pub books: Vec<BookSummary>,
+    /// Number of books matching the query across all pages
+    pub total: u32,
+    /// Cursor for the next page; `None` on the last page
+    pub next_cursor: Option<String>,
+}
+
+/// Encode the sort key values and ID of the last book of a page
+pub(crate) fn encode_cursor(values: &[Value]) -> String {
+    let json: Vec<serde_json::Value> = values
+        .iter()
+        .map(|value| match value {
+            Value::Integer(i) => serde_json::Value::from(*i),
+            Value::Real(f) => serde_json::Value::from(*f),
+            Value::Text(s) => serde_json::Value::from(s.as_str()),
+            Value::Null | Value::Blob(_) => serde_json::Value::Null,
+        })
+        .collect();
+    BASE64.encode(serde_json::Value::Array(json).to_string())
+}
+
+/// Decode a cursor made for a sort with `keys` sort keys
+pub(crate) fn decode_cursor(cursor: &str, keys: usize) -> Result<Vec<Value>, OmniReaderError> {
+    let invalid = || OmniReaderError::ParseError {
+        message: "Invalid library cursor".to_string(),
+    };
+    let bytes = BASE64.decode(cursor).map_err(|_| invalid())?;
+    let json: Vec<serde_json::Value> = serde_json::from_slice(&bytes).map_err(|_| invalid())?;
+    if json.len() != keys + 1 {
+        return Err(invalid());
+    }
+    json.into_iter()
+        .map(|value| match value {
+            serde_json::Value::Number(n) => match n.as_i64() {
+                Some(i) => Ok(Value::Integer(i)),
+                None => n.as_f64().map(Value::Real).ok_or_else(invalid),
+            },
+            serde_json::Value::String(s) => Ok(Value::Text(s)),
+            _ => Err(invalid()),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cursor() {
+        let values = vec![
+            Value::Text("Earthsea".to_string()),
+            Value::Real(0.5),
+            Value::Text("b1".to_string()),
+        ];
+        let cursor = encode_cursor(&values);
+        assert_eq!(decode_cursor(&cursor, 2).unwrap(), values);
+        assert!(decode_cursor(&cursor, 1).is_err());
+        assert!(decode_cursor("not a cursor", 2).is_err());
+    }
+}
@@ -0,0 +1,226 @@
+//! Format-agnostic locations inside a book
+//!
+//! Modelled on the Readium `Locator`: a resource reference (EPUB href or PDF
+//! page), a progression within that resource, a progression across the whole
+//! publication, and optional CFI / text offsets plus a text quote with
+//! surrounding context.
+
+use crate::book::BookType;
+use uniffi;
+
+/// Text surrounding a location, used to re-find it when offsets drift
+#[derive(Debug, Clone, PartialEq, uniffi::Record)]
+pub struct LocatorText {
+    /// Text immediately before the location
+    pub before: Option<String>,
+    /// The located text itself (selection for a highlight)
+    pub highlight: Option<String>,
+    /// Text immediately after the location
+    pub after: Option<String>,
+}
+
+/// A location inside a book
+#[derive(Debug, Clone, PartialEq, uniffi::Record)]
+pub struct Locator {
+    /// EPUB resource path (spine item), if any
+    pub href: Option<String>,
+    /// PDF page index (0-based), if any
+    pub page: Option<u32>,
+    /// Progression within the resource (0.0 - 1.0)
+    pub progression: f64,
+    /// Progression within the whole book (0.0 - 1.0)
+    pub total_progression: f64,
+    /// Progression within the whole book where a range ends, if known
+    pub end_total_progression: Option<f64>,
+    /// EPUB canonical fragment identifier
+    pub cfi: Option<String>,
+    /// Start character offset within the resource text
+    pub start_offset: Option<u32>,
+    /// End character offset within the resource text
+    pub end_offset: Option<u32>,
+    /// Quoted text and context
+    pub text: Option<LocatorText>,
+}
+
+impl Locator {
+    /// Create a locator from a global progression (0.0 - 1.0) only
+    pub fn from_total_progression(total_progression: f64) -> Self {
+        Self {
+            href: None,
+            page: None,
+            progression: 0.0,
+            total_progression: clamp_unit(total_progression),
+            end_total_progression: None,
+            cfi: None,
+            start_offset: None,
+            end_offset: None,
+            text: None,
+        }
+    }
+
+    /// Create a locator pointing at a PDF page (0-based)
+    pub fn from_pdf_page(page: u32, total_pages: u32) -> Self {
+        let total_progression = if total_pages == 0 {
+            0.0
+        } else {
+            page as f64 / total_pages as f64
+        };
+        Self {
+            page: Some(page),
+            ..Self::from_total_progression(total_progression)
+        }
+    }
+
+    /// Create a locator inside an EPUB spine item
+    pub fn from_epub_spine(
+        spine_index: u32,
+        spine_count: u32,
+        href: Option<String>,
+        progression: f64,
+    ) -> Self {
+        let progression = clamp_unit(progression);
+        let total_progression = if spine_count == 0 {
+            0.0
+        } else {
+            (spine_index as f64 + progression) / spine_count as f64
+        };
+        Self {
+            href,
+            progression,
+            cfi: Some(spine_cfi(spine_index)),
+            ..Self::from_total_progression(total_progression)
+        }
+    }
+
+    /// Create a locator from a percentage (0.0 - 100.0) for the given book type
+    pub fn from_percent(book_type: BookType, percent: f64, total_pages: u32) -> Self {
+        let total_progression = clamp_unit(percent / 100.0);
+        match book_type {
+            BookType::Pdf => {
+                let mut locator =
+                    Self::from_pdf_page(pdf_page_for(total_progression, total_pages), total_pages);
+                locator.total_progression = total_progression;
+                locator
+            }
+            BookType::Epub => {
+                let (spine_index, progression) = spine_position_for(total_progression, total_pages);
+                Self::from_epub_spine(spine_index, total_pages, None, progression)
+            }
+        }
+    }
+
+    /// Global position as a percentage (0.0 - 100.0)
+    pub fn percent(&self) -> f64 {
+        self.total_progression * 100.0
+    }
+
+    /// PDF page index for this locator, derived from progression if needed
+    pub fn pdf_page(&self, total_pages: u32) -> u32 {
+        self.page
+            .unwrap_or_else(|| pdf_page_for(self.total_progression, total_pages))
+    }
+
+    /// EPUB spine index for this locator, derived from CFI or progression
+    pub fn spine_index(&self, spine_count: u32) -> u32 {
+        self.cfi
+            .as_deref()
+            .and_then(spine_index_from_cfi)
+            .unwrap_or_else(|| spine_position_for(self.total_progression, spine_count).0)
+    }
+
+    /// Attach a text quote to this locator
+    pub fn with_text(
+        mut self,
+        before: Option<String>,
+        highlight: Option<String>,
+        after: Option<String>,
+    ) -> Self {
+        self.text = Some(LocatorText {
+            before,
+            highlight,
+            after,
+        });
+        self
+    }
+}
+
+/// Build a CFI pointing at the start of a spine item (0-based index)
+pub fn spine_cfi(spine_index: u32) -> String {
+    format!("epubcfi(/6/{}!)", (spine_index + 1) * 2)
+}
+
+/// Extract the 0-based spine index from a CFI such as `epubcfi(/6/4!/4/2:10)`
+pub fn spine_index_from_cfi(cfi: &str) -> Option<u32> {
+    let inner = cfi.strip_prefix("epubcfi(")?.strip_suffix(')')?;
+    let mut steps = inner.split('/').skip(1);
+    if steps.next()? != "6" {
+        return None;
+    }
+    let step = steps.next()?;
+    let digits: String = step.chars().take_while(|c| c.is_ascii_digit()).collect();
+    let step: u32 = digits.parse().ok()?;
+    if step < 2 || !step.is_multiple_of(2) {
+        return None;
+    }
+    Some(step / 2 - 1)
+}
+
+fn clamp_unit(value: f64) -> f64 {
+    if value.is_nan() {
+        0.0
+    } else {
+        value.clamp(0.0, 1.0)
+    }
+}
+
+fn pdf_page_for(total_progression: f64, total_pages: u32) -> u32 {
+    if total_pages == 0 {
+        return 0;
+    }
+    let page = (clamp_unit(total_progression) * total_pages as f64).floor() as u32;
+    page.min(total_pages - 1)
+}
+
+fn spine_position_for(total_progression: f64, spine_count: u32) -> (u32, f64) {
+    if spine_count == 0 {
+        return (0, 0.0);
+    }
+    let scaled = clamp_unit(total_progression) * spine_count as f64;
+    let index = (scaled.floor() as u32).min(spine_count - 1);
+    (index, clamp_unit(scaled - index as f64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pdf_page_round_trip() {
+        let locator = Locator::from_pdf_page(25, 100);
+        assert_eq!(locator.total_progression, 0.25);
+        assert_eq!(locator.pdf_page(100), 25);
+
+        let from_percent = Locator::from_percent(BookType::Pdf, 99.9, 100);
+        assert_eq!(from_percent.page, Some(99));
+    }
+
+    #[test]
+    fn test_epub_cfi_round_trip() {
+        let locator = Locator::from_epub_spine(3, 10, Some("ch4.xhtml".to_string()), 0.5);
+        assert_eq!(locator.cfi.as_deref(), Some("epubcfi(/6/8!)"));
+        assert_eq!(locator.total_progression, 0.35);
+        assert_eq!(locator.spine_index(10), 3);
+
+        assert_eq!(spine_index_from_cfi("epubcfi(/6/4!/4/2:10)"), Some(1));
+        assert_eq!(spine_index_from_cfi("epubcfi(/6/4[chap01]!/4)"), Some(1));
+        assert_eq!(spine_index_from_cfi("not a cfi"), None);
+    }
+
+    #[test]
+    fn test_percent_conversion() {
+        let locator = Locator::from_percent(BookType::Epub, 45.0, 10);
+        assert_eq!(locator.spine_index(10), 4);
+        assert!((locator.progression - 0.5).abs() < 1e-9);
+        assert!((locator.percent() - 45.0).abs() < 1e-9);
+    }
+}
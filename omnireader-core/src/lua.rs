@@ -0,0 +1,600 @@
+//! Reading and writing the Lua table literals KOReader uses for sidecar files
+//!
+//! Only the data subset is supported: `return { ... }` with nested tables,
+//! strings, numbers, booleans and nil. Tables keep their entry order so
+//! unknown settings survive a read-modify-write cycle.
+
+use crate::error::OmniReaderError;
+
+/// A Lua data value
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum LuaValue {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    Str(String),
+    Table(LuaTable),
+}
+
+/// A Lua table as an ordered list of key/value pairs
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct LuaTable {
+    pub entries: Vec<(LuaValue, LuaValue)>,
+}
+
+impl LuaValue {
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            LuaValue::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            LuaValue::Number(n) => Some(*n),
+            LuaValue::Str(s) => s.parse().ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_u32(&self) -> Option<u32> {
+        self.as_f64()
+            .filter(|n| *n >= 0.0 && *n <= u32::MAX as f64)
+            .map(|n| n as u32)
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            LuaValue::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_table(&self) -> Option<&LuaTable> {
+        match self {
+            LuaValue::Table(table) => Some(table),
+            _ => None,
+        }
+    }
+}
+
+impl From<&str> for LuaValue {
+    fn from(s: &str) -> Self {
+        LuaValue::Str(s.to_string())
+    }
+}
+
+impl From<String> for LuaValue {
+    fn from(s: String) -> Self {
+        LuaValue::Str(s)
+    }
+}
+
+impl From<f64> for LuaValue {
+    fn from(n: f64) -> Self {
+        LuaValue::Number(n)
+    }
+}
+
+impl From<u32> for LuaValue {
+    fn from(n: u32) -> Self {
+        LuaValue::Number(n as f64)
+    }
+}
+
+impl From<bool> for LuaValue {
+    fn from(b: bool) -> Self {
+        LuaValue::Bool(b)
+    }
+}
+
+impl From<LuaTable> for LuaValue {
+    fn from(table: LuaTable) -> Self {
+        LuaValue::Table(table)
+    }
+}
+
+impl LuaTable {
+    /// Value stored under a string key
+    pub fn get(&self, key: &str) -> Option<&LuaValue> {
+        self.entries
+            .iter()
+            .find(|(k, _)| k.as_str() == Some(key))
+            .map(|(_, v)| v)
+    }
+
+    /// Set a string key, replacing an existing entry in place
+    pub fn set(&mut self, key: &str, value: impl Into<LuaValue>) {
+        let value = value.into();
+        match self
+            .entries
+            .iter_mut()
+            .find(|(k, _)| k.as_str() == Some(key))
+        {
+            Some(entry) => entry.1 = value,
+            None => self.entries.push((LuaValue::from(key), value)),
+        }
+    }
+
+    /// Remove a string key
+    pub fn remove(&mut self, key: &str) {
+        self.entries.retain(|(k, _)| k.as_str() != Some(key));
+    }
+
+    /// Build an array-style table (keys 1..=n)
+    pub fn array(values: impl IntoIterator<Item = LuaValue>) -> Self {
+        Self {
+            entries: values
+                .into_iter()
+                .enumerate()
+                .map(|(i, v)| (LuaValue::Number(i as f64 + 1.0), v))
+                .collect(),
+        }
+    }
+
+    /// Values under numeric keys, in key order
+    pub fn array_values(&self) -> Vec<&LuaValue> {
+        let mut items: Vec<(f64, &LuaValue)> = self
+            .entries
+            .iter()
+            .filter_map(|(k, v)| match k {
+                LuaValue::Number(n) => Some((*n, v)),
+                _ => None,
+            })
+            .collect();
+        items.sort_by(|a, b| a.0.total_cmp(&b.0));
+        items.into_iter().map(|(_, v)| v).collect()
+    }
+}
+
+/// Parse a Lua data file such as `metadata.epub.lua`
+pub(crate) fn parse(source: &str) -> Result<LuaValue, OmniReaderError> {
+    let mut parser = Parser {
+        chars: source.chars().collect(),
+        pos: 0,
+    };
+    parser.skip_trivia();
+    if parser.eat_keyword("return") {
+        parser.skip_trivia();
+    }
+    let value = parser.value()?;
+    parser.skip_trivia();
+    if parser.pos < parser.chars.len() {
+        return Err(parser.error("trailing content"));
+    }
+    Ok(value)
+}
+
+/// Serialize a value as a `return { ... }` data file, in KOReader's layout
+pub(crate) fn serialize(value: &LuaValue, header: &str) -> String {
+    let mut out = String::new();
+    for line in header.lines() {
+        out.push_str("-- ");
+        out.push_str(line);
+        out.push('\n');
+    }
+    out.push_str("return ");
+    write_value(&mut out, value, 0);
+    out.push('\n');
+    out
+}
+
+fn write_value(out: &mut String, value: &LuaValue, depth: usize) {
+    match value {
+        LuaValue::Nil => out.push_str("nil"),
+        LuaValue::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+        LuaValue::Number(n) => out.push_str(&format_number(*n)),
+        LuaValue::Str(s) => write_string(out, s),
+        LuaValue::Table(table) => {
+            let mut entries: Vec<&(LuaValue, LuaValue)> = table.entries.iter().collect();
+            entries.sort_by(|a, b| key_order(&a.0, &b.0));
+            out.push_str("{\n");
+            for (key, value) in entries {
+                out.push_str(&"    ".repeat(depth + 1));
+                out.push('[');
+                write_value(out, key, depth + 1);
+                out.push_str("] = ");
+                write_value(out, value, depth + 1);
+                out.push_str(",\n");
+            }
+            out.push_str(&"    ".repeat(depth));
+            out.push('}');
+        }
+    }
+}
+
+/// Numeric keys first in ascending order, then string keys alphabetically
+fn key_order(a: &LuaValue, b: &LuaValue) -> std::cmp::Ordering {
+    use std::cmp::Ordering;
+    match (a, b) {
+        (LuaValue::Number(x), LuaValue::Number(y)) => x.total_cmp(y),
+        (LuaValue::Number(_), _) => Ordering::Less,
+        (_, LuaValue::Number(_)) => Ordering::Greater,
+        (LuaValue::Str(x), LuaValue::Str(y)) => x.cmp(y),
+        _ => Ordering::Equal,
+    }
+}
+
+fn format_number(n: f64) -> String {
+    if n.fract() == 0.0 && n.abs() < 9.0e15 {
+        format!("{}", n as i64)
+    } else {
+        format!("{}", n)
+    }
+}
+
+fn write_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\0' => out.push_str("\\0"),
+            _ => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn error(&self, message: &str) -> OmniReaderError {
+        let line = self.chars[..self.pos.min(self.chars.len())]
+            .iter()
+            .filter(|&&c| c == '\n')
+            .count()
+            + 1;
+        OmniReaderError::ParseError {
+            message: format!("Invalid Lua data at line {}: {}", line, message),
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn starts_with(&self, s: &str) -> bool {
+        s.chars()
+            .enumerate()
+            .all(|(i, c)| self.chars.get(self.pos + i) == Some(&c))
+    }
+
+    fn eat_keyword(&mut self, word: &str) -> bool {
+        let end = self.pos + word.chars().count();
+        let boundary = self
+            .chars
+            .get(end)
+            .is_none_or(|c| !(c.is_alphanumeric() || *c == '_'));
+        if self.starts_with(word) && boundary {
+            self.pos = end;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn skip_trivia(&mut self) {
+        loop {
+            while self.peek().is_some_and(char::is_whitespace) {
+                self.pos += 1;
+            }
+            if !self.starts_with("--") {
+                return;
+            }
+            self.pos += 2;
+            if let Some(level) = self.long_bracket_level() {
+                self.long_bracket_body(level);
+            } else {
+                while self.peek().is_some_and(|c| c != '\n') {
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<LuaValue, OmniReaderError> {
+        match self.peek() {
+            Some('{') => self.table(),
+            Some('"') | Some('\'') => self.quoted_string().map(LuaValue::Str),
+            Some('[') if self.long_bracket_level().is_some() => {
+                let level = self.long_bracket_level().unwrap_or(0);
+                Ok(LuaValue::Str(self.long_bracket_body(level)))
+            }
+            Some(c) if c.is_ascii_digit() || c == '-' || c == '.' => self.number(),
+            _ if self.eat_keyword("nil") => Ok(LuaValue::Nil),
+            _ if self.eat_keyword("true") => Ok(LuaValue::Bool(true)),
+            _ if self.eat_keyword("false") => Ok(LuaValue::Bool(false)),
+            _ => Err(self.error("expected a value")),
+        }
+    }
+
+    fn table(&mut self) -> Result<LuaValue, OmniReaderError> {
+        self.pos += 1;
+        let mut table = LuaTable::default();
+        let mut next_index = 1.0;
+        loop {
+            self.skip_trivia();
+            match self.peek() {
+                Some('}') => {
+                    self.pos += 1;
+                    return Ok(LuaValue::Table(table));
+                }
+                None => return Err(self.error("unterminated table")),
+                _ => {}
+            }
+
+            let key = if self.peek() == Some('[') && self.long_bracket_level().is_none() {
+                self.pos += 1;
+                self.skip_trivia();
+                let key = self.value()?;
+                self.skip_trivia();
+                self.expect(']')?;
+                self.skip_trivia();
+                self.expect('=')?;
+                Some(key)
+            } else {
+                self.name_before_equals().map(LuaValue::Str)
+            };
+            self.skip_trivia();
+            let value = self.value()?;
+            let key = key.unwrap_or_else(|| {
+                let key = LuaValue::Number(next_index);
+                next_index += 1.0;
+                key
+            });
+            if value != LuaValue::Nil {
+                table.entries.push((key, value));
+            }
+
+            self.skip_trivia();
+            match self.peek() {
+                Some(',') | Some(';') => self.pos += 1,
+                Some('}') => {}
+                _ => return Err(self.error("expected ',' or '}'")),
+            }
+        }
+    }
+
+    /// An identifier key (`name = value`), consuming through the `=`
+    fn name_before_equals(&mut self) -> Option<String> {
+        let start = self.pos;
+        if !self.peek().is_some_and(|c| c.is_alphabetic() || c == '_') {
+            return None;
+        }
+        while self.peek().is_some_and(|c| c.is_alphanumeric() || c == '_') {
+            self.pos += 1;
+        }
+        let name: String = self.chars[start..self.pos].iter().collect();
+        self.skip_trivia();
+        if self.peek() == Some('=') && self.chars.get(self.pos + 1) != Some(&'=') {
+            self.pos += 1;
+            Some(name)
+        } else {
+            self.pos = start;
+            None
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), OmniReaderError> {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected '{}'", c)))
+        }
+    }
+
+    fn number(&mut self) -> Result<LuaValue, OmniReaderError> {
+        let start = self.pos;
+        if self.peek() == Some('-') {
+            self.pos += 1;
+        }
+        if self.starts_with("0x") || self.starts_with("0X") {
+            self.pos += 2;
+            let digits_start = self.pos;
+            while self.peek().is_some_and(|c| c.is_ascii_hexdigit()) {
+                self.pos += 1;
+            }
+            let digits: String = self.chars[digits_start..self.pos].iter().collect();
+            let n = i64::from_str_radix(&digits, 16).map_err(|_| self.error("bad number"))?;
+            let negative = self.chars[start] == '-';
+            return Ok(LuaValue::Number(if negative { -n } else { n } as f64));
+        }
+        while let Some(c) = self.peek() {
+            let exponent_sign = (c == '-' || c == '+')
+                && matches!(self.chars.get(self.pos - 1), Some('e') | Some('E'));
+            if c.is_ascii_digit() || c == '.' || c == 'e' || c == 'E' || exponent_sign {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+        let text: String = self.chars[start..self.pos].iter().collect();
+        match text.as_str() {
+            "inf" | "-inf" | "nan" => Err(self.error("bad number")),
+            _ => text
+                .parse()
+                .map(LuaValue::Number)
+                .map_err(|_| self.error("bad number")),
+        }
+    }
+
+    fn quoted_string(&mut self) -> Result<String, OmniReaderError> {
+        let quote = self.peek().unwrap_or('"');
+        self.pos += 1;
+        let mut out = String::new();
+        loop {
+            let Some(c) = self.peek() else {
+                return Err(self.error("unterminated string"));
+            };
+            self.pos += 1;
+            if c == quote {
+                return Ok(out);
+            }
+            if c != '\\' {
+                out.push(c);
+                continue;
+            }
+            let Some(escaped) = self.peek() else {
+                return Err(self.error("unterminated string"));
+            };
+            self.pos += 1;
+            match escaped {
+                'n' => out.push('\n'),
+                't' => out.push('\t'),
+                'r' => out.push('\r'),
+                'a' => out.push('\u{7}'),
+                'b' => out.push('\u{8}'),
+                'f' => out.push('\u{c}'),
+                'v' => out.push('\u{b}'),
+                '\n' => out.push('\n'),
+                'z' => {
+                    while self.peek().is_some_and(char::is_whitespace) {
+                        self.pos += 1;
+                    }
+                }
+                'x' => {
+                    let hex: String = self.chars[self.pos..(self.pos + 2).min(self.chars.len())]
+                        .iter()
+                        .collect();
+                    let byte =
+                        u8::from_str_radix(&hex, 16).map_err(|_| self.error("bad escape"))?;
+                    self.pos += 2;
+                    out.push(byte as char);
+                }
+                'u' => {
+                    self.expect('{')?;
+                    let start = self.pos;
+                    while self.peek().is_some_and(|c| c != '}') {
+                        self.pos += 1;
+                    }
+                    let hex: String = self.chars[start..self.pos].iter().collect();
+                    self.expect('}')?;
+                    let c = u32::from_str_radix(&hex, 16)
+                        .ok()
+                        .and_then(char::from_u32)
+                        .ok_or_else(|| self.error("bad escape"))?;
+                    out.push(c);
+                }
+                d if d.is_ascii_digit() => {
+                    let start = self.pos - 1;
+                    while self.pos - start < 3 && self.peek().is_some_and(|c| c.is_ascii_digit()) {
+                        self.pos += 1;
+                    }
+                    let digits: String = self.chars[start..self.pos].iter().collect();
+                    let code: u32 = digits.parse().map_err(|_| self.error("bad escape"))?;
+                    out.push(char::from_u32(code).ok_or_else(|| self.error("bad escape"))?);
+                }
+                other => out.push(other),
+            }
+        }
+    }
+
+    /// Level of a long bracket (`[[`, `[=[`, ...) at the cursor
+    fn long_bracket_level(&self) -> Option<usize> {
+        if self.peek() != Some('[') {
+            return None;
+        }
+        let mut level = 0;
+        while self.chars.get(self.pos + 1 + level) == Some(&'=') {
+            level += 1;
+        }
+        (self.chars.get(self.pos + 1 + level) == Some(&'[')).then_some(level)
+    }
+
+    fn long_bracket_body(&mut self, level: usize) -> String {
+        self.pos += level + 2;
+        if self.peek() == Some('\n') {
+            self.pos += 1;
+        }
+        let close = format!("]{}]", "=".repeat(level));
+        let start = self.pos;
+        while self.pos < self.chars.len() && !self.starts_with(&close) {
+            self.pos += 1;
+        }
+        let body = self.chars[start..self.pos].iter().collect();
+        self.pos = (self.pos + close.len()).min(self.chars.len());
+        body
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_serialize() {
+        let source = r#"-- we can read Lua syntax here!
+return {
+    ["bookmarks"] = {},
+    ["cre_dom_version"] = 20240114,
+    ["doc_props"] = {
+        ["title"] = "A \"quoted\" title\nwith a line break",
+        ["language"] = 'en',
+    },
+    ["percent_finished"] = 0.25,
+    ["floating"] = -1.5e-3,
+    ["list"] = { "a", "b"; [5] = true, named = false, },
+    ["nothing"] = nil,
+    ["long"] = [==[raw ]] text]==],
+    ["escapes"] = "tab\tdec\65\u{E9}",
+}
+"#;
+        let value = parse(source).unwrap();
+        let table = value.as_table().unwrap();
+        assert_eq!(
+            table.get("cre_dom_version").unwrap().as_u32(),
+            Some(20240114)
+        );
+        assert_eq!(table.get("percent_finished").unwrap().as_f64(), Some(0.25));
+        assert_eq!(table.get("floating").unwrap().as_f64(), Some(-0.0015));
+        assert!(table.get("nothing").is_none());
+        assert_eq!(table.get("long").unwrap().as_str(), Some("raw ]] text"));
+        assert_eq!(table.get("escapes").unwrap().as_str(), Some("tab\tdecAé"));
+
+        let props = table.get("doc_props").unwrap().as_table().unwrap();
+        assert_eq!(
+            props.get("title").unwrap().as_str(),
+            Some("A \"quoted\" title\nwith a line break")
+        );
+
+        let list = table.get("list").unwrap().as_table().unwrap();
+        let values = list.array_values();
+        assert_eq!(values.len(), 3);
+        assert_eq!(values[0].as_str(), Some("a"));
+        assert_eq!(values[2].as_bool(), Some(true));
+        assert_eq!(list.get("named").unwrap().as_bool(), Some(false));
+
+        let written = serialize(&value, "test");
+        assert!(written.starts_with("-- test\nreturn {\n"));
+        assert!(written.contains("    [\"cre_dom_version\"] = 20240114,\n"));
+        assert_eq!(parse(&written).unwrap(), reorder(&value));
+
+        assert!(parse("return { [1] = }").is_err());
+        assert!(parse("return { \"open").is_err());
+    }
+
+    /// The value with table entries in serialization order
+    fn reorder(value: &LuaValue) -> LuaValue {
+        match value {
+            LuaValue::Table(table) => {
+                let mut entries: Vec<(LuaValue, LuaValue)> = table
+                    .entries
+                    .iter()
+                    .map(|(k, v)| (k.clone(), reorder(v)))
+                    .collect();
+                entries.sort_by(|a, b| key_order(&a.0, &b.0));
+                LuaValue::Table(LuaTable { entries })
+            }
+            other => other.clone(),
+        }
+    }
+}
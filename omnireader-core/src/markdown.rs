@@ -0,0 +1,394 @@
+//! Markdown export of annotations for note-taking vaults (Obsidian, Logseq)
+//!
+//! Output is rendered from a Handlebars template, so each vault can use its
+//! own conventions. The template receives the book metadata, the annotations
+//! grouped under chapter headings taken from the TOC, and deep links back to
+//! the reader. Helpers: `quote` (prefix every line with `> `) and `yaml`
+//! (quote a value for front-matter).
+
+use crate::annotation::{Annotation, AnnotationType, PaletteColor};
+use crate::book::{Book, BookType};
+use crate::db::Database;
+use crate::epub::{self, TocEntry};
+use crate::error::OmniReaderError;
+use crate::notebook::NotebookQuery;
+use handlebars::{Handlebars, handlebars_helper, no_escape};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+use uniffi;
+
+/// Default template: YAML front-matter, chapter headings, quote blocks and notes
+pub const DEFAULT_MARKDOWN_TEMPLATE: &str = r#"---
+title: {{yaml book.title}}
+{{#if book.author}}
+author: {{yaml book.author}}
+{{/if}}
+format: {{book.format}}
+annotations: {{count}}
+exported: {{exported_at}}
+source: {{book.link}}
+---
+
+# {{book.title}}
+
+{{#each chapters}}
+{{#if title}}
+## {{title}}
+
+{{/if}}
+{{#each annotations}}
+{{#if text}}
+{{quote text}}
+
+{{/if}}
+{{#if note}}
+{{note}}
+
+{{/if}}
+- {{kind}} · {{color.name}}{{#if page}} · page {{page}}{{/if}} · [Open in OmniReader]({{link}}){{#each tags}} #{{this}}{{/each}}
+
+{{/each}}
+{{/each}}
+"#;
+
+/// Options for a Markdown export
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct MarkdownExportOptions {
+    /// Handlebars template; `None` uses `DEFAULT_MARKDOWN_TEMPLATE`
+    pub template: Option<String>,
+    /// Prefix for deep links back to the reader, e.g. `omnireader://`
+    pub link_base: String,
+}
+
+impl Default for MarkdownExportOptions {
+    fn default() -> Self {
+        Self {
+            template: None,
+            link_base: "omnireader://".to_string(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct BookContext {
+    id: String,
+    title: String,
+    author: Option<String>,
+    format: &'static str,
+    total_pages: u32,
+    link: String,
+}
+
+#[derive(Serialize)]
+struct ColorContext {
+    id: String,
+    name: String,
+    hex: String,
+}
+
+#[derive(Serialize)]
+struct AnnotationContext {
+    id: String,
+    kind: &'static str,
+    text: Option<String>,
+    note: Option<String>,
+    color: ColorContext,
+    tags: Vec<String>,
+    page: Option<u32>,
+    percent: String,
+    created_at: String,
+    link: String,
+}
+
+#[derive(Serialize)]
+struct ChapterContext {
+    title: Option<String>,
+    annotations: Vec<AnnotationContext>,
+}
+
+#[derive(Serialize)]
+struct ExportContext {
+    book: BookContext,
+    count: usize,
+    exported_at: String,
+    chapters: Vec<ChapterContext>,
+}
+
+/// Export a book's annotations (optionally filtered) as Markdown
+///
+/// Filters in `query` other than `book_ids` narrow the exported set; the
+/// result is always limited to `book_id` and ordered by position.
+pub fn export_book_markdown(
+    db: &Database,
+    book_id: &str,
+    query: Option<NotebookQuery>,
+    options: &MarkdownExportOptions,
+) -> Result<String, OmniReaderError> {
+    let book = db.require_book(book_id)?;
+
+    let mut annotations = match query {
+        Some(query) => db.query_notebook(&NotebookQuery {
+            book_ids: vec![book_id.to_string()],
+            ..query
+        })?,
+        None => db.get_annotations(book_id)?,
+    };
+    annotations.sort_by(|a, b| {
+        a.locator
+            .total_progression
+            .total_cmp(&b.locator.total_progression)
+    });
+
+    let mut tags = HashMap::new();
+    for annotation in &annotations {
+        let names = db
+            .get_annotation_tags(&annotation.id)?
+            .into_iter()
+            .map(|tag| tag.name.replace(' ', "-"))
+            .collect();
+        tags.insert(annotation.id.clone(), names);
+    }
+
+    // The book file may have moved; fall back to captured chapter titles
+    let toc = match book.file_type {
+        BookType::Epub => epub::get_epub_toc(&book.file_path).unwrap_or_default(),
+        BookType::Pdf => Vec::new(),
+    };
+
+    render_markdown(
+        &book,
+        &annotations,
+        &tags,
+        &db.get_palette()?,
+        &toc,
+        options,
+    )
+}
+
+/// Export a book's annotations to `<dir>/<title>.md`, returning the file path
+pub fn write_book_markdown(
+    db: &Database,
+    book_id: &str,
+    query: Option<NotebookQuery>,
+    options: &MarkdownExportOptions,
+    dir: &str,
+) -> Result<String, OmniReaderError> {
+    let markdown = export_book_markdown(db, book_id, query, options)?;
+    let book = db.require_book(book_id)?;
+
+    std::fs::create_dir_all(dir)?;
+    let path = Path::new(dir).join(format!("{}.md", file_name_for(&book.title)));
+    std::fs::write(&path, markdown)?;
+    Ok(path.to_string_lossy().to_string())
+}
+
+/// Render annotations with a template
+pub(crate) fn render_markdown(
+    book: &Book,
+    annotations: &[Annotation],
+    tags: &HashMap<String, Vec<String>>,
+    palette: &[PaletteColor],
+    toc: &[TocEntry],
+    options: &MarkdownExportOptions,
+) -> Result<String, OmniReaderError> {
+    let mut handlebars = Handlebars::new();
+    handlebars.register_escape_fn(no_escape);
+    handlebars_helper!(quote: |text: str| {
+        text.lines().map(|line| format!("> {}", line).trim_end().to_string()).collect::<Vec<_>>().join("\n")
+    });
+    handlebars_helper!(yaml: |text: str| serde_json::to_string(text).unwrap_or_default());
+    handlebars.register_helper("quote", Box::new(quote));
+    handlebars.register_helper("yaml", Box::new(yaml));
+
+    let template = options
+        .template
+        .as_deref()
+        .unwrap_or(DEFAULT_MARKDOWN_TEMPLATE);
+    handlebars
+        .register_template_string("export", template)
+        .map_err(|e| OmniReaderError::ParseError {
+            message: format!("Invalid export template: {}", e),
+        })?;
+
+    let context = ExportContext {
+        book: BookContext {
+            id: book.id.clone(),
+            title: book.title.clone(),
+            author: book.author.clone(),
+            format: book.file_type.extension(),
+            total_pages: book.total_pages,
+            link: format!("{}book/{}", options.link_base, book.id),
+        },
+        count: annotations.len(),
+        exported_at: chrono::Utc::now().format("%Y-%m-%d").to_string(),
+        chapters: group_by_chapter(book, annotations, tags, palette, toc, &options.link_base),
+    };
+
+    handlebars
+        .render("export", &context)
+        .map_err(|e| OmniReaderError::ParseError {
+            message: format!("Failed to render export template: {}", e),
+        })
+}
+
+/// Group annotations (in reading order) under consecutive chapter headings
+fn group_by_chapter(
+    book: &Book,
+    annotations: &[Annotation],
+    tags: &HashMap<String, Vec<String>>,
+    palette: &[PaletteColor],
+    toc: &[TocEntry],
+    link_base: &str,
+) -> Vec<ChapterContext> {
+    let mut chapters: Vec<ChapterContext> = Vec::new();
+    for annotation in annotations {
+        let title = if toc.is_empty() {
+            annotation.chapter_title.clone()
+        } else {
+            let spine_index = annotation.locator.spine_index(book.total_pages);
+            epub::chapter_title_for(toc, spine_index, book.total_pages)
+                .or_else(|| annotation.chapter_title.clone())
+        };
+
+        let color = palette
+            .iter()
+            .find(|color| color.id == annotation.color)
+            .map(|color| ColorContext {
+                id: color.id.clone(),
+                name: color.name.clone(),
+                hex: color.hex.clone(),
+            })
+            .unwrap_or_else(|| ColorContext {
+                id: annotation.color.clone(),
+                name: annotation.color.clone(),
+                hex: String::new(),
+            });
+
+        let context = AnnotationContext {
+            id: annotation.id.clone(),
+            kind: match annotation.annotation_type {
+                AnnotationType::Highlight => "Highlight",
+                AnnotationType::Note => "Note",
+                AnnotationType::Bookmark => "Bookmark",
+            },
+            text: annotation.selected_text.clone(),
+            note: annotation.note_text.clone(),
+            color,
+            tags: tags.get(&annotation.id).cloned().unwrap_or_default(),
+            page: match book.file_type {
+                BookType::Pdf => Some(annotation.locator.pdf_page(book.total_pages) + 1),
+                BookType::Epub => None,
+            },
+            percent: format!("{:.1}", annotation.locator.percent()),
+            created_at: chrono::DateTime::from_timestamp(annotation.created_at, 0)
+                .map(|date| date.format("%Y-%m-%d").to_string())
+                .unwrap_or_default(),
+            link: format!("{}book/{}/annotation/{}", link_base, book.id, annotation.id),
+        };
+
+        match chapters.last_mut() {
+            Some(chapter) if chapter.title == title => chapter.annotations.push(context),
+            _ => chapters.push(ChapterContext {
+                title,
+                annotations: vec![context],
+            }),
+        }
+    }
+    chapters
+}
+
+/// Make a title safe to use as a file name
+pub(crate) fn file_name_for(title: &str) -> String {
+    let name: String = title
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '-',
+            c if c.is_control() => '-',
+            c => c,
+        })
+        .collect();
+    let name = name.trim().trim_matches('.').to_string();
+    if name.is_empty() {
+        "Untitled".to_string()
+    } else {
+        name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::annotation::HighlightColor;
+    use crate::locator::Locator;
+
+    fn fixture() -> (Database, Book, Vec<Annotation>) {
+        let db = Database::open_in_memory().unwrap();
+        let book = Book::new(
+            "On \"Quotes\"".to_string(),
+            Some("A. Writer".to_string()),
+            "/missing/book.pdf".to_string(),
+            BookType::Pdf,
+            100,
+        );
+        db.insert_book(&book).unwrap();
+
+        let mut highlight = Annotation::new_highlight(
+            book.id.clone(),
+            Locator::from_pdf_page(9, 100),
+            HighlightColor::Green,
+            Some("First line\nSecond line".to_string()),
+        );
+        highlight.chapter_title = Some("Introduction".to_string());
+        let mut note = Annotation::new_note(
+            book.id.clone(),
+            Locator::from_pdf_page(41, 100),
+            "Disagree with this".to_string(),
+        );
+        note.chapter_title = Some("Argument".to_string());
+        db.insert_annotation(&highlight).unwrap();
+        db.insert_annotation(&note).unwrap();
+        db.add_annotation_tag(&highlight.id, "thesis ch3").unwrap();
+        (db, book, vec![highlight, note])
+    }
+
+    #[test]
+    fn test_default_template() {
+        let (db, book, annotations) = fixture();
+        let markdown =
+            export_book_markdown(&db, &book.id, None, &MarkdownExportOptions::default()).unwrap();
+
+        assert!(markdown.starts_with("---\ntitle: \"On \\\"Quotes\\\"\"\nauthor: \"A. Writer\"\n"));
+        assert!(markdown.contains("## Introduction\n\n> First line\n> Second line\n"));
+        assert!(markdown.contains("- Highlight · Green · page 10 · [Open in OmniReader]"));
+        assert!(markdown.contains("#thesis-ch3"));
+        assert!(markdown.contains("## Argument\n\nDisagree with this\n"));
+        assert!(markdown.contains(&format!(
+            "omnireader://book/{}/annotation/{}",
+            book.id, annotations[1].id
+        )));
+    }
+
+    #[test]
+    fn test_custom_template_and_filter() {
+        let (db, book, annotations) = fixture();
+        let options = MarkdownExportOptions {
+            template: Some(
+                "{{#each chapters}}{{#each annotations}}{{id}};{{/each}}{{/each}}".to_string(),
+            ),
+            link_base: "app://".to_string(),
+        };
+        let query = NotebookQuery {
+            annotation_types: vec![AnnotationType::Note],
+            ..Default::default()
+        };
+        let output = export_book_markdown(&db, &book.id, Some(query), &options).unwrap();
+        assert_eq!(output, format!("{};", annotations[1].id));
+
+        let broken = MarkdownExportOptions {
+            template: Some("{{#each chapters}}".to_string()),
+            ..Default::default()
+        };
+        assert!(export_book_markdown(&db, &book.id, None, &broken).is_err());
+    }
+}
@@ -0,0 +1,147 @@
+//! Cross-library notebook queries over annotations
+//!
+//! A `NotebookQuery` combines optional filters (tags, colors, palette labels,
+//! types, books, date range and full-text search over selected and note text)
+//! and is compiled to SQL for `Database::query_notebook`.
+
+use crate::annotation::AnnotationType;
+use rusqlite::types::Value;
+use uniffi;
+
+/// Filters for a notebook query; empty filters match everything
+#[derive(Debug, Clone, Default, uniffi::Record)]
+pub struct NotebookQuery {
+    /// Match annotations carrying any of these tag names
+    pub tags: Vec<String>,
+    /// Match annotations with any of these palette color IDs
+    pub colors: Vec<String>,
+    /// Match annotations whose palette color has any of these labels
+    pub labels: Vec<String>,
+    /// Match annotations of any of these types
+    pub annotation_types: Vec<AnnotationType>,
+    /// Match annotations in any of these books
+    pub book_ids: Vec<String>,
+    /// Only annotations created at or after this Unix timestamp
+    pub created_after: Option<i64>,
+    /// Only annotations created before this Unix timestamp
+    pub created_before: Option<i64>,
+    /// Full-text search over selected text and note text
+    pub text: Option<String>,
+    /// Maximum number of results
+    pub limit: Option<u32>,
+    /// Number of results to skip
+    pub offset: u32,
+}
+
+impl NotebookQuery {
+    /// Build the WHERE clause (over alias `a`) and its parameters
+    pub(crate) fn where_clause(&self) -> (String, Vec<Value>) {
+        let mut conditions = Vec::new();
+        let mut values = Vec::new();
+
+        if !self.tags.is_empty() {
+            conditions.push(format!(
+                "a.id IN (SELECT at.annotation_id FROM annotation_tags at JOIN tags t ON t.id = at.tag_id 
+                 WHERE t.name COLLATE NOCASE IN ({}))",
+                placeholders(&mut values, self.tags.iter().cloned())
+            ));
+        }
+        if !self.colors.is_empty() {
+            conditions.push(format!(
+                "a.color IN ({})",
+                placeholders(&mut values, self.colors.iter().cloned())
+            ));
+        }
+        if !self.labels.is_empty() {
+            conditions.push(format!(
+                "a.color IN (SELECT id FROM palette_colors WHERE name COLLATE NOCASE IN ({}))",
+                placeholders(&mut values, self.labels.iter().cloned())
+            ));
+        }
+        if !self.annotation_types.is_empty() {
+            conditions.push(format!(
+                "a.annotation_type IN ({})",
+                placeholders(
+                    &mut values,
+                    self.annotation_types.iter().map(|t| t.as_str().to_string())
+                )
+            ));
+        }
+        if !self.book_ids.is_empty() {
+            conditions.push(format!(
+                "a.book_id IN ({})",
+                placeholders(&mut values, self.book_ids.iter().cloned())
+            ));
+        }
+        if let Some(after) = self.created_after {
+            values.push(Value::Integer(after));
+            conditions.push(format!("a.created_at >= ?{}", values.len()));
+        }
+        if let Some(before) = self.created_before {
+            values.push(Value::Integer(before));
+            conditions.push(format!("a.created_at < ?{}", values.len()));
+        }
+        if let Some(text) = self.text.as_deref().and_then(fts_match_expression) {
+            values.push(Value::Text(text));
+            conditions.push(format!(
+                "a.rowid IN (SELECT rowid FROM annotations_fts WHERE annotations_fts MATCH ?{})",
+                values.len()
+            ));
+        }
+
+        if conditions.is_empty() {
+            ("1".to_string(), values)
+        } else {
+            (conditions.join(" AND "), values)
+        }
+    }
+}
+
+/// Push values and return their numbered placeholders, e.g. `?3, ?4`
+fn placeholders(values: &mut Vec<Value>, items: impl Iterator<Item = String>) -> String {
+    let mut numbers = Vec::new();
+    for item in items {
+        values.push(Value::Text(item));
+        numbers.push(format!("?{}", values.len()));
+    }
+    numbers.join(", ")
+}
+
+/// Turn free text into an FTS5 expression matching every word as a prefix
+fn fts_match_expression(text: &str) -> Option<String> {
+    let terms: Vec<String> = text
+        .split_whitespace()
+        .map(|word| format!("\"{}\"*", word.replace('"', "\"\"")))
+        .collect();
+    (!terms.is_empty()).then(|| terms.join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_where_clause() {
+        let query = NotebookQuery {
+            tags: vec!["thesis-ch3".to_string()],
+            annotation_types: vec![AnnotationType::Note, AnnotationType::Highlight],
+            created_after: Some(100),
+            text: Some("memory \"palace\"".to_string()),
+            ..Default::default()
+        };
+        let (clause, values) = query.where_clause();
+        assert!(clause.contains("t.name COLLATE NOCASE IN (?1)"));
+        assert!(clause.contains("a.annotation_type IN (?2, ?3)"));
+        assert!(clause.contains("a.created_at >= ?4"));
+        assert!(clause.contains("MATCH ?5"));
+        assert_eq!(values.len(), 5);
+        assert_eq!(
+            values[4],
+            Value::Text("\"memory\"* \"\"\"palace\"\"\"*".to_string())
+        );
+
+        let (clause, values) = NotebookQuery::default().where_clause();
+        assert_eq!(clause, "1");
+        assert!(values.is_empty());
+    }
+}
@@ -0,0 +1,374 @@
+//! Library export as OPF sidecars in a Calibre-compatible folder layout
+//!
+//! Each book gets its own `<author>/<title>/` folder holding a copy of the
+//! book file, a Calibre-style `metadata.opf`, `cover.jpg` and an
+//! `annotations.json` with the reading position and a W3C Web Annotation
+//! collection. Calibre's "Add books from directories" picks up the file
+//! together with the OPF metadata and cover.
+
+use crate::book::{Book, BookIdentifier};
+use crate::db::Database;
+use crate::error::OmniReaderError;
+use crate::markdown::file_name_for;
+use crate::web_annotation::{annotation_collection, timestamp_to_iso};
+use serde_json::json;
+use std::collections::HashSet;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use uniffi;
+
+/// Options for a library export
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct LibraryExportOptions {
+    /// Books to export; empty exports the whole library
+    pub book_ids: Vec<String>,
+    /// Copy the book files next to their metadata
+    pub copy_books: bool,
+}
+
+impl Default for LibraryExportOptions {
+    fn default() -> Self {
+        Self {
+            book_ids: Vec::new(),
+            copy_books: true,
+        }
+    }
+}
+
+/// Outcome of a library export
+#[derive(Debug, Clone, Default, PartialEq, uniffi::Record)]
+pub struct LibraryExportReport {
+    /// Book folders written
+    pub exported: u32,
+    /// Titles of books whose file could not be copied (metadata was still written)
+    pub missing_files: Vec<String>,
+}
+
+/// Export books with OPF metadata, covers and annotations under `dir`
+pub fn export_library(
+    db: &Database,
+    dir: &str,
+    options: &LibraryExportOptions,
+) -> Result<LibraryExportReport, OmniReaderError> {
+    let mut books = db.get_all_books()?;
+    if !options.book_ids.is_empty() {
+        books.retain(|book| options.book_ids.contains(&book.id));
+    }
+    books.sort_by_key(|book| book.added_at);
+
+    let mut report = LibraryExportReport::default();
+    let mut used = HashSet::new();
+    for book in books {
+        let folder = book_folder(Path::new(dir), &book, &mut used);
+        std::fs::create_dir_all(&folder)?;
+
+        let tags: Vec<String> = db
+            .get_book_tags(&book.id)?
+            .into_iter()
+            .map(|tag| tag.name)
+            .collect();
+        let identifiers = db.get_book_identifiers(&book.id)?;
+        let cover = book.cover_data.as_deref().and_then(cover_jpeg);
+        std::fs::write(
+            folder.join("metadata.opf"),
+            render_opf(&book, &tags, &identifiers, cover.is_some()),
+        )?;
+        if let Some(cover) = cover {
+            std::fs::write(folder.join("cover.jpg"), cover)?;
+        }
+
+        let position = db.get_reading_position(&book.id)?.map(|position| {
+            json!({
+                "href": position.locator.href,
+                "page": position.locator.page,
+                "progression": position.locator.progression,
+                "totalProgression": position.locator.total_progression,
+                "cfi": position.locator.cfi,
+                "updated": timestamp_to_iso(position.updated_at),
+            })
+        });
+        let annotations = json!({
+            "bookId": book.id,
+            "title": book.title,
+            "readingPosition": position,
+            "annotations": annotation_collection(db, &book.id)?,
+        });
+        let annotations = serde_json::to_string_pretty(&annotations).map_err(|e| {
+            OmniReaderError::ParseError {
+                message: format!("Failed to serialize annotations: {}", e),
+            }
+        })?;
+        std::fs::write(folder.join("annotations.json"), annotations)?;
+
+        if options.copy_books {
+            let target = folder.join(format!(
+                "{}.{}",
+                file_name_for(&book_file_stem(&book)),
+                book.file_type.extension()
+            ));
+            if std::fs::copy(&book.file_path, target).is_err() {
+                report.missing_files.push(book.title.clone());
+            }
+        }
+        report.exported += 1;
+    }
+    Ok(report)
+}
+
+/// Render a Calibre-style OPF 2.0 metadata document
+pub fn render_opf(
+    book: &Book,
+    tags: &[String],
+    identifiers: &[BookIdentifier],
+    has_cover: bool,
+) -> String {
+    let mut metadata = Vec::new();
+    metadata.push(format!(
+        r#"<dc:identifier opf:scheme="uuid" id="uuid_id">{}</dc:identifier>"#,
+        xml_escape(&book.id)
+    ));
+    for identifier in identifiers {
+        metadata.push(format!(
+            r#"<dc:identifier opf:scheme="{}">{}</dc:identifier>"#,
+            xml_escape(&identifier.scheme.to_uppercase()),
+            xml_escape(&identifier.value)
+        ));
+    }
+    metadata.push(format!("<dc:title>{}</dc:title>", xml_escape(&book.title)));
+
+    let authors = authors(book);
+    let sorts: Vec<&str> = book
+        .author_sort
+        .as_deref()
+        .map(|sort| sort.split(" & ").collect())
+        .unwrap_or_default();
+    for (index, author) in authors.iter().enumerate() {
+        let file_as = if sorts.len() == authors.len() {
+            format!(r#" opf:file-as="{}""#, xml_escape(sorts[index]))
+        } else {
+            String::new()
+        };
+        metadata.push(format!(
+            r#"<dc:creator{} opf:role="aut">{}</dc:creator>"#,
+            file_as,
+            xml_escape(author)
+        ));
+    }
+    if let Some(publisher) = &book.publisher {
+        metadata.push(format!(
+            "<dc:publisher>{}</dc:publisher>",
+            xml_escape(publisher)
+        ));
+    }
+    if let Some(description) = &book.description {
+        metadata.push(format!(
+            "<dc:description>{}</dc:description>",
+            xml_escape(description)
+        ));
+    }
+    for tag in tags {
+        metadata.push(format!("<dc:subject>{}</dc:subject>", xml_escape(tag)));
+    }
+    let mut meta = |name: &str, content: String| {
+        metadata.push(format!(
+            r#"<meta name="{}" content="{}"/>"#,
+            name,
+            xml_escape(&content)
+        ));
+    };
+    if let Some(series) = &book.series {
+        meta("calibre:series", series.clone());
+        meta(
+            "calibre:series_index",
+            book.series_index.unwrap_or(1.0).to_string(),
+        );
+    }
+    if let Some(rating) = book.rating {
+        meta("calibre:rating", rating.to_string());
+    }
+    meta("calibre:timestamp", timestamp_to_iso(book.added_at));
+
+    let guide = if has_cover {
+        "\n    <guide>\n        <reference type=\"cover\" title=\"Cover\" href=\"cover.jpg\"/>\n    </guide>"
+    } else {
+        ""
+    };
+    format!(
+        r#"<?xml version='1.0' encoding='utf-8'?>
+<package xmlns="http://www.idpf.org/2007/opf" unique-identifier="uuid_id" version="2.0">
+    <metadata xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:opf="http://www.idpf.org/2007/opf">
+        {}
+    </metadata>{}
+</package>
+"#,
+        metadata.join("\n        "),
+        guide
+    )
+}
+
+/// Authors of a book, split on Calibre's " & " separator
+fn authors(book: &Book) -> Vec<&str> {
+    book.author
+        .as_deref()
+        .map(|author| {
+            author
+                .split(" & ")
+                .map(str::trim)
+                .filter(|a| !a.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// `<dir>/<first author>/<title>`, suffixed with " (n)" when already taken
+fn book_folder(dir: &Path, book: &Book, used: &mut HashSet<PathBuf>) -> PathBuf {
+    let author = authors(book).first().copied().unwrap_or("Unknown");
+    let base = dir.join(file_name_for(author));
+    let title = file_name_for(&book.title);
+    let mut folder = base.join(&title);
+    let mut n = 2;
+    while used.contains(&folder) || folder.exists() {
+        folder = base.join(format!("{} ({})", title, n));
+        n += 1;
+    }
+    used.insert(folder.clone());
+    folder
+}
+
+/// `<title> - <author>`, as Calibre names book files
+fn book_file_stem(book: &Book) -> String {
+    match authors(book).first() {
+        Some(author) => format!("{} - {}", book.title, author),
+        None => book.title.clone(),
+    }
+}
+
+/// The cover as JPEG bytes, converting other image formats
+fn cover_jpeg(data: &[u8]) -> Option<Vec<u8>> {
+    if data.starts_with(&[0xFF, 0xD8]) {
+        return Some(data.to_vec());
+    }
+    let image = image::load_from_memory(data).ok()?;
+    let mut jpeg = Vec::new();
+    image
+        .to_rgb8()
+        .write_to(&mut Cursor::new(&mut jpeg), image::ImageFormat::Jpeg)
+        .ok()?;
+    Some(jpeg)
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::annotation::{Annotation, HighlightColor, ReadingPosition};
+    use crate::book::{BookImport, BookType};
+    use crate::locator::Locator;
+
+    #[test]
+    fn test_export_library() {
+        let dir = std::env::temp_dir().join(format!("omnireader-export-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("source.epub");
+        std::fs::write(&source, b"epub bytes").unwrap();
+
+        let db = Database::open_in_memory().unwrap();
+        let mut book = Book::new(
+            "Tom & Jerry: <Collected>".to_string(),
+            Some("Ursula K. Le Guin & Second Author".to_string()),
+            source.to_string_lossy().to_string(),
+            BookType::Epub,
+            4,
+        );
+        book.author_sort = Some("Le Guin, Ursula K. & Author, Second".to_string());
+        book.series = Some("Hainish Cycle".to_string());
+        book.series_index = Some(1.5);
+        book.rating = Some(8);
+        let mut png = Vec::new();
+        image::RgbImage::new(2, 2)
+            .write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        book.cover_data = Some(png);
+        db.insert_books(&[BookImport {
+            book: book.clone(),
+            tags: vec!["Classics".to_string()],
+            identifiers: vec![BookIdentifier {
+                scheme: "isbn".to_string(),
+                value: "9780060125639".to_string(),
+            }],
+        }])
+        .unwrap();
+        let missing = Book::new(
+            "Gone".to_string(),
+            None,
+            "/nowhere/gone.pdf".to_string(),
+            BookType::Pdf,
+            1,
+        );
+        db.insert_book(&missing).unwrap();
+
+        let locator = Locator::from_epub_spine(1, 4, Some("ch2.xhtml".to_string()), 0.5);
+        db.insert_annotation(&Annotation::new_highlight(
+            book.id.clone(),
+            locator.clone(),
+            HighlightColor::Green,
+            Some("A passage".to_string()),
+        ))
+        .unwrap();
+        db.save_reading_position(&ReadingPosition::new(book.id.clone(), locator))
+            .unwrap();
+
+        let out = dir.join("export");
+        let report = export_library(
+            &db,
+            &out.to_string_lossy(),
+            &LibraryExportOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(report.exported, 2);
+        assert_eq!(report.missing_files, vec!["Gone".to_string()]);
+
+        let folder = out
+            .join("Ursula K. Le Guin")
+            .join("Tom & Jerry- -Collected-");
+        let opf = std::fs::read_to_string(folder.join("metadata.opf")).unwrap();
+        assert!(opf.contains("<dc:title>Tom &amp; Jerry: &lt;Collected&gt;</dc:title>"));
+        assert!(opf.contains(
+            r#"<dc:creator opf:file-as="Author, Second" opf:role="aut">Second Author</dc:creator>"#
+        ));
+        assert!(opf.contains(r#"<dc:identifier opf:scheme="ISBN">9780060125639</dc:identifier>"#));
+        assert!(opf.contains("<dc:subject>Classics</dc:subject>"));
+        assert!(opf.contains(r#"<meta name="calibre:series_index" content="1.5"/>"#));
+        assert!(opf.contains(r#"<meta name="calibre:rating" content="8"/>"#));
+        assert!(opf.contains(r#"href="cover.jpg""#));
+
+        let cover = std::fs::read(folder.join("cover.jpg")).unwrap();
+        assert!(cover.starts_with(&[0xFF, 0xD8]));
+        assert_eq!(
+            std::fs::read(folder.join("Tom & Jerry- -Collected- - Ursula K. Le Guin.epub"))
+                .unwrap(),
+            b"epub bytes"
+        );
+
+        let annotations: serde_json::Value = serde_json::from_str(
+            &std::fs::read_to_string(folder.join("annotations.json")).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(annotations["readingPosition"]["href"], "ch2.xhtml");
+        assert_eq!(annotations["annotations"]["total"], 1);
+        assert!(
+            out.join("Unknown")
+                .join("Gone")
+                .join("metadata.opf")
+                .exists()
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
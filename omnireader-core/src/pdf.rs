@@ -127,6 +127,28 @@ pub fn get_pdf_page_count(file_path: &str) -> Result<u32, OmniReaderError> {
     Ok(document.pages().len() as u32)
 }
 
+/// Extract the plain text of every page, in page order
+pub(crate) fn extract_pdf_text(file_path: &str) -> Result<Vec<String>, OmniReaderError> {
+    let pdfium = get_pdfium()?;
+
+    let document =
+        pdfium
+            .load_pdf_from_file(file_path, None)
+            .map_err(|e| OmniReaderError::ParseError {
+                message: format!("Failed to load PDF: {}", e),
+            })?;
+
+    let mut pages = Vec::new();
+    for page in document.pages().iter() {
+        let text = page
+            .text()
+            .map(|text| text.all().split_whitespace().collect::<Vec<_>>().join(" "))
+            .unwrap_or_default();
+        pages.push(text);
+    }
+    Ok(pages)
+}
+
 /// Render a page to PNG bytes
 fn render_page_to_png(page: &PdfPage, width: u32) -> Result<Vec<u8>, OmniReaderError> {
     // Calculate height based on aspect ratio
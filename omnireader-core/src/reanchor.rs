@@ -0,0 +1,449 @@
+//! Re-anchoring annotations after a book file changes
+//!
+//! Every highlight keeps a W3C-style text quote (exact text plus prefix and
+//! suffix) in its `Locator`. When the book content changes, the quote is
+//! searched for in the new text: exact matches are ranked by how well their
+//! context and position agree with the stored anchor, and quotes that were
+//! edited are found with bit-parallel approximate matching (Myers' algorithm).
+//! Annotations that cannot be found are flagged as orphaned for review.
+
+use crate::annotation::{AnchorStatus, Annotation};
+use crate::db::Database;
+use crate::error::OmniReaderError;
+use crate::locator::{self, Locator};
+use crate::search::{self, TextResource};
+use std::collections::HashMap;
+use uniffi;
+
+/// Maximum pattern length handled by one bit-parallel pass
+const MAX_FUZZY_CHARS: usize = 64;
+
+/// Fraction of the pattern allowed to differ in a fuzzy match
+const MAX_ERROR_RATIO: f64 = 0.2;
+
+/// W3C Web Annotation TextQuoteSelector
+#[derive(Debug, Clone, PartialEq, uniffi::Record)]
+pub struct TextQuoteSelector {
+    /// The quoted text
+    pub exact: String,
+    /// Text immediately before the quote
+    pub prefix: Option<String>,
+    /// Text immediately after the quote
+    pub suffix: Option<String>,
+}
+
+impl TextQuoteSelector {
+    /// Read the selector stored in a locator's text quote
+    pub fn from_locator(locator: &Locator) -> Option<Self> {
+        let text = locator.text.as_ref()?;
+        let exact = normalize(text.highlight.as_deref()?);
+        if exact.is_empty() {
+            return None;
+        }
+        Some(Self {
+            exact,
+            prefix: text.before.as_deref().map(normalize),
+            suffix: text.after.as_deref().map(normalize),
+        })
+    }
+}
+
+/// Summary of a re-anchoring run
+#[derive(Debug, Clone, Default, PartialEq, uniffi::Record)]
+pub struct ReanchorReport {
+    /// Annotations found at their recorded location
+    pub anchored: u32,
+    /// Annotations moved to a new location
+    pub relocated: u32,
+    /// Annotations that could not be found
+    pub orphaned: u32,
+    /// Annotations without a text quote, left untouched
+    pub skipped: u32,
+}
+
+/// Outcome for a single annotation
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Reanchored {
+    /// New location and status
+    Found(Locator, AnchorStatus),
+    /// The quote no longer occurs in the content
+    Orphaned,
+    /// The annotation has no quote to search for
+    Skipped,
+}
+
+/// Re-anchor all annotations of a book against its current file
+pub fn reanchor_book_annotations(
+    db: &Database,
+    book_id: &str,
+) -> Result<ReanchorReport, OmniReaderError> {
+    let book = db.require_book(book_id)?;
+    let resources = search::load_text_resources(&book.file_path, book.file_type)?;
+
+    let mut report = ReanchorReport::default();
+    for annotation in db.get_annotations(book_id)? {
+        match reanchor(&annotation, &resources) {
+            Reanchored::Found(locator, status) => {
+                match status {
+                    AnchorStatus::Relocated => report.relocated += 1,
+                    _ => report.anchored += 1,
+                }
+                db.update_annotation_anchor(&annotation.id, &locator, status)?;
+            }
+            Reanchored::Orphaned => {
+                report.orphaned += 1;
+                db.update_annotation_anchor(
+                    &annotation.id,
+                    &annotation.locator,
+                    AnchorStatus::Orphaned,
+                )?;
+            }
+            Reanchored::Skipped => report.skipped += 1,
+        }
+    }
+    Ok(report)
+}
+
+/// Find the current location of an annotation's quote in the given content
+pub(crate) fn reanchor(annotation: &Annotation, resources: &[TextResource]) -> Reanchored {
+    let Some(quote) = TextQuoteSelector::from_locator(&annotation.locator) else {
+        return Reanchored::Skipped;
+    };
+    let Some(found) = locate_quote(&quote, &annotation.locator, resources) else {
+        return Reanchored::Orphaned;
+    };
+
+    if unmoved(&annotation.locator, &quote, found, resources) {
+        // Keep the recorded locator, including a CFI finer than the spine item
+        return Reanchored::Found(annotation.locator.clone(), AnchorStatus::Anchored);
+    }
+    let locator = search::locator_for_range(resources, found.index, found.start, found.end);
+    Reanchored::Found(locator, AnchorStatus::Relocated)
+}
+
+/// Whether an exact match is where the locator recorded it
+///
+/// Locators without character offsets (e.g. CFI only) count as unmoved when
+/// the match is in the same resource and its context agrees with the quote.
+fn unmoved(
+    old: &Locator,
+    quote: &TextQuoteSelector,
+    found: QuoteMatch,
+    resources: &[TextResource],
+) -> bool {
+    let resource = &resources[found.index];
+    let same_resource = match (&old.href, old.page, old.cfi.as_deref()) {
+        (Some(href), _, _) => resource.href.as_ref() == Some(href),
+        (None, Some(page), _) => resource.page == Some(page),
+        (None, None, Some(cfi)) => {
+            resource.href.is_some()
+                && locator::spine_index_from_cfi(cfi) == Some(found.index as u32)
+        }
+        (None, None, None) => false,
+    };
+    if !found.exact || !same_resource {
+        return false;
+    }
+    if old.start_offset.is_some() || old.end_offset.is_some() {
+        return old.start_offset == Some(found.start as u32)
+            && old.end_offset == Some(found.end as u32);
+    }
+    // Stored context is normalized, so whitespace next to the quote is trimmed
+    let text: Vec<char> = resource.text.chars().collect();
+    let before: String = text[..found.start].iter().collect();
+    let after: String = text[found.end..].iter().collect();
+    before
+        .trim_end()
+        .ends_with(quote.prefix.as_deref().unwrap_or(""))
+        && after
+            .trim_start()
+            .starts_with(quote.suffix.as_deref().unwrap_or(""))
+}
+
+/// A located quote: resource index and char range
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct QuoteMatch {
+    pub index: usize,
+    pub start: usize,
+    pub end: usize,
+    pub exact: bool,
+}
+
+/// Locate a text quote, preferring exact matches near the hinted position
+pub(crate) fn locate_quote(
+    quote: &TextQuoteSelector,
+    hint: &Locator,
+    resources: &[TextResource],
+) -> Option<QuoteMatch> {
+    let exact: Vec<char> = quote.exact.chars().collect();
+    let prefix: Vec<char> = quote.prefix.as_deref().unwrap_or("").chars().collect();
+    let suffix: Vec<char> = quote.suffix.as_deref().unwrap_or("").chars().collect();
+    let count = resources.len().max(1) as f64;
+
+    let mut best: Option<(f64, QuoteMatch)> = None;
+    for (index, resource) in resources.iter().enumerate() {
+        let text: Vec<char> = resource.text.chars().collect();
+        for start in find_all(&text, &exact) {
+            let end = start + exact.len();
+            let before_score = common_suffix(&text[..start], &prefix) as f64;
+            let after_score = common_prefix(&text[end..], &suffix) as f64;
+            let context =
+                (before_score + after_score) / (prefix.len() + suffix.len()).max(1) as f64;
+            let position = (index as f64 + start as f64 / text.len().max(1) as f64) / count;
+            let score = context - (position - hint.total_progression).abs();
+            if best.as_ref().is_none_or(|(b, _)| score > *b) {
+                best = Some((
+                    score,
+                    QuoteMatch {
+                        index,
+                        start,
+                        end,
+                        exact: true,
+                    },
+                ));
+            }
+        }
+    }
+    if let Some((_, found)) = best {
+        return Some(found);
+    }
+
+    locate_fuzzy(&exact, hint, resources)
+}
+
+/// Approximate match of (the head and tail of) a quote
+fn locate_fuzzy(exact: &[char], hint: &Locator, resources: &[TextResource]) -> Option<QuoteMatch> {
+    let head = &exact[..exact.len().min(MAX_FUZZY_CHARS)];
+    let max_errors = ((head.len() as f64 * MAX_ERROR_RATIO).floor() as usize).max(1);
+    if head.len() <= max_errors * 2 {
+        return None;
+    }
+    let count = resources.len().max(1) as f64;
+
+    // (errors, distance from hint) ranks candidates
+    let mut best: Option<((usize, f64), QuoteMatch)> = None;
+    for (index, resource) in resources.iter().enumerate() {
+        let text: Vec<char> = resource.text.chars().collect();
+        let Some((head_end, errors)) = myers_best_match(head, &text, max_errors) else {
+            continue;
+        };
+        let start = (head_end + 1).saturating_sub(head.len());
+        let end = if exact.len() > head.len() {
+            let tail = &exact[exact.len() - MAX_FUZZY_CHARS..];
+            let window_end = (start + exact.len() + exact.len() / 2).min(text.len());
+            myers_best_match(tail, &text[start..window_end], max_errors)
+                .map(|(tail_end, _)| start + tail_end + 1)
+                .unwrap_or((start + exact.len()).min(text.len()))
+        } else {
+            head_end + 1
+        };
+        let position = (index as f64 + start as f64 / text.len().max(1) as f64) / count;
+        let rank = (errors, (position - hint.total_progression).abs());
+        if best.as_ref().is_none_or(|(b, _)| rank < *b) {
+            best = Some((
+                rank,
+                QuoteMatch {
+                    index,
+                    start,
+                    end,
+                    exact: false,
+                },
+            ));
+        }
+    }
+    best.map(|(_, found)| found)
+}
+
+/// Find the text position (inclusive end index) where `pattern` matches with
+/// the fewest edits, if within `max_errors`. Pattern must be 1..=64 chars.
+fn myers_best_match(pattern: &[char], text: &[char], max_errors: usize) -> Option<(usize, usize)> {
+    let m = pattern.len();
+    debug_assert!(m > 0 && m <= MAX_FUZZY_CHARS);
+
+    let mut peq: HashMap<char, u64> = HashMap::new();
+    for (i, c) in pattern.iter().enumerate() {
+        *peq.entry(*c).or_insert(0) |= 1 << i;
+    }
+
+    let last = 1u64 << (m - 1);
+    let mut pv = !0u64;
+    let mut mv = 0u64;
+    let mut score = m;
+    let mut best: Option<(usize, usize)> = None;
+
+    for (j, c) in text.iter().enumerate() {
+        let eq = peq.get(c).copied().unwrap_or(0);
+        let xv = eq | mv;
+        let xh = ((eq & pv).wrapping_add(pv) ^ pv) | eq;
+        let mut ph = mv | !(xh | pv);
+        let mut mh = pv & xh;
+        if ph & last != 0 {
+            score += 1;
+        } else if mh & last != 0 {
+            score -= 1;
+        }
+        ph <<= 1;
+        mh <<= 1;
+        pv = mh | !(xv | ph);
+        mv = ph & xv;
+
+        if score <= max_errors && best.is_none_or(|(_, errors)| score < errors) {
+            best = Some((j, score));
+        }
+    }
+    best
+}
+
+fn find_all(text: &[char], needle: &[char]) -> Vec<usize> {
+    if needle.is_empty() || needle.len() > text.len() {
+        return Vec::new();
+    }
+    (0..=text.len() - needle.len())
+        .filter(|&i| text[i..i + needle.len()] == *needle)
+        .collect()
+}
+
+fn common_prefix(a: &[char], b: &[char]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
+
+fn common_suffix(a: &[char], b: &[char]) -> usize {
+    a.iter()
+        .rev()
+        .zip(b.iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count()
+}
+
+/// Collapse whitespace the same way extracted book text is collapsed
+fn normalize(s: &str) -> String {
+    s.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::annotation::HighlightColor;
+
+    fn resources(chapters: &[&str]) -> Vec<TextResource> {
+        chapters
+            .iter()
+            .enumerate()
+            .map(|(i, text)| TextResource {
+                href: Some(format!("ch{}.xhtml", i + 1)),
+                page: None,
+                text: text.to_string(),
+            })
+            .collect()
+    }
+
+    fn highlight(original: &[TextResource], quote: &str) -> Annotation {
+        let hit = search::search_resources(original, quote, 1).remove(0);
+        Annotation::new_highlight(
+            "book".to_string(),
+            hit.locator,
+            HighlightColor::Yellow,
+            Some(quote.to_string()),
+        )
+    }
+
+    #[test]
+    fn test_unchanged_content_stays_anchored() {
+        let original = resources(&["Call me Ishmael. Some years ago, never mind how long."]);
+        let annotation = highlight(&original, "Some years ago");
+        match reanchor(&annotation, &original) {
+            Reanchored::Found(locator, status) => {
+                assert_eq!(status, AnchorStatus::Anchored);
+                assert_eq!(locator, annotation.locator);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_cfi_only_annotation_stays_anchored() {
+        let original = resources(&["Call me Ishmael. Some years ago, never mind how long."]);
+        let mut locator = Locator::from_total_progression(0.3).with_text(
+            Some("Call me Ishmael. ".to_string()),
+            Some("Some years ago".to_string()),
+            Some(", never mind".to_string()),
+        );
+        locator.cfi = Some("epubcfi(/6/2!/4/2/1:17)".to_string());
+        let annotation = Annotation::new_highlight(
+            "book".to_string(),
+            locator,
+            HighlightColor::Yellow,
+            Some("Some years ago".to_string()),
+        );
+        match reanchor(&annotation, &original) {
+            Reanchored::Found(locator, status) => {
+                assert_eq!(status, AnchorStatus::Anchored);
+                assert_eq!(locator, annotation.locator);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // Once the passage moves to another chapter, the CFI is replaced
+        let edition = resources(&["Preface.", "Call me Ishmael. Some years ago, never mind."]);
+        match reanchor(&annotation, &edition) {
+            Reanchored::Found(locator, status) => {
+                assert_eq!(status, AnchorStatus::Relocated);
+                assert_eq!(locator.cfi.as_deref(), Some("epubcfi(/6/4!)"));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_exact_quote_relocated_by_context() {
+        let original = resources(&["The cat sat. The dog ran far away. The cat slept."]);
+        let annotation = highlight(&original, "The cat slept");
+
+        // A new edition inserts a chapter and repeats the phrase elsewhere
+        let edition = resources(&[
+            "Preface: The cat slept is a famous line.",
+            "The cat sat. The dog ran far away. The cat slept.",
+        ]);
+        match reanchor(&annotation, &edition) {
+            Reanchored::Found(locator, status) => {
+                assert_eq!(status, AnchorStatus::Relocated);
+                assert_eq!(locator.href.as_deref(), Some("ch2.xhtml"));
+                assert_eq!(locator.start_offset, Some(35));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_fuzzy_match_after_typo_fix() {
+        let original = resources(&["It was the best of tmies, it was the worst of times."]);
+        let annotation = highlight(&original, "It was the best of tmies");
+
+        let edition =
+            resources(&["Foreword. It was the best of times, it was the worst of times."]);
+        match reanchor(&annotation, &edition) {
+            Reanchored::Found(locator, status) => {
+                assert_eq!(status, AnchorStatus::Relocated);
+                let text = locator.text.unwrap();
+                assert_eq!(text.highlight.as_deref(), Some("It was the best of times"));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_removed_text_is_orphaned() {
+        let original = resources(&["A paragraph that the editor later removed entirely."]);
+        let annotation = highlight(&original, "the editor later removed");
+
+        let edition = resources(&["Completely different prose now lives here."]);
+        assert_eq!(reanchor(&annotation, &edition), Reanchored::Orphaned);
+
+        let note = Annotation::new_note(
+            "book".to_string(),
+            Locator::from_total_progression(0.5),
+            "no quote".to_string(),
+        );
+        assert_eq!(reanchor(&note, &edition), Reanchored::Skipped);
+    }
+}
@@ -0,0 +1,184 @@
+//! Full-text search inside a book
+//!
+//! Book content is flattened into one plain-text resource per EPUB spine item
+//! or PDF page; hits are reported as `Locator`s with character offsets and
+//! surrounding context.
+
+use crate::book::BookType;
+use crate::error::OmniReaderError;
+use crate::locator::{Locator, spine_cfi};
+use crate::{epub, pdf};
+use uniffi;
+
+/// Number of characters of context captured before and after a hit
+const CONTEXT_CHARS: usize = 32;
+
+/// A single search match
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct SearchHit {
+    /// Spine index (EPUB) or page index (PDF) containing the match
+    pub resource_index: u32,
+    /// Location of the match, including a text quote with context
+    pub locator: Locator,
+}
+
+/// Plain text of one EPUB spine item or PDF page
+pub(crate) struct TextResource {
+    pub href: Option<String>,
+    pub page: Option<u32>,
+    pub text: String,
+}
+
+/// Load the plain text of a book, one entry per spine item or page
+pub(crate) fn load_text_resources(
+    file_path: &str,
+    file_type: BookType,
+) -> Result<Vec<TextResource>, OmniReaderError> {
+    match file_type {
+        BookType::Epub => Ok(epub::extract_epub_text(file_path)?
+            .into_iter()
+            .map(|chapter| TextResource {
+                href: Some(chapter.href),
+                page: None,
+                text: chapter.text,
+            })
+            .collect()),
+        BookType::Pdf => Ok(pdf::extract_pdf_text(file_path)?
+            .into_iter()
+            .enumerate()
+            .map(|(index, text)| TextResource {
+                href: None,
+                page: Some(index as u32),
+                text,
+            })
+            .collect()),
+    }
+}
+
+/// Search a book for a case-insensitive phrase
+#[uniffi::export]
+pub fn search_book(
+    file_path: &str,
+    file_type: BookType,
+    query: &str,
+    max_results: u32,
+) -> Result<Vec<SearchHit>, OmniReaderError> {
+    let resources = load_text_resources(file_path, file_type)?;
+    Ok(search_resources(&resources, query, max_results as usize))
+}
+
+/// Search already-loaded text resources
+pub(crate) fn search_resources(
+    resources: &[TextResource],
+    query: &str,
+    max_results: usize,
+) -> Vec<SearchHit> {
+    let needle = fold(query.trim());
+    if needle.is_empty() || max_results == 0 {
+        return Vec::new();
+    }
+
+    let mut hits = Vec::new();
+    for (index, resource) in resources.iter().enumerate() {
+        let haystack = fold(&resource.text);
+        let mut start = 0;
+        while start + needle.len() <= haystack.len() {
+            if haystack[start..start + needle.len()] == needle[..] {
+                hits.push(SearchHit {
+                    resource_index: index as u32,
+                    locator: locator_for_range(resources, index, start, start + needle.len()),
+                });
+                if hits.len() >= max_results {
+                    return hits;
+                }
+                start += needle.len();
+            } else {
+                start += 1;
+            }
+        }
+    }
+    hits
+}
+
+/// Build a locator for a character range within one resource
+pub(crate) fn locator_for_range(
+    resources: &[TextResource],
+    index: usize,
+    start: usize,
+    end: usize,
+) -> Locator {
+    let resource = &resources[index];
+    let chars: Vec<char> = resource.text.chars().collect();
+    let len = chars.len().max(1) as f64;
+    let count = resources.len().max(1) as f64;
+    let progression = start as f64 / len;
+
+    let before: String = chars[start.saturating_sub(CONTEXT_CHARS)..start]
+        .iter()
+        .collect();
+    let highlight: String = chars[start..end].iter().collect();
+    let after: String = chars[end..(end + CONTEXT_CHARS).min(chars.len())]
+        .iter()
+        .collect();
+
+    Locator {
+        href: resource.href.clone(),
+        page: resource.page,
+        progression,
+        total_progression: (index as f64 + progression) / count,
+        end_total_progression: Some((index as f64 + end as f64 / len) / count),
+        cfi: resource.href.as_ref().map(|_| spine_cfi(index as u32)),
+        start_offset: Some(start as u32),
+        end_offset: Some(end as u32),
+        text: None,
+    }
+    .with_text(
+        Some(before).filter(|s| !s.is_empty()),
+        Some(highlight),
+        Some(after).filter(|s| !s.is_empty()),
+    )
+}
+
+/// Lowercase a string char-by-char, keeping a 1:1 mapping to char offsets
+fn fold(s: &str) -> Vec<char> {
+    s.chars()
+        .map(|c| c.to_lowercase().next().unwrap_or(c))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resource(text: &str) -> TextResource {
+        TextResource {
+            href: Some("ch.xhtml".to_string()),
+            page: None,
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_search_resources() {
+        let resources = vec![
+            resource("It was the best of times."),
+            resource("It was the worst of Times, it was the age of wisdom."),
+        ];
+        let hits = search_resources(&resources, "times", 10);
+        assert_eq!(hits.len(), 2);
+
+        let second = &hits[1].locator;
+        assert_eq!(hits[1].resource_index, 1);
+        assert_eq!(second.start_offset, Some(20));
+        assert_eq!(second.cfi.as_deref(), Some("epubcfi(/6/4!)"));
+        let text = second.text.as_ref().unwrap();
+        assert_eq!(text.highlight.as_deref(), Some("Times"));
+        assert_eq!(text.before.as_deref(), Some("It was the worst of "));
+        assert!(second.total_progression > 0.5);
+        assert!(second.end_total_progression.unwrap() > second.total_progression);
+
+        assert_eq!(search_resources(&resources, "times", 1).len(), 1);
+        assert!(search_resources(&resources, "times", 0).is_empty());
+        assert!(search_resources(&resources, "  ", 10).is_empty());
+    }
+}
@@ -0,0 +1,543 @@
+//! Reading sessions and reading statistics
+//!
+//! A session spans one sitting with a book. Apps start and stop sessions
+//! explicitly when a book is opened and closed, and report every page turn
+//! through [`track_reading_position`], which also starts a session when
+//! none is open and splits sessions at pauses longer than
+//! [`IDLE_TIMEOUT_SECS`].
+//!
+//! Only reading past the furthest point of a session counts, so paging back
+//! and reading again is not counted twice. PDF pages are real pages; for
+//! EPUB, characters are counted from the text length of each spine item and
+//! converted to pages of [`CHARS_PER_PAGE`] characters.
+
+use crate::annotation::ReadingPosition;
+use crate::book::{Book, BookType};
+use crate::db::Database;
+use crate::epub;
+use crate::error::OmniReaderError;
+use crate::status;
+use chrono::{DateTime, Datelike, Duration, NaiveDate};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use uniffi;
+
+/// A pause longer than this ends the session; the next page turn starts a new one
+pub const IDLE_TIMEOUT_SECS: i64 = 5 * 60;
+
+/// Characters per EPUB page, for page counts and reading speeds
+pub const CHARS_PER_PAGE: u64 = 1500;
+
+/// One sitting with a book
+#[derive(Debug, Clone, PartialEq, uniffi::Record)]
+pub struct ReadingSession {
+    pub id: String,
+    pub book_id: String,
+    /// Unix timestamp
+    pub started_at: i64,
+    /// Unix timestamp; `None` while the session is open
+    pub ended_at: Option<i64>,
+    /// Unix timestamp of the last position update
+    pub last_activity_at: i64,
+    /// Position in the whole book (0.0 - 1.0) when the session started
+    pub start_progression: f64,
+    /// Position in the whole book (0.0 - 1.0) at the last update
+    pub end_progression: f64,
+    /// PDF page index (0-based) when the session started
+    pub start_page: Option<u32>,
+    /// PDF page index (0-based) at the last update
+    pub end_page: Option<u32>,
+    /// Share of the book newly read in this session (0.0 - 1.0)
+    pub progress: f64,
+    pub pages_read: f64,
+    /// EPUB characters read; zero for PDF
+    pub characters_read: u64,
+}
+
+impl ReadingSession {
+    /// Length in seconds; an open session lasts until its last update
+    pub fn duration(&self) -> i64 {
+        (self.ended_at.unwrap_or(self.last_activity_at) - self.started_at).max(0)
+    }
+}
+
+/// Reading time and amount, overall or for one book or period
+#[derive(Debug, Clone, Default, PartialEq, uniffi::Record)]
+pub struct ReadingTotals {
+    pub seconds: i64,
+    pub sessions: u32,
+    pub pages_read: f64,
+    pub characters_read: u64,
+    /// Zero without any reading time
+    pub pages_per_hour: f64,
+}
+
+/// Reading totals of one book
+#[derive(Debug, Clone, PartialEq, uniffi::Record)]
+pub struct BookReadingTime {
+    pub book_id: String,
+    pub totals: ReadingTotals,
+    /// Unix timestamp of the start of the last session
+    pub last_read_at: i64,
+}
+
+/// Reading totals of one day or week
+#[derive(Debug, Clone, PartialEq, uniffi::Record)]
+pub struct PeriodReadingTime {
+    /// First day of the period, `YYYY-MM-DD`; weeks start on Monday
+    pub start: String,
+    pub totals: ReadingTotals,
+}
+
+/// Length of the periods reading time is grouped by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, uniffi::Enum)]
+pub enum StatsPeriod {
+    Day,
+    Week,
+}
+
+/// Reading totals of the whole library, with daily streaks
+#[derive(Debug, Clone, Default, PartialEq, uniffi::Record)]
+pub struct ReadingStats {
+    pub totals: ReadingTotals,
+    /// Consecutive days with reading up to today, or up to yesterday when
+    /// nothing was read yet today
+    pub current_streak_days: u32,
+    pub longest_streak_days: u32,
+}
+
+/// Estimated reading time left at the current position
+#[derive(Debug, Clone, Default, PartialEq, uniffi::Record)]
+pub struct TimeLeft {
+    /// `None` for PDF, which has no chapters here
+    pub chapter_seconds: Option<i64>,
+    pub book_seconds: Option<i64>,
+}
+
+/// Start a session with a book, ending any other open session
+pub fn start_reading_session(
+    db: &Database,
+    book_id: &str,
+) -> Result<ReadingSession, OmniReaderError> {
+    let book = db.require_book(book_id)?;
+    let now = chrono::Utc::now().timestamp();
+    if let Some(open) = db.open_reading_session()? {
+        let end = open.last_activity_at;
+        close_session(db, open, end)?;
+    }
+    if book.file_type == BookType::Epub && db.book_lengths(book_id)?.is_none() {
+        // Measured once per book; a book that cannot be read counts pages only
+        if let Ok(chapters) = epub::extract_epub_text(&book.file_path) {
+            let lengths: Vec<u64> = chapters
+                .iter()
+                .map(|chapter| chapter.text.chars().count() as u64)
+                .collect();
+            db.save_book_lengths(book_id, &lengths)?;
+        }
+    }
+    let position = db.get_reading_position(book_id)?;
+    let session = new_session(&book, position.as_ref(), now);
+    db.save_reading_session(&session)?;
+    Ok(session)
+}
+
+/// Save a reading position reached by reading, and count it in the open session
+///
+/// `position.updated_at` is taken as the time of the page turn. Crossing a
+/// status threshold also starts or finishes a read of the book.
+pub fn track_reading_position(
+    db: &Database,
+    position: &ReadingPosition,
+) -> Result<ReadingSession, OmniReaderError> {
+    let book = db.require_book(&position.book_id)?;
+    let now = position.updated_at;
+    status::save_read_position(db, &book, position)?;
+    db.update_last_read(&book.id)?;
+
+    let open = match db.open_reading_session()? {
+        Some(open)
+            if open.book_id == book.id && now - open.last_activity_at <= IDLE_TIMEOUT_SECS =>
+        {
+            Some(open)
+        }
+        Some(open) => {
+            let end = open.last_activity_at;
+            close_session(db, open, end)?;
+            None
+        }
+        None => None,
+    };
+    let Some(mut session) = open else {
+        // Nothing was read yet in a session started by this update
+        let session = new_session(&book, Some(position), now);
+        db.save_reading_session(&session)?;
+        return Ok(session);
+    };
+
+    let to = position.locator.total_progression;
+    // Progress only ever grows past the furthest point, so it is also the distance to it
+    let from = session.start_progression + session.progress;
+    if to > from {
+        session.progress = (session.progress + to - from).min(1.0);
+        match book.file_type {
+            BookType::Pdf => session.pages_read += (to - from) * book.total_pages as f64,
+            BookType::Epub => {
+                if let Some(lengths) = db.book_lengths(&book.id)? {
+                    let read = char_offset(&lengths, to) - char_offset(&lengths, from);
+                    session.characters_read += read;
+                    session.pages_read += read as f64 / CHARS_PER_PAGE as f64;
+                }
+            }
+        }
+    }
+    session.end_progression = to;
+    session.end_page = position.locator.page;
+    session.last_activity_at = session.last_activity_at.max(now);
+    db.save_reading_session(&session)?;
+    Ok(session)
+}
+
+/// End the open session; `None` when there is none or nothing was read in it
+///
+/// A session left alone for longer than [`IDLE_TIMEOUT_SECS`] ends at its
+/// last page turn.
+pub fn stop_reading_session(db: &Database) -> Result<Option<ReadingSession>, OmniReaderError> {
+    let Some(open) = db.open_reading_session()? else {
+        return Ok(None);
+    };
+    let now = chrono::Utc::now().timestamp();
+    let end = if now - open.last_activity_at > IDLE_TIMEOUT_SECS {
+        open.last_activity_at
+    } else {
+        now
+    };
+    close_session(db, open, end)
+}
+
+/// Reading totals of the whole library and daily streaks
+///
+/// Days are calendar days at `utc_offset_minutes` from UTC.
+pub fn reading_stats(
+    db: &Database,
+    utc_offset_minutes: i32,
+) -> Result<ReadingStats, OmniReaderError> {
+    reading_stats_at(db, chrono::Utc::now().timestamp(), utc_offset_minutes)
+}
+
+fn reading_stats_at(
+    db: &Database,
+    now: i64,
+    utc_offset_minutes: i32,
+) -> Result<ReadingStats, OmniReaderError> {
+    let sessions = db.get_reading_sessions_since(i64::MIN)?;
+    let days: BTreeSet<NaiveDate> = sessions
+        .iter()
+        .map(|session| local_date(session.started_at, utc_offset_minutes))
+        .collect();
+
+    let today = local_date(now, utc_offset_minutes);
+    let mut day = if days.contains(&today) {
+        today
+    } else {
+        today - Duration::days(1)
+    };
+    let mut current = 0;
+    while days.contains(&day) {
+        current += 1;
+        day -= Duration::days(1);
+    }
+
+    Ok(ReadingStats {
+        totals: totals(&sessions),
+        current_streak_days: current,
+        longest_streak_days: longest_streak(&days),
+    })
+}
+
+/// Reading totals per book, most read first
+pub fn reading_time_per_book(db: &Database) -> Result<Vec<BookReadingTime>, OmniReaderError> {
+    let mut books: HashMap<String, Vec<ReadingSession>> = HashMap::new();
+    for session in db.get_reading_sessions_since(i64::MIN)? {
+        books
+            .entry(session.book_id.clone())
+            .or_default()
+            .push(session);
+    }
+    let mut times: Vec<BookReadingTime> = books
+        .into_iter()
+        .map(|(book_id, sessions)| BookReadingTime {
+            last_read_at: sessions.iter().map(|s| s.started_at).max().unwrap_or(0),
+            totals: totals(&sessions),
+            book_id,
+        })
+        .collect();
+    times.sort_by(|a, b| {
+        b.totals
+            .seconds
+            .cmp(&a.totals.seconds)
+            .then_with(|| b.last_read_at.cmp(&a.last_read_at))
+    });
+    Ok(times)
+}
+
+/// Reading totals per day or week since `since`, oldest first; periods
+/// without reading are left out
+pub fn reading_time_per_period(
+    db: &Database,
+    period: StatsPeriod,
+    since: i64,
+    utc_offset_minutes: i32,
+) -> Result<Vec<PeriodReadingTime>, OmniReaderError> {
+    let mut periods: BTreeMap<NaiveDate, Vec<ReadingSession>> = BTreeMap::new();
+    for session in db.get_reading_sessions_since(since)? {
+        let day = local_date(session.started_at, utc_offset_minutes);
+        let start = match period {
+            StatsPeriod::Day => day,
+            StatsPeriod::Week => week_start(day),
+        };
+        periods.entry(start).or_default().push(session);
+    }
+    Ok(periods
+        .into_iter()
+        .map(|(start, sessions)| PeriodReadingTime {
+            start: start.format("%Y-%m-%d").to_string(),
+            totals: totals(&sessions),
+        })
+        .collect())
+}
+
+/// Estimated time left in the current chapter and the book
+///
+/// Based on the reading speed in this book, or in the whole library before
+/// enough of this book has been read.
+pub fn estimate_time_left(db: &Database, book_id: &str) -> Result<TimeLeft, OmniReaderError> {
+    let book = db.require_book(book_id)?;
+    let position = db
+        .get_reading_position(book_id)?
+        .map_or(0.0, |position| position.locator.total_progression);
+
+    let own = db.get_reading_sessions(book_id)?;
+    let speed = |sessions: &[ReadingSession]| {
+        let seconds: i64 = sessions.iter().map(ReadingSession::duration).sum();
+        let pages: f64 = sessions.iter().map(|s| s.pages_read).sum();
+        (seconds > 0 && pages > 0.0).then(|| pages / seconds as f64)
+    };
+    let pages_per_second = match speed(&own) {
+        Some(speed) => Some(speed),
+        None => speed(&db.get_reading_sessions_since(i64::MIN)?),
+    };
+    let seconds = |pages: f64| pages_per_second.map(|speed| (pages / speed).round() as i64);
+
+    match book.file_type {
+        BookType::Pdf => Ok(TimeLeft {
+            chapter_seconds: None,
+            book_seconds: seconds((1.0 - position) * book.total_pages as f64),
+        }),
+        BookType::Epub => match db.book_lengths(book_id)? {
+            Some(lengths) if !lengths.is_empty() => {
+                let total: u64 = lengths.iter().sum();
+                let offset = char_offset(&lengths, position);
+                let (chapter, within) = spine_position(lengths.len(), position);
+                let chapter_left = (1.0 - within) * lengths[chapter] as f64;
+                Ok(TimeLeft {
+                    chapter_seconds: seconds(chapter_left / CHARS_PER_PAGE as f64),
+                    book_seconds: seconds((total - offset) as f64 / CHARS_PER_PAGE as f64),
+                })
+            }
+            // Without chapter lengths, only the share of the book read per second is known
+            _ => {
+                let seconds: i64 = own.iter().map(ReadingSession::duration).sum();
+                let progress: f64 = own.iter().map(|s| s.progress).sum();
+                if seconds == 0 || progress <= 0.0 || book.total_pages == 0 {
+                    return Ok(TimeLeft::default());
+                }
+                let per_second = progress / seconds as f64;
+                let (_, within) = spine_position(book.total_pages as usize, position);
+                Ok(TimeLeft {
+                    chapter_seconds: Some(
+                        ((1.0 - within) / book.total_pages as f64 / per_second).round() as i64,
+                    ),
+                    book_seconds: Some(((1.0 - position) / per_second).round() as i64),
+                })
+            }
+        },
+    }
+}
+
+fn new_session(book: &Book, position: Option<&ReadingPosition>, now: i64) -> ReadingSession {
+    let progression = position.map_or(0.0, |p| p.locator.total_progression);
+    let page = position.and_then(|p| p.locator.page);
+    ReadingSession {
+        id: uuid::Uuid::new_v4().to_string(),
+        book_id: book.id.clone(),
+        started_at: now,
+        ended_at: None,
+        last_activity_at: now,
+        start_progression: progression,
+        end_progression: progression,
+        start_page: page,
+        end_page: page,
+        progress: 0.0,
+        pages_read: 0.0,
+        characters_read: 0,
+    }
+}
+
+/// End a session at `end`, dropping it when nothing was read
+fn close_session(
+    db: &Database,
+    mut session: ReadingSession,
+    end: i64,
+) -> Result<Option<ReadingSession>, OmniReaderError> {
+    session.ended_at = Some(end.max(session.started_at));
+    if session.duration() == 0 && session.progress == 0.0 {
+        db.delete_reading_session(&session.id)?;
+        return Ok(None);
+    }
+    db.save_reading_session(&session)?;
+    Ok(Some(session))
+}
+
+pub(crate) fn totals(sessions: &[ReadingSession]) -> ReadingTotals {
+    let seconds: i64 = sessions.iter().map(ReadingSession::duration).sum();
+    let pages_read: f64 = sessions.iter().map(|s| s.pages_read).sum();
+    ReadingTotals {
+        seconds,
+        sessions: sessions.len() as u32,
+        pages_read,
+        characters_read: sessions.iter().map(|s| s.characters_read).sum(),
+        pages_per_hour: if seconds > 0 {
+            pages_read * 3600.0 / seconds as f64
+        } else {
+            0.0
+        },
+    }
+}
+
+/// Spine item and progression within it for a position in the whole book,
+/// inverting [`crate::locator::Locator::from_epub_spine`]
+fn spine_position(spine_count: usize, total_progression: f64) -> (usize, f64) {
+    let scaled = total_progression.clamp(0.0, 1.0) * spine_count as f64;
+    let index = (scaled.floor() as usize).min(spine_count.saturating_sub(1));
+    (index, (scaled - index as f64).clamp(0.0, 1.0))
+}
+
+/// Characters before a position in the whole book
+fn char_offset(lengths: &[u64], total_progression: f64) -> u64 {
+    if lengths.is_empty() {
+        return 0;
+    }
+    let (index, within) = spine_position(lengths.len(), total_progression);
+    let before: u64 = lengths[..index].iter().sum();
+    before + (within * lengths[index] as f64).round() as u64
+}
+
+/// Most consecutive days in a set of days
+pub(crate) fn longest_streak(days: &BTreeSet<NaiveDate>) -> u32 {
+    let mut longest = 0;
+    let mut run = 0;
+    let mut previous: Option<NaiveDate> = None;
+    for &day in days {
+        run = match previous {
+            Some(previous) if day - previous == Duration::days(1) => run + 1,
+            _ => 1,
+        };
+        longest = longest.max(run);
+        previous = Some(day);
+    }
+    longest
+}
+
+/// Monday of the week of `day`
+pub(crate) fn week_start(day: NaiveDate) -> NaiveDate {
+    day - Duration::days(day.weekday().num_days_from_monday() as i64)
+}
+
+/// Calendar day of a Unix timestamp at `utc_offset_minutes` from UTC
+pub(crate) fn local_date(timestamp: i64, utc_offset_minutes: i32) -> NaiveDate {
+    DateTime::from_timestamp(timestamp + utc_offset_minutes as i64 * 60, 0)
+        .unwrap_or_default()
+        .date_naive()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::locator::Locator;
+
+    fn turn(db: &Database, book: &Book, page: u32, at: i64) -> ReadingSession {
+        let mut position = ReadingPosition::new(
+            book.id.clone(),
+            Locator::from_pdf_page(page, book.total_pages),
+        );
+        position.updated_at = at;
+        track_reading_position(db, &position).unwrap()
+    }
+
+    #[test]
+    fn test_sessions_and_stats() {
+        let db = Database::open_in_memory().unwrap();
+        let book = Book::new(
+            "Manual".to_string(),
+            None,
+            "/books/manual.pdf".to_string(),
+            BookType::Pdf,
+            200,
+        );
+        db.insert_book(&book).unwrap();
+
+        // 10 pages in 20 minutes, then a pause splits the session
+        let start = 1_741_773_600;
+        turn(&db, &book, 0, start);
+        for page in 1..=10 {
+            turn(&db, &book, page, start + page as i64 * 120);
+        }
+        let resumed = turn(&db, &book, 11, start + 3600);
+        assert_eq!(resumed.pages_read, 0.0);
+        // Paging back and reading again is not counted twice
+        turn(&db, &book, 5, start + 3660);
+        let session = turn(&db, &book, 8, start + 3720);
+        assert_eq!(session.pages_read, 0.0);
+        turn(&db, &book, 21, start + 3900);
+
+        // Now: an explicit session
+        let started = start_reading_session(&db, &book.id).unwrap();
+        assert_eq!(started.start_page, Some(21));
+        assert!(stop_reading_session(&db).unwrap().is_none());
+
+        let sessions = db.get_reading_sessions(&book.id).unwrap();
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions[0].duration(), 1200);
+        assert!((sessions[0].pages_read - 10.0).abs() < 1e-9);
+        assert!((sessions[1].pages_read - 10.0).abs() < 1e-9);
+        assert_eq!(sessions[1].duration(), 300);
+
+        let stats = reading_stats_at(&db, start + 2 * 86_400, 0).unwrap();
+        assert_eq!(stats.totals.seconds, 1500);
+        assert!((stats.totals.pages_per_hour - 48.0).abs() < 1e-9);
+        assert_eq!(stats.longest_streak_days, 1);
+        assert_eq!(stats.current_streak_days, 0);
+        // A streak lasts until the end of the day after the last reading
+        let stats = reading_stats_at(&db, start + 86_400, 0).unwrap();
+        assert_eq!(stats.current_streak_days, 1);
+
+        let days = reading_time_per_period(&db, StatsPeriod::Day, 0, 0).unwrap();
+        assert_eq!(days.len(), 1);
+        assert_eq!(days[0].totals.sessions, 2);
+        let per_book = reading_time_per_book(&db).unwrap();
+        assert_eq!(per_book[0].book_id, book.id);
+
+        // 179 pages left at 48 pages per hour
+        let left = estimate_time_left(&db, &book.id).unwrap();
+        assert_eq!(left.chapter_seconds, None);
+        assert_eq!(left.book_seconds, Some((179.0 * 75.0f64).round() as i64));
+    }
+
+    #[test]
+    fn test_char_offset() {
+        let lengths = [100, 300];
+        assert_eq!(char_offset(&lengths, 0.25), 50);
+        assert_eq!(char_offset(&lengths, 0.75), 250);
+        assert_eq!(char_offset(&lengths, 1.0), 400);
+        assert_eq!(spine_position(2, 0.75), (1, 0.5));
+    }
+}
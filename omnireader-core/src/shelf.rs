@@ -0,0 +1,543 @@
+//! Smart shelves: saved library queries
+//!
+//! A shelf query is a list of terms that all have to match, e.g.
+//! `author:le guin status:reading tag:scifi added:<30d format:epub progress:>50%`.
+//!
+//! - `field:value` matches one field; `-field:value` excludes matches
+//! - Text fields (`title`, `author`, `series`, `publisher`) match substrings;
+//!   `tag` and `collection` match whole names. Their values run until the next
+//!   term, or can be quoted: `author:"le guin"`
+//! - `status:` unread, reading, finished or abandoned; `format:` epub or pdf
+//! - Dates (`added`, `read`, `finished`) take an age such as `<30d` (within the
+//!   last 30 days; units d, w, m, y) or a day such as `>=2024-01-01`
+//! - Numbers (`progress` in percent, `rating` in stars, `pages`) take `<`,
+//!   `<=`, `>`, `>=` or `=` (the default)
+//! - Other words match the title or author
+//!
+//! Queries are compiled to SQL each time a shelf is listed, so shelves follow
+//! the library. Errors carry the character offset of the offending term.
+
+use crate::book::{BookType, ReadingStatus};
+use crate::error::OmniReaderError;
+use crate::library::PROGRESS;
+use chrono::NaiveDate;
+use rusqlite::types::Value;
+use uniffi;
+
+const DAY: i64 = 86_400;
+
+/// A saved query listed like a collection
+#[derive(Debug, Clone, PartialEq, Eq, uniffi::Record)]
+pub struct SmartShelf {
+    /// Unique identifier (UUID v4)
+    pub id: String,
+    pub name: String,
+    /// Query in the shelf query language
+    pub query: String,
+    /// Display order among smart shelves
+    pub position: u32,
+    /// Unix timestamp when the shelf was created
+    pub created_at: i64,
+}
+
+impl SmartShelf {
+    /// Create a new smart shelf with generated UUID
+    pub fn new(name: String, query: String, position: u32) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            name: name.trim().to_string(),
+            query: query.trim().to_string(),
+            position,
+            created_at: chrono::Utc::now().timestamp(),
+        }
+    }
+}
+
+/// Check a shelf query, failing with `InvalidQuery` at the first error
+#[uniffi::export]
+pub fn check_shelf_query(query: &str) -> Result<(), OmniReaderError> {
+    ShelfQuery::parse(query).map(|_| ())
+}
+
+/// A parsed shelf query
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ShelfQuery {
+    terms: Vec<Term>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Term {
+    negated: bool,
+    filter: Filter,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Filter {
+    /// Word in the title or author
+    Text(String),
+    /// Substring of a book column
+    Contains(&'static str, String),
+    Tag(String),
+    Collection(String),
+    Status(ReadingStatus),
+    Format(BookType),
+    /// Timestamp column compared to a date or age
+    Date(&'static str, DateBound),
+    Progress(Comparison, f64),
+    Rating(Comparison, f64),
+    Pages(Comparison, f64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Comparison {
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    Equal,
+}
+
+impl Comparison {
+    fn sql(self) -> &'static str {
+        match self {
+            Comparison::Less => "<",
+            Comparison::LessOrEqual => "<=",
+            Comparison::Greater => ">",
+            Comparison::GreaterOrEqual => ">=",
+            Comparison::Equal => "=",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DateBound {
+    /// Newer (`Less`) or older (`Greater`) than this many seconds
+    Age(Comparison, i64),
+    /// Compared to the day starting at this Unix timestamp (UTC)
+    Day(Comparison, i64),
+}
+
+/// Whitespace-separated piece of a query, with quotes removed
+struct Token {
+    /// Character offset of the first character
+    start: usize,
+    text: String,
+    /// Character offset of the value (after `field:`), if the token has one
+    value_start: Option<usize>,
+    quoted: bool,
+}
+
+const FIELDS: &[&str] = &[
+    "title",
+    "author",
+    "series",
+    "publisher",
+    "tag",
+    "collection",
+    "status",
+    "format",
+    "added",
+    "read",
+    "finished",
+    "progress",
+    "rating",
+    "pages",
+];
+
+/// Fields whose unquoted value continues over the following plain words
+const TEXT_FIELDS: &[&str] = &[
+    "title",
+    "author",
+    "series",
+    "publisher",
+    "tag",
+    "collection",
+];
+
+impl ShelfQuery {
+    /// Parse a query; an empty query matches every book
+    pub(crate) fn parse(query: &str) -> Result<Self, OmniReaderError> {
+        let tokens = tokenize(query)?;
+        let mut terms = Vec::new();
+        let mut index = 0;
+        while index < tokens.len() {
+            let token = &tokens[index];
+            index += 1;
+            let (negated, body) = match token.text.strip_prefix('-') {
+                Some(rest) if !rest.is_empty() => (true, rest),
+                _ => (false, token.text.as_str()),
+            };
+            let Some(field) = field_of(body) else {
+                if let Some((name, _)) = body.split_once(':')
+                    && !name.is_empty()
+                    && name.chars().all(|c| c.is_ascii_alphabetic())
+                    && !token.quoted
+                {
+                    return Err(invalid(
+                        format!("Unknown field '{}'", name),
+                        token.start + usize::from(negated),
+                    ));
+                }
+                terms.push(Term {
+                    negated,
+                    filter: Filter::Text(body.to_string()),
+                });
+                continue;
+            };
+
+            let mut value = body[field.len() + 1..].to_string();
+            let value_start = token.value_start.unwrap_or(token.start);
+            if TEXT_FIELDS.contains(&field) && !token.quoted {
+                while let Some(next) = tokens.get(index) {
+                    if next.quoted || next.value_start.is_some() || next.text.starts_with('-') {
+                        break;
+                    }
+                    value.push(' ');
+                    value.push_str(&next.text);
+                    index += 1;
+                }
+            }
+            if value.is_empty() {
+                return Err(invalid(
+                    format!("Missing value for '{}'", field),
+                    value_start,
+                ));
+            }
+            terms.push(Term {
+                negated,
+                filter: parse_filter(field, &value, value_start)?,
+            });
+        }
+        Ok(Self { terms })
+    }
+
+    /// Build the WHERE clause (over alias `b` for `books`) and its parameters,
+    /// with ages counted back from `now`
+    pub(crate) fn where_clause(&self, now: i64) -> (String, Vec<Value>) {
+        let mut conditions = Vec::new();
+        let mut values = Vec::new();
+        for term in &self.terms {
+            let condition = term.filter.condition(&mut values, now);
+            conditions.push(if term.negated {
+                // A book without a value (never read, no rating) matches the negation
+                format!("NOT COALESCE({}, 0)", condition)
+            } else {
+                condition
+            });
+        }
+        if conditions.is_empty() {
+            ("1".to_string(), values)
+        } else {
+            (conditions.join(" AND "), values)
+        }
+    }
+}
+
+impl Filter {
+    fn condition(&self, values: &mut Vec<Value>, now: i64) -> String {
+        let mut push = |value: Value| {
+            values.push(value);
+            format!("?{}", values.len())
+        };
+        match self {
+            Filter::Text(word) => {
+                let pattern = push(Value::Text(like_pattern(word)));
+                format!(
+                    "(b.title LIKE {0} ESCAPE '\\' OR b.author LIKE {0} ESCAPE '\\')",
+                    pattern
+                )
+            }
+            Filter::Contains(column, text) => format!(
+                "b.{} LIKE {} ESCAPE '\\'",
+                column,
+                push(Value::Text(like_pattern(text)))
+            ),
+            Filter::Tag(name) => format!(
+                "b.id IN (SELECT bt.book_id FROM book_tags bt JOIN tags t ON t.id = bt.tag_id \
+                 WHERE t.name = {} COLLATE NOCASE)",
+                push(Value::Text(name.clone()))
+            ),
+            Filter::Collection(name) => format!(
+                "b.id IN (SELECT cb.book_id FROM collection_books cb \
+                 JOIN collections c ON c.id = cb.collection_id WHERE c.name = {} COLLATE NOCASE)",
+                push(Value::Text(name.clone()))
+            ),
+            Filter::Status(status) => format!(
+                "b.reading_status = {}",
+                push(Value::Text(status.as_str().to_string()))
+            ),
+            Filter::Format(book_type) => format!(
+                "b.file_type = {}",
+                push(Value::Text(book_type.extension().to_string()))
+            ),
+            Filter::Date(column, DateBound::Age(comparison, seconds)) => {
+                let since = push(Value::Integer(now - seconds));
+                match comparison {
+                    Comparison::Greater | Comparison::GreaterOrEqual => {
+                        format!("b.{} < {}", column, since)
+                    }
+                    _ => format!("b.{} >= {}", column, since),
+                }
+            }
+            Filter::Date(column, DateBound::Day(comparison, day)) => {
+                let (start, end) = (*day, day + DAY);
+                match comparison {
+                    Comparison::Less => format!("b.{} < {}", column, push(Value::Integer(start))),
+                    Comparison::LessOrEqual => {
+                        format!("b.{} < {}", column, push(Value::Integer(end)))
+                    }
+                    Comparison::Greater => {
+                        format!("b.{} >= {}", column, push(Value::Integer(end)))
+                    }
+                    Comparison::GreaterOrEqual => {
+                        format!("b.{} >= {}", column, push(Value::Integer(start)))
+                    }
+                    Comparison::Equal => format!(
+                        "(b.{0} >= {1} AND b.{0} < {2})",
+                        column,
+                        push(Value::Integer(start)),
+                        push(Value::Integer(end))
+                    ),
+                }
+            }
+            Filter::Progress(comparison, percent) => format!(
+                "{} * 100 {} {}",
+                PROGRESS,
+                comparison.sql(),
+                push(Value::Real(*percent))
+            ),
+            Filter::Rating(comparison, stars) => format!(
+                "b.rating {} {}",
+                comparison.sql(),
+                push(Value::Real(stars * 2.0))
+            ),
+            Filter::Pages(comparison, pages) => format!(
+                "b.total_pages {} {}",
+                comparison.sql(),
+                push(Value::Real(*pages))
+            ),
+        }
+    }
+}
+
+/// Split a query at whitespace outside double quotes
+fn tokenize(query: &str) -> Result<Vec<Token>, OmniReaderError> {
+    let mut tokens = Vec::new();
+    let mut chars = query.chars().enumerate().peekable();
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        let mut token = Token {
+            start,
+            text: String::new(),
+            value_start: None,
+            quoted: false,
+        };
+        while let Some(&(offset, c)) = chars.peek() {
+            if c.is_whitespace() {
+                break;
+            }
+            chars.next();
+            if c == '"' {
+                token.quoted = true;
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, c)) => token.text.push(c),
+                        None => return Err(invalid("Unterminated quote".to_string(), offset)),
+                    }
+                }
+                continue;
+            }
+            token.text.push(c);
+            let body = token.text.strip_prefix('-').unwrap_or(&token.text);
+            if c == ':' && token.value_start.is_none() && field_of(body).is_some() {
+                token.value_start = Some(offset + 1);
+            }
+        }
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+/// The known field a `field:value` term starts with
+fn field_of(term: &str) -> Option<&'static str> {
+    let (name, _) = term.split_once(':')?;
+    let name = name.to_lowercase();
+    FIELDS.iter().copied().find(|field| *field == name)
+}
+
+fn parse_filter(
+    field: &'static str,
+    value: &str,
+    position: usize,
+) -> Result<Filter, OmniReaderError> {
+    Ok(match field {
+        "title" | "author" | "series" | "publisher" => Filter::Contains(field, value.to_string()),
+        "tag" => Filter::Tag(value.to_string()),
+        "collection" => Filter::Collection(value.to_string()),
+        "status" => {
+            Filter::Status(ReadingStatus::parse(&value.to_lowercase()).ok_or_else(|| {
+                invalid(
+                    format!(
+                        "Unknown status '{}'; expected unread, reading, finished or abandoned",
+                        value
+                    ),
+                    position,
+                )
+            })?)
+        }
+        "format" => Filter::Format(BookType::from_extension(value).ok_or_else(|| {
+            invalid(
+                format!("Unknown format '{}'; expected epub or pdf", value),
+                position,
+            )
+        })?),
+        "added" => Filter::Date("added_at", parse_date(value, position)?),
+        "read" => Filter::Date("last_read_at", parse_date(value, position)?),
+        "finished" => Filter::Date("finished_at", parse_date(value, position)?),
+        "progress" => {
+            let (comparison, number) = parse_comparison(value, position);
+            let number = number.strip_suffix('%').unwrap_or(number);
+            Filter::Progress(comparison.0, parse_number(number, comparison.1)?)
+        }
+        "rating" => {
+            let (comparison, number) = parse_comparison(value, position);
+            Filter::Rating(comparison.0, parse_number(number, comparison.1)?)
+        }
+        _ => {
+            let (comparison, number) = parse_comparison(value, position);
+            Filter::Pages(comparison.0, parse_number(number, comparison.1)?)
+        }
+    })
+}
+
+/// Split an optional comparison operator off a value at `position`; returns
+/// the comparison and the position of the rest, and the rest
+fn parse_comparison(value: &str, position: usize) -> ((Comparison, usize), &str) {
+    for (prefix, comparison) in [
+        ("<=", Comparison::LessOrEqual),
+        (">=", Comparison::GreaterOrEqual),
+        ("<", Comparison::Less),
+        (">", Comparison::Greater),
+        ("=", Comparison::Equal),
+    ] {
+        if let Some(rest) = value.strip_prefix(prefix) {
+            return ((comparison, position + prefix.len()), rest);
+        }
+    }
+    ((Comparison::Equal, position), value)
+}
+
+fn parse_number(text: &str, position: usize) -> Result<f64, OmniReaderError> {
+    text.parse::<f64>()
+        .ok()
+        .filter(|n| n.is_finite())
+        .ok_or_else(|| invalid(format!("Expected a number, found '{}'", text), position))
+}
+
+/// An age such as `<30d` or a day such as `>=2024-01-01`
+fn parse_date(value: &str, position: usize) -> Result<DateBound, OmniReaderError> {
+    let ((comparison, position), text) = parse_comparison(value, position);
+    if let Ok(day) = NaiveDate::parse_from_str(text, "%Y-%m-%d") {
+        let start = day.and_time(chrono::NaiveTime::MIN).and_utc().timestamp();
+        return Ok(DateBound::Day(comparison, start));
+    }
+    let age = text.char_indices().last().and_then(|(at, unit)| {
+        let seconds = match unit {
+            'd' => DAY,
+            'w' => 7 * DAY,
+            'm' => 30 * DAY,
+            'y' => 365 * DAY,
+            _ => return None,
+        };
+        let count: u32 = text[..at].parse().ok()?;
+        Some(count as i64 * seconds)
+    });
+    match age {
+        Some(seconds) => Ok(DateBound::Age(comparison, seconds)),
+        None => Err(invalid(
+            format!(
+                "Expected an age such as 30d or a date such as 2024-01-31, found '{}'",
+                text
+            ),
+            position,
+        )),
+    }
+}
+
+/// Escape `%`, `_` and `\` and match anywhere
+fn like_pattern(text: &str) -> String {
+    let mut pattern = String::from("%");
+    for c in text.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('%');
+    pattern
+}
+
+fn invalid(message: String, position: usize) -> OmniReaderError {
+    OmniReaderError::InvalidQuery {
+        message,
+        position: position as u32,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position(query: &str) -> u32 {
+        match ShelfQuery::parse(query) {
+            Err(OmniReaderError::InvalidQuery { position, .. }) => position,
+            other => panic!("expected an invalid query, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let query = ShelfQuery::parse(
+            "author:le guin status:reading -tag:\"sci fi\" added:<30d format:EPUB progress:>50% dragons",
+        )
+        .unwrap();
+        let filters: Vec<_> = query.terms.iter().map(|t| (t.negated, &t.filter)).collect();
+        assert_eq!(
+            filters,
+            vec![
+                (false, &Filter::Contains("author", "le guin".to_string())),
+                (false, &Filter::Status(ReadingStatus::Reading)),
+                (true, &Filter::Tag("sci fi".to_string())),
+                (
+                    false,
+                    &Filter::Date("added_at", DateBound::Age(Comparison::Less, 30 * DAY))
+                ),
+                (false, &Filter::Format(BookType::Epub)),
+                (false, &Filter::Progress(Comparison::Greater, 50.0)),
+                (false, &Filter::Text("dragons".to_string())),
+            ]
+        );
+
+        let (clause, values) = query.where_clause(100 * DAY);
+        assert!(clause.starts_with("b.author LIKE ?1"));
+        assert!(clause.contains("NOT COALESCE(b.id IN"));
+        assert!(clause.contains("b.added_at >= ?4"));
+        assert_eq!(values[0], Value::Text("%le guin%".to_string()));
+        assert_eq!(values[3], Value::Integer(70 * DAY));
+        assert_eq!(ShelfQuery::parse("  ").unwrap().where_clause(0).0, "1");
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(position("status:reading colour:red"), 15);
+        assert_eq!(position("status:done"), 7);
+        assert_eq!(position("format:epub added:<30x"), 19);
+        assert_eq!(position("progress:>lots"), 10);
+        assert_eq!(position("author:"), 7);
+        assert_eq!(position("title:\"open"), 6);
+    }
+}
@@ -0,0 +1,327 @@
+//! Reading status lifecycle and reread history
+//!
+//! Every time a book is read it gets a [`BookRead`] with its own dates and
+//! rating, and the book's `status`, `started_at` and `finished_at` follow the
+//! latest read. Statuses change through [`set_reading_status`], or on their
+//! own as the reading position crosses the [`StatusThresholds`].
+
+use crate::annotation::ReadingPosition;
+use crate::book::{Book, BookType, ReadingStatus};
+use crate::db::Database;
+use crate::error::OmniReaderError;
+use crate::locator::Locator;
+use serde::{Deserialize, Serialize};
+use uniffi;
+
+const THRESHOLDS_SETTING: &str = "reading.status_thresholds";
+
+/// One read of a book, from start to finish or abandonment
+#[derive(Debug, Clone, PartialEq, uniffi::Record)]
+pub struct BookRead {
+    /// Unique identifier (UUID v4)
+    pub id: String,
+    pub book_id: String,
+    /// `Reading`, `Finished` or `Abandoned`
+    pub status: ReadingStatus,
+    /// Unix timestamp
+    pub started_at: i64,
+    /// Unix timestamp the read was finished or abandoned
+    pub finished_at: Option<i64>,
+    /// Rating out of 10 (two per star)
+    pub rating: Option<u32>,
+}
+
+/// Reading progress at which status changes on its own
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, uniffi::Record)]
+pub struct StatusThresholds {
+    /// Percent (0.0 - 100.0) past which a book is being read
+    pub started_percent: f64,
+    /// Percent (0.0 - 100.0) past which a book is finished
+    pub finished_percent: f64,
+}
+
+impl Default for StatusThresholds {
+    fn default() -> Self {
+        Self {
+            started_percent: 1.0,
+            finished_percent: 99.0,
+        }
+    }
+}
+
+/// Get the automatic status thresholds
+pub fn get_status_thresholds(db: &Database) -> Result<StatusThresholds, OmniReaderError> {
+    match db.get_setting(THRESHOLDS_SETTING)? {
+        Some(json) => serde_json::from_str(&json).map_err(|e| OmniReaderError::ParseError {
+            message: format!("Invalid status thresholds: {}", e),
+        }),
+        None => Ok(StatusThresholds::default()),
+    }
+}
+
+/// Replace the automatic status thresholds
+pub fn set_status_thresholds(
+    db: &Database,
+    thresholds: &StatusThresholds,
+) -> Result<(), OmniReaderError> {
+    let StatusThresholds {
+        started_percent,
+        finished_percent,
+    } = *thresholds;
+    if !(0.0..=100.0).contains(&started_percent)
+        || !(0.0..=100.0).contains(&finished_percent)
+        || started_percent >= finished_percent
+    {
+        return Err(OmniReaderError::ParseError {
+            message: format!(
+                "Invalid status thresholds: started {}%, finished {}%",
+                started_percent, finished_percent
+            ),
+        });
+    }
+    let json = serde_json::to_string(thresholds).map_err(|e| OmniReaderError::ParseError {
+        message: format!("Failed to serialize status thresholds: {}", e),
+    })?;
+    db.set_setting(THRESHOLDS_SETTING, &json)
+}
+
+/// Change a book's reading status now
+///
+/// Finishing or abandoning closes the current read, or records a read that
+/// starts and ends now when none is in progress. Reading a book that was
+/// finished or abandoned starts a reread. Marking a book unread drops the
+/// read in progress and keeps the history.
+pub fn set_reading_status(
+    db: &Database,
+    book_id: &str,
+    status: ReadingStatus,
+) -> Result<Book, OmniReaderError> {
+    let book = db.require_book(book_id)?;
+    transition(db, &book, status, chrono::Utc::now().timestamp())?;
+    db.require_book(book_id)
+}
+
+/// Change the dates, status or rating of a past or current read
+pub fn update_book_read(db: &Database, read: &BookRead) -> Result<Book, OmniReaderError> {
+    let stored = db
+        .get_book_read(&read.id)?
+        .ok_or_else(|| OmniReaderError::Database {
+            message: format!("Read not found: {}", read.id),
+        })?;
+    if read.status == ReadingStatus::Unread {
+        return Err(OmniReaderError::ParseError {
+            message: "A read cannot be unread".to_string(),
+        });
+    }
+    db.save_book_read(&BookRead {
+        book_id: stored.book_id.clone(),
+        ..read.clone()
+    })?;
+    refresh_book_status(db, &stored.book_id)?;
+    db.require_book(&stored.book_id)
+}
+
+/// Delete a read from a book's history
+pub fn delete_book_read(db: &Database, read_id: &str) -> Result<Book, OmniReaderError> {
+    let read = db
+        .get_book_read(read_id)?
+        .ok_or_else(|| OmniReaderError::Database {
+            message: format!("Read not found: {}", read_id),
+        })?;
+    db.delete_book_read_row(read_id)?;
+    refresh_book_status(db, &read.book_id)?;
+    db.require_book(&read.book_id)
+}
+
+/// The most recently finished read of a book
+pub fn last_finished_read(
+    db: &Database,
+    book_id: &str,
+) -> Result<Option<BookRead>, OmniReaderError> {
+    Ok(db
+        .get_book_reads(book_id)?
+        .into_iter()
+        .filter(|read| read.status == ReadingStatus::Finished)
+        .max_by_key(|read| read.finished_at))
+}
+
+/// Change status as the position moves from `previous` to `current` past a
+/// threshold, at Unix timestamp `at`
+pub(crate) fn apply_status_thresholds(
+    db: &Database,
+    book: &Book,
+    previous: Option<&Locator>,
+    current: &Locator,
+    at: i64,
+) -> Result<(), OmniReaderError> {
+    let thresholds = get_status_thresholds(db)?;
+    let from = previous.map_or(0.0, |locator| completion(book, locator));
+    let to = completion(book, current);
+    let crossed = |threshold: f64| from < threshold && threshold <= to;
+    if crossed(thresholds.finished_percent) && book.status != ReadingStatus::Finished {
+        transition(db, book, ReadingStatus::Finished, at)?;
+    } else if crossed(thresholds.started_percent) && book.status != ReadingStatus::Reading {
+        transition(db, book, ReadingStatus::Reading, at)?;
+    }
+    Ok(())
+}
+
+/// Save a reading position reached by reading, here or in another reader,
+/// changing status as it crosses a threshold at `position.updated_at`
+///
+/// Positions from shared-folder sync are saved as they are: the status they
+/// led to on the other device is synced along with them.
+pub(crate) fn save_read_position(
+    db: &Database,
+    book: &Book,
+    position: &ReadingPosition,
+) -> Result<(), OmniReaderError> {
+    let previous = db.get_reading_position(&book.id)?;
+    db.save_reading_position(position)?;
+    apply_status_thresholds(
+        db,
+        book,
+        previous.as_ref().map(|p| &p.locator),
+        &position.locator,
+        position.updated_at,
+    )
+}
+
+/// Percent of the book read up to a position; the last PDF page is the end
+/// even though its locator starts short of 100%
+fn completion(book: &Book, locator: &Locator) -> f64 {
+    match (book.file_type, locator.page) {
+        (BookType::Pdf, Some(page)) if page + 1 >= book.total_pages => 100.0,
+        _ => locator.percent(),
+    }
+}
+
+fn transition(
+    db: &Database,
+    book: &Book,
+    status: ReadingStatus,
+    at: i64,
+) -> Result<(), OmniReaderError> {
+    let current = match book.status {
+        ReadingStatus::Reading => db
+            .get_book_reads(&book.id)?
+            .into_iter()
+            .rfind(|read| read.status == ReadingStatus::Reading),
+        _ => None,
+    };
+    match (status, current) {
+        (ReadingStatus::Unread, current) => {
+            if let Some(read) = current {
+                db.delete_book_read_row(&read.id)?;
+            }
+            return db.set_book_status(&book.id, ReadingStatus::Unread, None, None);
+        }
+        (ReadingStatus::Reading, Some(_)) => {}
+        (ReadingStatus::Reading, None) => db.save_book_read(&new_read(book, status, at, None))?,
+        (_, Some(read)) => db.save_book_read(&BookRead {
+            status,
+            finished_at: Some(at.max(read.started_at)),
+            ..read
+        })?,
+        (_, None) if book.status == status => {}
+        (_, None) => db.save_book_read(&new_read(book, status, at, Some(at)))?,
+    }
+    refresh_book_status(db, &book.id)
+}
+
+fn new_read(book: &Book, status: ReadingStatus, at: i64, finished_at: Option<i64>) -> BookRead {
+    BookRead {
+        id: uuid::Uuid::new_v4().to_string(),
+        book_id: book.id.clone(),
+        status,
+        started_at: at,
+        finished_at,
+        rating: None,
+    }
+}
+
+/// Make the book's status and dates follow its latest read
+fn refresh_book_status(db: &Database, book_id: &str) -> Result<(), OmniReaderError> {
+    match db.get_book_reads(book_id)?.pop() {
+        Some(read) => db.set_book_status(
+            book_id,
+            read.status,
+            Some(read.started_at),
+            read.finished_at,
+        ),
+        None => db.set_book_status(book_id, ReadingStatus::Unread, None, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::annotation::ReadingPosition;
+    use crate::session::track_reading_position;
+
+    #[test]
+    fn test_status_lifecycle() {
+        let db = Database::open_in_memory().unwrap();
+        let book = Book::new(
+            "Dune".to_string(),
+            None,
+            "/books/dune.pdf".to_string(),
+            BookType::Pdf,
+            50,
+        );
+        db.insert_book(&book).unwrap();
+        let turn = |page: u32, at: i64| {
+            let mut position =
+                ReadingPosition::new(book.id.clone(), Locator::from_pdf_page(page, 50));
+            position.updated_at = at;
+            track_reading_position(&db, &position).unwrap();
+            db.get_book(&book.id).unwrap().unwrap()
+        };
+
+        assert_eq!(turn(0, 1000).status, ReadingStatus::Unread);
+        let reading = turn(1, 1100);
+        assert_eq!(reading.status, ReadingStatus::Reading);
+        assert_eq!(reading.started_at, Some(1100));
+        let finished = turn(49, 2000);
+        assert_eq!(finished.status, ReadingStatus::Finished);
+        assert_eq!(finished.finished_at, Some(2000));
+
+        // Going back to the end does not finish it again; starting over is a reread
+        turn(30, 3000);
+        assert_eq!(turn(49, 3100).status, ReadingStatus::Finished);
+        turn(0, 4000);
+        assert_eq!(turn(2, 4100).status, ReadingStatus::Reading);
+        let abandoned = set_reading_status(&db, &book.id, ReadingStatus::Abandoned).unwrap();
+        assert_eq!(abandoned.started_at, Some(4100));
+
+        let reads = db.get_book_reads(&book.id).unwrap();
+        assert_eq!(reads.len(), 2);
+        assert_eq!(reads[1].status, ReadingStatus::Abandoned);
+        let last = last_finished_read(&db, &book.id).unwrap().unwrap();
+        assert_eq!(last.finished_at, Some(2000));
+
+        let rated = BookRead {
+            rating: Some(8),
+            ..last
+        };
+        update_book_read(&db, &rated).unwrap();
+        assert_eq!(db.get_book_reads(&book.id).unwrap()[0].rating, Some(8));
+        let book = delete_book_read(&db, &reads[1].id).unwrap();
+        assert_eq!(book.status, ReadingStatus::Finished);
+
+        let unread = set_reading_status(&db, &book.id, ReadingStatus::Unread).unwrap();
+        assert_eq!(unread.status, ReadingStatus::Unread);
+        assert_eq!(db.get_book_reads(&book.id).unwrap().len(), 1);
+
+        assert!(
+            set_status_thresholds(
+                &db,
+                &StatusThresholds {
+                    started_percent: 50.0,
+                    finished_percent: 10.0,
+                }
+            )
+            .is_err()
+        );
+    }
+}
@@ -0,0 +1,1005 @@
+//! Multi-device sync through a shared folder (Syncthing, Nextcloud, ...)
+//!
+//! Every device appends its changes to its own journal,
+//! `<folder>/<device id>.jsonl`, one JSON change per line, and never writes
+//! to another device's journal, so file-sync tools never see conflicting
+//! edits. A change sets one field of a book, annotation, palette color or
+//! reading position and is stamped with a hybrid logical clock (HLC).
+//!
+//! Merging is last-writer-wins per field: the change with the greatest HLC
+//! wins, and HLCs are totally ordered (ties broken by device ID), so every
+//! device converges on the same state whatever order it reads journals in.
+//! Deletes are recorded as a `deleted` tombstone field and are final.
+//!
+//! Local changes are found by comparing the library with the last synced
+//! value of every field, so no other code path needs to record them. Their
+//! HLC is taken from when the row was last written, which triggers record,
+//! so the latest edit wins even when another device syncs after it.
+//!
+//! With a passphrase, the store is encrypted: every journal line is sealed
+//! with the store's keyring (see [`crate::crypto`]), kept in the store as
+//! `keyring.json`, and lines that do not open are ignored. A store is
+//! encrypted from its first sync on; once it has a keyring, syncing without
+//! the passphrase fails rather than writing plaintext.
+
+use crate::crypto::Keyring;
+use crate::db::Database;
+use crate::error::OmniReaderError;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use rusqlite::types::Value as SqlValue;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use uniffi;
+
+/// Field name of deletion tombstones
+const DELETED: &str = "deleted";
+/// Associated data of sealed journal lines
+const JOURNAL_AAD: &[u8] = b"journal";
+
+/// Columns synced as a single field, so a moved highlight never mixes the
+/// range of one device with the offsets of another
+const LOCATOR_COLUMNS: &[&str] = &[
+    "href",
+    "page",
+    "progression",
+    "total_progression",
+    "cfi",
+    "start_offset",
+    "end_offset",
+    "text_before",
+    "text_highlight",
+    "text_after",
+    "end_total_progression",
+];
+
+/// A synced table and how its columns map to fields
+struct Entity {
+    /// Name used in journals
+    name: &'static str,
+    table: &'static str,
+    key: &'static str,
+    /// Field name and the columns it covers
+    fields: &'static [(&'static str, &'static [&'static str])],
+}
+
+/// Synced entities, in the order they are written (books and palette colors
+/// before what refers to them)
+const ENTITIES: &[Entity] = &[
+    Entity {
+        name: "book",
+        table: "books",
+        key: "id",
+        fields: &[
+            ("title", &["title"]),
+            ("author", &["author"]),
+            ("file_path", &["file_path"]),
+            ("file_type", &["file_type"]),
+            ("added_at", &["added_at"]),
+            ("last_read_at", &["last_read_at"]),
+            ("total_pages", &["total_pages"]),
+            ("author_sort", &["author_sort"]),
+            ("series", &["series"]),
+            ("series_index", &["series_index"]),
+            ("publisher", &["publisher"]),
+            ("description", &["description"]),
+            ("rating", &["rating"]),
+            ("status", &["reading_status", "started_at", "finished_at"]),
+        ],
+    },
+    Entity {
+        name: "read",
+        table: "book_reads",
+        key: "id",
+        fields: &[
+            ("book_id", &["book_id"]),
+            ("status", &["status"]),
+            ("started_at", &["started_at"]),
+            ("finished_at", &["finished_at"]),
+            ("rating", &["rating"]),
+        ],
+    },
+    Entity {
+        name: "palette",
+        table: "palette_colors",
+        key: "id",
+        fields: &[
+            ("name", &["name"]),
+            ("hex", &["hex"]),
+            ("position", &["position"]),
+        ],
+    },
+    Entity {
+        name: "annotation",
+        table: "annotations",
+        key: "id",
+        fields: &[
+            ("book_id", &["book_id"]),
+            ("annotation_type", &["annotation_type"]),
+            ("color", &["color"]),
+            ("selected_text", &["selected_text"]),
+            ("note_text", &["note_text"]),
+            ("chapter_title", &["chapter_title"]),
+            ("created_at", &["created_at"]),
+            ("updated_at", &["updated_at"]),
+            ("anchor_status", &["anchor_status"]),
+            ("locator", LOCATOR_COLUMNS),
+        ],
+    },
+    Entity {
+        name: "position",
+        table: "reading_positions",
+        key: "book_id",
+        fields: &[
+            ("updated_at", &["updated_at"]),
+            ("locator", LOCATOR_COLUMNS),
+        ],
+    },
+];
+
+/// Outcome of a sync run
+#[derive(Debug, Clone, Default, PartialEq, uniffi::Record)]
+pub struct SyncReport {
+    /// Local changes appended to this device's journal
+    pub sent: u32,
+    /// Changes from other devices that won and were applied
+    pub received: u32,
+    /// Rows from other devices that could not be written locally (e.g. a book
+    /// whose file path is already taken by another book); retried on every sync
+    pub skipped: u32,
+    /// Book files uploaded, for transports that store them
+    pub books_uploaded: u32,
+}
+
+/// The last synced value and version of one field
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct SyncField {
+    pub entity: String,
+    pub entity_id: String,
+    pub field: String,
+    /// JSON-encoded value
+    pub value: String,
+    /// Encoded [`Hlc`] of the change that set the value
+    pub hlc: String,
+}
+
+/// One line of a journal
+#[derive(Debug, Serialize, Deserialize)]
+struct Change {
+    hlc: String,
+    entity: String,
+    id: String,
+    field: String,
+    value: Value,
+}
+
+/// Hybrid logical clock: wall-clock milliseconds, a counter for events
+/// within the same millisecond, and the device ID as a tie-breaker
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Hlc {
+    millis: u64,
+    counter: u32,
+    device: String,
+}
+
+impl Hlc {
+    fn zero(device: &str) -> Self {
+        Self {
+            millis: 0,
+            counter: 0,
+            device: device.to_string(),
+        }
+    }
+
+    /// Advance the clock for a local event
+    fn tick(&mut self, now: u64) -> Hlc {
+        if now > self.millis {
+            self.millis = now;
+            self.counter = 0;
+        } else {
+            self.counter += 1;
+        }
+        self.clone()
+    }
+
+    /// Version a local edit made at `edited_at`, ordered after the version
+    /// of the value it replaces
+    fn edit(device: &str, edited_at: u64, replaced: Option<&Hlc>) -> Hlc {
+        let stamp = Hlc {
+            millis: edited_at,
+            counter: 0,
+            device: device.to_string(),
+        };
+        match replaced {
+            Some(replaced) if *replaced >= stamp => Hlc {
+                millis: replaced.millis,
+                counter: replaced.counter + 1,
+                device: device.to_string(),
+            },
+            _ => stamp,
+        }
+    }
+
+    /// Move the clock past a timestamp received from another device
+    fn observe(&mut self, other: &Hlc, now: u64) {
+        let millis = now.max(self.millis).max(other.millis);
+        self.counter = if millis == self.millis && millis == other.millis {
+            self.counter.max(other.counter) + 1
+        } else if millis == self.millis {
+            self.counter + 1
+        } else if millis == other.millis {
+            other.counter + 1
+        } else {
+            0
+        };
+        self.millis = millis;
+    }
+
+    /// Fixed-width encoding, `<millis>-<counter>-<device>`
+    fn encode(&self) -> String {
+        format!("{:013}-{:06}-{}", self.millis, self.counter, self.device)
+    }
+
+    fn parse(s: &str) -> Option<Hlc> {
+        let mut parts = s.splitn(3, '-');
+        Some(Hlc {
+            millis: parts.next()?.parse().ok()?,
+            counter: parts.next()?.parse().ok()?,
+            device: parts.next()?.to_string(),
+        })
+    }
+}
+
+impl Ord for Hlc {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.millis, self.counter, &self.device).cmp(&(other.millis, other.counter, &other.device))
+    }
+}
+
+impl PartialOrd for Hlc {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Local changes per journal batch, bounding the size of each upload
+const BATCH_CHANGES: usize = 1000;
+
+/// How far another device's journal has been read
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct JournalCursor {
+    /// Bytes of the journal consumed, always at a line boundary
+    pub offset: u64,
+    /// Version of the journal at `offset`, for stores that track one
+    pub etag: Option<String>,
+}
+
+/// Bytes read from a device's journal
+pub(crate) struct JournalChunk {
+    /// Offset of the first byte
+    pub start: u64,
+    pub bytes: Vec<u8>,
+    /// Version of the journal these bytes were read from
+    pub etag: Option<String>,
+}
+
+/// Where device journals are shared
+pub(crate) trait JournalStore {
+    /// IDs of the devices with a journal in the store
+    fn devices(&mut self) -> Result<Vec<String>, OmniReaderError>;
+
+    /// A device's journal from `cursor`, or `None` when it has not changed
+    ///
+    /// A journal shorter than the cursor was replaced (e.g. by a conflict
+    /// copy) and is read again from the start; merging is idempotent.
+    fn read(
+        &mut self,
+        device: &str,
+        cursor: &JournalCursor,
+    ) -> Result<Option<JournalChunk>, OmniReaderError>;
+
+    /// Append a batch of complete lines to a device's journal
+    ///
+    /// `seq` identifies the batch; appending a batch again after an
+    /// interrupted attempt must be harmless.
+    fn append(&mut self, device: &str, seq: i64, batch: &[u8]) -> Result<(), OmniReaderError>;
+
+    /// The keyring of an encrypted store
+    fn keyring(&mut self) -> Result<Option<Vec<u8>>, OmniReaderError>;
+
+    /// Store the keyring, returning whether it was written
+    ///
+    /// Without `replace`, an existing keyring is left alone, so two devices
+    /// encrypting a new store at once end up sharing one.
+    fn save_keyring(&mut self, keyring: &[u8], replace: bool) -> Result<bool, OmniReaderError>;
+}
+
+/// Name of the keyring of an encrypted folder
+const KEYRING_FILE: &str = "keyring.json";
+
+/// Journals kept as `<device id>.jsonl` files in a local folder
+struct FolderStore<'a> {
+    folder: &'a Path,
+}
+
+impl FolderStore<'_> {
+    fn journal(&self, device: &str) -> std::path::PathBuf {
+        self.folder.join(format!("{}.jsonl", device))
+    }
+}
+
+impl JournalStore for FolderStore<'_> {
+    fn devices(&mut self) -> Result<Vec<String>, OmniReaderError> {
+        let mut devices: Vec<String> = std::fs::read_dir(self.folder)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "jsonl"))
+            .filter_map(|path| path.file_stem().map(|s| s.to_string_lossy().to_string()))
+            .collect();
+        devices.sort();
+        Ok(devices)
+    }
+
+    fn read(
+        &mut self,
+        device: &str,
+        cursor: &JournalCursor,
+    ) -> Result<Option<JournalChunk>, OmniReaderError> {
+        let mut file = File::open(self.journal(device))?;
+        let len = file.metadata()?.len();
+        let start = if cursor.offset > len {
+            0
+        } else {
+            cursor.offset
+        };
+        file.seek(SeekFrom::Start(start))?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        Ok(Some(JournalChunk {
+            start,
+            bytes,
+            etag: None,
+        }))
+    }
+
+    fn append(&mut self, device: &str, _seq: i64, batch: &[u8]) -> Result<(), OmniReaderError> {
+        let path = self.journal(device);
+        let mut journal = OpenOptions::new().create(true).append(true).open(&path)?;
+        // Terminate a line cut short by an interrupted append, so it cannot
+        // swallow the first line of this batch
+        let len = journal.metadata()?.len();
+        if len > 0 {
+            let mut last = [0u8];
+            let mut reader = File::open(&path)?;
+            reader.seek(SeekFrom::Start(len - 1))?;
+            reader.read_exact(&mut last)?;
+            if last[0] != b'\n' {
+                journal.write_all(b"\n")?;
+            }
+        }
+        journal.write_all(batch)?;
+        journal.sync_all()?;
+        Ok(())
+    }
+
+    fn keyring(&mut self) -> Result<Option<Vec<u8>>, OmniReaderError> {
+        match std::fs::read(self.folder.join(KEYRING_FILE)) {
+            Ok(keyring) => Ok(Some(keyring)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save_keyring(&mut self, keyring: &[u8], replace: bool) -> Result<bool, OmniReaderError> {
+        let path = self.folder.join(KEYRING_FILE);
+        if !replace {
+            return match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    file.write_all(keyring)?;
+                    file.sync_all()?;
+                    Ok(true)
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(false),
+                Err(e) => Err(e.into()),
+            };
+        }
+        // Replaced in one step, so no device ever reads half a keyring
+        let partial = self.folder.join(format!("{}.partial", KEYRING_FILE));
+        std::fs::write(&partial, keyring)?;
+        std::fs::rename(&partial, &path)?;
+        Ok(true)
+    }
+}
+
+/// Sync the library with the other devices' journals in `folder`
+///
+/// Local changes since the last sync are appended to this device's journal
+/// first, then new lines of every other journal are merged in. With a
+/// `passphrase`, the folder is encrypted.
+pub fn sync_with_folder(
+    db: &Database,
+    folder: &str,
+    passphrase: Option<&str>,
+) -> Result<SyncReport, OmniReaderError> {
+    let folder = Path::new(folder);
+    std::fs::create_dir_all(folder)?;
+    sync_with_store(db, &mut FolderStore { folder }, passphrase)
+}
+
+/// Seal an encrypted folder's future changes with a new key, optionally
+/// changing its passphrase
+///
+/// Journal lines already written stay sealed with the keys they were
+/// written with.
+pub fn rotate_folder_sync_key(
+    folder: &str,
+    passphrase: &str,
+    new_passphrase: Option<&str>,
+) -> Result<(), OmniReaderError> {
+    rotate_store_key(
+        &mut FolderStore {
+            folder: Path::new(folder),
+        },
+        passphrase,
+        new_passphrase,
+    )
+}
+
+/// Rotate the data key of an encrypted store
+pub(crate) fn rotate_store_key(
+    store: &mut impl JournalStore,
+    passphrase: &str,
+    new_passphrase: Option<&str>,
+) -> Result<(), OmniReaderError> {
+    let stored = store
+        .keyring()?
+        .ok_or_else(|| OmniReaderError::Encryption {
+            message: "Sync store is not encrypted".to_string(),
+        })?;
+    let mut keyring = Keyring::unlock(&stored, passphrase)?;
+    keyring.rotate(new_passphrase)?;
+    store.save_keyring(&keyring.to_bytes(), true)?;
+    Ok(())
+}
+
+/// Unlock an encrypted store's keyring, or encrypt a store not used yet
+fn unlock_store(
+    store: &mut impl JournalStore,
+    passphrase: &str,
+) -> Result<Keyring, OmniReaderError> {
+    if let Some(stored) = store.keyring()? {
+        return Keyring::unlock(&stored, passphrase);
+    }
+    let keyring = Keyring::create(passphrase)?;
+    if store.save_keyring(&keyring.to_bytes(), false)? {
+        return Ok(keyring);
+    }
+    // Another device created the keyring first
+    let stored = store
+        .keyring()?
+        .ok_or_else(|| OmniReaderError::Encryption {
+            message: "Sync store keyring disappeared".to_string(),
+        })?;
+    Keyring::unlock(&stored, passphrase)
+}
+
+/// Sync the library through any journal store
+pub(crate) fn sync_with_store(
+    db: &Database,
+    store: &mut impl JournalStore,
+    passphrase: Option<&str>,
+) -> Result<SyncReport, OmniReaderError> {
+    let devices = store.devices()?;
+    let keyring = match passphrase {
+        Some(passphrase) => Some(unlock_store(store, passphrase)?),
+        None if store.keyring()?.is_some() => {
+            return Err(OmniReaderError::Encryption {
+                message: "Sync store is encrypted; a passphrase is required".to_string(),
+            });
+        }
+        None => None,
+    };
+
+    let (device_id, mut clock) = match db.sync_state()? {
+        Some((device_id, clock)) => {
+            let clock = Hlc::parse(&clock).unwrap_or_else(|| Hlc::zero(&device_id));
+            (device_id, clock)
+        }
+        None => {
+            let device_id = uuid::Uuid::new_v4().to_string();
+            let clock = Hlc::zero(&device_id);
+            (device_id, clock)
+        }
+    };
+
+    let mut known: HashMap<(String, String, String), SyncField> = db
+        .sync_fields()?
+        .into_iter()
+        .map(|f| ((f.entity.clone(), f.entity_id.clone(), f.field.clone()), f))
+        .collect();
+    let pending: BTreeSet<(String, String)> = db.sync_pending()?.into_iter().collect();
+    let mut report = SyncReport::default();
+
+    // Local changes are recorded and queued together, then written to the
+    // store; batches left over by an interrupted sync go out first
+    let local = local_changes(db, &known, &pending, &mut clock)?;
+    if !local.is_empty() {
+        let mut batches = Vec::new();
+        for chunk in local.chunks(BATCH_CHANGES) {
+            let mut lines = String::new();
+            for change in chunk {
+                let line = serde_json::to_string(change).map_err(json_error)?;
+                match &keyring {
+                    Some(keyring) => {
+                        lines.push_str(&BASE64.encode(keyring.seal(line.as_bytes(), JOURNAL_AAD)?))
+                    }
+                    None => lines.push_str(&line),
+                }
+                lines.push('\n');
+            }
+            batches.push(lines);
+        }
+        let fields: Vec<SyncField> = local.into_iter().map(Change::into_field).collect();
+        db.queue_sync_batches(&fields, &batches)?;
+        db.save_sync_state(&device_id, &clock.encode())?;
+        for field in fields {
+            known.insert(field_key(&field), field);
+        }
+    }
+    for (seq, batch) in db.sync_outbox()? {
+        store.append(&device_id, seq, batch.as_bytes())?;
+        db.remove_sync_batch(seq)?;
+        report.sent += batch.lines().count() as u32;
+    }
+
+    // Other devices' changes
+    let mut winners = Vec::new();
+    for other in devices {
+        if other == device_id {
+            continue;
+        }
+        let cursor = db.sync_cursor(&other)?;
+        let Some(chunk) = store.read(&other, &cursor)? else {
+            continue;
+        };
+        // A journal still being synced may end in the middle of a line
+        let complete = chunk
+            .bytes
+            .iter()
+            .rposition(|&b| b == b'\n')
+            .map_or(0, |i| i + 1);
+        let changes = String::from_utf8_lossy(&chunk.bytes[..complete])
+            .lines()
+            .filter_map(|line| match &keyring {
+                Some(keyring) => {
+                    let sealed = BASE64.decode(line).ok()?;
+                    serde_json::from_slice(&keyring.open(&sealed, JOURNAL_AAD).ok()?).ok()
+                }
+                None => serde_json::from_str::<Change>(line).ok(),
+            })
+            .collect::<Vec<_>>();
+        for change in changes {
+            let Some(hlc) = Hlc::parse(&change.hlc) else {
+                continue;
+            };
+            clock.observe(&hlc, now_millis());
+            let field = change.into_field();
+            let key = field_key(&field);
+            let newer = known
+                .get(&key)
+                .and_then(|current| Hlc::parse(&current.hlc))
+                .is_none_or(|current| hlc > current);
+            if newer {
+                winners.push(field.clone());
+                known.insert(key, field);
+            }
+        }
+        let cursor = JournalCursor {
+            offset: chunk.start + complete as u64,
+            etag: chunk.etag.filter(|_| complete == chunk.bytes.len()),
+        };
+        db.save_sync_cursor(&other, &cursor)?;
+    }
+    report.received = winners.len() as u32;
+    db.save_sync_fields(&winners)?;
+
+    // Write merged state of every entity that received a change, and retry
+    // the ones that could not be written last time
+    let mut touched: BTreeSet<(String, String)> = winners
+        .iter()
+        .map(|f| (f.entity.clone(), f.entity_id.clone()))
+        .collect();
+    touched.extend(pending);
+    let mut still_pending = Vec::new();
+    for entity in ENTITIES {
+        let mut upserts = Vec::new();
+        let mut deletes = Vec::new();
+        for (_, id) in touched.iter().filter(|(name, _)| name == entity.name) {
+            let state =
+                |field: &str| known.get(&(entity.name.to_string(), id.clone(), field.to_string()));
+            if state(DELETED).is_some() {
+                deletes.push(id.clone());
+                continue;
+            }
+            let mut values = Vec::new();
+            for (field, columns) in entity.fields {
+                let value = state(field)
+                    .and_then(|f| serde_json::from_str(&f.value).ok())
+                    .unwrap_or(Value::Null);
+                values.extend(json_to_columns(&value, columns.len()));
+            }
+            upserts.push((id.clone(), values));
+        }
+        if upserts.is_empty() && deletes.is_empty() {
+            continue;
+        }
+        let failed = db.apply_sync_rows(
+            entity.table,
+            entity.key,
+            &columns(entity),
+            &upserts,
+            &deletes,
+        )?;
+        still_pending.extend(failed.into_iter().map(|id| (entity.name.to_string(), id)));
+    }
+    report.skipped = still_pending.len() as u32;
+    db.set_sync_pending(&still_pending)?;
+
+    db.save_sync_state(&device_id, &clock.encode())?;
+    Ok(report)
+}
+
+/// Fields whose value in the library differs from the last synced one
+fn local_changes(
+    db: &Database,
+    known: &HashMap<(String, String, String), SyncField>,
+    pending: &BTreeSet<(String, String)>,
+    clock: &mut Hlc,
+) -> Result<Vec<Change>, OmniReaderError> {
+    let mut changes = Vec::new();
+    for entity in ENTITIES {
+        let rows = db.sync_rows(entity.table, entity.key, &columns(entity))?;
+        let edited = db.sync_change_times(entity.table)?;
+        let mut version = |id: &str, field: &str| {
+            let replaced = known
+                .get(&(entity.name.to_string(), id.to_string(), field.to_string()))
+                .and_then(|f| Hlc::parse(&f.hlc));
+            match edited.get(id) {
+                Some(&edited_at) => Hlc::edit(&clock.device, edited_at, replaced.as_ref()),
+                // Written before edit times were recorded
+                None => clock.tick(now_millis()),
+            }
+            .encode()
+        };
+        let mut present = BTreeSet::new();
+        for (id, values) in rows {
+            let tombstoned =
+                known.contains_key(&(entity.name.to_string(), id.clone(), DELETED.to_string()));
+            present.insert(id.clone());
+            if tombstoned {
+                continue;
+            }
+            let mut values = values.into_iter();
+            for (field, columns) in entity.fields {
+                let value = columns_to_json(values.by_ref().take(columns.len()).collect());
+                let unchanged = known
+                    .get(&(entity.name.to_string(), id.clone(), field.to_string()))
+                    .and_then(|f| serde_json::from_str::<Value>(&f.value).ok())
+                    .is_some_and(|synced| synced == value);
+                if !unchanged {
+                    changes.push(Change {
+                        hlc: version(&id, field),
+                        entity: entity.name.to_string(),
+                        id: id.clone(),
+                        field: field.to_string(),
+                        value,
+                    });
+                }
+            }
+        }
+
+        // Entities synced before but gone from the library were deleted here;
+        // pending ones were never written in the first place
+        let deleted: BTreeSet<&str> = known
+            .keys()
+            .filter(|(name, id, _)| {
+                name == entity.name
+                    && !present.contains(id)
+                    && !pending.contains(&(name.clone(), id.clone()))
+            })
+            .map(|(_, id, _)| id.as_str())
+            .collect();
+        for id in deleted {
+            let key = (entity.name.to_string(), id.to_string(), DELETED.to_string());
+            if !known.contains_key(&key) {
+                changes.push(Change {
+                    hlc: version(id, DELETED),
+                    entity: entity.name.to_string(),
+                    id: id.to_string(),
+                    field: DELETED.to_string(),
+                    value: Value::Bool(true),
+                });
+            }
+        }
+    }
+    Ok(changes)
+}
+
+impl Change {
+    fn into_field(self) -> SyncField {
+        SyncField {
+            entity: self.entity,
+            entity_id: self.id,
+            field: self.field,
+            value: self.value.to_string(),
+            hlc: self.hlc,
+        }
+    }
+}
+
+fn field_key(field: &SyncField) -> (String, String, String) {
+    (
+        field.entity.clone(),
+        field.entity_id.clone(),
+        field.field.clone(),
+    )
+}
+
+fn columns(entity: &Entity) -> Vec<&'static str> {
+    entity
+        .fields
+        .iter()
+        .flat_map(|(_, columns)| columns.iter().copied())
+        .collect()
+}
+
+/// A field's value: the column itself, or an array for multi-column fields
+fn columns_to_json(values: Vec<SqlValue>) -> Value {
+    let mut json: Vec<Value> = values
+        .into_iter()
+        .map(|value| match value {
+            SqlValue::Null | SqlValue::Blob(_) => Value::Null,
+            SqlValue::Integer(i) => Value::from(i),
+            SqlValue::Real(f) => Value::from(f),
+            SqlValue::Text(s) => Value::String(s),
+        })
+        .collect();
+    if json.len() == 1 {
+        json.remove(0)
+    } else {
+        Value::Array(json)
+    }
+}
+
+fn json_to_columns(value: &Value, count: usize) -> Vec<SqlValue> {
+    let single = |value: &Value| match value {
+        Value::Bool(b) => SqlValue::Integer(*b as i64),
+        Value::Number(n) => n
+            .as_i64()
+            .map(SqlValue::Integer)
+            .or_else(|| n.as_f64().map(SqlValue::Real))
+            .unwrap_or(SqlValue::Null),
+        Value::String(s) => SqlValue::Text(s.clone()),
+        _ => SqlValue::Null,
+    };
+    match value {
+        Value::Array(items) if count > 1 => (0..count)
+            .map(|i| items.get(i).map_or(SqlValue::Null, single))
+            .collect(),
+        _ if count > 1 => vec![SqlValue::Null; count],
+        _ => vec![single(value)],
+    }
+}
+
+fn now_millis() -> u64 {
+    chrono::Utc::now().timestamp_millis().max(0) as u64
+}
+
+fn json_error(e: serde_json::Error) -> OmniReaderError {
+    OmniReaderError::ParseError {
+        message: format!("Failed to serialize sync journal: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::annotation::{Annotation, HighlightColor, PaletteColor, ReadingPosition};
+    use crate::book::{Book, BookType};
+    use crate::locator::Locator;
+
+    #[test]
+    fn test_hlc_order() {
+        let mut a = Hlc::zero("a");
+        let first = a.tick(1000);
+        let second = a.tick(900);
+        assert!(second > first);
+        let mut b = Hlc::zero("b");
+        b.observe(&second, 500);
+        assert!(b.tick(500) > second);
+        assert_eq!(Hlc::parse(&second.encode()), Some(second));
+    }
+
+    #[test]
+    fn test_sync_two_devices() {
+        let folder = std::env::temp_dir().join(format!("omnireader-sync-{}", uuid::Uuid::new_v4()));
+        let folder_str = folder.to_string_lossy().to_string();
+        let a = Database::open_in_memory().unwrap();
+        let b = Database::open_in_memory().unwrap();
+
+        let book = Book::new(
+            "Dune".to_string(),
+            Some("Frank Herbert".to_string()),
+            "/books/dune.epub".to_string(),
+            BookType::Epub,
+            10,
+        );
+        a.insert_book(&book).unwrap();
+        let highlight = Annotation::new_highlight(
+            book.id.clone(),
+            Locator::from_total_progression(0.25),
+            HighlightColor::Yellow,
+            Some("fear is the mind-killer".to_string()),
+        );
+        a.insert_annotation(&highlight).unwrap();
+        let bookmark = Annotation::new_bookmark(
+            book.id.clone(),
+            Locator::from_total_progression(0.5),
+            None,
+            Some("Chapter 2".to_string()),
+            None,
+        );
+        a.insert_annotation(&bookmark).unwrap();
+
+        let report = sync_with_folder(&a, &folder_str, None).unwrap();
+        assert!(report.sent > 0);
+        let report = sync_with_folder(&b, &folder_str, None).unwrap();
+        assert_eq!(report.sent, 0);
+        assert!(report.received > 0);
+        assert_eq!(b.get_book(&book.id).unwrap().unwrap().title, "Dune");
+        assert_eq!(
+            b.get_annotation(&highlight.id).unwrap(),
+            a.get_annotation(&highlight.id).unwrap()
+        );
+
+        // Concurrent edits of different fields both survive
+        b.update_annotation_note(&highlight.id, Some("Litany".to_string()))
+            .unwrap();
+        a.update_annotation_color(&highlight.id, "green").unwrap();
+        a.delete_annotation(&bookmark.id).unwrap();
+        a.save_reading_position(&ReadingPosition::new(
+            book.id.clone(),
+            Locator::from_total_progression(0.4),
+        ))
+        .unwrap();
+        sync_with_folder(&b, &folder_str, None).unwrap();
+        sync_with_folder(&a, &folder_str, None).unwrap();
+        sync_with_folder(&b, &folder_str, None).unwrap();
+
+        for db in [&a, &b] {
+            let merged = db.get_annotation(&highlight.id).unwrap().unwrap();
+            assert_eq!(merged.note_text.as_deref(), Some("Litany"));
+            assert_eq!(merged.color, "green");
+            assert!(db.get_annotation(&bookmark.id).unwrap().is_none());
+            let position = db.get_reading_position(&book.id).unwrap().unwrap();
+            assert_eq!(position.locator.total_progression, 0.4);
+        }
+
+        // Nothing left to exchange once both sides have converged
+        assert_eq!(
+            sync_with_folder(&a, &folder_str, None).unwrap(),
+            SyncReport::default()
+        );
+        assert_eq!(
+            sync_with_folder(&b, &folder_str, None).unwrap(),
+            SyncReport::default()
+        );
+
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn test_sync_latest_edit_wins() {
+        let folder = std::env::temp_dir().join(format!("omnireader-sync-{}", uuid::Uuid::new_v4()));
+        let folder_str = folder.to_string_lossy().to_string();
+        let a = Database::open_in_memory().unwrap();
+        let b = Database::open_in_memory().unwrap();
+        let book = Book::new(
+            "Dune".to_string(),
+            None,
+            "/books/dune.epub".to_string(),
+            BookType::Epub,
+            10,
+        );
+        a.insert_book(&book).unwrap();
+        let highlight = Annotation::new_highlight(
+            book.id.clone(),
+            Locator::from_total_progression(0.25),
+            HighlightColor::Yellow,
+            Some("fear is the mind-killer".to_string()),
+        );
+        a.insert_annotation(&highlight).unwrap();
+        sync_with_folder(&a, &folder_str, None).unwrap();
+        sync_with_folder(&b, &folder_str, None).unwrap();
+
+        // B edits first, A edits later but syncs first: A's edit still wins
+        b.update_annotation_note(&highlight.id, Some("Older".to_string()))
+            .unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        let purple = PaletteColor::new("Purple".to_string(), "#9C27B0".to_string(), 5);
+        a.save_palette_color(&purple).unwrap();
+        a.update_annotation_note(&highlight.id, Some("Newer".to_string()))
+            .unwrap();
+        a.update_annotation_color(&highlight.id, &purple.id)
+            .unwrap();
+        sync_with_folder(&a, &folder_str, None).unwrap();
+        sync_with_folder(&b, &folder_str, None).unwrap();
+        sync_with_folder(&a, &folder_str, None).unwrap();
+
+        for db in [&a, &b] {
+            let merged = db.get_annotation(&highlight.id).unwrap().unwrap();
+            assert_eq!(merged.note_text.as_deref(), Some("Newer"));
+            assert_eq!(merged.color, purple.id);
+            assert_eq!(
+                db.get_palette_color(&purple.id).unwrap(),
+                Some(purple.clone())
+            );
+        }
+
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn test_sync_encrypted_folder() {
+        let folder = std::env::temp_dir().join(format!("omnireader-sync-{}", uuid::Uuid::new_v4()));
+        let folder_str = folder.to_string_lossy().to_string();
+        let a = Database::open_in_memory().unwrap();
+        let book = Book::new(
+            "Dune".to_string(),
+            None,
+            "/books/dune.epub".to_string(),
+            BookType::Epub,
+            10,
+        );
+        a.insert_book(&book).unwrap();
+        let highlight = Annotation::new_highlight(
+            book.id.clone(),
+            Locator::from_total_progression(0.25),
+            HighlightColor::Yellow,
+            Some("fear is the mind-killer".to_string()),
+        );
+        a.insert_annotation(&highlight).unwrap();
+        sync_with_folder(&a, &folder_str, Some("spice")).unwrap();
+        for entry in std::fs::read_dir(&folder).unwrap() {
+            let contents = std::fs::read_to_string(entry.unwrap().path()).unwrap();
+            assert!(!contents.contains("Dune") && !contents.contains("mind-killer"));
+        }
+
+        // Without the right passphrase nothing is read or written
+        let b = Database::open_in_memory().unwrap();
+        assert!(sync_with_folder(&b, &folder_str, None).is_err());
+        assert!(matches!(
+            sync_with_folder(&b, &folder_str, Some("melange")),
+            Err(OmniReaderError::WrongPassphrase)
+        ));
+        assert!(b.get_all_books().unwrap().is_empty());
+
+        // After a rotation, old and new lines both open with the new passphrase
+        rotate_folder_sync_key(&folder_str, "spice", Some("melange")).unwrap();
+        a.update_annotation_note(&highlight.id, Some("Litany".to_string()))
+            .unwrap();
+        assert!(sync_with_folder(&a, &folder_str, Some("spice")).is_err());
+        sync_with_folder(&a, &folder_str, Some("melange")).unwrap();
+        sync_with_folder(&b, &folder_str, Some("melange")).unwrap();
+        assert_eq!(b.get_book(&book.id).unwrap().unwrap().title, "Dune");
+        let merged = b.get_annotation(&highlight.id).unwrap().unwrap();
+        assert_eq!(merged.note_text.as_deref(), Some("Litany"));
+
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+}
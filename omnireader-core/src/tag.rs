@@ -0,0 +1,22 @@
+//! Tag model
+
+use uniffi;
+
+/// A free-form tag
+#[derive(Debug, Clone, PartialEq, Eq, uniffi::Record)]
+pub struct Tag {
+    /// Unique identifier (UUID v4)
+    pub id: String,
+    /// Tag name (unique, case-insensitive)
+    pub name: String,
+}
+
+impl Tag {
+    /// Create a new tag with generated UUID
+    pub fn new(name: String) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            name: name.trim().to_string(),
+        }
+    }
+}
@@ -0,0 +1,645 @@
+//! W3C Web Annotation Data Model (JSON-LD) import and export
+//!
+//! Annotations map to `Annotation` objects whose target carries a
+//! `TextQuoteSelector`, a `TextPositionSelector` and a `FragmentSelector`
+//! (EPUB CFI, or `page=N` per RFC 3778 for PDFs). Notes and tags become
+//! `TextualBody` bodies with `commenting` and `tagging` purposes. Fields the
+//! model has no vocabulary for (palette color, progression, chapter title,
+//! anchor status) are kept in `omnireader:` extension properties so a
+//! round trip through JSON-LD loses nothing.
+
+use crate::annotation::{
+    AnchorStatus, Annotation, AnnotationImport, AnnotationType, HighlightColor, PaletteColor,
+};
+use crate::db::Database;
+use crate::error::OmniReaderError;
+use crate::locator::{Locator, LocatorText};
+use serde_json::{Value, json};
+
+const ANNO_CONTEXT: &str = "http://www.w3.org/ns/anno.jsonld";
+const OMNIREADER_NS: &str = "https://omnireader.app/ns#";
+const CFI_SPEC: &str = "http://www.idpf.org/epub/linking/cfi/epub-cfi.html";
+const PDF_FRAGMENT_SPEC: &str = "http://tools.ietf.org/rfc/rfc3778";
+
+/// Namespace for IDs of annotations imported from other tools
+const WEB_ANNOTATION_NAMESPACE: uuid::Uuid =
+    uuid::Uuid::from_u128(0x77656261_6e6e_6f74_6174_696f6e730000);
+
+/// An annotation together with the data that lives outside the `annotations` table
+#[derive(Debug, Clone, PartialEq)]
+pub struct WebAnnotation {
+    pub annotation: Annotation,
+    pub tags: Vec<String>,
+    pub color: Option<PaletteColor>,
+}
+
+/// Export all annotations of a book as a JSON-LD `AnnotationCollection`
+pub fn export_web_annotations(db: &Database, book_id: &str) -> Result<String, OmniReaderError> {
+    let collection = annotation_collection(db, book_id)?;
+    serde_json::to_string_pretty(&collection).map_err(|e| OmniReaderError::ParseError {
+        message: format!("Failed to serialize annotations: {}", e),
+    })
+}
+
+/// Build the `AnnotationCollection` for a book's annotations
+pub(crate) fn annotation_collection(
+    db: &Database,
+    book_id: &str,
+) -> Result<Value, OmniReaderError> {
+    let palette = db.get_palette()?;
+    let mut items = Vec::new();
+    for annotation in db.get_annotations(book_id)? {
+        let tags = db
+            .get_annotation_tags(&annotation.id)?
+            .into_iter()
+            .map(|tag| tag.name)
+            .collect();
+        let color = palette.iter().find(|c| c.id == annotation.color).cloned();
+        items.push(to_json_ld(&WebAnnotation {
+            annotation,
+            tags,
+            color,
+        }));
+    }
+
+    Ok(json!({
+        "@context": context(),
+        "type": "AnnotationCollection",
+        "total": items.len(),
+        "first": {
+            "type": "AnnotationPage",
+            "items": items,
+        },
+    }))
+}
+
+/// Import JSON-LD annotations into a book in one transaction, skipping IDs
+/// that already exist
+///
+/// Accepts a single annotation, an array, an `AnnotationPage` or an
+/// `AnnotationCollection`. Returns the number of annotations imported.
+pub fn import_web_annotations(
+    db: &Database,
+    book_id: &str,
+    json: &str,
+) -> Result<u32, OmniReaderError> {
+    let value: Value = serde_json::from_str(json).map_err(|e| OmniReaderError::ParseError {
+        message: format!("Invalid JSON-LD: {}", e),
+    })?;
+
+    let mut imports = Vec::new();
+    for item in annotation_items(&value) {
+        let mut web = from_json_ld(item)?;
+        if db.get_annotation(&web.annotation.id)?.is_some() {
+            continue;
+        }
+        web.annotation.book_id = book_id.to_string();
+        if let Some(color) = &web.color {
+            web.annotation.color = match db.get_palette_color(&color.id)? {
+                Some(existing) => existing.id,
+                None => {
+                    let mut entry = db.palette_color_for_hex(&color.hex)?;
+                    if entry.name == entry.hex && color.name != color.hex {
+                        entry.name = color.name.clone();
+                        db.save_palette_color(&entry)?;
+                    }
+                    entry.id
+                }
+            };
+        }
+        // A palette ID from another library without its color
+        if db.get_palette_color(&web.annotation.color)?.is_none() {
+            web.annotation.color = HighlightColor::Yellow.palette_id().to_string();
+        }
+        imports.push(AnnotationImport {
+            annotation: web.annotation,
+            tags: web.tags,
+        });
+    }
+    db.insert_annotations(&imports)
+}
+
+/// Serialize one annotation as a JSON-LD `Annotation`
+pub fn to_json_ld(web: &WebAnnotation) -> Value {
+    let annotation = &web.annotation;
+    let locator = &annotation.locator;
+
+    let mut selectors = Vec::new();
+    let quote = locator.text.as_ref();
+    let exact = quote
+        .and_then(|text| text.highlight.clone())
+        .or_else(|| annotation.selected_text.clone());
+    if let Some(exact) = &exact {
+        let mut selector = json!({ "type": "TextQuoteSelector", "exact": exact });
+        if let Some(prefix) = quote.and_then(|text| text.before.as_ref()) {
+            selector["prefix"] = json!(prefix);
+        }
+        if let Some(suffix) = quote.and_then(|text| text.after.as_ref()) {
+            selector["suffix"] = json!(suffix);
+        }
+        selectors.push(selector);
+    }
+    if let (Some(start), Some(end)) = (locator.start_offset, locator.end_offset) {
+        selectors.push(json!({ "type": "TextPositionSelector", "start": start, "end": end }));
+    }
+    if let Some(cfi) = &locator.cfi {
+        selectors.push(json!({ "type": "FragmentSelector", "conformsTo": CFI_SPEC, "value": cfi }));
+    }
+    if let Some(page) = locator.page {
+        selectors.push(json!({
+            "type": "FragmentSelector",
+            "conformsTo": PDF_FRAGMENT_SPEC,
+            "value": format!("page={}", page + 1),
+        }));
+    }
+
+    let mut target = json!({ "source": format!("urn:uuid:{}", annotation.book_id) });
+    if !selectors.is_empty() {
+        target["selector"] = Value::Array(selectors);
+    }
+
+    let mut bodies = Vec::new();
+    if let Some(note) = &annotation.note_text {
+        bodies.push(json!({
+            "type": "TextualBody",
+            "value": note,
+            "format": "text/plain",
+            "purpose": "commenting",
+        }));
+    }
+    for tag in &web.tags {
+        bodies.push(json!({ "type": "TextualBody", "value": tag, "purpose": "tagging" }));
+    }
+
+    let mut value = json!({
+        "@context": context(),
+        "id": format!("urn:uuid:{}", annotation.id),
+        "type": "Annotation",
+        "motivation": match annotation.annotation_type {
+            AnnotationType::Highlight => "highlighting",
+            AnnotationType::Note => "commenting",
+            AnnotationType::Bookmark => "bookmarking",
+        },
+        "created": timestamp_to_iso(annotation.created_at),
+        "modified": timestamp_to_iso(annotation.updated_at),
+        "target": target,
+        "omnireader:progression": locator.progression,
+        "omnireader:totalProgression": locator.total_progression,
+        "omnireader:anchorStatus": annotation.anchor_status.as_str(),
+    });
+    if !bodies.is_empty() {
+        value["body"] = Value::Array(bodies);
+    }
+    if let Some(end) = locator.end_total_progression {
+        value["omnireader:endTotalProgression"] = json!(end);
+    }
+    if let Some(href) = &locator.href {
+        value["omnireader:href"] = json!(href);
+    }
+    if let Some(chapter_title) = &annotation.chapter_title {
+        value["omnireader:chapterTitle"] = json!(chapter_title);
+    }
+    if annotation.selected_text != exact {
+        value["omnireader:selectedText"] = json!(annotation.selected_text);
+    }
+    match quote {
+        None if exact.is_some() => value["omnireader:quoteFromSelection"] = json!(true),
+        Some(text) if text.highlight.is_none() => {
+            value["omnireader:quoteWithoutHighlight"] = json!(true)
+        }
+        _ => {}
+    }
+    match &web.color {
+        Some(color) => {
+            value["omnireader:color"] = json!({
+                "id": color.id,
+                "name": color.name,
+                "hex": color.hex,
+                "position": color.position,
+            })
+        }
+        None => value["omnireader:color"] = json!({ "id": annotation.color }),
+    }
+    value
+}
+
+/// Parse one JSON-LD `Annotation`
+///
+/// Annotations from other tools get an ID derived from theirs when it is not
+/// a `urn:uuid:` (or from the whole annotation when it has none), so importing
+/// them again finds them, and an empty `book_id` for the caller to fill in.
+pub fn from_json_ld(value: &Value) -> Result<WebAnnotation, OmniReaderError> {
+    let object = value
+        .as_object()
+        .ok_or_else(|| OmniReaderError::ParseError {
+            message: "Web annotation must be a JSON object".to_string(),
+        })?;
+
+    let foreign_id = object.get("id").and_then(Value::as_str);
+    let id = match foreign_id.and_then(|id| id.strip_prefix("urn:uuid:")) {
+        Some(id) => id.to_string(),
+        None => {
+            let key = foreign_id.map_or_else(|| value.to_string(), str::to_string);
+            uuid::Uuid::new_v5(&WEB_ANNOTATION_NAMESPACE, key.as_bytes()).to_string()
+        }
+    };
+    let target = one_or_many(object.get("target"))
+        .into_iter()
+        .next()
+        .cloned()
+        .unwrap_or(Value::Null);
+    let book_id = target
+        .get("source")
+        .and_then(Value::as_str)
+        .and_then(|source| source.strip_prefix("urn:uuid:"))
+        .unwrap_or_default()
+        .to_string();
+
+    let mut locator = Locator::from_total_progression(
+        object
+            .get("omnireader:totalProgression")
+            .and_then(Value::as_f64)
+            .unwrap_or(0.0),
+    );
+    locator.progression = object
+        .get("omnireader:progression")
+        .and_then(Value::as_f64)
+        .unwrap_or(0.0);
+    locator.end_total_progression = object
+        .get("omnireader:endTotalProgression")
+        .and_then(Value::as_f64);
+    locator.href = object
+        .get("omnireader:href")
+        .and_then(Value::as_str)
+        .map(str::to_string);
+
+    let mut exact = None;
+    for selector in one_or_many(target.get("selector")) {
+        match selector.get("type").and_then(Value::as_str) {
+            Some("TextQuoteSelector") => {
+                exact = selector
+                    .get("exact")
+                    .and_then(Value::as_str)
+                    .map(str::to_string);
+                locator.text = Some(LocatorText {
+                    before: selector
+                        .get("prefix")
+                        .and_then(Value::as_str)
+                        .map(str::to_string),
+                    highlight: exact.clone(),
+                    after: selector
+                        .get("suffix")
+                        .and_then(Value::as_str)
+                        .map(str::to_string),
+                });
+            }
+            Some("TextPositionSelector") => {
+                locator.start_offset = selector.get("start").and_then(as_u32);
+                locator.end_offset = selector.get("end").and_then(as_u32);
+            }
+            Some("FragmentSelector") => {
+                let fragment = selector
+                    .get("value")
+                    .and_then(Value::as_str)
+                    .unwrap_or_default();
+                let conforms_to = selector.get("conformsTo").and_then(Value::as_str);
+                if conforms_to == Some(CFI_SPEC) || fragment.starts_with("epubcfi(") {
+                    locator.cfi = Some(fragment.to_string());
+                } else if let Some(page) = fragment.strip_prefix("page=") {
+                    locator.page = page.parse::<u32>().ok().map(|page| page.saturating_sub(1));
+                }
+            }
+            _ => {}
+        }
+    }
+    // The quote selector may have been derived from fields other than the locator quote
+    if object
+        .get("omnireader:quoteFromSelection")
+        .and_then(Value::as_bool)
+        == Some(true)
+    {
+        locator.text = None;
+    }
+    if object
+        .get("omnireader:quoteWithoutHighlight")
+        .and_then(Value::as_bool)
+        == Some(true)
+        && let Some(text) = locator.text.as_mut()
+    {
+        text.highlight = None;
+    }
+
+    let mut note_text = None;
+    let mut tags = Vec::new();
+    for body in one_or_many(object.get("body")) {
+        let text = match body {
+            Value::String(text) => Some(text.clone()),
+            _ => body
+                .get("value")
+                .and_then(Value::as_str)
+                .map(str::to_string),
+        };
+        let Some(text) = text else { continue };
+        match body.get("purpose").and_then(Value::as_str) {
+            Some("tagging") => tags.push(text),
+            _ if note_text.is_none() => note_text = Some(text),
+            _ => {}
+        }
+    }
+
+    let annotation_type = match one_or_many(object.get("motivation"))
+        .first()
+        .and_then(|m| m.as_str())
+    {
+        Some("bookmarking") => AnnotationType::Bookmark,
+        Some("commenting") => AnnotationType::Note,
+        _ => AnnotationType::Highlight,
+    };
+    let selected_text = match object.get("omnireader:selectedText") {
+        Some(Value::String(text)) => Some(text.clone()),
+        Some(Value::Null) => None,
+        _ => exact,
+    };
+
+    let color_value = object.get("omnireader:color");
+    let color = color_value.and_then(|color| {
+        Some(PaletteColor {
+            id: color.get("id")?.as_str()?.to_string(),
+            name: color.get("name")?.as_str()?.to_string(),
+            hex: color.get("hex")?.as_str()?.to_string(),
+            position: color.get("position").and_then(as_u32).unwrap_or(0),
+        })
+        .filter(|color| PaletteColor::is_valid_hex(&color.hex))
+    });
+    let color_id = color_value
+        .and_then(|color| color.get("id"))
+        .and_then(Value::as_str)
+        .unwrap_or(HighlightColor::Yellow.palette_id())
+        .to_string();
+
+    let created_at = object
+        .get("created")
+        .and_then(Value::as_str)
+        .and_then(iso_to_timestamp)
+        .unwrap_or_else(|| chrono::Utc::now().timestamp());
+    let updated_at = object
+        .get("modified")
+        .and_then(Value::as_str)
+        .and_then(iso_to_timestamp)
+        .unwrap_or(created_at);
+
+    Ok(WebAnnotation {
+        annotation: Annotation {
+            id,
+            book_id,
+            annotation_type,
+            locator,
+            color: color_id,
+            selected_text,
+            note_text,
+            chapter_title: object
+                .get("omnireader:chapterTitle")
+                .and_then(Value::as_str)
+                .map(str::to_string),
+            created_at,
+            updated_at,
+            anchor_status: object
+                .get("omnireader:anchorStatus")
+                .and_then(Value::as_str)
+                .and_then(AnchorStatus::parse)
+                .unwrap_or(AnchorStatus::Anchored),
+        },
+        tags,
+        color,
+    })
+}
+
+fn context() -> Value {
+    json!([ANNO_CONTEXT, { "omnireader": OMNIREADER_NS }])
+}
+
+/// Collect annotation objects from any of the accepted container shapes
+fn annotation_items(value: &Value) -> Vec<&Value> {
+    match value {
+        Value::Array(items) => items.iter().collect(),
+        Value::Object(object) => match object.get("type").and_then(Value::as_str) {
+            Some("AnnotationCollection") => object
+                .get("first")
+                .map(annotation_items)
+                .unwrap_or_default(),
+            Some("AnnotationPage") => one_or_many(object.get("items")),
+            _ => vec![value],
+        },
+        _ => Vec::new(),
+    }
+}
+
+/// JSON-LD allows single values where arrays are expected
+fn one_or_many(value: Option<&Value>) -> Vec<&Value> {
+    match value {
+        Some(Value::Array(items)) => items.iter().collect(),
+        Some(Value::Null) | None => Vec::new(),
+        Some(value) => vec![value],
+    }
+}
+
+fn as_u32(value: &Value) -> Option<u32> {
+    value.as_u64().and_then(|n| u32::try_from(n).ok())
+}
+
+pub(crate) fn timestamp_to_iso(timestamp: i64) -> String {
+    chrono::DateTime::from_timestamp(timestamp, 0)
+        .unwrap_or_default()
+        .to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+}
+
+fn iso_to_timestamp(iso: &str) -> Option<i64> {
+    chrono::DateTime::parse_from_rfc3339(iso)
+        .ok()
+        .map(|date| date.timestamp())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::annotation::HighlightColor;
+    use crate::book::{Book, BookType};
+
+    fn full_highlight() -> WebAnnotation {
+        let mut annotation = Annotation::new_highlight(
+            uuid::Uuid::new_v4().to_string(),
+            Locator {
+                href: Some("OEBPS/ch3.xhtml".to_string()),
+                page: None,
+                progression: 0.25,
+                total_progression: 0.4,
+                end_total_progression: Some(0.41),
+                cfi: Some("epubcfi(/6/8!/4/2:10)".to_string()),
+                start_offset: Some(120),
+                end_offset: Some(140),
+                text: None,
+            }
+            .with_text(
+                Some("before ".to_string()),
+                Some("the quoted words".to_string()),
+                Some(" after".to_string()),
+            ),
+            HighlightColor::Pink,
+            Some("the quoted words".to_string()),
+        );
+        annotation.note_text = Some("A note\nover two lines".to_string());
+        annotation.chapter_title = Some("Chapter 3".to_string());
+        annotation.created_at = 1_700_000_000;
+        annotation.updated_at = 1_700_000_500;
+        annotation.anchor_status = AnchorStatus::Relocated;
+        WebAnnotation {
+            annotation,
+            tags: vec!["thesis-ch3".to_string(), "disagree".to_string()],
+            color: Some(PaletteColor {
+                id: "pink".to_string(),
+                name: "disagree".to_string(),
+                hex: "#E91E63".to_string(),
+                position: 3,
+            }),
+        }
+    }
+
+    #[test]
+    fn test_round_trip_highlight() {
+        let web = full_highlight();
+        let value = to_json_ld(&web);
+        assert_eq!(value["motivation"], "highlighting");
+        assert_eq!(value["target"]["selector"][0]["type"], "TextQuoteSelector");
+        assert_eq!(value["target"]["selector"][1]["start"], 120);
+        assert_eq!(
+            value["target"]["selector"][2]["value"],
+            "epubcfi(/6/8!/4/2:10)"
+        );
+        assert_eq!(value["body"][1]["purpose"], "tagging");
+
+        let text = serde_json::to_string(&value).unwrap();
+        let parsed = from_json_ld(&serde_json::from_str(&text).unwrap()).unwrap();
+        assert_eq!(parsed, web);
+    }
+
+    #[test]
+    fn test_round_trip_pdf_note_and_bookmark() {
+        let note = Annotation::new_note(
+            uuid::Uuid::new_v4().to_string(),
+            Locator::from_pdf_page(6, 20),
+            "Margin note".to_string(),
+        );
+        let bookmark = Annotation::new_bookmark(
+            note.book_id.clone(),
+            Locator::from_pdf_page(7, 20),
+            None,
+            None,
+            Some("Snippet text".to_string()),
+        );
+        for annotation in [note, bookmark] {
+            let web = WebAnnotation {
+                annotation,
+                tags: Vec::new(),
+                color: None,
+            };
+            let value = to_json_ld(&web);
+            let parsed = from_json_ld(&value).unwrap();
+            assert_eq!(parsed, web);
+        }
+    }
+
+    #[test]
+    fn test_import_hypothesis_style() {
+        let json = r#"{
+            "@context": "http://www.w3.org/ns/anno.jsonld",
+            "id": "https://hypothes.is/a/abc123",
+            "type": "Annotation",
+            "motivation": "commenting",
+            "body": "Plain string body",
+            "target": {
+                "source": "https://example.com/book",
+                "selector": {"type": "TextQuoteSelector", "exact": "some text"}
+            }
+        }"#;
+        let db = Database::open_in_memory().unwrap();
+        let book = Book::new(
+            "Book".to_string(),
+            None,
+            "/path/book.epub".to_string(),
+            BookType::Epub,
+            5,
+        );
+        db.insert_book(&book).unwrap();
+
+        assert_eq!(import_web_annotations(&db, &book.id, json).unwrap(), 1);
+        // Importing the same file again finds the annotation by its foreign ID
+        assert_eq!(import_web_annotations(&db, &book.id, json).unwrap(), 0);
+        let annotations = db.get_annotations(&book.id).unwrap();
+        assert_eq!(annotations.len(), 1);
+        assert_eq!(annotations[0].annotation_type, AnnotationType::Note);
+        assert_eq!(annotations[0].selected_text.as_deref(), Some("some text"));
+        assert_eq!(
+            annotations[0].note_text.as_deref(),
+            Some("Plain string body")
+        );
+    }
+
+    #[test]
+    fn test_database_round_trip() {
+        let source = Database::open_in_memory().unwrap();
+        let book = Book::new(
+            "Book".to_string(),
+            None,
+            "/path/book.epub".to_string(),
+            BookType::Epub,
+            5,
+        );
+        source.insert_book(&book).unwrap();
+        let mut web = full_highlight();
+        web.annotation.book_id = book.id.clone();
+        let custom = source.palette_color_for_hex("#00AA00").unwrap();
+        source
+            .save_palette_color(&PaletteColor {
+                name: "definition".to_string(),
+                ..custom.clone()
+            })
+            .unwrap();
+        web.annotation.color = custom.id.clone();
+        source.insert_annotation(&web.annotation).unwrap();
+        for tag in &web.tags {
+            source.add_annotation_tag(&web.annotation.id, tag).unwrap();
+        }
+        let exported = export_web_annotations(&source, &book.id).unwrap();
+
+        let target = Database::open_in_memory().unwrap();
+        target.insert_book(&book).unwrap();
+        assert_eq!(
+            import_web_annotations(&target, &book.id, &exported).unwrap(),
+            1
+        );
+        assert_eq!(
+            import_web_annotations(&target, &book.id, &exported).unwrap(),
+            0
+        );
+
+        let imported = target.get_annotation(&web.annotation.id).unwrap().unwrap();
+        let color = target.get_palette_color(&imported.color).unwrap().unwrap();
+        assert_eq!(color.name, "definition");
+        assert_eq!(color.hex, "#00AA00");
+        assert_eq!(
+            Annotation {
+                color: web.annotation.color.clone(),
+                ..imported
+            },
+            web.annotation
+        );
+        let mut tags: Vec<_> = target
+            .get_annotation_tags(&web.annotation.id)
+            .unwrap()
+            .into_iter()
+            .map(|tag| tag.name)
+            .collect();
+        tags.sort();
+        assert_eq!(tags, vec!["disagree", "thesis-ch3"]);
+    }
+}
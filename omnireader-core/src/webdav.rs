@@ -0,0 +1,487 @@
+//! WebDAV sync transport (Nextcloud, ownCloud, ...)
+//!
+//! Uses the change-journal model of [`crate::sync`]. WebDAV cannot append to
+//! a file, so each device's journal is a collection of immutable segments,
+//! `journals/<device id>/<seq>.jsonl`, read back as their concatenation.
+//!
+//! - Segments are created with `If-None-Match: *`, so a retried upload never
+//!   overwrites anything. Batches stay in the local outbox until their
+//!   segment is stored, which makes an interrupted journal upload resume
+//!   where it stopped on the next sync.
+//! - One `PROPFIND` of `journals/` returns every device's collection ETag;
+//!   journals whose ETag has not changed since the last sync are skipped.
+//! - Book files are optionally uploaded to `books/<book id>.<ext>`, replaced
+//!   with `If-Match` when the local file changed size.
+//! - With a passphrase, journal lines and book files are sealed before they
+//!   leave the device; the keyring is `journals/keyring.json`, so the
+//!   `PROPFIND` of `journals/` also tells whether the folder is encrypted.
+
+use crate::crypto::Keyring;
+use crate::db::Database;
+use crate::error::OmniReaderError;
+use crate::sync::{
+    JournalChunk, JournalCursor, JournalStore, SyncReport, rotate_store_key, sync_with_store,
+};
+use base64::Engine;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use uniffi;
+use ureq::http::{Request, Response, request::Builder};
+use ureq::{Agent, AsSendBody, Body, SendBody};
+
+/// Properties requested when listing a collection
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:"><d:prop><d:resourcetype/><d:getetag/><d:getcontentlength/></d:prop></d:propfind>"#;
+/// Keyring of an encrypted folder
+const KEYRING_PATH: &str = "journals/keyring.json";
+
+/// Connection settings for a WebDAV sync folder
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct WebDavConfig {
+    /// URL of the sync folder, e.g.
+    /// `https://cloud.example.com/remote.php/dav/files/me/OmniReader/`
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Upload book files missing from (or changed on) the server
+    pub upload_books: bool,
+    /// Encrypt journals and book files with a key unlocked by this passphrase
+    pub passphrase: Option<String>,
+}
+
+/// Sync the library through a WebDAV folder
+pub fn sync_with_webdav(
+    db: &Database,
+    config: WebDavConfig,
+) -> Result<SyncReport, OmniReaderError> {
+    let mut dav = WebDav::new(&config);
+    dav.mkcol("")?;
+    let mut report = sync_with_store(db, &mut dav, config.passphrase.as_deref())?;
+    if config.upload_books {
+        let keyring = match (&config.passphrase, dav.keyring()?) {
+            (Some(passphrase), Some(stored)) => Some(Keyring::unlock(&stored, passphrase)?),
+            _ => None,
+        };
+        report.books_uploaded = dav.upload_books(db, keyring.as_ref())?;
+    }
+    Ok(report)
+}
+
+/// Seal an encrypted WebDAV folder's future uploads with a new key,
+/// optionally changing its passphrase from `config.passphrase`
+pub fn rotate_webdav_sync_key(
+    config: WebDavConfig,
+    new_passphrase: Option<String>,
+) -> Result<(), OmniReaderError> {
+    let passphrase = config
+        .passphrase
+        .clone()
+        .ok_or_else(|| OmniReaderError::Encryption {
+            message: "A passphrase is required to rotate the key".to_string(),
+        })?;
+    rotate_store_key(
+        &mut WebDav::new(&config),
+        &passphrase,
+        new_passphrase.as_deref(),
+    )
+}
+
+/// A member of a WebDAV collection
+struct Resource {
+    name: String,
+    etag: Option<String>,
+    size: u64,
+    collection: bool,
+}
+
+struct WebDav {
+    agent: Agent,
+    /// Folder URL, ending in `/`
+    base: String,
+    authorization: Option<String>,
+    /// Journal collection ETags from the last `devices` listing
+    etags: HashMap<String, Option<String>>,
+    /// Whether the last `devices` listing included the keyring
+    keyring_listed: Option<bool>,
+}
+
+impl WebDav {
+    fn new(config: &WebDavConfig) -> Self {
+        let agent = Agent::config_builder()
+            .http_status_as_error(false)
+            .allow_non_standard_methods(true)
+            .build()
+            .new_agent();
+        let authorization = config.username.as_ref().map(|username| {
+            let credentials = format!(
+                "{}:{}",
+                username,
+                config.password.as_deref().unwrap_or_default()
+            );
+            format!(
+                "Basic {}",
+                base64::engine::general_purpose::STANDARD.encode(credentials)
+            )
+        });
+        let mut base = config.url.clone();
+        if !base.ends_with('/') {
+            base.push('/');
+        }
+        Self {
+            agent,
+            base,
+            authorization,
+            etags: HashMap::new(),
+            keyring_listed: None,
+        }
+    }
+
+    fn request(&self, method: &str, path: &str) -> Builder {
+        let builder = Request::builder()
+            .method(method)
+            .uri(format!("{}{}", self.base, path));
+        match &self.authorization {
+            Some(authorization) => builder.header("Authorization", authorization),
+            None => builder,
+        }
+    }
+
+    fn send(
+        &self,
+        request: Result<Request<impl AsSendBody>, ureq::http::Error>,
+    ) -> Result<Response<Body>, OmniReaderError> {
+        let request = request.map_err(|e| OmniReaderError::IoError {
+            message: format!("Invalid WebDAV request: {}", e),
+        })?;
+        self.agent
+            .run(request)
+            .map_err(|e| OmniReaderError::IoError {
+                message: format!("WebDAV request failed: {}", e),
+            })
+    }
+
+    /// Create a collection, if it does not exist yet
+    fn mkcol(&self, path: &str) -> Result<(), OmniReaderError> {
+        let response = self.send(self.request("MKCOL", path).body(()))?;
+        match response.status().as_u16() {
+            // 405: the collection already exists
+            200..=299 | 405 => Ok(()),
+            status => Err(status_error("MKCOL", path, status)),
+        }
+    }
+
+    /// Members of a collection; empty when it does not exist
+    fn propfind(&self, path: &str) -> Result<Vec<Resource>, OmniReaderError> {
+        let request = self
+            .request("PROPFIND", path)
+            .header("Depth", "1")
+            .header("Content-Type", "application/xml; charset=utf-8")
+            .body(PROPFIND_BODY.as_bytes());
+        let mut response = self.send(request)?;
+        match response.status().as_u16() {
+            207 => {}
+            404 => return Ok(Vec::new()),
+            status => return Err(status_error("PROPFIND", path, status)),
+        }
+        let xml = response
+            .body_mut()
+            .read_to_string()
+            .map_err(|e| OmniReaderError::IoError {
+                message: format!("WebDAV request failed: {}", e),
+            })?;
+        parse_multistatus(&xml, &format!("{}{}", self.base, path))
+    }
+
+    fn get(&self, path: &str, from: u64) -> Result<Vec<u8>, OmniReaderError> {
+        let mut request = self.request("GET", path);
+        if from > 0 {
+            request = request.header("Range", format!("bytes={}-", from));
+        }
+        let mut response = self.send(request.body(()))?;
+        let status = response.status().as_u16();
+        let skip = match status {
+            206 => 0,
+            // The server ignored the range
+            200 => from as usize,
+            416 => return Ok(Vec::new()),
+            status => return Err(status_error("GET", path, status)),
+        };
+        let bytes = response
+            .body_mut()
+            .with_config()
+            .limit(u64::MAX)
+            .read_to_vec()
+            .map_err(|e| OmniReaderError::IoError {
+                message: format!("WebDAV request failed: {}", e),
+            })?;
+        Ok(bytes.get(skip..).unwrap_or_default().to_vec())
+    }
+
+    /// Upload local book files that are missing or differ in size on the server
+    fn upload_books(
+        &self,
+        db: &Database,
+        keyring: Option<&Keyring>,
+    ) -> Result<u32, OmniReaderError> {
+        self.mkcol("books/")?;
+        let remote: HashMap<String, Resource> = self
+            .propfind("books/")?
+            .into_iter()
+            .map(|resource| (resource.name.clone(), resource))
+            .collect();
+        let mut uploaded = 0;
+        for book in db.get_all_books()? {
+            let name = format!("{}.{}", book.id, book.file_type.extension());
+            let Ok(mut file) = File::open(&book.file_path) else {
+                continue;
+            };
+            let size = file.metadata()?.len();
+            let size = keyring.map_or(size, |keyring| keyring.sealed_len(size));
+            let path = format!("books/{}", name);
+            let request = self
+                .request("PUT", &path)
+                .header("Content-Length", size.to_string());
+            let request = match remote.get(&name) {
+                Some(existing) if existing.size == size => continue,
+                Some(existing) => match &existing.etag {
+                    Some(etag) => request.header("If-Match", etag),
+                    None => request,
+                },
+                None => request.header("If-None-Match", "*"),
+            };
+            let mut sealed;
+            let reader: &mut dyn Read = match keyring {
+                Some(keyring) => {
+                    sealed = keyring.seal_reader(&mut file, format!("book:{}", book.id).as_bytes());
+                    &mut sealed
+                }
+                None => &mut file,
+            };
+            let response = self.send(request.body(SendBody::from_reader(reader)))?;
+            match response.status().as_u16() {
+                200..=299 => uploaded += 1,
+                // Changed on the server meanwhile; looked at again next sync
+                412 => {}
+                status => return Err(status_error("PUT", &path, status)),
+            }
+        }
+        Ok(uploaded)
+    }
+}
+
+impl JournalStore for WebDav {
+    fn devices(&mut self) -> Result<Vec<String>, OmniReaderError> {
+        self.mkcol("journals/")?;
+        let mut devices = Vec::new();
+        self.etags.clear();
+        self.keyring_listed = Some(false);
+        for resource in self.propfind("journals/")? {
+            if resource.collection {
+                devices.push(resource.name.clone());
+                self.etags.insert(resource.name, resource.etag);
+            } else if format!("journals/{}", resource.name) == KEYRING_PATH {
+                self.keyring_listed = Some(true);
+            }
+        }
+        devices.sort();
+        Ok(devices)
+    }
+
+    fn read(
+        &mut self,
+        device: &str,
+        cursor: &JournalCursor,
+    ) -> Result<Option<JournalChunk>, OmniReaderError> {
+        let etag = self.etags.get(device).cloned().flatten();
+        if etag.is_some() && cursor.etag == etag {
+            return Ok(None);
+        }
+
+        let dir = format!("journals/{}/", device);
+        let mut segments: Vec<Resource> = self
+            .propfind(&dir)?
+            .into_iter()
+            .filter(|resource| !resource.collection && resource.name.ends_with(".jsonl"))
+            .collect();
+        segments.sort_by(|a, b| a.name.cmp(&b.name));
+        let total: u64 = segments.iter().map(|segment| segment.size).sum();
+        let start = if cursor.offset > total {
+            0
+        } else {
+            cursor.offset
+        };
+
+        let mut bytes = Vec::new();
+        let mut position = 0;
+        for segment in segments {
+            let end = position + segment.size;
+            if end > start {
+                let from = start.saturating_sub(position);
+                bytes.extend(self.get(&format!("{}{}", dir, segment.name), from)?);
+            }
+            position = end;
+        }
+        Ok(Some(JournalChunk { start, bytes, etag }))
+    }
+
+    fn append(&mut self, device: &str, seq: i64, batch: &[u8]) -> Result<(), OmniReaderError> {
+        let dir = format!("journals/{}/", device);
+        self.mkcol("journals/")?;
+        self.mkcol(&dir)?;
+        let path = format!("{}{:010}.jsonl", dir, seq);
+        let request = self
+            .request("PUT", &path)
+            .header("If-None-Match", "*")
+            .header("Content-Type", "application/jsonl")
+            .body(batch);
+        let response = self.send(request)?;
+        match response.status().as_u16() {
+            200..=299 => Ok(()),
+            // Stored by an attempt whose response was lost
+            412 if self.get(&path, 0)? == batch => Ok(()),
+            412 => Err(OmniReaderError::IoError {
+                message: format!("Journal segment {} already exists with other content", path),
+            }),
+            status => Err(status_error("PUT", &path, status)),
+        }
+    }
+
+    fn keyring(&mut self) -> Result<Option<Vec<u8>>, OmniReaderError> {
+        if self.keyring_listed == Some(false) {
+            return Ok(None);
+        }
+        let mut response = self.send(self.request("GET", KEYRING_PATH).body(()))?;
+        match response.status().as_u16() {
+            200..=299 => {}
+            404 => return Ok(None),
+            status => return Err(status_error("GET", KEYRING_PATH, status)),
+        }
+        let keyring = response
+            .body_mut()
+            .read_to_vec()
+            .map_err(|e| OmniReaderError::IoError {
+                message: format!("WebDAV request failed: {}", e),
+            })?;
+        Ok(Some(keyring))
+    }
+
+    fn save_keyring(&mut self, keyring: &[u8], replace: bool) -> Result<bool, OmniReaderError> {
+        self.mkcol("journals/")?;
+        let mut request = self
+            .request("PUT", KEYRING_PATH)
+            .header("Content-Type", "application/json");
+        if !replace {
+            request = request.header("If-None-Match", "*");
+        }
+        let response = self.send(request.body(keyring))?;
+        match response.status().as_u16() {
+            200..=299 => {
+                self.keyring_listed = Some(true);
+                Ok(true)
+            }
+            412 if !replace => {
+                self.keyring_listed = Some(true);
+                Ok(false)
+            }
+            status => Err(status_error("PUT", KEYRING_PATH, status)),
+        }
+    }
+}
+
+/// Members of a `PROPFIND` multistatus response, excluding the collection itself
+fn parse_multistatus(xml: &str, url: &str) -> Result<Vec<Resource>, OmniReaderError> {
+    let doc = roxmltree::Document::parse(xml).map_err(|e| OmniReaderError::ParseError {
+        message: format!("Invalid WebDAV response: {}", e),
+    })?;
+    let dav = |node: &roxmltree::Node, name: &str| {
+        node.is_element()
+            && node.tag_name().name() == name
+            && node.tag_name().namespace() == Some("DAV:")
+    };
+    let this = url_path(url).trim_end_matches('/');
+
+    let mut resources = Vec::new();
+    for response in doc.descendants().filter(|n| dav(n, "response")) {
+        let Some(href) = response
+            .descendants()
+            .find(|n| dav(n, "href"))
+            .and_then(|n| n.text())
+        else {
+            continue;
+        };
+        let path = url_path(href.trim()).trim_end_matches('/');
+        if path == this {
+            continue;
+        }
+        let text = |name: &str| {
+            response
+                .descendants()
+                .find(|n| dav(n, name))
+                .and_then(|n| n.text())
+                .map(|t| t.trim().to_string())
+        };
+        resources.push(Resource {
+            name: path.rsplit('/').next().unwrap_or(path).to_string(),
+            etag: text("getetag"),
+            size: text("getcontentlength")
+                .and_then(|size| size.parse().ok())
+                .unwrap_or(0),
+            collection: response.descendants().any(|n| dav(&n, "collection")),
+        });
+    }
+    Ok(resources)
+}
+
+/// Path part of an absolute URL; other strings are returned as they are
+fn url_path(url: &str) -> &str {
+    match url.find("://") {
+        Some(scheme) => {
+            let rest = &url[scheme + 3..];
+            rest.find('/').map_or("/", |slash| &rest[slash..])
+        }
+        None => url,
+    }
+}
+
+fn status_error(method: &str, path: &str, status: u16) -> OmniReaderError {
+    OmniReaderError::IoError {
+        message: format!("WebDAV {} {} failed with status {}", method, path, status),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_multistatus() {
+        let xml = r#"<?xml version="1.0"?>
+<d:multistatus xmlns:d="DAV:" xmlns:oc="http://owncloud.org/ns">
+  <d:response>
+    <d:href>/remote.php/dav/files/me/OmniReader/journals/</d:href>
+    <d:propstat><d:prop><d:resourcetype><d:collection/></d:resourcetype><d:getetag>"root"</d:getetag></d:prop></d:propstat>
+  </d:response>
+  <d:response>
+    <d:href>/remote.php/dav/files/me/OmniReader/journals/device-a/</d:href>
+    <d:propstat><d:prop><d:resourcetype><d:collection/></d:resourcetype><d:getetag>"a1"</d:getetag></d:prop></d:propstat>
+  </d:response>
+  <d:response>
+    <d:href>https://cloud.example.com/remote.php/dav/files/me/OmniReader/journals/notes.txt</d:href>
+    <d:propstat><d:prop><d:resourcetype/><d:getcontentlength>42</d:getcontentlength></d:prop></d:propstat>
+  </d:response>
+</d:multistatus>"#;
+        let resources = parse_multistatus(
+            xml,
+            "https://cloud.example.com/remote.php/dav/files/me/OmniReader/journals/",
+        )
+        .unwrap();
+        assert_eq!(resources.len(), 2);
+        assert_eq!(resources[0].name, "device-a");
+        assert!(resources[0].collection);
+        assert_eq!(resources[0].etag.as_deref(), Some("\"a1\""));
+        assert_eq!(resources[1].name, "notes.txt");
+        assert_eq!(resources[1].size, 42);
+        assert!(!resources[1].collection);
+    }
+}
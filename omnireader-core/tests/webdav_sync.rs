@@ -0,0 +1,342 @@
+//! WebDAV sync against an in-process stand-in server
+
+use omnireader_core::annotation::HighlightColor;
+use omnireader_core::book::{Book, BookType};
+use omnireader_core::sync::SyncReport;
+use omnireader_core::webdav::{WebDavConfig, sync_with_webdav};
+use omnireader_core::{Annotation, Database, Locator, OmniReaderError};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tiny_http::{Header, Response, Server};
+
+const AUTHORIZATION: &str = "Basic cmVhZGVyOnNlY3JldA=="; // reader:secret
+
+/// A resource of the stand-in server; collections have no content
+struct Resource {
+    content: Option<Vec<u8>>,
+    version: u64,
+}
+
+/// Minimal in-memory WebDAV server: MKCOL, PUT with preconditions, ranged
+/// GET and depth-1 PROPFIND, with collection ETags that change whenever a
+/// member changes (as on Nextcloud)
+#[derive(Default)]
+struct StandIn {
+    resources: Mutex<BTreeMap<String, Resource>>,
+    log: Mutex<Vec<(String, String)>>,
+    /// Journal uploads to reject before accepting them again
+    failing_puts: AtomicUsize,
+}
+
+impl StandIn {
+    fn start() -> (Arc<StandIn>, String) {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let port = server.server_addr().to_ip().unwrap().port();
+        let state = Arc::new(StandIn::default());
+        state.resources.lock().unwrap().insert(
+            "/dav/".to_string(),
+            Resource {
+                content: None,
+                version: 1,
+            },
+        );
+        let handler = state.clone();
+        std::thread::spawn(move || {
+            for request in server.incoming_requests() {
+                handler.handle(request);
+            }
+        });
+        (state, format!("http://127.0.0.1:{}/dav/omnireader", port))
+    }
+
+    fn handle(&self, mut request: tiny_http::Request) {
+        let method = request.method().to_string();
+        let path = request.url().to_string();
+        self.log
+            .lock()
+            .unwrap()
+            .push((method.clone(), path.clone()));
+        let header = |name: &'static str| {
+            request
+                .headers()
+                .iter()
+                .find(|h| h.field.equiv(name))
+                .map(|h| h.value.to_string())
+        };
+        if header("Authorization").as_deref() != Some(AUTHORIZATION) {
+            request.respond(Response::empty(401)).unwrap();
+            return;
+        }
+        let range = header("Range");
+        let if_match = header("If-Match");
+        let if_none_match = header("If-None-Match");
+        let mut body = Vec::new();
+        request.as_reader().read_to_end(&mut body).unwrap();
+
+        let mut resources = self.resources.lock().unwrap();
+        let parent = parent_of(&path);
+        let response = match method.as_str() {
+            "MKCOL" => {
+                if resources.contains_key(&path) {
+                    Response::from_data(Vec::new()).with_status_code(405)
+                } else if !resources.contains_key(&parent) {
+                    Response::from_data(Vec::new()).with_status_code(409)
+                } else {
+                    touch(&mut resources, &path, None);
+                    Response::from_data(Vec::new()).with_status_code(201)
+                }
+            }
+            "PUT" => {
+                let existing = resources.get(&path).map(etag_of);
+                let failing = path.contains("/journals/")
+                    && self
+                        .failing_puts
+                        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                        .is_ok();
+                if failing {
+                    Response::from_data(Vec::new()).with_status_code(503)
+                } else if !resources.contains_key(&parent) {
+                    Response::from_data(Vec::new()).with_status_code(409)
+                } else if (if_none_match.as_deref() == Some("*") && existing.is_some())
+                    || if_match.is_some_and(|etag| existing.as_deref() != Some(etag.as_str()))
+                {
+                    Response::from_data(Vec::new()).with_status_code(412)
+                } else {
+                    touch(&mut resources, &path, Some(body));
+                    Response::from_data(Vec::new()).with_status_code(201)
+                }
+            }
+            "GET" => match resources.get(&path).and_then(|r| r.content.clone()) {
+                None => Response::from_data(Vec::new()).with_status_code(404),
+                Some(content) => match range
+                    .and_then(|r| r.strip_prefix("bytes=")?.strip_suffix('-')?.parse().ok())
+                {
+                    Some(from) if from >= content.len() => {
+                        Response::from_data(Vec::new()).with_status_code(416)
+                    }
+                    Some(from) => {
+                        Response::from_data(content[from..].to_vec()).with_status_code(206)
+                    }
+                    None => Response::from_data(content),
+                },
+            },
+            "PROPFIND" => {
+                if !resources.contains_key(&path) {
+                    Response::from_data(Vec::new()).with_status_code(404)
+                } else {
+                    let mut xml =
+                        String::from(r#"<?xml version="1.0"?><d:multistatus xmlns:d="DAV:">"#);
+                    for (member, resource) in resources
+                        .iter()
+                        .filter(|(p, _)| **p == path || parent_of(p) == path)
+                    {
+                        let (kind, length) = match &resource.content {
+                            None => ("<d:collection/>".to_string(), String::new()),
+                            Some(content) => (
+                                String::new(),
+                                format!(
+                                    "<d:getcontentlength>{}</d:getcontentlength>",
+                                    content.len()
+                                ),
+                            ),
+                        };
+                        xml.push_str(&format!(
+                            "<d:response><d:href>{}</d:href><d:propstat><d:prop><d:resourcetype>{}</d:resourcetype>\
+                             <d:getetag>{}</d:getetag>{}</d:prop><d:status>HTTP/1.1 200 OK</d:status></d:propstat></d:response>",
+                            member,
+                            kind,
+                            etag_of(resource),
+                            length
+                        ));
+                    }
+                    xml.push_str("</d:multistatus>");
+                    Response::from_data(xml.into_bytes())
+                        .with_status_code(207)
+                        .with_header(Header::from_bytes("Content-Type", "application/xml").unwrap())
+                }
+            }
+            _ => Response::from_data(Vec::new()).with_status_code(405),
+        };
+        drop(resources);
+        let _ = request.respond(response);
+    }
+
+    fn take_log(&self) -> Vec<(String, String)> {
+        std::mem::take(&mut *self.log.lock().unwrap())
+    }
+
+    fn content(&self, suffix: &str) -> Option<Vec<u8>> {
+        self.resources
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(path, _)| path.ends_with(suffix))
+            .and_then(|(_, resource)| resource.content.clone())
+    }
+}
+
+fn parent_of(path: &str) -> String {
+    let trimmed = path.trim_end_matches('/');
+    match trimmed.rfind('/') {
+        Some(i) => trimmed[..=i].to_string(),
+        None => "/".to_string(),
+    }
+}
+
+fn etag_of(resource: &Resource) -> String {
+    format!("\"v{}\"", resource.version)
+}
+
+/// Create or update a resource and bump the version of every ancestor collection
+fn touch(resources: &mut BTreeMap<String, Resource>, path: &str, content: Option<Vec<u8>>) {
+    let version = resources.values().map(|r| r.version).max().unwrap_or(0) + 1;
+    resources.insert(path.to_string(), Resource { content, version });
+    let mut parent = parent_of(path);
+    while let Some(collection) = resources.get_mut(&parent) {
+        collection.version = version;
+        if parent == "/" {
+            break;
+        }
+        parent = parent_of(&parent);
+    }
+}
+
+fn config(url: &str, upload_books: bool) -> WebDavConfig {
+    WebDavConfig {
+        url: url.to_string(),
+        username: Some("reader".to_string()),
+        password: Some("secret".to_string()),
+        upload_books,
+        passphrase: None,
+    }
+}
+
+#[test]
+fn test_webdav_sync() {
+    let (server, url) = StandIn::start();
+    let dir = std::env::temp_dir().join(format!("omnireader-webdav-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("dune.epub");
+    std::fs::write(&file, b"epub bytes").unwrap();
+
+    let a = Database::open_in_memory().unwrap();
+    let b = Database::open_in_memory().unwrap();
+    let book = Book::new(
+        "Dune".to_string(),
+        Some("Frank Herbert".to_string()),
+        file.to_string_lossy().to_string(),
+        BookType::Epub,
+        10,
+    );
+    a.insert_book(&book).unwrap();
+    let highlight = Annotation::new_highlight(
+        book.id.clone(),
+        Locator::from_total_progression(0.25),
+        HighlightColor::Yellow,
+        Some("fear is the mind-killer".to_string()),
+    );
+    a.insert_annotation(&highlight).unwrap();
+
+    // An interrupted upload is resumed by the next sync
+    server.failing_puts.store(1, Ordering::SeqCst);
+    assert!(sync_with_webdav(&a, config(&url, true)).is_err());
+    let report = sync_with_webdav(&a, config(&url, true)).unwrap();
+    assert!(report.sent > 0);
+    assert_eq!(report.books_uploaded, 1);
+    assert_eq!(
+        server
+            .content(&format!("/books/{}.epub", book.id))
+            .as_deref(),
+        Some(&b"epub bytes"[..])
+    );
+
+    let report = sync_with_webdav(&b, config(&url, false)).unwrap();
+    assert!(report.received > 0);
+    assert_eq!(b.get_book(&book.id).unwrap().unwrap().title, "Dune");
+    assert_eq!(
+        b.get_annotation(&highlight.id).unwrap(),
+        a.get_annotation(&highlight.id).unwrap()
+    );
+
+    b.update_annotation_note(&highlight.id, Some("Litany".to_string()))
+        .unwrap();
+    sync_with_webdav(&b, config(&url, false)).unwrap();
+    sync_with_webdav(&a, config(&url, false)).unwrap();
+    let merged = a.get_annotation(&highlight.id).unwrap().unwrap();
+    assert_eq!(merged.note_text.as_deref(), Some("Litany"));
+
+    // Unchanged journals are skipped on their collection ETag alone
+    server.take_log();
+    assert_eq!(
+        sync_with_webdav(&a, config(&url, false)).unwrap(),
+        SyncReport::default()
+    );
+    let log = server.take_log();
+    assert!(
+        !log.iter()
+            .any(|(method, _)| method == "GET" || method == "PUT")
+    );
+    assert_eq!(
+        log.iter()
+            .filter(|(method, _)| method == "PROPFIND")
+            .count(),
+        1
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_webdav_sync_encrypted() {
+    let (server, url) = StandIn::start();
+    let dir = std::env::temp_dir().join(format!("omnireader-webdav-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("dune.epub");
+    std::fs::write(&file, b"epub bytes").unwrap();
+    let encrypted = |upload_books: bool, passphrase: &str| WebDavConfig {
+        passphrase: Some(passphrase.to_string()),
+        ..config(&url, upload_books)
+    };
+
+    let a = Database::open_in_memory().unwrap();
+    let book = Book::new(
+        "Dune".to_string(),
+        Some("Frank Herbert".to_string()),
+        file.to_string_lossy().to_string(),
+        BookType::Epub,
+        10,
+    );
+    a.insert_book(&book).unwrap();
+    let report = sync_with_webdav(&a, encrypted(true, "spice")).unwrap();
+    assert_eq!(report.books_uploaded, 1);
+
+    // The server only ever sees ciphertext
+    for (path, resource) in server.resources.lock().unwrap().iter() {
+        let content = resource.content.as_deref().unwrap_or_default();
+        assert!(
+            !content.windows(4).any(|w| w == b"Dune" || w == b"epub"),
+            "plaintext in {}",
+            path
+        );
+    }
+    assert!(server.content("/journals/keyring.json").is_some());
+    // Re-syncing does not upload the sealed book again
+    assert_eq!(
+        sync_with_webdav(&a, encrypted(true, "spice"))
+            .unwrap()
+            .books_uploaded,
+        0
+    );
+
+    let b = Database::open_in_memory().unwrap();
+    assert!(sync_with_webdav(&b, config(&url, false)).is_err());
+    assert!(matches!(
+        sync_with_webdav(&b, encrypted(false, "melange")),
+        Err(OmniReaderError::WrongPassphrase)
+    ));
+    sync_with_webdav(&b, encrypted(false, "spice")).unwrap();
+    assert_eq!(b.get_book(&book.id).unwrap().unwrap().title, "Dune");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}